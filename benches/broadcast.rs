@@ -0,0 +1,110 @@
+//! Broadcast fan-out: shared `Bytes` payload vs a copy per recipient.
+//!
+//! Prints allocations per broadcast for both strategies before timing them;
+//! the shared variant only allocates the queue node per recipient.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ntex::util::Bytes;
+use ntex::ws;
+use websocket_server::config::{HistoryConfig, HubConfig, RoomsConfig};
+use websocket_server::history::History;
+use websocket_server::hub::{ConnStats, Hub, Outbound};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const PAYLOAD_SIZE: usize = 1024;
+
+fn setup(recipients: usize) -> (Hub, Vec<Outbound>) {
+    let hub = Hub::new(
+        &HubConfig {
+            queue_size: usize::MAX,
+            ..HubConfig::default()
+        },
+        RoomsConfig::default(),
+        History::open(&HistoryConfig::default()).unwrap(),
+        None,
+    );
+    let queues = (0..recipients as u64).map(|id| hub.register(id, Arc::new(ConnStats::default()))).collect();
+    (hub, queues)
+}
+
+fn drain(queues: &mut [Outbound]) {
+    for q in queues {
+        while q.rx.try_recv().is_ok() {
+            q.written(1);
+        }
+    }
+}
+
+/// Broadcast by serializing into a fresh buffer for every recipient
+fn broadcast_copied(hub: &Hub, payload: &[u8], recipients: usize) {
+    for id in 0..recipients as u64 {
+        hub.send(id, ws::Message::Binary(Bytes::copy_from_slice(payload)));
+    }
+}
+
+/// Broadcast one shared buffer
+fn broadcast_shared(hub: &Hub, payload: &[u8]) {
+    hub.broadcast_binary(Bytes::copy_from_slice(payload));
+}
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_broadcast(c: &mut Criterion) {
+    let payload = vec![b'x'; PAYLOAD_SIZE];
+    let mut group = c.benchmark_group("broadcast");
+
+    for recipients in [100, 1_000, 10_000] {
+        let (hub, mut queues) = setup(recipients);
+
+        let copied = allocations(|| broadcast_copied(&hub, &payload, recipients));
+        drain(&mut queues);
+        let shared = allocations(|| broadcast_shared(&hub, &payload));
+        drain(&mut queues);
+        println!(
+            "{} recipients: {} allocations copied, {} shared",
+            recipients, copied, shared
+        );
+
+        group.bench_with_input(BenchmarkId::new("copied", recipients), &recipients, |b, &n| {
+            b.iter(|| {
+                broadcast_copied(&hub, &payload, n);
+                drain(&mut queues);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("shared", recipients), &recipients, |b, _| {
+            b.iter(|| {
+                broadcast_shared(&hub, &payload);
+                drain(&mut queues);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_broadcast);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+//! Websocket codec encode/decode.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ntex::codec::{Decoder, Encoder};
+use ntex::util::{Bytes, BytesMut};
+use ntex::ws;
+
+fn bench_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    let server = ws::Codec::new();
+    let client = ws::Codec::new().client_mode();
+
+    for size in [16, 1024, 64 * 1024] {
+        let payload = Bytes::from(vec![0u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &payload, |b, payload| {
+            let mut dst = BytesMut::with_capacity(size + 16);
+            b.iter(|| {
+                dst.clear();
+                server
+                    .encode(ws::Message::Binary(payload.clone()), &mut dst)
+                    .unwrap();
+            })
+        });
+
+        // clients mask their frames, decode the way server receives them
+        let mut frame = BytesMut::new();
+        client
+            .encode(ws::Message::Binary(payload.clone()), &mut frame)
+            .unwrap();
+        let frame = frame.freeze();
+        group.bench_with_input(BenchmarkId::new("decode", size), &frame, |b, frame| {
+            b.iter(|| {
+                let mut src = BytesMut::from(&frame[..]);
+                server.decode(&mut src).unwrap().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_codec);
+criterion_main!(benches);
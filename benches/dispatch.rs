@@ -0,0 +1,54 @@
+//! Frame dispatch through the connection handler.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ntex::util::Bytes;
+use ntex::ws;
+use websocket_server::auth::Authenticator;
+use websocket_server::config::{AuthConfig, HistoryConfig, HubConfig, RoomsConfig};
+use websocket_server::history::History;
+use websocket_server::hub::Hub;
+use websocket_server::session::WsState;
+
+fn bench_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    let mut state = WsState::new(
+        1,
+        None,
+        None,
+        Arc::new(Hub::new(
+            &HubConfig {
+                queue_size: usize::MAX,
+                ..HubConfig::default()
+            },
+            RoomsConfig::default(),
+            History::open(&HistoryConfig::default()).unwrap(),
+            None,
+        )),
+        Arc::new(Authenticator::new(&AuthConfig::default(), None)),
+    );
+
+    for size in [16, 1024, 64 * 1024] {
+        let text = Bytes::from(format!(
+            r#"{{"type":"echo","id":"1","payload":"{}"}}"#,
+            "a".repeat(size)
+        ));
+        let bin = Bytes::from(vec![0u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("text", size), &text, |b, text| {
+            b.iter(|| state.handle_frame(ws::Frame::Text(text.clone())))
+        });
+        group.bench_with_input(BenchmarkId::new("binary", size), &bin, |b, bin| {
+            b.iter(|| state.handle_frame(ws::Frame::Binary(bin.clone())))
+        });
+    }
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("ping", |b| {
+        b.iter(|| state.handle_frame(ws::Frame::Ping(Bytes::new())))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_dispatch);
+criterion_main!(benches);
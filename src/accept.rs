@@ -0,0 +1,101 @@
+//! Rate limiting of websocket handshakes.
+//!
+//! After a deploy every client reconnects at once; without a limit the
+//! handshakes (auth, session setup) of such a storm compete with live
+//! traffic. New upgrades are limited per client IP and across the server,
+//! each a token bucket with a burst, and refused with 429 and `Retry-After`
+//! so well-behaved clients back off instead of retrying at once. Limiting
+//! happens at the upgrade request, a TLS handshake has already been done.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::AcceptConfig;
+use crate::metrics::METRICS;
+
+/// Per IP buckets kept before idle ones are dropped
+const PRUNE_AT: usize = 4096;
+
+/// Token bucket as GCRA: the time the bucket is full again
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Time one token takes to come back
+    interval: Duration,
+    /// Time the whole burst takes to come back
+    burst: Duration,
+}
+
+impl Bucket {
+    /// `None` if `per_sec` is 0 (unlimited)
+    fn new(per_sec: f64, burst: u32) -> Option<Bucket> {
+        if per_sec <= 0.0 {
+            return None;
+        }
+        let interval = Duration::from_secs_f64(1.0 / per_sec);
+        Some(Bucket {
+            interval,
+            burst: interval * burst.max(1),
+        })
+    }
+
+    /// New `tat` if a token is available, else how long until one is
+    fn take(&self, tat: Instant, now: Instant) -> Result<Instant, Duration> {
+        let tat = tat.max(now) + self.interval;
+        match tat.checked_duration_since(now + self.burst) {
+            Some(wait) if !wait.is_zero() => Err(wait),
+            _ => Ok(tat),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AcceptLimiter {
+    per_ip: Option<Bucket>,
+    global: Option<Bucket>,
+    ips: Mutex<HashMap<IpAddr, Instant>>,
+    all: Mutex<Instant>,
+}
+
+impl AcceptLimiter {
+    pub fn new(config: &AcceptConfig) -> AcceptLimiter {
+        AcceptLimiter {
+            per_ip: Bucket::new(config.per_ip_per_sec, config.per_ip_burst),
+            global: Bucket::new(config.per_sec, config.burst),
+            ips: Mutex::new(HashMap::new()),
+            all: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Admit a handshake from `ip`, else when to retry; a refused handshake
+    /// uses up neither limit
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+        let per_ip = match (self.per_ip, ip) {
+            (Some(bucket), Some(ip)) => {
+                let tat = ips.get(&ip).copied().unwrap_or(now);
+                Some((ip, bucket.take(tat, now).map_err(|wait| self.refuse(wait))?))
+            }
+            _ => None,
+        };
+        if let Some(bucket) = self.global {
+            let mut all = self.all.lock().unwrap();
+            *all = bucket.take(*all, now).map_err(|wait| self.refuse(wait))?;
+        }
+        if let Some((ip, tat)) = per_ip {
+            if ips.len() >= PRUNE_AT {
+                // full again, the same as no entry
+                ips.retain(|_, tat| *tat > now);
+            }
+            ips.insert(ip, tat);
+        }
+        Ok(())
+    }
+
+    fn refuse(&self, wait: Duration) -> Duration {
+        METRICS.handshakes_throttled_total.inc();
+        wait
+    }
+}
@@ -0,0 +1,291 @@
+//! Access log, one line per HTTP request and per websocket session.
+//!
+//! Lines go to stdout or `access_log.file`, apart from the application log
+//! and its filter. The `combined` format is Apache's with the request time
+//! in seconds appended; websocket sessions are logged as a `WS` request
+//! whose status is the close code (`-` if the connection dropped) and whose
+//! size is the payload bytes sent, followed by frame and byte counts. `json`
+//! writes one object per line, `"kind": "http"` or `"kind": "ws"`.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{future::Future, io, pin::Pin};
+
+use ntex::http::body::{BodySize, MessageBody};
+use ntex::http::{header, StatusCode};
+use ntex::service::{Service, Transform};
+use ntex::web::{WebRequest, WebResponse};
+use serde_json::json;
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::events::{Event, Subscriber};
+use crate::hub::ConnStats;
+use crate::logging::DateTime;
+use crate::rolling::SharedFile;
+
+static FORMAT: OnceLock<AccessLogFormat> = OnceLock::new();
+
+/// Log file instead of stdout, if configured
+static FILE: OnceLock<SharedFile> = OnceLock::new();
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Set format and output, before the server starts
+pub fn init(config: &AccessLogConfig) -> io::Result<()> {
+    if let Some(ref file) = config.file {
+        let file = SharedFile::open(file)
+            .map_err(|e| io::Error::new(e.kind(), format!("access_log.file: {}", e)))?;
+        let _ = FILE.set(file);
+    }
+    let _ = FORMAT.set(config.format);
+    Ok(())
+}
+
+fn format() -> AccessLogFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+fn write(line: &str) {
+    let line = format!("{}\n", line);
+    match FILE.get() {
+        Some(file) => {
+            let _ = file.clone().write_all(line.as_bytes());
+        }
+        None => {
+            let _ = io::stdout().lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_time(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let t = DateTime::from_unix(secs);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        t.day,
+        MONTHS[(t.month - 1) as usize],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+/// `2000-10-10T13:55:36.123Z`
+fn iso_time(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let t = DateTime::from_unix(since.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        t.year,
+        t.month,
+        t.day,
+        t.hour,
+        t.minute,
+        t.second,
+        since.subsec_millis()
+    )
+}
+
+/// Quote a value for the combined format
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.escape_default()),
+        None => "\"-\"".to_string(),
+    }
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+/// What is logged of a request before it is handled
+struct Request {
+    at: SystemTime,
+    start: Instant,
+    peer: Option<SocketAddr>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    upgrade: bool,
+}
+
+impl Request {
+    fn new<E>(req: &WebRequest<E>) -> Request {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Request {
+            at: SystemTime::now(),
+            start: Instant::now(),
+            peer: req.peer_addr(),
+            method: req.method().to_string(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_string(), |p| p.to_string()),
+            version: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            upgrade: req.head().upgrade(),
+        }
+    }
+
+    fn log(&self, status: u16, size: Option<u64>) {
+        let elapsed = self.start.elapsed();
+        let line = match format() {
+            AccessLogFormat::Off => return,
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} {} {} {:.6}",
+                or_dash(self.peer.map(|p| p.ip())),
+                clf_time(self.at),
+                self.method,
+                self.target.escape_default(),
+                self.version,
+                status,
+                or_dash(size),
+                quoted(self.referer.as_deref()),
+                quoted(self.user_agent.as_deref()),
+                elapsed.as_secs_f64()
+            ),
+            AccessLogFormat::Json => json!({
+                "kind": "http",
+                "time": iso_time(self.at),
+                "remote": self.peer.map(|p| p.ip().to_string()),
+                "method": self.method,
+                "target": self.target,
+                "version": self.version,
+                "status": status,
+                "bytes": size,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_ms": elapsed.as_secs_f64() * 1000.0,
+            })
+            .to_string(),
+        };
+        write(&line);
+    }
+}
+
+/// Logs websocket sessions when they end
+#[derive(Debug, Default)]
+pub struct SessionLog;
+
+impl Subscriber for SessionLog {
+    fn event(&self, event: &Event<'_>) {
+        if let Event::Disconnected { id, peer, stats } = *event {
+            session(id, peer, stats);
+        }
+    }
+}
+
+/// Log a websocket session when it ends
+fn session(id: u64, peer: Option<SocketAddr>, stats: &ConnStats) {
+    let duration = stats.age();
+    let at = SystemTime::now() - duration;
+    let (frames_in, bytes_in) = stats.received_totals();
+    let (frames_out, bytes_out) = stats.sent_totals();
+    let line = match format() {
+        AccessLogFormat::Off => return,
+        AccessLogFormat::Combined => format!(
+            "{} - {} [{}] \"WS /ws#{}\" {} {} \"-\" \"-\" {:.6} frames_in={} bytes_in={} frames_out={}",
+            or_dash(peer.map(|p| p.ip())),
+            or_dash(stats.user().map(|u| u.escape_default())),
+            clf_time(at),
+            id,
+            or_dash(stats.close_code()),
+            bytes_out,
+            duration.as_secs_f64(),
+            frames_in,
+            bytes_in,
+            frames_out
+        ),
+        AccessLogFormat::Json => json!({
+            "kind": "ws",
+            "time": iso_time(at),
+            "remote": peer.map(|p| p.ip().to_string()),
+            "id": id,
+            "user": stats.user(),
+            "tenant": stats.tenant(),
+            "duration_ms": duration.as_secs_f64() * 1000.0,
+            "frames_in": frames_in,
+            "bytes_in": bytes_in,
+            "frames_out": frames_out,
+            "bytes_out": bytes_out,
+            "close_code": stats.close_code(),
+        })
+        .to_string(),
+    };
+    write(&line);
+}
+
+/// Middleware logging every request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessLog;
+
+impl<S> Transform<S> for AccessLog {
+    type Service = AccessLogMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        AccessLogMiddleware { service }
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, E> Service<WebRequest<E>> for AccessLogMiddleware<S>
+where
+    S: Service<WebRequest<E>, Response = WebResponse>,
+    S::Future: 'static,
+{
+    type Response = WebResponse;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: WebRequest<E>) -> Self::Future {
+        if format() == AccessLogFormat::Off {
+            return Box::pin(self.service.call(req));
+        }
+        let request = Request::new(&req);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            // streamed bodies are not wrapped to count them, unknown size
+            let size = match res.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                BodySize::Empty | BodySize::None => Some(0),
+                BodySize::Stream => None,
+            };
+            // a websocket upgrade writes its 101 itself and answers 200 here
+            let status = match res.status() {
+                StatusCode::OK if request.upgrade => StatusCode::SWITCHING_PROTOCOLS,
+                status => status,
+            };
+            request.log(status.as_u16(), size);
+            Ok(res)
+        })
+    }
+}
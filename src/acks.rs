@@ -0,0 +1,313 @@
+//! Room messages whose publisher waits for the members to acknowledge them.
+//!
+//! A `room.publish` with `ack`, or `POST /admin/rooms/{room}/publish` with
+//! one, sends `room.message` with `"ack": true` to the members of the room
+//! on this node, who answer with `room.ack` naming `room` and `seq`:
+//!
+//! ```json
+//! {"type": "room.publish", "id": "5", "payload": {"room": "ops", "data": {"cmd": "reload"}, "ack": {"quorum": 100, "timeout_ms": 5000}}}
+//! {"type": "room.publish", "id": "5", "payload": {"room": "ops", "seq": 12}}
+//! {"type": "room.message", "payload": {"room": "ops", "seq": 12, "from": 17, "data": {"cmd": "reload"}, "ack": true}}
+//! {"type": "room.ack", "payload": {"room": "ops", "seq": 12}}
+//! {"type": "room.acked", "id": "5", "payload": {"room": "ops", "seq": 12, "members": 3, "acked": 3, "complete": true, "missing": []}}
+//! ```
+//!
+//! The report comes once `quorum` percent of the members acknowledged, or
+//! when `timeout_ms` passed with `complete` false, naming the members that
+//! did not. Members that left or closed meanwhile count as missing.
+//!
+//! With `"exactly_once": true` the `room.message` says so too, and the
+//! member's `room.ack` is answered with `room.release`, which the member
+//! answers with `room.complete`:
+//!
+//! ```json
+//! {"type": "room.message", "payload": {"room": "ops", "seq": 12, "from": 17, "data": {"cmd": "reload"}, "ack": true, "exactly_once": true}}
+//! {"type": "room.ack", "payload": {"room": "ops", "seq": 12}}
+//! {"type": "room.release", "payload": {"room": "ops", "seq": 12}}
+//! {"type": "room.complete", "payload": {"room": "ops", "seq": 12}}
+//! ```
+//!
+//! The member keeps the `room` and `seq` of the messages it acknowledged
+//! until they are released, and only hands on one it does not have. The
+//! server keeps each message until its `room.complete`; with `[store]` the
+//! unfinished ones go with the resumption state of a closed connection, and
+//! `session.resume` sends them again, with `"dup": true`, or their
+//! `room.release`. A member with `MAX_UNRELEASED` unfinished messages is not
+//! sent more and counts as missing.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use ntex::time;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Time to wait for acknowledgments unless `timeout_ms` says otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest time to wait for acknowledgments
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Exactly-once messages a member may have unfinished
+pub const MAX_UNRELEASED: usize = 256;
+
+/// What a publisher asks for
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AckRequest {
+    /// Percent of the members to wait for, 100 for all
+    #[serde(default = "default_quorum")]
+    pub quorum: u8,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Deliver it once only, with the `room.release` handshake
+    #[serde(default)]
+    pub exactly_once: bool,
+}
+
+fn default_quorum() -> u8 {
+    100
+}
+
+impl AckRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.quorum {
+            1..=100 => Ok(()),
+            q => Err(format!("ack.quorum must be 1 to 100 percent, not {}", q)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout_ms
+            .map_or(DEFAULT_TIMEOUT, Duration::from_millis)
+            .min(MAX_TIMEOUT)
+    }
+}
+
+/// Member that did not acknowledge in time
+#[derive(Debug, Clone, Serialize)]
+pub struct Missing {
+    pub connection_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// Outcome of a message's acknowledgments
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub room: String,
+    pub seq: u64,
+    /// Members the message was sent to
+    pub members: usize,
+    pub acked: usize,
+    /// Whether the quorum was reached
+    pub complete: bool,
+    pub missing: Vec<Missing>,
+}
+
+/// Exactly-once message whose handshake with a member is not over
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub room: String,
+    pub seq: u64,
+    pub from: u64,
+    pub data: Value,
+    /// Whether the member acknowledged it, so only its release is due
+    pub received: bool,
+}
+
+/// Exactly-once messages of a member, by room and sequence number
+type Unfinished = BTreeMap<(String, u64), Delivery>;
+
+#[derive(Debug)]
+struct Tracking {
+    members: BTreeSet<u64>,
+    acked: BTreeSet<u64>,
+    /// Acknowledgments making the quorum
+    needed: usize,
+    reached: Option<oneshot::Sender<()>>,
+    /// Taken by `wait`
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+/// Messages waiting for acknowledgments, by room and sequence number
+#[derive(Debug, Default)]
+pub struct Acks {
+    pending: Mutex<HashMap<(String, u64), Tracking>>,
+    /// Unfinished exactly-once messages, by member
+    deliveries: Mutex<HashMap<u64, Unfinished>>,
+}
+
+impl Acks {
+    /// Wait for `quorum` percent of `members` to acknowledge message `seq`
+    /// of `room`; before it is sent, so no acknowledgment comes too early
+    pub fn track(&self, room: &str, seq: u64, members: &[u64], quorum: u8) {
+        let (tx, rx) = oneshot::channel();
+        let needed = (members.len() * quorum as usize).div_ceil(100);
+        let mut tracking = Tracking {
+            members: members.iter().copied().collect(),
+            acked: BTreeSet::new(),
+            needed,
+            reached: Some(tx),
+            rx: Some(rx),
+        };
+        if needed == 0 {
+            tracking.reach();
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert((room.to_string(), seq), tracking);
+    }
+
+    /// Note that member `conn` acknowledged message `seq` of `room`,
+    /// returns `false` if no one waits for it from `conn`
+    pub fn ack(&self, conn: u64, room: &str, seq: u64) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(tracking) = pending.get_mut(&(room.to_string(), seq)) else {
+            return false;
+        };
+        if !tracking.members.contains(&conn) || !tracking.acked.insert(conn) {
+            return false;
+        }
+        if tracking.acked.len() >= tracking.needed {
+            tracking.reach();
+        }
+        true
+    }
+
+    /// Wait until the quorum of message `seq` of `room` is reached or
+    /// `timeout` passed, then report on it; `user` names a member's user
+    pub async fn wait(
+        &self,
+        room: &str,
+        seq: u64,
+        timeout: Duration,
+        user: impl Fn(u64) -> Option<String>,
+    ) -> Report {
+        let key = (room.to_string(), seq);
+        let rx = self
+            .pending
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(|tracking| tracking.rx.take());
+        if let Some(rx) = rx {
+            let _ = time::timeout(timeout, rx).await;
+        }
+        let tracking = self.pending.lock().unwrap().remove(&key);
+        let Some(tracking) = tracking else {
+            return Report {
+                room: key.0,
+                seq,
+                members: 0,
+                acked: 0,
+                complete: true,
+                missing: Vec::new(),
+            };
+        };
+        let missing = tracking
+            .members
+            .difference(&tracking.acked)
+            .map(|id| Missing {
+                connection_id: *id,
+                user: user(*id),
+            })
+            .collect();
+        Report {
+            room: key.0,
+            seq,
+            members: tracking.members.len(),
+            acked: tracking.acked.len(),
+            complete: tracking.acked.len() >= tracking.needed,
+            missing,
+        }
+    }
+
+    /// Keep exactly-once message `delivery` until each of `members`
+    /// completes it; returns the members it may be sent to, those without
+    /// `MAX_UNRELEASED` unfinished ones
+    pub fn deliver(&self, members: &[u64], delivery: &Delivery) -> Vec<u64> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let key = (delivery.room.clone(), delivery.seq);
+        members
+            .iter()
+            .copied()
+            .filter(|member| {
+                let unfinished = deliveries.entry(*member).or_default();
+                if unfinished.len() >= MAX_UNRELEASED {
+                    return false;
+                }
+                unfinished.insert(key.clone(), delivery.clone());
+                true
+            })
+            .collect()
+    }
+
+    /// Note that member `conn` received exactly-once message `seq` of
+    /// `room`, returns `false` if it has no such message to be released
+    pub fn receive(&self, conn: u64, room: &str, seq: u64) -> bool {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let delivery = deliveries
+            .get_mut(&conn)
+            .and_then(|unfinished| unfinished.get_mut(&(room.to_string(), seq)));
+        match delivery {
+            Some(delivery) => {
+                delivery.received = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget exactly-once message `seq` of `room` completed by member
+    /// `conn`, returns `false` if it was not released to it
+    pub fn complete(&self, conn: u64, room: &str, seq: u64) -> bool {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let Some(unfinished) = deliveries.get_mut(&conn) else {
+            return false;
+        };
+        let key = (room.to_string(), seq);
+        if !unfinished
+            .get(&key)
+            .is_some_and(|delivery| delivery.received)
+        {
+            return false;
+        }
+        unfinished.remove(&key);
+        if unfinished.is_empty() {
+            deliveries.remove(&conn);
+        }
+        true
+    }
+
+    /// Take the unfinished exactly-once messages of closed connection `conn`
+    pub fn closed(&self, conn: u64) -> Vec<Delivery> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries
+            .remove(&conn)
+            .map(|unfinished| unfinished.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Give connection `conn` the unfinished exactly-once messages of the
+    /// session it resumed
+    pub fn resumed(&self, conn: u64, resumed: &[Delivery]) {
+        if resumed.is_empty() {
+            return;
+        }
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let unfinished = deliveries.entry(conn).or_default();
+        for delivery in resumed {
+            let key = (delivery.room.clone(), delivery.seq);
+            unfinished.insert(key, delivery.clone());
+        }
+    }
+}
+
+impl Tracking {
+    fn reach(&mut self) {
+        if let Some(tx) = self.reached.take() {
+            let _ = tx.send(());
+        }
+    }
+}
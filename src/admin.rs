@@ -0,0 +1,1356 @@
+//! Admin API, mounted under `/admin`.
+//!
+//! Every route requires `Authorization: Bearer <admin.token>`, the read-only
+//! dashboard routes also take it from the dashboard's cookie.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use ntex::http::header;
+use ntex::util::Bytes;
+use ntex::web::{self, types::Json, types::Query, types::State, ws, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::acks::AckRequest;
+use crate::archive::Archive;
+use crate::auth;
+use crate::config::{Config, JobConfig};
+use crate::history::{Fetch, Search};
+use crate::hub::{Hub, Metadata, Target};
+use crate::jobs::{self, JobError};
+use crate::kv::{KvError, Scope};
+use crate::labels::Selector;
+use crate::logging::{self, LogLevels};
+use crate::pipeline::{self, ActivateError};
+use crate::reconnect::Cause;
+use crate::record::{self, RecordError};
+use crate::rooms::{Moderation, RoomError};
+use crate::rpc::{self, CallError};
+use crate::{
+    bandwidth, bans, dashboard, flags, graphql, ipfilter, profiling, protocol, reconnect,
+    reporting, tap, tasks, utf8,
+};
+
+/// Cookie the dashboard login stores the admin token in
+const DASHBOARD_COOKIE: &str = "wss_admin";
+
+/// Header of a broadcast naming its ordering key
+const ORDERING_KEY: &str = "x-ordering-key";
+
+/// Register admin routes
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .service(
+                web::resource("/log-level")
+                    .route(web::get().to(get_log_level))
+                    .route(web::put().to(put_log_level)),
+            )
+            .service(web::resource("/profile").route(web::get().to(get_profile)))
+            .service(web::resource("/tasks").route(web::get().to(get_tasks)))
+            .service(web::resource("/connections").route(web::get().to(get_connections)))
+            .service(web::resource("/connections/{id}").route(web::delete().to(delete_connection)))
+            .service(web::resource("/bandwidth").route(web::get().to(get_bandwidth)))
+            .service(web::resource("/connections/{id}/call").route(web::post().to(post_call)))
+            .service(web::resource("/connections/{id}/tap").route(web::get().to(tap::index)))
+            .service(
+                web::resource("/connections/{id}/record")
+                    .route(web::post().to(post_record))
+                    .route(web::delete().to(delete_record)),
+            )
+            .service(web::resource("/presence/{user}").route(web::get().to(get_presence)))
+            .service(
+                web::resource("/presence/{user}/last-seen").route(web::get().to(get_last_seen)),
+            )
+            .service(
+                web::resource("/bans")
+                    .route(web::get().to(get_bans))
+                    .route(web::post().to(post_ban)),
+            )
+            .service(web::resource("/bans/{user}").route(web::delete().to(delete_ban)))
+            .service(
+                web::resource("/ip-rules")
+                    .route(web::get().to(get_ip_rules))
+                    .route(web::post().to(post_ip_rule))
+                    .route(web::delete().to(delete_ip_rule)),
+            )
+            .service(web::resource("/cluster").route(web::get().to(get_cluster)))
+            .service(web::resource("/cluster/drain").route(web::post().to(post_cluster_drain)))
+            .service(web::resource("/rooms").route(web::get().to(get_rooms)))
+            .service(web::resource("/rooms/{room}/invites").route(web::post().to(post_invite)))
+            .service(web::resource("/rooms/{room}/migrate").route(web::post().to(post_migrate)))
+            .service(web::resource("/rooms/{room}/publish").route(web::post().to(post_publish)))
+            .service(web::resource("/rooms/{room}/receipts").route(web::get().to(get_receipts)))
+            .service(
+                web::resource("/rooms/{room}/moderation").route(web::post().to(post_moderation)),
+            )
+            .service(web::resource("/history/archive").route(web::get().to(get_history_archive)))
+            .service(web::resource("/history/search").route(web::get().to(get_history_search)))
+            .service(web::resource("/jobs").route(web::get().to(get_jobs)))
+            .service(
+                web::resource("/jobs/{name}")
+                    .route(web::put().to(put_job))
+                    .route(web::delete().to(delete_job)),
+            )
+            .service(web::resource("/kv").route(web::get().to(get_kv_scopes)))
+            .service(web::resource("/kv/{scope}").route(web::get().to(get_kv)))
+            .service(
+                web::resource("/kv/{scope}/{key}")
+                    .route(web::put().to(put_kv))
+                    .route(web::delete().to(delete_kv)),
+            )
+            .service(web::resource("/crdts").route(web::get().to(get_crdts)))
+            .service(web::resource("/locks").route(web::get().to(get_locks)))
+            .service(web::resource("/locks/{scope}/{name}").route(web::delete().to(delete_lock)))
+            .service(web::resource("/errors").route(web::get().to(get_errors)))
+            .service(web::resource("/graphql").route(web::post().to(graphql::post)))
+            .service(web::resource("/dashboard").route(web::get().to(dashboard::page)))
+            .service(web::resource("/stream").route(web::get().to(dashboard::stream)))
+            .service(
+                web::resource("/flags")
+                    .route(web::get().to(get_flags))
+                    .route(web::put().to(put_flags)),
+            )
+            .service(web::resource("/pipelines").route(web::get().to(get_pipelines)))
+            .service(web::resource("/pipelines/active").route(web::put().to(put_active_pipeline)))
+            .service(web::resource("/broadcast").route(web::post().to(post_broadcast)))
+            .service(web::resource("/drain").route(web::post().to(post_drain))),
+    );
+}
+
+/// Check admin bearer token, returns error response if request is not allowed
+pub(crate) fn authorize(req: &HttpRequest, config: &Config) -> Result<(), HttpResponse> {
+    check_token(req, config, false)
+}
+
+/// Like `authorize`, also accepts the dashboard cookie. Only for read-only
+/// routes, a cookie is sent along with cross-site requests too.
+pub(crate) fn authorize_dashboard(req: &HttpRequest, config: &Config) -> Result<(), HttpResponse> {
+    check_token(req, config, true)
+}
+
+fn check_token(req: &HttpRequest, config: &Config, cookie: bool) -> Result<(), HttpResponse> {
+    let token = match config.admin.token {
+        Some(ref token) => token,
+        None => return Err(HttpResponse::Forbidden().body("admin api is disabled")),
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let provided = match provided {
+        None if cookie => auth::cookie(req, DASHBOARD_COOKIE).map(|v| percent_decode(&v)),
+        provided => provided,
+    };
+    if auth::secret_matches(provided.as_deref(), token) {
+        Ok(())
+    } else {
+        Err(HttpResponse::Unauthorized().finish())
+    }
+}
+
+/// Undo `encodeURIComponent` of the dashboard login
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// `GET /admin/log-level`
+async fn get_log_level(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match logging::levels() {
+        Some(levels) => HttpResponse::Ok().json(&levels),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// `PUT /admin/log-level`
+///
+/// Body: `{"level": "info", "modules": {"ntex::ws": "trace"}}`
+async fn put_log_level(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    levels: Json<LogLevels>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let levels = levels.into_inner();
+    log::info!("Log levels changed to `{}`", levels);
+    logging::set_levels(levels.clone());
+    HttpResponse::Ok().json(&levels)
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileQuery {
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+    #[serde(default = "default_profile_format")]
+    format: profiling::Format,
+}
+
+fn default_profile_seconds() -> u64 {
+    10
+}
+
+fn default_profile_format() -> profiling::Format {
+    profiling::Format::Flamegraph
+}
+
+/// `GET /admin/profile?seconds=10&format=flamegraph|pprof`
+///
+/// Samples the process CPU for the requested time (capped at 60s).
+async fn get_profile(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    query: Query<ProfileQuery>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let duration = Duration::from_secs(query.seconds);
+    if duration.is_zero() || duration > profiling::MAX_DURATION {
+        return HttpResponse::BadRequest().body("seconds must be within 1..=60");
+    }
+    log::info!("Capturing {}s CPU profile", query.seconds);
+    match profiling::capture(duration, query.format).await {
+        // idle process, nothing was sampled
+        Ok(body) if body.is_empty() => HttpResponse::NoContent().finish(),
+        Ok(body) => HttpResponse::Ok()
+            .content_type(query.format.content_type())
+            .body(body),
+        // only one profiler may run at a time
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+/// `GET /admin/tasks`
+///
+/// Internal tasks with their state, queue depth and time since last activity.
+async fn get_tasks(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&tasks::list())
+}
+
+/// `GET /admin/connections`
+async fn get_connections(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&hub.connections())
+}
+
+#[derive(Debug, Deserialize)]
+struct BandwidthQuery {
+    #[serde(default = "default_bandwidth_limit")]
+    limit: usize,
+}
+
+fn default_bandwidth_limit() -> usize {
+    20
+}
+
+/// `GET /admin/bandwidth?limit=20`
+///
+/// Bytes in and out by user and tenant, open and closed connections
+/// together, most first.
+async fn get_bandwidth(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    query: Query<BandwidthQuery>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&bandwidth::report(&hub.connections(), query.limit))
+}
+
+/// `GET /admin/presence/{user}`, the user's sessions on every instance
+/// sharing the session store; 404 without `[store]`
+async fn get_presence(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    user: web::types::Path<String>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let Some(store) = hub.store() else {
+        return HttpResponse::NotFound().body("sessions are not stored");
+    };
+    match store.presence(&user).await {
+        Ok(sessions) => HttpResponse::Ok().json(&sessions),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LastSeenQuery {
+    #[serde(default = "default_transitions_limit")]
+    transitions: usize,
+}
+
+fn default_transitions_limit() -> usize {
+    20
+}
+
+/// `GET /admin/presence/{user}/last-seen?transitions=20`
+///
+/// Whether the user is online, when they were last seen and their latest
+/// presence changes; 404 without `[store.last_seen]`.
+async fn get_last_seen(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    user: web::types::Path<String>,
+    query: Query<LastSeenQuery>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let Some(store) = hub.store().filter(|store| store.records_last_seen()) else {
+        return HttpResponse::NotFound().body("last-seen times are not recorded");
+    };
+    let result = async {
+        let seen = store.last_seen(&user).await?;
+        let transitions = store.transitions(&user, query.transitions).await?;
+        Ok::<_, String>((seen, transitions))
+    };
+    match result.await {
+        Ok((seen, transitions)) => HttpResponse::Ok().json(&serde_json::json!({
+            "user": user.as_str(),
+            "online": seen.online,
+            "last_seen": seen.last_seen,
+            "transitions": transitions,
+        })),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+/// `GET /admin/rooms`
+async fn get_rooms(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&hub.rooms().list())
+}
+
+/// `GET /admin/cluster`, 404 unless in cluster mode
+async fn get_cluster(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match hub.cluster() {
+        Some(cluster) => HttpResponse::Ok().json(&serde_json::json!({
+            "node": cluster.node(),
+            "members": cluster.members(),
+            "moves": cluster.moves(),
+        })),
+        None => HttpResponse::NotFound().body("cluster mode is off"),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MigrateRequest {
+    #[serde(default)]
+    node: Option<String>,
+}
+
+/// `POST /admin/rooms/{room}/migrate`, on the room's owner
+///
+/// Body (optional): `{"node": "b"}`, by default the node the ring gives the
+/// room without this one. The members stay connected.
+async fn post_migrate(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    room: web::types::Path<String>,
+    body: Bytes,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let request = if body.is_empty() {
+        MigrateRequest::default()
+    } else {
+        match serde_json::from_slice::<MigrateRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        }
+    };
+    let Some(cluster) = hub.cluster() else {
+        return HttpResponse::NotFound().body("cluster mode is off");
+    };
+    match cluster.migrate(&hub, &room, request.node.as_deref()) {
+        Ok(node) => HttpResponse::Ok().json(&serde_json::json!({ "room": *room, "node": node })),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+/// `POST /admin/cluster/drain`
+///
+/// Hands every room this node owns over to the other nodes, e.g. before
+/// taking it down.
+async fn post_cluster_drain(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let Some(cluster) = hub.cluster() else {
+        return HttpResponse::NotFound().body("cluster mode is off");
+    };
+    match cluster.drain_rooms(&hub) {
+        Ok(moved) => {
+            let moved: Vec<_> = moved
+                .into_iter()
+                .map(|(room, node)| serde_json::json!({ "room": room, "node": node }))
+                .collect();
+            HttpResponse::Ok().json(&serde_json::json!({ "moved": moved }))
+        }
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct InviteRequest {
+    ttl_secs: u64,
+    max_uses: u32,
+}
+
+impl Default for InviteRequest {
+    fn default() -> Self {
+        InviteRequest {
+            ttl_secs: 86400,
+            max_uses: 1,
+        }
+    }
+}
+
+/// `POST /admin/rooms/{room}/invites`
+///
+/// Body (optional): `{"ttl_secs": 86400, "max_uses": 1}`, answered with the
+/// invite token and its expiry.
+async fn post_invite(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    room: web::types::Path<String>,
+    body: Bytes,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let request = if body.is_empty() {
+        InviteRequest::default()
+    } else {
+        match serde_json::from_slice::<InviteRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        }
+    };
+    let ttl = Duration::from_secs(request.ttl_secs);
+    match hub.rooms().invite(&room, ttl, request.max_uses) {
+        Ok((token, invite)) => {
+            log::info!("Invite {} to room {} minted", invite.jti, room);
+            HttpResponse::Created().json(&serde_json::json!({
+                "token": token,
+                "expires_at": invite.exp,
+                "max_uses": invite.max_uses,
+            }))
+        }
+        Err(RoomError::InvitesDisabled) => {
+            HttpResponse::Forbidden().body("room invites are disabled")
+        }
+        Err(e) => HttpResponse::BadRequest().body(protocol::Error::from(e).message),
+    }
+}
+
+/// `GET /admin/rooms/{room}/receipts`
+async fn get_receipts(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    room: web::types::Path<String>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match hub.rooms().receipts(None, &room) {
+        Ok(receipts) => HttpResponse::Ok().json(&receipts),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// `POST /admin/rooms/{room}/moderation`
+///
+/// Body: a `room.moderate` action, e.g. `{"action": "mute", "user": "alice"}`.
+async fn post_moderation(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    room: web::types::Path<String>,
+    action: Json<Moderation>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let action = action.into_inner();
+    log::info!("Room {} moderated: {:?}", room, action);
+    match hub.moderate(None, &room, action) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(RoomError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(protocol::Error::from(e).message),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    ack: Option<AckRequest>,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// `POST /admin/rooms/{room}/publish`
+///
+/// Body: `{"data": ..}`, sent to the members as `room.message` from
+/// connection 0 and answered with its `{"room", "seq"}`, the room being the
+/// partition of `key` for a partitioned room. With an `ack` request it is
+/// answered with the acknowledgment report once it is in, see `acks`. 404
+/// if the room does not exist, 409 if another cluster node owns it.
+async fn post_publish(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    room: web::types::Path<String>,
+    publish: Json<PublishRequest>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let PublishRequest { data, ack, key } = publish.into_inner();
+    if let Some(Err(e)) = ack.as_ref().map(AckRequest::validate) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let room = hub.rooms().route(&room, key.as_deref());
+    let seq = match hub.post(&room, &data, ack.as_ref()) {
+        Ok(seq) => seq,
+        Err(RoomError::NotFound) => return HttpResponse::NotFound().finish(),
+        Err(RoomError::NotOwner) => {
+            return HttpResponse::Conflict().body("room is owned by another node")
+        }
+        Err(e) => return HttpResponse::BadRequest().body(protocol::Error::from(e).message),
+    };
+    let Some(ack) = ack else {
+        return HttpResponse::Ok().json(&serde_json::json!({ "room": room, "seq": seq }));
+    };
+    let report = hub
+        .acks()
+        .wait(&room, seq, ack.timeout(), |member| hub.user(member))
+        .await;
+    HttpResponse::Ok().json(&report)
+}
+
+/// `GET /admin/history/search?query=..&room=..&since=..&until=..&limit=..&offset=..`
+///
+/// Like `history.search`, over all rooms.
+async fn get_history_search(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    search: Query<Search>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match hub.rooms().search(None, &search) {
+        Ok(page) => HttpResponse::Ok().json(&page),
+        Err(e) => HttpResponse::BadRequest().body(protocol::Error::from(e).message),
+    }
+}
+
+/// `GET /admin/history/archive?room=..&before=..&after=..&limit=..`
+///
+/// Like `history.fetch`, over the archived messages of a room; 404 if no
+/// archive is configured, 502 if the bucket cannot be read.
+async fn get_history_archive(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    archive: State<Option<Arc<Archive>>>,
+    fetch: Query<Fetch>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let archive = match *archive {
+        Some(ref archive) => archive,
+        None => return HttpResponse::NotFound().body("history archive is not configured"),
+    };
+    match archive.fetch(&fetch).await {
+        Ok(page) => HttpResponse::Ok().json(&page),
+        Err(e) => {
+            log::warn!("Cannot read history archive: {}", e);
+            HttpResponse::BadGateway().body(e)
+        }
+    }
+}
+
+/// `DELETE /admin/connections/{id}`
+///
+/// Closes the connection with 1008 (policy violation).
+async fn delete_connection(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    id: web::types::Path<u64>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    if kick(&hub, *id, "kicked by admin") {
+        log::info!("Connection {} kicked", id);
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CallRequest {
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// `POST /admin/connections/{id}/call`
+///
+/// Sends the connection an `rpc.call` and answers with `{"result"}` once the
+/// client did; 502 with `{"error"}` if it failed the call, 504 on timeout.
+async fn post_call(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    id: web::types::Path<u64>,
+    call: Json<CallRequest>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let call = call.into_inner();
+    let timeout = call
+        .timeout_ms
+        .map_or(rpc::DEFAULT_TIMEOUT, Duration::from_millis);
+    match rpc::call(&hub, *id, &call.method, call.params, timeout).await {
+        Ok(result) => HttpResponse::Ok().json(&serde_json::json!({ "result": result })),
+        Err(CallError::Failed(error)) => {
+            HttpResponse::BadGateway().json(&serde_json::json!({ "error": error }))
+        }
+        Err(e @ CallError::NotConnected) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e @ CallError::Busy) => HttpResponse::TooManyRequests().body(e.to_string()),
+        Err(e @ CallError::Disconnected) => HttpResponse::BadGateway().body(e.to_string()),
+        Err(e @ CallError::TimedOut) => HttpResponse::GatewayTimeout().body(e.to_string()),
+    }
+}
+
+/// `POST /admin/connections/{id}/record`
+///
+/// Starts recording the connection's frames, answers with `{"file"}`; 404
+/// without `[record]`, 409 if it is recorded already.
+async fn post_record(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    id: web::types::Path<u64>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let Some(ref record) = config.record else {
+        return HttpResponse::NotFound().body("recording is not configured");
+    };
+    let Some(stats) = hub.stats(*id) else {
+        return HttpResponse::NotFound().body("no such connection");
+    };
+    match record::start(record, *id, &stats) {
+        Ok(file) => HttpResponse::Created().json(&serde_json::json!({ "file": file })),
+        Err(e @ RecordError::Recording) => HttpResponse::Conflict().body(e.to_string()),
+        Err(RecordError::Io(e)) => {
+            log::error!("Cannot record connection {}: {}", id, e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+/// `DELETE /admin/connections/{id}/record`
+///
+/// Stops recording the connection, answers with `{"file", "frames", "bytes"}`.
+async fn delete_record(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    id: web::types::Path<u64>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match hub.stats(*id).and_then(|stats| stats.recorder().stop()) {
+        Some(summary) => HttpResponse::Ok().json(&summary),
+        None => HttpResponse::NotFound().body("connection is not recorded"),
+    }
+}
+
+/// Ban `user` and close their connections, returns how many were closed
+pub(crate) fn ban_user(hub: &Hub, user: &str) -> usize {
+    bans::ban(user);
+    let kicked = hub
+        .user_connections(user)
+        .into_iter()
+        .filter(|id| kick(hub, *id, "banned"))
+        .count();
+    log::info!("User {} banned, {} connections closed", user, kicked);
+    kicked
+}
+
+pub(crate) fn kick(hub: &Hub, id: u64, reason: &str) -> bool {
+    hub.close(
+        id,
+        ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(reason.to_string()),
+        },
+    )
+}
+
+/// `GET /admin/bans`
+async fn get_bans(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&bans::list())
+}
+
+#[derive(Debug, Deserialize)]
+struct Ban {
+    user: String,
+}
+
+/// `POST /admin/bans`
+///
+/// Body: `{"user": "alice"}`, the user's open connections are closed.
+async fn post_ban(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    ban: Json<Ban>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let kicked = ban_user(&hub, &ban.user);
+    HttpResponse::Ok().json(&serde_json::json!({ "closed": kicked }))
+}
+
+/// `DELETE /admin/bans/{user}`
+async fn delete_ban(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    user: web::types::Path<String>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    if bans::unban(&user) {
+        log::info!("User {} unbanned", user);
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// `GET /admin/ip-rules`
+///
+/// Rules from the config and rules added at runtime; only the latter can be
+/// removed through the API.
+async fn get_ip_rules(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (configured, runtime) = ipfilter::list();
+    HttpResponse::Ok().json(&serde_json::json!({
+        "config": configured,
+        "runtime": runtime,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct IpRule {
+    list: ipfilter::List,
+    cidr: ipfilter::Cidr,
+}
+
+/// `POST /admin/ip-rules`
+///
+/// Body: `{"list": "deny", "cidr": "203.0.113.0/24"}`, 201 if added, 200 if
+/// it was there already. Open connections are not closed.
+async fn post_ip_rule(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    rule: Json<IpRule>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match ipfilter::add(rule.list, rule.cidr) {
+        Ok(true) => {
+            log::info!("IP rule added: {:?} {}", rule.list, rule.cidr);
+            HttpResponse::Created().finish()
+        }
+        Ok(false) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            log::error!("Cannot save IP rules: {}", e);
+            HttpResponse::InternalServerError().body(format!("cannot save rules: {}", e))
+        }
+    }
+}
+
+/// `DELETE /admin/ip-rules`
+///
+/// Body as for `POST`, 404 if there is no such runtime rule.
+async fn delete_ip_rule(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    rule: Json<IpRule>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match ipfilter::remove(rule.list, rule.cidr) {
+        Ok(true) => {
+            log::info!("IP rule removed: {:?} {}", rule.list, rule.cidr);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Cannot save IP rules: {}", e);
+            HttpResponse::InternalServerError().body(format!("cannot save rules: {}", e))
+        }
+    }
+}
+
+/// `GET /admin/jobs`
+///
+/// Jobs from the config and jobs added at runtime; only the latter can be
+/// changed through the API.
+async fn get_jobs(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (configured, runtime) = jobs::list();
+    HttpResponse::Ok().json(&serde_json::json!({
+        "config": configured,
+        "runtime": runtime,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct Job {
+    cron: String,
+    room: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// `PUT /admin/jobs/{name}`
+///
+/// Body: `{"cron": "*/15 * * * *", "room": "app", "data": {"cmd": "refresh"}}`,
+/// 201 if added, 200 if it replaced one; 409 for a configured job's name.
+async fn put_job(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    name: web::types::Path<String>,
+    job: Json<Job>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let job = job.into_inner();
+    let job = JobConfig {
+        name: name.into_inner(),
+        cron: job.cron,
+        room: job.room,
+        data: job.data,
+    };
+    let name = job.name.clone();
+    match jobs::put(job) {
+        Ok(true) => {
+            log::info!("Job {} added", name);
+            HttpResponse::Created().finish()
+        }
+        Ok(false) => {
+            log::info!("Job {} replaced", name);
+            HttpResponse::Ok().finish()
+        }
+        Err(e @ JobError::Configured) => HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// `DELETE /admin/jobs/{name}`
+///
+/// 404 if there is no such runtime job.
+async fn delete_job(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    name: web::types::Path<String>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    if jobs::remove(&name) {
+        log::info!("Job {} removed", name);
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// `GET /admin/kv`
+///
+/// Scopes with keys, `{"room:lobby": 3, "tenant:acme": 1}`.
+async fn get_kv_scopes(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&hub.kv().scopes())
+}
+
+/// `GET /admin/kv/{scope}`
+///
+/// Keys of `room:<name>`, `tenant:<name>` or `tenant` with their value and
+/// version.
+async fn get_kv(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    scope: web::types::Path<String>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    match scope.parse::<Scope>() {
+        Ok(scope) => HttpResponse::Ok().json(&hub.kv().entries(&scope)),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KvWrite {
+    value: serde_json::Value,
+    if_version: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvDelete {
+    if_version: Option<u64>,
+}
+
+fn kv_error(e: KvError) -> HttpResponse {
+    match e {
+        KvError::Conflict(_) => HttpResponse::Conflict().body(e.to_string()),
+        e => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// `PUT /admin/kv/{scope}/{key}`
+///
+/// Body: `{"value": .., "if_version": 3}`, answered with the `{"version"}`
+/// of the write and sent to the key's subscribers; 409 if `if_version` is
+/// not the key's.
+async fn put_kv(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    path: web::types::Path<(String, String)>,
+    write: Json<KvWrite>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (scope, key) = path.into_inner();
+    let scope = match scope.parse::<Scope>() {
+        Ok(scope) => scope,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let KvWrite { value, if_version } = write.into_inner();
+    if value.is_null() {
+        return HttpResponse::BadRequest().body("`value` must be set, DELETE removes a key");
+    }
+    match hub.kv_set(&scope, &key, value, if_version) {
+        Ok(version) => HttpResponse::Ok().json(&serde_json::json!({ "version": version })),
+        Err(e) => kv_error(e),
+    }
+}
+
+/// `DELETE /admin/kv/{scope}/{key}?if_version=3`
+///
+/// 404 if there is no such key, 409 if `if_version` is not the key's.
+async fn delete_kv(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    path: web::types::Path<(String, String)>,
+    query: Query<KvDelete>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (scope, key) = path.into_inner();
+    let scope = match scope.parse::<Scope>() {
+        Ok(scope) => scope,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match hub.kv_delete(&scope, &key, query.if_version) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => kv_error(e),
+    }
+}
+
+/// `GET /admin/crdts`
+///
+/// Shared objects of every scope with their value, by name.
+async fn get_crdts(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let scopes: BTreeMap<_, BTreeMap<_, _>> = hub
+        .crdts()
+        .list()
+        .into_iter()
+        .map(|(scope, objects)| {
+            let objects = objects
+                .into_iter()
+                .map(|(name, object)| {
+                    let object =
+                        serde_json::json!({ "kind": object.kind(), "value": object.value() });
+                    (name, object)
+                })
+                .collect();
+            (scope, objects)
+        })
+        .collect();
+    HttpResponse::Ok().json(&scopes)
+}
+
+/// `GET /admin/locks`
+///
+/// Held locks by scope and name, with their holder, lease and number of
+/// connections waiting.
+async fn get_locks(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&hub.locks().list())
+}
+
+/// `DELETE /admin/locks/{scope}/{name}`
+///
+/// Take a lock away from its holder, which gets `lock.lost`, and hand it to
+/// the next connection waiting; 404 if it is not held.
+async fn delete_lock(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    path: web::types::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (scope, name) = path.into_inner();
+    let scope = match scope.parse::<Scope>() {
+        Ok(scope) => scope,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match hub.locks().force_release(&scope, &name) {
+        Some(notices) => {
+            log::info!("Lock {} of {} released", name, scope);
+            hub.lock_notices(notices);
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// `GET /admin/errors`
+///
+/// Recently reported client errors and handler panics, newest first.
+async fn get_errors(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    HttpResponse::Ok().json(&reporting::recent())
+}
+
+/// `GET /admin/flags`
+async fn get_flags(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let (flags, tenants) = flags::snapshot();
+    HttpResponse::Ok().json(&serde_json::json!({ "flags": flags, "tenants": tenants }))
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagsUpdate {
+    #[serde(default)]
+    tenant: Option<String>,
+    /// `null` clears the override
+    flags: BTreeMap<String, Option<bool>>,
+}
+
+/// `PUT /admin/flags`
+///
+/// Body: `{"tenant": "acme", "flags": {"echo": false, "broadcast": null}}`,
+/// without `tenant` the global overrides are changed.
+async fn put_flags(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    update: Json<FlagsUpdate>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let update = update.into_inner();
+    if let Err(e) = flags::set(update.tenant.as_deref(), &update.flags) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    log::info!(
+        "Feature flags of {} changed: {:?}",
+        update.tenant.as_deref().unwrap_or("all tenants"),
+        update.flags
+    );
+    get_flags(req, config).await
+}
+
+/// `GET /admin/pipelines`
+///
+/// Registered pipelines with their open connections, and the active one of
+/// each websocket route.
+async fn get_pipelines(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let mut connections: BTreeMap<&str, usize> = BTreeMap::new();
+    for conn in hub.connections() {
+        *connections.entry(conn.pipeline).or_default() += 1;
+    }
+    let pipelines: Vec<_> = pipeline::names()
+        .into_iter()
+        .map(|name| {
+            let open = connections.get(name).copied().unwrap_or(0);
+            serde_json::json!({ "name": name, "connections": open })
+        })
+        .collect();
+    HttpResponse::Ok().json(&serde_json::json!({
+        "pipelines": pipelines,
+        "routes": pipeline::routes(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivePipeline {
+    route: String,
+    pipeline: String,
+    /// Close the connections of the pipeline replaced, with reconnect hints
+    #[serde(default)]
+    close: bool,
+}
+
+/// `PUT /admin/pipelines/active`
+///
+/// Body: `{"route": "/ws", "pipeline": "v2", "close": false}`. New
+/// connections of the route get the pipeline, open ones keep theirs unless
+/// `close` closes those of the replaced one with `maintenance` hints.
+async fn put_active_pipeline(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    active: Json<ActivePipeline>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let active = active.into_inner();
+    let previous = match pipeline::activate(&active.route, &active.pipeline) {
+        Ok(previous) => previous,
+        Err(e @ ActivateError::NoRoute(_)) => return HttpResponse::NotFound().body(e.to_string()),
+        Err(e @ ActivateError::NoPipeline(_)) => {
+            return HttpResponse::BadRequest().body(e.to_string())
+        }
+    };
+    let mut closed = 0;
+    if active.close && previous != active.pipeline {
+        for conn in hub.connections() {
+            let reason = reconnect::close_reason(&config.reconnect, Cause::Maintenance);
+            if conn.pipeline == previous && hub.close(conn.id, reason) {
+                closed += 1;
+            }
+        }
+    }
+    log::info!(
+        "Pipeline of {} switched from {} to {}, {} connections closed",
+        active.route,
+        previous,
+        active.pipeline,
+        closed
+    );
+    HttpResponse::Ok().json(&serde_json::json!({ "previous": previous, "closed": closed }))
+}
+
+/// `POST /admin/broadcast`
+///
+/// Sends request body to every connection, as a text message unless
+/// content type is `application/octet-stream`. Query parameters select
+/// connections by `hello` metadata, e.g. `?device=mobile`, and `labels` by
+/// a label selector, e.g. `?labels=plan=pro`; broadcasts with the same
+/// `x-ordering-key` header arrive in order.
+async fn post_broadcast(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    filter: Query<Metadata>,
+    body: Bytes,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let binary = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes() == b"application/octet-stream");
+    let msg = if binary {
+        ws::Message::Binary(body)
+    } else {
+        match utf8::to_bytestring(body) {
+            Some(text) => ws::Message::Text(text),
+            None => return HttpResponse::BadRequest().body("body is not valid utf-8"),
+        }
+    };
+    let key = match req.headers().get(ORDERING_KEY).map(|v| v.to_str()) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("x-ordering-key is not ascii"),
+        None => None,
+    };
+    let mut metadata = filter.into_inner();
+    let labels = match metadata.remove("labels").map(|s| s.parse()) {
+        Some(Ok(labels)) => labels,
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e),
+        None => Selector::default(),
+    };
+    let target = Target { metadata, labels };
+    let recipients = hub.fan_out(msg, target, key).await;
+    HttpResponse::Ok().json(&serde_json::json!({ "recipients": recipients }))
+}
+
+#[derive(Debug, Deserialize)]
+struct Drain {
+    cause: Cause,
+    /// Connections to close, all if unset
+    #[serde(default)]
+    count: Option<usize>,
+    /// Override `[reconnect]` for these closes
+    #[serde(default)]
+    retry_after_secs: Option<u64>,
+    #[serde(default)]
+    endpoint: Option<String>,
+}
+
+/// `POST /admin/drain`
+///
+/// Body: `{"cause": "maintenance"}` or `{"cause": "overload", "count": 100}`,
+/// optionally with `retry_after_secs` and `endpoint`; closes connections
+/// with reconnect hints.
+async fn post_drain(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    drain: Json<Drain>,
+) -> HttpResponse {
+    if let Err(res) = authorize(&req, &config) {
+        return res;
+    }
+    let drain = drain.into_inner();
+    let mut hints = config.reconnect.clone();
+    if let Some(retry_after_secs) = drain.retry_after_secs {
+        hints.retry_after_secs = retry_after_secs;
+    }
+    if drain.endpoint.is_some() {
+        hints.endpoint = drain.endpoint;
+    }
+    if let Err(e) = reconnect::validate(&hints) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let closed = reconnect::close(&hub, &hints, drain.cause, drain.count);
+    log::info!("Drained {} connections for {:?}", closed, drain.cause);
+    HttpResponse::Ok().json(&serde_json::json!({ "closed": closed }))
+}
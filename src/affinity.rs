@@ -0,0 +1,104 @@
+//! Sticky sessions behind a load balancer.
+//!
+//! With `[affinity]`, the websocket handshake response names the serving
+//! node in a cookie and a header. Clients send the cookie again when they
+//! reconnect, or `?affinity=<token>` if they cannot keep cookies, so a load
+//! balancer routing on it brings a client back to the node holding its
+//! state: room memberships, read positions and moderation are per node.
+//!
+//! The server does no routing itself. A token naming another node means the
+//! balancer sent the client elsewhere, e.g. because that node is down: the
+//! connection is served anyway, counted in `wss_affinity_misses_total` and
+//! the token replaced by this node's.
+
+use ntex::http::header::{self, HeaderName, HeaderValue};
+use ntex::web::HttpRequest;
+
+use crate::auth;
+use crate::config::Config;
+use crate::metrics::METRICS;
+
+/// Token naming this node, `None` without `[affinity]`
+pub fn token(config: &Config) -> Option<&str> {
+    let affinity = config.affinity.as_ref()?;
+    affinity
+        .node
+        .as_deref()
+        .or(config.cluster.as_ref().map(|c| c.node.as_str()))
+}
+
+/// Check that `[affinity]` names this node with a token fit for a cookie
+pub fn validate(config: &Config) -> Result<(), String> {
+    let Some(ref affinity) = config.affinity else {
+        return Ok(());
+    };
+    let token = token(config).ok_or("affinity.node is required without [cluster]")?;
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if token.is_empty() || !token.chars().all(valid) {
+        return Err(format!(
+            "affinity token `{}` may only have letters, digits, `-`, `_` and `.`",
+            token
+        ));
+    }
+    if !affinity.cookie.chars().all(valid) {
+        return Err(format!(
+            "affinity.cookie `{}` is not a cookie name",
+            affinity.cookie
+        ));
+    }
+    if !affinity.header.is_empty() {
+        HeaderName::try_from(affinity.header.as_str())
+            .map_err(|_| format!("affinity.header `{}` is not a header name", affinity.header))?;
+    }
+    Ok(())
+}
+
+/// Note the token a reconnecting client brought, from its cookie or
+/// `?affinity=`
+pub fn check(config: &Config, req: &HttpRequest, query: Option<&str>) {
+    let (Some(affinity), Some(token)) = (config.affinity.as_ref(), token(config)) else {
+        return;
+    };
+    let cookie = match affinity.cookie.is_empty() {
+        true => None,
+        false => auth::cookie(req, &affinity.cookie),
+    };
+    match query.map(str::to_string).or(cookie) {
+        Some(ref requested) if requested != token => {
+            METRICS.affinity_misses_total.inc();
+            log::debug!(
+                "Handshake with affinity token {}, served by {}",
+                requested,
+                token
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Headers of the handshake response naming this node
+pub fn headers(config: &Config) -> Vec<(HeaderName, HeaderValue)> {
+    let (Some(affinity), Some(token)) = (config.affinity.as_ref(), token(config)) else {
+        return Vec::new();
+    };
+    let mut headers = Vec::new();
+    if !affinity.cookie.is_empty() {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            affinity.cookie, token, affinity.max_age_secs
+        );
+        if affinity.secure {
+            cookie.push_str("; Secure");
+        }
+        if let Ok(value) = HeaderValue::try_from(cookie) {
+            headers.push((header::SET_COOKIE, value));
+        }
+    }
+    if let (Ok(name), Ok(value)) = (
+        HeaderName::try_from(affinity.header.as_str()),
+        HeaderValue::try_from(token),
+    ) {
+        headers.push((name, value));
+    }
+    headers
+}
@@ -0,0 +1,165 @@
+//! Aggregation windows of high-frequency rooms.
+//!
+//! Rooms matching a `[[rooms.limits]]` pattern with `aggregate_ms` do not
+//! get their messages delivered one by one: the first message opens a
+//! window, and when it ends the members get what was published meanwhile
+//! as one frame, combined by the pattern's `reducer`:
+//!
+//! - `array`: `room.batch` with the `room.message` payloads in order,
+//! - `last`: the newest `room.message` only,
+//! - `merge`: one `room.message` with the data objects merged, later fields
+//!   winning, and the `seq` and `from` of the newest.
+//!
+//! Messages are sequenced and stored as they arrive, only delivery waits.
+//! A window is sent early once it holds `MAX_WINDOW` messages. Members'
+//! subscription filters apply to the messages before they are combined.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::StreamExt;
+use ntex::{time, ws};
+use serde_json::{json, Value};
+
+use crate::config::{Aggregation, Reducer};
+use crate::hub::Hub;
+use crate::protocol;
+use crate::tasks::TaskHandle;
+
+/// Messages of a window past which it is sent at once
+pub const MAX_WINDOW: usize = 1000;
+
+/// Messages of one room waiting for the end of their window
+#[derive(Debug)]
+pub struct Window {
+    opened: Instant,
+    pub reducer: Reducer,
+    /// `room.message` payloads in order
+    pub payloads: Vec<Value>,
+    /// Members as of the newest message
+    pub members: Vec<u64>,
+}
+
+/// Open windows by room
+#[derive(Debug)]
+pub struct Aggregator {
+    windows: Mutex<HashMap<String, Window>>,
+    /// Ends of opened windows, for `run`
+    due: mpsc::UnboundedSender<(Instant, Instant, String)>,
+    due_rx: Mutex<Option<mpsc::UnboundedReceiver<(Instant, Instant, String)>>>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        let (due, due_rx) = mpsc::unbounded();
+        Aggregator {
+            windows: Mutex::new(HashMap::new()),
+            due,
+            due_rx: Mutex::new(Some(due_rx)),
+        }
+    }
+}
+
+impl Aggregator {
+    /// Add a message of room `name` for `members` to its window, opening
+    /// one; returns the window if that made it full
+    pub fn add(
+        &self,
+        name: &str,
+        payload: Value,
+        members: Vec<u64>,
+        aggregation: Aggregation,
+    ) -> Option<Window> {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(name.to_string()).or_insert_with(|| {
+            let opened = Instant::now();
+            let _ =
+                self.due
+                    .unbounded_send((opened + aggregation.window, opened, name.to_string()));
+            Window {
+                opened,
+                reducer: aggregation.reducer,
+                payloads: Vec::new(),
+                members: Vec::new(),
+            }
+        });
+        window.payloads.push(payload);
+        window.members = members;
+        match window.payloads.len() >= MAX_WINDOW {
+            true => windows.remove(name),
+            false => None,
+        }
+    }
+
+    /// Window of room `name` opened at `opened`, unless sent early
+    fn take(&self, name: &str, opened: Instant) -> Option<Window> {
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get(name) {
+            Some(window) if window.opened == opened => windows.remove(name),
+            _ => None,
+        }
+    }
+}
+
+/// One frame of `payloads` as `reducer` says, `None` if there are none
+pub fn reduce(room: &str, reducer: Reducer, payloads: &[&Value]) -> Option<ws::Message> {
+    let newest = *payloads.last()?;
+    let payload = match reducer {
+        Reducer::Array => {
+            let payload = json!({ "room": room, "messages": payloads });
+            return Some(protocol::message("room.batch", None, &payload));
+        }
+        Reducer::Last => newest.clone(),
+        Reducer::Merge => {
+            let mut data = Value::Null;
+            for payload in payloads {
+                match (&mut data, &payload["data"]) {
+                    (Value::Object(merged), Value::Object(fields)) => {
+                        merged.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())))
+                    }
+                    (data, other) => *data = other.clone(),
+                }
+            }
+            let mut payload = newest.clone();
+            payload["data"] = data;
+            payload
+        }
+    };
+    Some(protocol::message("room.message", None, &payload))
+}
+
+/// Deliver windows as they end
+pub async fn run(hub: Arc<Hub>) {
+    let Some(mut windows) = hub.aggregator().due_rx.lock().unwrap().take() else {
+        return;
+    };
+    let task = TaskHandle::register("aggregate", None);
+    let mut due: BinaryHeap<Reverse<(Instant, Instant, String)>> = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while let Some(Reverse((at, _, _))) = due.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse((_, opened, name)) = due.pop().expect("peeked");
+            if let Some(window) = hub.aggregator().take(&name, opened) {
+                hub.flush(&name, window);
+            }
+        }
+        task.set_queue_depth(due.len());
+        task.set_state("waiting");
+        let wait = match due.peek() {
+            Some(Reverse((at, _, _))) => at.saturating_duration_since(now),
+            None => Duration::from_secs(3600),
+        };
+        match select(Box::pin(time::sleep(wait)), windows.next()).await {
+            Either::Left(_) => {}
+            Either::Right((Some(window), _)) => due.push(Reverse(window)),
+            Either::Right((None, _)) => return,
+        }
+    }
+}
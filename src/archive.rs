@@ -0,0 +1,196 @@
+//! Archival of aged room history to an S3-compatible bucket.
+//!
+//! Every `interval_secs`, the messages older than `after_secs` of each room
+//! are uploaded as one batch and then dropped from the history, keeping its
+//! log small. A batch is the messages as JSON lines, raw DEFLATE compressed, at
+//! `<prefix><hex of room name>/<first seq>-<last seq>.jsonl.deflate` with
+//! zero-padded sequence numbers, so a listing tells which batches hold a
+//! range. Archived messages are read back through `GET /admin/history/archive`
+//! and can no longer be edited or deleted. With `history.encryption` set,
+//! batches are encrypted after compression and end in `.jsonl.deflate.enc`.
+
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::Duration;
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use ntex::time;
+
+use crate::config::{ArchiveConfig, EncryptionConfig};
+use crate::crypto::Cipher;
+use crate::history::{self, Entry, Fetch, FetchPage};
+use crate::hub::Hub;
+use crate::metrics::METRICS;
+use crate::s3::S3;
+use crate::tasks::TaskHandle;
+
+/// Max size of an inflated batch
+const MAX_BATCH: usize = 256 * 1024 * 1024;
+
+/// Suffix of batch objects, followed by `.enc` if encrypted
+const SUFFIX: &str = ".jsonl.deflate";
+
+pub struct Archive {
+    config: ArchiveConfig,
+    s3: S3,
+    cipher: Option<Cipher>,
+}
+
+/// Sequence range of a batch object
+struct Batch {
+    key: String,
+    first: u64,
+    last: u64,
+    encrypted: bool,
+}
+
+impl Archive {
+    pub fn new(
+        config: ArchiveConfig,
+        encryption: Option<&EncryptionConfig>,
+    ) -> Result<Archive, String> {
+        if config.bucket.is_empty() {
+            return Err("archive bucket is empty".to_string());
+        }
+        Ok(Archive {
+            s3: S3::new(config.clone())?,
+            cipher: encryption.map(Cipher::new).transpose()?,
+            config,
+        })
+    }
+
+    /// Upload and drop aged messages of every room
+    async fn sweep(&self, hub: &Hub) {
+        let history = hub.rooms().history();
+        let cutoff = history::now_millis().saturating_sub(self.config.after_secs * 1000);
+        for (room, entries) in history.aged(cutoff) {
+            let (first, last) = match (entries.first(), entries.last()) {
+                (Some(first), Some(last)) => (first.seq, last.seq),
+                _ => continue,
+            };
+            let mut key = format!(
+                "{}{:020}-{:020}{}",
+                self.room_prefix(&room),
+                first,
+                last,
+                SUFFIX
+            );
+            let mut jsonl = Vec::new();
+            for entry in &entries {
+                serde_json::to_writer(&mut jsonl, entry).expect("serializable entry");
+                jsonl.push(b'\n');
+            }
+            let mut body = compress_to_vec(&jsonl, 6);
+            if let Some(ref cipher) = self.cipher {
+                body = cipher.seal(&body);
+                key.push_str(".enc");
+            }
+            if let Err(e) = self.s3.put(&key, body).await {
+                log::warn!("Cannot archive history of room {}: {}", room, e);
+                continue;
+            }
+            log::info!("Archived messages {}..={} of room {}", first, last, room);
+            METRICS.messages_archived_total.add(entries.len() as u64);
+            // edits made during the upload are lost with the hot copies
+            if let Err(e) = history.archived(&room, last) {
+                log::warn!("Cannot compact history of room {}: {}", room, e);
+            }
+        }
+    }
+
+    /// Page of the archived messages of a room, like `history.fetch`
+    pub async fn fetch(&self, fetch: &Fetch) -> Result<FetchPage, String> {
+        let prefix = self.room_prefix(&fetch.room);
+        let mut batches: Vec<Batch> = self
+            .s3
+            .list(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|key| batch(&prefix, key))
+            .filter(|b| fetch.before.is_none_or(|before| b.first < before))
+            .filter(|b| fetch.after.is_none_or(|after| b.last > after))
+            .collect();
+        // download from the end the page starts at until it is full
+        if fetch.after.is_none() {
+            batches.reverse();
+        }
+        let limit = fetch.limit.clamp(1, history::MAX_FETCH_MESSAGES);
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut loaded = 0;
+        for batch in &batches {
+            let mut messages = self.load(batch).await?;
+            if fetch.after.is_none() {
+                messages.append(&mut entries);
+                entries = messages;
+            } else {
+                entries.append(&mut messages);
+            }
+            loaded += 1;
+            if history::page(entries.iter(), fetch).0.len() >= limit {
+                break;
+            }
+        }
+        let (messages, more) = history::page(entries.iter(), fetch);
+        Ok(FetchPage {
+            room: fetch.room.clone(),
+            messages,
+            more: more || loaded < batches.len(),
+            archived: None,
+        })
+    }
+
+    async fn load(&self, batch: &Batch) -> Result<Vec<Entry>, String> {
+        let key = &batch.key;
+        let mut body = self.s3.get(key).await?.to_vec();
+        if batch.encrypted {
+            let cipher = self.cipher.as_ref().ok_or_else(|| {
+                format!(
+                    "{}: encrypted, but history.encryption is not configured",
+                    key
+                )
+            })?;
+            body = cipher.open(&body).map_err(|e| format!("{}: {}", key, e))?;
+        }
+        let jsonl = decompress_to_vec_with_limit(&body, MAX_BATCH)
+            .map_err(|e| format!("{}: cannot inflate: {:?}", key, e.status))?;
+        let mut entries = Vec::new();
+        for line in jsonl.lines() {
+            let line = line.map_err(|e| format!("{}: {}", key, e))?;
+            let entry = serde_json::from_str(&line).map_err(|e| format!("{}: {}", key, e))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn room_prefix(&self, room: &str) -> String {
+        format!("{}{}/", self.config.prefix, history::encode_room(room))
+    }
+}
+
+/// Range of a batch key below `prefix`, `None` for other objects
+fn batch(prefix: &str, key: String) -> Option<Batch> {
+    let name = key.strip_prefix(prefix)?;
+    let (name, encrypted) = match name.strip_suffix(".enc") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let (first, last) = name.strip_suffix(SUFFIX)?.split_once('-')?;
+    Some(Batch {
+        first: first.parse().ok()?,
+        last: last.parse().ok()?,
+        encrypted,
+        key,
+    })
+}
+
+pub async fn run(archive: Arc<Archive>, hub: Arc<Hub>) {
+    let task = TaskHandle::register("archive", None);
+    let interval = Duration::from_secs(archive.config.interval_secs.max(1));
+    loop {
+        task.set_state("sleeping");
+        time::sleep(interval).await;
+        task.set_state("archiving");
+        archive.sweep(&hub).await;
+    }
+}
@@ -0,0 +1,93 @@
+//! AsyncAPI document for the websocket API, see `--export-asyncapi`.
+
+use serde_json::{json, Map, Value};
+
+use crate::protocol::{Direction, Layout, MessageType, MESSAGE_TYPES};
+use crate::schema::Schema;
+
+/// Full JSON schema of a message, including envelope fields
+fn message_schema(msg: &MessageType) -> Value {
+    let mut schema = match msg.layout {
+        Layout::Envelope => json!({
+            "type": "object",
+            "properties": { "payload": msg.schema.to_json_schema() },
+            "required": ["type"],
+        }),
+        Layout::Flat => match msg.schema {
+            Schema::Object(_) => msg.schema.to_json_schema(),
+            _ => json!({ "type": "object", "properties": {}, "required": [] }),
+        },
+    };
+    schema["properties"]["type"] = json!({ "const": msg.name });
+    schema["properties"]["id"] = json!({
+        "type": "string",
+        "description": "Correlation id, copied from request to reply",
+    });
+    if msg.direction != Direction::ServerToClient {
+        schema["properties"]["version"] = json!({
+            "type": "integer",
+            "minimum": 1,
+            "maximum": msg.version(),
+            "description": "Payload version, 1 if absent; older versions are upgraded",
+        });
+    }
+    if let Some(required) = schema["required"].as_array_mut() {
+        if !required.iter().any(|v| v == "type") {
+            required.insert(0, json!("type"));
+        }
+    }
+    schema
+}
+
+fn refs<F: Fn(Direction) -> bool>(filter: F) -> Vec<Value> {
+    MESSAGE_TYPES
+        .iter()
+        .filter(|m| filter(m.direction))
+        .map(|m| json!({ "$ref": format!("#/components/messages/{}", m.name) }))
+        .collect()
+}
+
+/// Build AsyncAPI 2.6 document
+pub fn document() -> Value {
+    let mut messages = Map::new();
+    for msg in MESSAGE_TYPES {
+        messages.insert(
+            msg.name.to_string(),
+            json!({
+                "name": msg.name,
+                "summary": msg.summary,
+                "x-version": msg.version(),
+                "contentType": "application/json",
+                "payload": message_schema(msg),
+            }),
+        );
+    }
+
+    json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Text frames carry JSON messages, binary frames are echoed.",
+        },
+        "servers": {
+            "plain": { "url": "localhost:80", "protocol": "ws" },
+            "tls": { "url": "localhost:443", "protocol": "wss" },
+        },
+        "defaultContentType": "application/json",
+        "channels": {
+            "/ws": {
+                // AsyncAPI 2 names operations from the client's point of view
+                "publish": {
+                    "summary": "Messages sent by clients",
+                    "message": { "oneOf": refs(|d| d != Direction::ServerToClient) },
+                },
+                "subscribe": {
+                    "summary": "Messages sent by the server",
+                    "message": { "oneOf": refs(|d| d != Direction::ClientToServer) },
+                },
+            },
+        },
+        "components": { "messages": messages },
+    })
+}
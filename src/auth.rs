@@ -0,0 +1,261 @@
+//! Handshake authentication.
+//!
+//! A websocket upgrade may carry a signed session cookie (`[auth.cookie]`):
+//!
+//! ```text
+//! <base64url(claims json)>.<base64url(HMAC-SHA256(key, first part))>
+//! ```
+//!
+//! Claims are `{"sub": "user", "roles": [...], "exp": unix_secs, "tenant": ...}`,
+//! all but `sub` optional. With `[auth.oidc]` a bearer token, from the
+//! `Authorization` header or the `access_token` query parameter, is
+//! validated against the issuer instead (see `oidc`). Without
+//! `auth.required` anonymous connections are accepted, an invalid or expired
+//! credential is always rejected.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use ntex::http::header;
+use ntex::web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::challenge::Challenges;
+use crate::config::{AuthConfig, CookieAuthConfig, SessionPolicy, SessionsConfig, WhenExceeded};
+use crate::oidc::Oidc;
+
+/// Authenticated user of a connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "sub")]
+    pub user: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Unix seconds after which the credential is no longer valid
+    #[serde(default, rename = "exp", skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Tenant for feature flags, wins over the `tenant` query parameter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// All claims of the credential, for `labels`
+    #[serde(skip)]
+    pub claims: Value,
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Identity {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|exp| exp <= now_secs())
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// Why a handshake was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credential and `auth.required` is set
+    Missing,
+    Invalid(String),
+    Expired,
+    /// Credential could not be checked, e.g. the issuer is unreachable
+    Unavailable(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => f.write_str("authentication required"),
+            AuthError::Invalid(reason) => write!(f, "invalid credential: {}", reason),
+            AuthError::Expired => f.write_str("credential expired"),
+            AuthError::Unavailable(reason) => {
+                write!(f, "authentication unavailable: {}", reason)
+            }
+        }
+    }
+}
+
+pub struct Authenticator {
+    required: bool,
+    cookie: Option<CookieAuthConfig>,
+    oidc: Option<Arc<Oidc>>,
+    sessions: SessionsConfig,
+    challenges: Option<Challenges>,
+}
+
+impl Authenticator {
+    /// `node` is the cluster node id, challenges are bound to it
+    pub fn new(config: &AuthConfig, node: Option<&str>) -> Authenticator {
+        Authenticator {
+            required: config.required,
+            cookie: config.cookie.clone(),
+            oidc: config.oidc.clone().map(|c| Arc::new(Oidc::new(c))),
+            sessions: config.sessions.clone(),
+            challenges: config
+                .challenge
+                .as_ref()
+                .map(|challenge| Challenges::new(challenge, node)),
+        }
+    }
+
+    /// Connections per user, 0 for unlimited, and how extra ones are handled
+    pub fn connection_quota(&self) -> (usize, WhenExceeded) {
+        (self.sessions.max_connections, self.sessions.when_exceeded)
+    }
+
+    /// Proof-of-work for anonymous handshakes, if configured
+    pub fn challenges(&self) -> Option<&Challenges> {
+        self.challenges.as_ref()
+    }
+
+    /// Token validator, its keys are refreshed by `oidc::refresh_keys`
+    pub fn oidc(&self) -> Option<Arc<Oidc>> {
+        self.oidc.clone()
+    }
+
+    /// How another connection of an already connected user is handled
+    pub fn session_policy(&self, tenant: Option<&str>) -> SessionPolicy {
+        self.sessions.policy(tenant)
+    }
+
+    /// Identity of handshake request, `None` for anonymous
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let identity = match (&self.oidc, bearer(req)) {
+            (Some(oidc), Some(token)) => Some(oidc.validate(&token)?),
+            _ => match self.cookie {
+                Some(ref config) => match cookie(req, &config.name) {
+                    Some(value) => Some(verify_cookie(&value, config.key.as_bytes())?),
+                    None => None,
+                },
+                None => None,
+            },
+        };
+        match identity {
+            None if self.required => Err(AuthError::Missing),
+            Some(ref id) if id.is_expired() => Err(AuthError::Expired),
+            identity => Ok(identity),
+        }
+    }
+
+    /// Identity of a credential presented on an open connection, a JWT
+    /// goes to the OIDC issuer and anything else is a session cookie value
+    pub fn reauthenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let identity = match (&self.oidc, &self.cookie) {
+            (Some(oidc), _) if token.matches('.').count() == 2 => oidc.validate(token)?,
+            (_, Some(config)) => verify_cookie(token, config.key.as_bytes())?,
+            (Some(oidc), None) => oidc.validate(token)?,
+            (None, None) => return Err(invalid("authentication is not configured")),
+        };
+        if identity.is_expired() {
+            return Err(AuthError::Expired);
+        }
+        Ok(identity)
+    }
+}
+
+/// Value of cookie `name` from the `Cookie` headers
+pub(crate) fn cookie(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v.trim_matches('"').to_string())
+}
+
+/// Bearer token from `Authorization`, or `access_token` for browser clients
+/// which cannot set headers on a websocket handshake
+fn bearer(req: &HttpRequest) -> Option<String> {
+    let header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match header {
+        Some(token) => Some(token.trim().to_string()),
+        None => req
+            .query_string()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(n, _)| *n == "access_token")
+            .map(|(_, v)| v.to_string()),
+    }
+}
+
+fn invalid(reason: &str) -> AuthError {
+    AuthError::Invalid(reason.to_string())
+}
+
+/// Check signature of a session cookie and decode its claims
+pub fn verify_cookie(value: &str, key: &[u8]) -> Result<Identity, AuthError> {
+    let claims = verify_signed(value, key)?;
+    let claims: Value =
+        serde_json::from_slice(&claims).map_err(|e| AuthError::Invalid(e.to_string()))?;
+    let mut identity: Identity =
+        serde_json::from_value(claims.clone()).map_err(|e| AuthError::Invalid(e.to_string()))?;
+    identity.claims = claims;
+    Ok(identity)
+}
+
+/// Sign claims into a session cookie value, for issuers sharing the key
+pub fn sign_cookie(identity: &Identity, key: &[u8]) -> String {
+    sign(
+        &serde_json::to_vec(identity).expect("serializable identity"),
+        key,
+    )
+}
+
+/// `<base64url(claims)>.<base64url(HMAC-SHA256(key, first part))>`
+pub(crate) fn sign(claims: &[u8], key: &[u8]) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(claims);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key size");
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// Whether `provided` is the secret `expected`, in the same time wherever
+/// they differ, so a secret cannot be guessed byte by byte from response
+/// times; digests are compared so that neither does its length show
+pub(crate) fn secret_matches(provided: Option<&str>, expected: &str) -> bool {
+    let Some(provided) = provided else {
+        return false;
+    };
+    let provided = Sha256::digest(provided.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    provided
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Check signature of a `sign` result, returns the claims
+pub(crate) fn verify_signed(value: &str, key: &[u8]) -> Result<Vec<u8>, AuthError> {
+    let (payload, signature) = value.split_once('.').ok_or_else(|| invalid("malformed"))?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| invalid("malformed signature"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key size");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| invalid("bad signature"))?;
+    URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| invalid("malformed payload"))
+}
@@ -0,0 +1,166 @@
+//! Bytes sent and received, by connection, user and tenant.
+//!
+//! Each connection counts the frames and payload bytes it receives and
+//! sends; `GET /admin/connections` lists them, so a client whose few bytes
+//! in make many bytes out stands out. `GET /admin/bandwidth` rolls them up
+//! by user and by tenant, over the open connections and those that closed
+//! since the server started.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::hub::{ConnInfo, ConnStats};
+
+/// Traffic of a connection or a rollup of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+}
+
+impl Usage {
+    pub fn of(stats: &ConnStats) -> Usage {
+        let (frames_in, bytes_in) = stats.received_totals();
+        let (frames_out, bytes_out) = stats.sent_totals();
+        Usage {
+            frames_in,
+            bytes_in,
+            frames_out,
+            bytes_out,
+        }
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.frames_in += other.frames_in;
+        self.bytes_in += other.bytes_in;
+        self.frames_out += other.frames_out;
+        self.bytes_out += other.bytes_out;
+    }
+
+    fn total(&self) -> u64 {
+        self.bytes_in + self.bytes_out
+    }
+}
+
+/// Traffic of the connections of one user or tenant
+#[derive(Debug, Clone, Serialize)]
+pub struct Rollup {
+    pub name: String,
+    /// Open connections
+    pub connections: usize,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Rollups by user and tenant, most bytes first; anonymous connections
+/// have no user, those of no tenant no tenant
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub users: Vec<Rollup>,
+    pub anonymous: Usage,
+    pub tenants: Vec<Rollup>,
+}
+
+#[derive(Debug)]
+struct Closed {
+    users: BTreeMap<String, Usage>,
+    anonymous: Usage,
+    tenants: BTreeMap<String, Usage>,
+}
+
+/// Traffic of the connections that closed
+static CLOSED: Mutex<Closed> = Mutex::new(Closed {
+    users: BTreeMap::new(),
+    anonymous: Usage {
+        frames_in: 0,
+        bytes_in: 0,
+        frames_out: 0,
+        bytes_out: 0,
+    },
+    tenants: BTreeMap::new(),
+});
+
+/// Keep the traffic of a connection that closed
+pub fn closed(stats: &ConnStats) {
+    let usage = Usage::of(stats);
+    let mut closed = CLOSED.lock().unwrap();
+    match stats.user() {
+        Some(user) => closed
+            .users
+            .entry(user.to_string())
+            .or_default()
+            .add(&usage),
+        None => closed.anonymous.add(&usage),
+    }
+    if let Some(tenant) = stats.tenant() {
+        closed
+            .tenants
+            .entry(tenant.to_string())
+            .or_default()
+            .add(&usage);
+    }
+}
+
+/// Rollups of `open` connections and those that closed, at most `limit`
+/// users and tenants each
+pub fn report(open: &[ConnInfo], limit: usize) -> Report {
+    let closed = CLOSED.lock().unwrap();
+    let mut users: HashMap<&str, Rollup> = closed
+        .users
+        .iter()
+        .map(|(user, usage)| (user.as_str(), rollup(user, *usage)))
+        .collect();
+    let mut tenants: HashMap<&str, Rollup> = closed
+        .tenants
+        .iter()
+        .map(|(tenant, usage)| (tenant.as_str(), rollup(tenant, *usage)))
+        .collect();
+    let mut anonymous = closed.anonymous;
+    for conn in open {
+        match conn.user {
+            Some(ref user) => {
+                let rollup = users
+                    .entry(user)
+                    .or_insert_with(|| rollup(user, Usage::default()));
+                rollup.connections += 1;
+                rollup.usage.add(&conn.usage);
+            }
+            None => anonymous.add(&conn.usage),
+        }
+        if let Some(ref tenant) = conn.tenant {
+            let rollup = tenants
+                .entry(tenant)
+                .or_insert_with(|| rollup(tenant, Usage::default()));
+            rollup.connections += 1;
+            rollup.usage.add(&conn.usage);
+        }
+    }
+    Report {
+        users: top(users.into_values().collect(), limit),
+        anonymous,
+        tenants: top(tenants.into_values().collect(), limit),
+    }
+}
+
+fn rollup(name: &str, usage: Usage) -> Rollup {
+    Rollup {
+        name: name.to_string(),
+        connections: 0,
+        usage,
+    }
+}
+
+fn top(mut rollups: Vec<Rollup>, limit: usize) -> Vec<Rollup> {
+    rollups.sort_by(|a, b| {
+        b.usage
+            .total()
+            .cmp(&a.usage.total())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    rollups.truncate(limit);
+    rollups
+}
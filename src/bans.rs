@@ -0,0 +1,28 @@
+//! Users banned through the admin API.
+//!
+//! Bans are kept in memory only and are lost on restart; a banned user's
+//! handshakes are refused with 403.
+
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+
+static BANNED: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// Ban `user`, returns `false` if already banned
+pub fn ban(user: &str) -> bool {
+    BANNED.write().unwrap().insert(user.to_string())
+}
+
+/// Lift ban of `user`, returns `false` if not banned
+pub fn unban(user: &str) -> bool {
+    BANNED.write().unwrap().remove(user)
+}
+
+pub fn is_banned(user: &str) -> bool {
+    BANNED.read().unwrap().contains(user)
+}
+
+/// Banned users, sorted
+pub fn list() -> Vec<String> {
+    BANNED.read().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,292 @@
+//! Proof-of-work challenge for anonymous handshakes.
+//!
+//! With `[auth.challenge]` an anonymous client first fetches a challenge
+//! from `GET /ws/challenge`, then finds a `solution` string such that
+//! `SHA-256(challenge + solution)` starts with `difficulty` zero bits and
+//! connects with `/ws?challenge=..&solution=..`. That is cheap for one
+//! client and costly for a bot opening thousands; authenticated handshakes
+//! skip it. With difficulty 0 the challenge is a plain single-use token.
+//!
+//! Challenges are signed like invites, `{"nonce", "exp", "difficulty",
+//! "node"}` under `key`. A solved challenge is remembered until it expires
+//! and cannot be used twice, but only by the node it was solved on, so a
+//! challenge is only accepted by the node that issued it: the cluster node,
+//! or the process outside a cluster. Behind a load balancer the client must
+//! reach the same node for both requests, e.g. with `[affinity]`, whose
+//! cookie and header the challenge response carries too. With `key` set, a
+//! cluster node accepts its challenges across a restart, having forgotten
+//! which were used.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ntex::web::{types::State, HttpResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::affinity;
+use crate::auth::{self, Authenticator};
+use crate::config::{ChallengeConfig, Config};
+
+/// Challenge id generator, unique together with the issue time
+static NEXT_CHALLENGE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    nonce: String,
+    /// Unix seconds the challenge expires
+    exp: u64,
+    /// Leading zero bits the solution's hash needs
+    difficulty: u8,
+    /// Node that issued the challenge and alone accepts it
+    #[serde(default)]
+    node: String,
+}
+
+pub struct Challenges {
+    config: ChallengeConfig,
+    key: Vec<u8>,
+    /// This node, named in the challenges it issues
+    node: String,
+    /// Nonces of solved challenges with their expiry
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl std::fmt::Debug for Challenges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Challenges")
+            .field("difficulty", &self.config.difficulty)
+            .field("node", &self.node)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Challenge as served to clients
+#[derive(Debug, Serialize)]
+pub struct Issued {
+    pub challenge: String,
+    pub difficulty: u8,
+    pub expires_at: u64,
+}
+
+impl Challenges {
+    /// Challenges of `node`, the cluster node id, or of this process only
+    /// outside a cluster
+    pub fn new(config: &ChallengeConfig, node: Option<&str>) -> Challenges {
+        let key = match config.key {
+            Some(ref key) => key.as_bytes().to_vec(),
+            None => {
+                // only this process accepts its challenges
+                let mut key = vec![0; 32];
+                SystemRandom::new()
+                    .fill(&mut key)
+                    .expect("system randomness");
+                key
+            }
+        };
+        let node = match node {
+            Some(node) => node.to_string(),
+            None => {
+                let mut id = [0; 8];
+                SystemRandom::new()
+                    .fill(&mut id)
+                    .expect("system randomness");
+                id.iter().map(|b| format!("{:02x}", b)).collect()
+            }
+        };
+        Challenges {
+            config: config.clone(),
+            key,
+            node,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issue(&self) -> Issued {
+        let now = auth::now_secs();
+        let claims = Claims {
+            nonce: format!(
+                "{:x}-{:x}",
+                now,
+                NEXT_CHALLENGE.fetch_add(1, Ordering::Relaxed)
+            ),
+            exp: now + self.config.ttl_secs,
+            difficulty: self.config.difficulty,
+            node: self.node.clone(),
+        };
+        let challenge = auth::sign(
+            &serde_json::to_vec(&claims).expect("serializable challenge"),
+            &self.key,
+        );
+        Issued {
+            challenge,
+            difficulty: claims.difficulty,
+            expires_at: claims.exp,
+        }
+    }
+
+    /// Check a solved challenge and use it up, returns why it is refused
+    pub fn check(&self, challenge: Option<&str>, solution: Option<&str>) -> Result<(), String> {
+        let challenge = challenge.ok_or("challenge required, see /ws/challenge")?;
+        let claims = auth::verify_signed(challenge, &self.key).map_err(|e| e.to_string())?;
+        let claims: Claims =
+            serde_json::from_slice(&claims).map_err(|_| "malformed challenge".to_string())?;
+        let now = auth::now_secs();
+        if claims.exp <= now {
+            return Err("challenge expired".to_string());
+        }
+        // other nodes don't know whether it was used already
+        if claims.node != self.node {
+            return Err("challenge issued by another node".to_string());
+        }
+        let solution = solution.unwrap_or_default();
+        let hash = Sha256::new()
+            .chain_update(challenge)
+            .chain_update(solution)
+            .finalize();
+        if leading_zeros(&hash) < u32::from(claims.difficulty) {
+            return Err("wrong challenge solution".to_string());
+        }
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, exp| *exp > now);
+        if used.insert(claims.nonce, claims.exp).is_some() {
+            return Err("challenge already used".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// `GET /ws/challenge`, 404 unless challenges are configured
+pub async fn index(config: State<Arc<Config>>, auth: State<Arc<Authenticator>>) -> HttpResponse {
+    match auth.challenges() {
+        Some(challenges) => {
+            let mut res = HttpResponse::Ok();
+            // brings the handshake back to this node
+            for (name, value) in affinity::headers(&config) {
+                res.header(name, value);
+            }
+            res.json(&challenges.issue())
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenges(node: Option<&str>, difficulty: u8) -> Challenges {
+        let config = ChallengeConfig {
+            difficulty,
+            key: Some("shared".to_string()),
+            ..ChallengeConfig::default()
+        };
+        Challenges::new(&config, node)
+    }
+
+    fn solve(issued: &Issued) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| {
+                let hash = Sha256::new()
+                    .chain_update(&issued.challenge)
+                    .chain_update(solution)
+                    .finalize();
+                leading_zeros(&hash) >= u32::from(issued.difficulty)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn solved_once() {
+        let a = challenges(Some("a"), 8);
+        let issued = a.issue();
+        let solution = solve(&issued);
+        assert_eq!(a.check(Some(&issued.challenge), Some(&solution)), Ok(()));
+        assert_eq!(
+            a.check(Some(&issued.challenge), Some(&solution)),
+            Err("challenge already used".to_string())
+        );
+    }
+
+    #[test]
+    fn bound_to_the_issuing_node() {
+        let issued = challenges(Some("a"), 4).issue();
+        let solution = solve(&issued);
+        for other in [challenges(Some("b"), 4), challenges(None, 4)] {
+            assert_eq!(
+                other.check(Some(&issued.challenge), Some(&solution)),
+                Err("challenge issued by another node".to_string())
+            );
+        }
+        // the same node after a restart, with the same key
+        let restarted = challenges(Some("a"), 4);
+        assert_eq!(
+            restarted.check(Some(&issued.challenge), Some(&solution)),
+            Ok(())
+        );
+        // outside a cluster every process is a node of its own
+        let issued = challenges(None, 0).issue();
+        assert!(challenges(None, 0)
+            .check(Some(&issued.challenge), None)
+            .is_err());
+    }
+
+    #[test]
+    fn refused() {
+        let a = challenges(Some("a"), 16);
+        let issued = a.issue();
+        assert!(a.check(None, None).is_err());
+        assert!(a.check(Some("nonsense"), Some("1")).is_err());
+        let tampered = format!("x{}", issued.challenge);
+        assert!(a.check(Some(&tampered), Some("1")).is_err());
+        // one in 65536 solutions works by chance, this one does not
+        let wrong = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| {
+                let hash = Sha256::new()
+                    .chain_update(&issued.challenge)
+                    .chain_update(solution)
+                    .finalize();
+                leading_zeros(&hash) < 16
+            })
+            .unwrap();
+        assert_eq!(
+            a.check(Some(&issued.challenge), Some(&wrong)),
+            Err("wrong challenge solution".to_string())
+        );
+
+        let expired = Challenges::new(
+            &ChallengeConfig {
+                ttl_secs: 0,
+                ..ChallengeConfig::default()
+            },
+            Some("a"),
+        );
+        let issued = expired.issue();
+        assert_eq!(
+            expired.check(Some(&issued.challenge), None),
+            Err("challenge expired".to_string())
+        );
+    }
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zeros(&[0, 0, 0x10]), 19);
+        assert_eq!(leading_zeros(&[0x80]), 0);
+        assert_eq!(leading_zeros(&[0, 0]), 16);
+    }
+}
@@ -0,0 +1,351 @@
+//! Virtual channels multiplexed over one connection.
+//!
+//! Browsers cap the sockets per origin, so an application with several
+//! independent streams opens channels on one connection instead:
+//!
+//! ```json
+//! {"type": "channel.open", "id": "1", "payload": {"channel": 3, "service": "echo", "params": {}}}
+//! {"type": "channel.data", "payload": {"channel": 3, "data": {"any": "json"}}}
+//! {"type": "channel.close", "payload": {"channel": 3}}
+//! ```
+//!
+//! The client picks channel ids, unique among its open channels. Each
+//! channel is served by a handler of the named service, created on open and
+//! dropped on close or when the connection closes; a handler sends to the
+//! client through its `Sender`, also later from a task of its own. Services
+//! are registered by name at startup, `echo` is built in.
+//!
+//! A channel opened with `"credits": n` is flow controlled, in messages:
+//! the server sends at most `n` `channel.data` until the client grants more
+//! with `channel.credit`, holding back what a handler sends meanwhile, and
+//! the client may send as many as the `credits` of the open reply, the
+//! server granting more as its handler takes them. A busy channel then
+//! waits on its own window instead of filling the connection's queue ahead
+//! of the others.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+use ntex::ws;
+use serde_json::{json, Value};
+
+use crate::hub::Hub;
+use crate::protocol;
+
+/// Max channels open at once per connection
+pub const MAX_CHANNELS: usize = 64;
+
+/// Messages a flow controlled channel takes from the client before the
+/// server grants more
+pub const RECEIVE_WINDOW: u32 = 64;
+
+/// Messages held back per channel for lack of client credit, further ones
+/// are refused
+const MAX_HELD: usize = 1024;
+
+/// Opens channels of one kind
+pub trait Service: Send + Sync {
+    /// Handler of a new channel, with the client's `params`; `Err` refuses
+    /// the open with a reason
+    fn open(&self, sender: Sender, params: &Value) -> Result<Box<dyn Handler>, String>;
+}
+
+/// Serves one open channel, on the connection's worker
+pub trait Handler {
+    /// Data from the client; `Err` closes the channel with a reason
+    fn data(&mut self, data: Value) -> Result<(), String>;
+}
+
+static SERVICES: RwLock<Vec<(&'static str, Arc<dyn Service>)>> = RwLock::new(Vec::new());
+
+/// Serve channels opened with `service` by `name`, replacing an earlier one
+pub fn register(name: &'static str, service: Arc<dyn Service>) {
+    let mut services = SERVICES.write().unwrap();
+    services.retain(|(n, _)| *n != name);
+    services.push((name, service));
+}
+
+fn service(name: &str) -> Option<Arc<dyn Service>> {
+    let services = SERVICES.read().unwrap();
+    services
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, service)| service.clone())
+}
+
+/// Register the built-in services
+pub fn init() {
+    register("echo", Arc::new(Echo));
+}
+
+/// Server to client side of a channel
+#[derive(Debug, Default)]
+struct Outflow {
+    /// Set once closed by either side
+    closed: bool,
+    /// Messages the client accepts, `None` without flow control
+    credit: Option<u32>,
+    /// Sent while out of credit
+    held: VecDeque<ws::Message>,
+}
+
+/// Sends to the client on one channel
+#[derive(Clone)]
+pub struct Sender {
+    hub: Arc<Hub>,
+    conn: u64,
+    channel: u64,
+    flow: Rc<RefCell<Outflow>>,
+}
+
+impl Sender {
+    pub fn channel(&self) -> u64 {
+        self.channel
+    }
+
+    /// Queue `data` for the client, or hold it back until the client
+    /// grants credit; `false` if the channel or connection is closed or
+    /// too much is held back
+    pub fn send(&self, data: &Value) -> bool {
+        let mut flow = self.flow.borrow_mut();
+        if flow.closed {
+            return false;
+        }
+        let msg = protocol::message(
+            "channel.data",
+            None,
+            &json!({ "channel": self.channel, "data": data }),
+        );
+        match flow.credit {
+            Some(0) if flow.held.len() >= MAX_HELD => false,
+            Some(0) => {
+                flow.held.push_back(msg);
+                true
+            }
+            Some(credit) => {
+                flow.credit = Some(credit - 1);
+                self.hub.send(self.conn, msg)
+            }
+            None => self.hub.send(self.conn, msg),
+        }
+    }
+
+    /// Messages the client takes before more are held back, `None` without
+    /// flow control
+    pub fn credit(&self) -> Option<u32> {
+        self.flow.borrow().credit
+    }
+
+    /// Add client credit and send what it allows of the held messages
+    fn grant(&self, credits: u32) {
+        let mut flow = self.flow.borrow_mut();
+        let Some(credit) = flow.credit else {
+            return;
+        };
+        let mut credit = credit.saturating_add(credits);
+        while credit > 0 {
+            let Some(msg) = flow.held.pop_front() else {
+                break;
+            };
+            credit -= 1;
+            self.hub.send(self.conn, msg);
+        }
+        flow.credit = Some(credit);
+    }
+
+    fn set_closed(&self) {
+        let mut flow = self.flow.borrow_mut();
+        flow.closed = true;
+        flow.held.clear();
+    }
+
+    /// Close the channel from the server side
+    pub fn close(&self, reason: &str) {
+        if self.is_closed() {
+            return;
+        }
+        self.set_closed();
+        self.hub.send(
+            self.conn,
+            protocol::message(
+                "channel.close",
+                None,
+                &json!({ "channel": self.channel, "reason": reason }),
+            ),
+        );
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.flow.borrow().closed
+    }
+}
+
+/// Client to server side of a flow controlled channel
+#[derive(Debug)]
+struct Inflow {
+    /// Messages the client may still send
+    credit: u32,
+    /// Messages taken by the handler since credit was last granted
+    taken: u32,
+}
+
+struct Open {
+    sender: Sender,
+    handler: Box<dyn Handler>,
+    inflow: Option<Inflow>,
+}
+
+/// Open channels of a connection
+pub struct Channels {
+    hub: Arc<Hub>,
+    conn: u64,
+    open: HashMap<u64, Open>,
+}
+
+impl Channels {
+    pub fn new(hub: Arc<Hub>, conn: u64) -> Channels {
+        Channels {
+            hub,
+            conn,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Forget channels their handlers closed
+    fn prune(&mut self) {
+        self.open.retain(|_, open| !open.sender.is_closed());
+    }
+
+    /// Open `channel` of service `name`, flow controlled if the client
+    /// grants `credits`; returns the credits granted to the client then
+    pub fn open(
+        &mut self,
+        channel: u64,
+        name: &str,
+        params: &Value,
+        credits: Option<u32>,
+    ) -> Result<Option<u32>, String> {
+        self.prune();
+        if self.open.contains_key(&channel) {
+            return Err(format!("channel {} is open already", channel));
+        }
+        if self.open.len() >= MAX_CHANNELS {
+            return Err(format!("at most {} channels can be open", MAX_CHANNELS));
+        }
+        let service = service(name).ok_or_else(|| format!("unknown service `{}`", name))?;
+        let sender = Sender {
+            hub: self.hub.clone(),
+            conn: self.conn,
+            channel,
+            flow: Rc::new(RefCell::new(Outflow {
+                credit: credits,
+                ..Outflow::default()
+            })),
+        };
+        let handler = service.open(sender.clone(), params)?;
+        let inflow = credits.map(|_| Inflow {
+            credit: RECEIVE_WINDOW,
+            taken: 0,
+        });
+        let granted = inflow.as_ref().map(|inflow| inflow.credit);
+        self.open.insert(
+            channel,
+            Open {
+                sender,
+                handler,
+                inflow,
+            },
+        );
+        Ok(granted)
+    }
+
+    /// Pass client data to the channel's handler
+    pub fn data(&mut self, channel: u64, data: Value) -> Result<(), String> {
+        self.prune();
+        let open = self
+            .open
+            .get_mut(&channel)
+            .ok_or_else(|| format!("channel {} is not open", channel))?;
+        if let Some(ref mut inflow) = open.inflow {
+            if inflow.credit == 0 {
+                open.sender.close("flow control window exceeded");
+                self.open.remove(&channel);
+                return Ok(());
+            }
+            inflow.credit -= 1;
+        }
+        if let Err(reason) = open.handler.data(data) {
+            open.sender.close(&reason);
+            self.open.remove(&channel);
+            return Ok(());
+        }
+        if let Some(ref mut inflow) = open.inflow {
+            inflow.taken += 1;
+            // in batches, not a grant per message
+            if inflow.taken >= RECEIVE_WINDOW / 2 {
+                let credits = std::mem::take(&mut inflow.taken);
+                inflow.credit += credits;
+                self.hub.send(
+                    self.conn,
+                    protocol::message(
+                        "channel.credit",
+                        None,
+                        &json!({ "channel": channel, "credits": credits }),
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Credit granted by the client for data of `channel`
+    pub fn credit(&mut self, channel: u64, credits: u32) -> Result<(), String> {
+        self.prune();
+        let open = self
+            .open
+            .get(&channel)
+            .ok_or_else(|| format!("channel {} is not open", channel))?;
+        if open.sender.credit().is_none() {
+            return Err(format!("channel {} is not flow controlled", channel));
+        }
+        open.sender.grant(credits);
+        Ok(())
+    }
+
+    /// Close a channel from the client side, closing one not open is not
+    /// an error
+    pub fn close(&mut self, channel: u64) {
+        if let Some(open) = self.open.remove(&channel) {
+            open.sender.set_closed();
+        }
+    }
+}
+
+impl Drop for Channels {
+    /// Stop senders still held by tasks once the connection is gone
+    fn drop(&mut self) {
+        for open in self.open.values() {
+            open.sender.set_closed();
+        }
+    }
+}
+
+/// Sends data back on the same channel
+struct Echo;
+
+impl Service for Echo {
+    fn open(&self, sender: Sender, _params: &Value) -> Result<Box<dyn Handler>, String> {
+        Ok(Box::new(EchoHandler(sender)))
+    }
+}
+
+struct EchoHandler(Sender);
+
+impl Handler for EchoHandler {
+    fn data(&mut self, data: Value) -> Result<(), String> {
+        self.0.send(&data);
+        Ok(())
+    }
+}
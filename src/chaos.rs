@@ -0,0 +1,106 @@
+//! Faults injected into deliveries, to test clients against a hostile server.
+//!
+//! With `[chaos]`, the messages the hub delivers to a connection (room
+//! messages, broadcasts, direct messages and redeliveries, not the answers
+//! to the client's own frames) are held back, dropped, sent twice or end the
+//! connection at the configured probabilities:
+//!
+//! ```toml
+//! [chaos]
+//! connections = 0.5
+//! delay = 0.1
+//! delay_ms = 2000
+//! drop = 0.01
+//! duplicate = 0.01
+//! disconnect = 0.001
+//! ```
+//!
+//! Each probability is rolled for every text or binary message of the
+//! `connections` fraction of connections picked at the handshake. A delay of
+//! up to `delay_ms` holds back the messages after it too, as latency would;
+//! a disconnect closes the connection without a close frame, as a lost
+//! network would. Never configure it in production, the server warns at
+//! startup when it is.
+
+use std::time::Duration;
+
+use nanorand::{Rng, WyRand};
+use ntex::ws;
+
+use crate::config::ChaosConfig;
+use crate::metrics::METRICS;
+
+/// Check the probabilities are probabilities
+pub fn validate(config: &ChaosConfig) -> Result<(), String> {
+    let probabilities = [
+        ("connections", config.connections),
+        ("delay", config.delay),
+        ("drop", config.drop),
+        ("duplicate", config.duplicate),
+        ("disconnect", config.disconnect),
+    ];
+    for (name, p) in probabilities {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(format!("chaos.{} must be between 0 and 1, not {}", name, p));
+        }
+    }
+    Ok(())
+}
+
+/// What to do to a message on its way out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Hold it and the messages after it back
+    pub delay: Option<Duration>,
+    pub drop: bool,
+    pub duplicate: bool,
+    /// Close the connection instead of sending it
+    pub disconnect: bool,
+}
+
+#[derive(Debug)]
+pub struct Chaos {
+    config: ChaosConfig,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Chaos {
+        Chaos { config }
+    }
+
+    /// Whether a new connection gets faults
+    pub fn picks(&self) -> bool {
+        roll(self.config.connections)
+    }
+
+    /// Faults of `msg`, none for control frames
+    pub fn faults(&self, msg: &ws::Message) -> Faults {
+        if !matches!(msg, ws::Message::Text(_) | ws::Message::Binary(_)) {
+            return Faults::default();
+        }
+        let config = &self.config;
+        let faults = Faults {
+            delay: (config.delay_ms > 0 && roll(config.delay))
+                .then(|| Duration::from_millis(WyRand::new().generate_range(1..=config.delay_ms))),
+            drop: roll(config.drop),
+            duplicate: roll(config.duplicate),
+            disconnect: roll(config.disconnect),
+        };
+        if faults.delay.is_some() {
+            METRICS.chaos_delayed_total.inc();
+        }
+        if faults.disconnect {
+            METRICS.chaos_disconnects_total.inc();
+        } else if faults.drop {
+            METRICS.chaos_dropped_total.inc();
+        } else if faults.duplicate {
+            METRICS.chaos_duplicated_total.inc();
+        }
+        faults
+    }
+}
+
+/// `true` with probability `p`
+fn roll(p: f64) -> bool {
+    p >= 1.0 || (p > 0.0 && (WyRand::new().generate_range(0..1_000_000u32) as f64) < p * 1e6)
+}
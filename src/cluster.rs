@@ -0,0 +1,1074 @@
+//! Cluster mode, rooms shared by several nodes.
+//!
+//! Every room is owned by one live node, picked by consistent hashing of its
+//! name, so a node joining or failing only moves the rooms it takes over or
+//! owned. The owner assigns sequence numbers: a publish on another node is
+//! checked there (membership, mute, slow mode) and forwarded to the owner,
+//! which stores it and replicates it to every node. So all nodes deliver and
+//! store the messages of a room in the same order, and a new owner continues
+//! the sequence from its copy of the history.
+//!
+//! Nodes talk over the `/cluster` routes of their HTTP listener, with
+//! `Authorization: Bearer <cluster.secret>`. Each peer has one queue drained
+//! in order by a task, which sends batches of queued items to
+//! `POST /cluster/messages`; a batch of only replicated messages is retried a
+//! few times if the peer is unreachable, forwarded publishes fail with a
+//! retryable error instead.
+//!
+//! Membership is gossiped SWIM-style, without a coordinator: every
+//! `gossip_interval_ms` a node probes one other node in turn with
+//! `POST /cluster/gossip`, both sides exchanging and merging their member
+//! lists. If the probe fails, up to `INDIRECT_PROBES` other nodes are asked to
+//! probe it through `POST /cluster/probe`; if none reaches it, it becomes
+//! suspect and is declared dead unless it refutes the suspicion within
+//! `suspect_timeout_secs`. A member's state is versioned by an incarnation
+//! number only the member itself raises, to refute suspicion; a node starts
+//! with the current time as incarnation, so it rejoins after a restart. The
+//! nodes of `cluster.nodes` are the members known at startup.
+//!
+//! A room can also be handed to another node, to rebalance or before taking
+//! a node down, with `POST /admin/rooms/{room}/migrate` or every room at once
+//! with `POST /admin/cluster/drain`. The owner stops sequencing the room and
+//! sends the move, with the room's last sequence number, to every node in
+//! its queue, so the new owner has all earlier messages when it takes over.
+//! Members stay connected: their nodes forward publishes to the new owner
+//! from then on. Moves are gossiped too and the newest of a room wins; one
+//! to a node that died falls back to the ring. Publishes forwarded to the old
+//! owner meanwhile fail with a retryable error.
+//!
+//! Only publishing, direct messages and the objects of `crdt` are clustered.
+//! Membership, caps, moderation, read receipts and edits are per node, like
+//! connections.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use ntex::http::client::Client;
+use ntex::http::header;
+use ntex::time;
+use ntex::util::Bytes;
+use ntex::web::{self, types::State, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::auth;
+use crate::config::{ClusterConfig, Config};
+use crate::crdt::Object;
+use crate::history::{self, Entry};
+use crate::hub::Hub;
+use crate::kv::Scope;
+use crate::protocol::{self, ErrorCode};
+use crate::tasks::TaskHandle;
+
+/// Points of every node on the hash ring
+const VIRTUAL_NODES: usize = 64;
+
+/// Max items sent to a peer in one request
+const MAX_BATCH: usize = 64;
+
+/// Attempts to send a batch of replicated messages
+const ATTEMPTS: u32 = 3;
+
+/// Nodes asked to probe a node that failed a direct probe
+const INDIRECT_PROBES: usize = 2;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Consistent hash ring of node ids
+#[derive(Debug)]
+struct Ring {
+    /// Sorted by hash
+    points: Vec<(u64, String)>,
+}
+
+impl Ring {
+    fn new<'a>(nodes: impl Iterator<Item = &'a String>) -> Ring {
+        let mut points: Vec<(u64, String)> = nodes
+            .flat_map(|node| {
+                (0..VIRTUAL_NODES).map(move |i| (hash(&format!("{}#{}", node, i)), node.clone()))
+            })
+            .collect();
+        points.sort();
+        Ring { points }
+    }
+
+    /// Node owning `room`, the first point at or after its hash
+    fn owner(&self, room: &str) -> &str {
+        let hash = hash(room);
+        let i = self.points.partition_point(|(point, _)| *point < hash);
+        &self.points[i % self.points.len()].1
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+/// State of a member, later ones override earlier ones of the same
+/// incarnation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    /// Failed a probe, dead unless refuted in time
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug)]
+struct Member {
+    url: String,
+    incarnation: u64,
+    state: MemberState,
+    /// Time of the last state change
+    since: Instant,
+}
+
+/// A member as gossiped and listed by the admin API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rumor {
+    pub node: String,
+    pub url: String,
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+/// Room handed to another node than the ring gives it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Move {
+    pub room: String,
+    pub node: String,
+    /// Time of the move, unix ms; the newest move of a room wins
+    pub at: u64,
+    /// Sequence number of the room's last message when handed over
+    pub seq: u64,
+}
+
+impl Move {
+    fn is_newer(&self, other: &Move) -> bool {
+        (self.at, &self.node) > (other.at, &other.node)
+    }
+}
+
+#[derive(Debug)]
+struct Membership {
+    members: BTreeMap<String, Member>,
+    /// Of the members not dead
+    ring: Ring,
+}
+
+/// Item sent to a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Item {
+    /// Publish of a connection `from` of the sending node, to be sequenced
+    Publish {
+        room: String,
+        from: u64,
+        #[serde(default)]
+        user: Option<String>,
+        data: Value,
+    },
+    /// Message sequenced by the room's owner
+    Deliver { room: String, entry: Entry },
+    /// Room handed over by its owner
+    Move(Move),
+    /// State of a shared object after an update on the sending node
+    Crdt {
+        scope: String,
+        name: String,
+        object: Object,
+    },
+    /// Direct message to a user's connections on every node
+    Direct {
+        #[serde(default)]
+        tenant: Option<String>,
+        from: String,
+        to: String,
+        data: Value,
+    },
+}
+
+/// Result of an item, `None` for deliveries
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Outcome {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch {
+    node: String,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Outcomes {
+    results: Vec<Option<Outcome>>,
+}
+
+/// Member list exchanged by a probe and its answer
+#[derive(Debug, Serialize, Deserialize)]
+struct Gossip {
+    node: String,
+    members: Vec<Rumor>,
+    #[serde(default)]
+    moves: Vec<Move>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Probe {
+    node: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProbeResult {
+    ack: bool,
+}
+
+/// Connection and message id a forwarded publish is answered to
+type Reply = (u64, Option<String>);
+
+#[derive(Debug)]
+struct Outgoing {
+    item: Item,
+    reply: Option<Reply>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    url: String,
+    tx: mpsc::UnboundedSender<Outgoing>,
+    /// Taken by the peer's task
+    rx: Mutex<Option<mpsc::UnboundedReceiver<Outgoing>>>,
+}
+
+impl Peer {
+    fn new(url: &str) -> Peer {
+        let (tx, rx) = mpsc::unbounded();
+        Peer {
+            url: url.trim_end_matches('/').to_string(),
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    config: ClusterConfig,
+    membership: RwLock<Membership>,
+    /// Members not dead but this node; a peer's queue ends when it dies
+    peers: RwLock<HashMap<String, Peer>>,
+    /// Turn of the next member to probe
+    next_probe: AtomicUsize,
+    /// Rooms handed over, by name
+    moves: RwLock<HashMap<String, Move>>,
+    /// Read while a room is sequenced here, written while one is handed over
+    handoff: RwLock<()>,
+}
+
+impl Cluster {
+    pub fn new(config: ClusterConfig) -> Result<Cluster, String> {
+        let url = match config
+            .url
+            .as_ref()
+            .or_else(|| config.nodes.get(&config.node))
+        {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                return Err(format!(
+                    "cluster node {} has neither a url nor an entry in cluster.nodes",
+                    config.node
+                ))
+            }
+        };
+        if config.secret.is_empty() {
+            return Err("cluster secret is empty".to_string());
+        }
+        let now = Instant::now();
+        let mut members: BTreeMap<String, Member> = config
+            .nodes
+            .iter()
+            .map(|(id, url)| {
+                let member = Member {
+                    url: url.trim_end_matches('/').to_string(),
+                    incarnation: 0,
+                    state: MemberState::Alive,
+                    since: now,
+                };
+                (id.clone(), member)
+            })
+            .collect();
+        members.insert(
+            config.node.clone(),
+            Member {
+                url,
+                incarnation: history::now_millis(),
+                state: MemberState::Alive,
+                since: now,
+            },
+        );
+        let peers = members
+            .iter()
+            .filter(|(id, _)| **id != config.node)
+            .map(|(id, member)| (id.clone(), Peer::new(&member.url)))
+            .collect();
+        Ok(Cluster {
+            membership: RwLock::new(Membership {
+                ring: Ring::new(members.keys()),
+                members,
+            }),
+            peers: RwLock::new(peers),
+            next_probe: AtomicUsize::new(0),
+            moves: RwLock::new(HashMap::new()),
+            handoff: RwLock::new(()),
+            config,
+        })
+    }
+
+    /// Id of this node
+    pub fn node(&self) -> &str {
+        &self.config.node
+    }
+
+    /// Id of the node owning `room`
+    pub fn owner(&self, room: &str) -> String {
+        let membership = self.membership.read().unwrap();
+        if let Some(moved) = self.moves.read().unwrap().get(room) {
+            let live = membership
+                .members
+                .get(&moved.node)
+                .is_some_and(|m| m.state != MemberState::Dead);
+            if live {
+                return moved.node.clone();
+            }
+        }
+        membership.ring.owner(room).to_string()
+    }
+
+    /// Hold while checking for ownership of a room and sequencing its
+    /// message, so it is not handed over in between
+    pub fn sequencing(&self) -> RwLockReadGuard<'_, ()> {
+        self.handoff.read().unwrap()
+    }
+
+    /// Rooms handed over, ordered by name
+    pub fn moves(&self) -> Vec<Move> {
+        let mut moves: Vec<Move> = self.moves.read().unwrap().values().cloned().collect();
+        moves.sort_by(|a, b| a.room.cmp(&b.room));
+        moves
+    }
+
+    /// Hand `room` over to `node`, by default to the one the ring gives it
+    /// without this node; returns the new owner
+    pub fn migrate(&self, hub: &Hub, room: &str, node: Option<&str>) -> Result<String, String> {
+        let _handoff = self.handoff.write().unwrap();
+        let owner = self.owner(room);
+        if owner != self.config.node {
+            return Err(format!("room {} is owned by node {}", room, owner));
+        }
+        let node = match node {
+            Some(node) => node.to_string(),
+            None => self
+                .successors()
+                .map(|ring| ring.owner(room).to_string())
+                .ok_or("no other live node")?,
+        };
+        self.hand_over(hub, room, &node)?;
+        Ok(node)
+    }
+
+    /// Hand every room this node owns over to the nodes the ring gives
+    /// them without it; returns the rooms with their new owners
+    pub fn drain_rooms(&self, hub: &Hub) -> Result<Vec<(String, String)>, String> {
+        let _handoff = self.handoff.write().unwrap();
+        let ring = self.successors().ok_or("no other live node")?;
+        let mut moved = Vec::new();
+        for room in hub.rooms().names() {
+            if self.owner(&room) != self.config.node {
+                continue;
+            }
+            let node = ring.owner(&room).to_string();
+            self.hand_over(hub, &room, &node)?;
+            moved.push((room, node));
+        }
+        Ok(moved)
+    }
+
+    /// Ring of the live members but this node, `None` if there are none
+    fn successors(&self) -> Option<Ring> {
+        let membership = self.membership.read().unwrap();
+        let others: Vec<&String> = membership
+            .members
+            .iter()
+            .filter(|(id, m)| **id != self.config.node && m.state != MemberState::Dead)
+            .map(|(id, _)| id)
+            .collect();
+        match others.is_empty() {
+            true => None,
+            false => Some(Ring::new(others.into_iter())),
+        }
+    }
+
+    /// Send the move of `room` to `node` to every peer, with `handoff` held
+    fn hand_over(&self, hub: &Hub, room: &str, node: &str) -> Result<(), String> {
+        if node == self.config.node {
+            return Err(format!("room {} is owned by this node already", room));
+        }
+        // nothing is sequenced meanwhile, and the rooms are not locked below
+        let seq = hub.rooms().last_seq(room);
+        let peers = self.peers.read().unwrap();
+        if !peers.contains_key(node) {
+            return Err(format!("node {} is not a live member", node));
+        }
+        let mut moves = self.moves.write().unwrap();
+        let at = match moves.get(room) {
+            Some(last) => history::now_millis().max(last.at + 1),
+            None => history::now_millis(),
+        };
+        let moved = Move {
+            room: room.to_string(),
+            node: node.to_string(),
+            at,
+            seq,
+        };
+        for peer in peers.values() {
+            let item = Item::Move(moved.clone());
+            let _ = peer.tx.unbounded_send(Outgoing { item, reply: None });
+        }
+        log::info!(
+            "Room {} handed over to cluster node {} at seq {}",
+            room,
+            node,
+            moved.seq
+        );
+        moves.insert(room.to_string(), moved);
+        Ok(())
+    }
+
+    /// Merge moves sent or gossiped by other nodes, taking over rooms moved
+    /// to this node
+    fn adopt(&self, hub: &Hub, moves: Vec<Move>) {
+        let mut taken = Vec::new();
+        {
+            let mut known = self.moves.write().unwrap();
+            for moved in moves {
+                if known
+                    .get(&moved.room)
+                    .is_some_and(|old| !moved.is_newer(old))
+                {
+                    continue;
+                }
+                if moved.node == self.config.node {
+                    taken.push((moved.room.clone(), moved.seq));
+                }
+                known.insert(moved.room.clone(), moved);
+            }
+        }
+        for (room, seq) in taken {
+            hub.rooms().resume(&room, seq);
+            log::info!("Room {} taken over by this node after seq {}", room, seq);
+        }
+    }
+
+    /// All members known, dead ones included, ordered by id
+    pub fn members(&self) -> Vec<Rumor> {
+        let membership = self.membership.read().unwrap();
+        membership
+            .members
+            .iter()
+            .map(|(id, member)| Rumor {
+                node: id.clone(),
+                url: member.url.clone(),
+                incarnation: member.incarnation,
+                state: member.state,
+            })
+            .collect()
+    }
+
+    /// Forward a publish of connection `from` to the room's owner, the
+    /// connection gets the owner's answer as reply to message `id`. Returns
+    /// `false` if the owner is no longer a peer.
+    pub fn forward(
+        &self,
+        owner: &str,
+        room: &str,
+        from: u64,
+        user: Option<String>,
+        data: &Value,
+        id: Option<&str>,
+    ) -> bool {
+        let item = Item::Publish {
+            room: room.to_string(),
+            from,
+            user,
+            data: data.clone(),
+        };
+        let reply = Some((from, id.map(str::to_string)));
+        match self.peers.read().unwrap().get(owner) {
+            Some(peer) => peer.tx.unbounded_send(Outgoing { item, reply }).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Send a message sequenced here to every other node; messages of this
+    /// node's connections are tagged with its id
+    pub fn replicate(&self, room: &str, entry: &Entry) {
+        let mut entry = entry.clone();
+        if entry.node.is_none() {
+            entry.node = Some(self.config.node.clone());
+        }
+        for peer in self.peers.read().unwrap().values() {
+            let item = Item::Deliver {
+                room: room.to_string(),
+                entry: entry.clone(),
+            };
+            let _ = peer.tx.unbounded_send(Outgoing { item, reply: None });
+        }
+    }
+
+    /// Send the state of object `name` of `scope` to every other node
+    pub fn replicate_crdt(&self, scope: &Scope, name: &str, object: &Object) {
+        for peer in self.peers.read().unwrap().values() {
+            let item = Item::Crdt {
+                scope: scope.to_string(),
+                name: name.to_string(),
+                object: object.clone(),
+            };
+            let _ = peer.tx.unbounded_send(Outgoing { item, reply: None });
+        }
+    }
+
+    /// Send a direct message to every other node, for the connections of
+    /// its recipient there
+    pub fn replicate_direct(&self, tenant: Option<&str>, from: &str, to: &str, data: &Value) {
+        for peer in self.peers.read().unwrap().values() {
+            let item = Item::Direct {
+                tenant: tenant.map(str::to_string),
+                from: from.to_string(),
+                to: to.to_string(),
+                data: data.clone(),
+            };
+            let _ = peer.tx.unbounded_send(Outgoing { item, reply: None });
+        }
+    }
+
+    /// Handle an item sent by node `from`
+    fn receive(&self, hub: &Hub, from: &str, item: Item) -> Option<Outcome> {
+        match item {
+            Item::Publish {
+                room,
+                from: conn,
+                user,
+                data,
+            } => {
+                let _sequencing = self.sequencing();
+                if self.owner(&room) != self.config.node {
+                    return Some(Outcome {
+                        error: Some(format!("node {} does not own the room", self.config.node)),
+                        ..Outcome::default()
+                    });
+                }
+                let entry = Entry {
+                    seq: 0,
+                    from: conn,
+                    user,
+                    node: Some(from.to_string()),
+                    data,
+                    at: history::now_millis(),
+                    edited_at: None,
+                    deleted: false,
+                };
+                let seq = hub.sequence(&room, entry);
+                Some(Outcome {
+                    seq: Some(seq),
+                    ..Outcome::default()
+                })
+            }
+            Item::Deliver { room, mut entry } => {
+                // own connections' messages, sequenced by the owner
+                if entry.node.as_deref() == Some(self.config.node.as_str()) {
+                    entry.node = None;
+                }
+                hub.deliver(&room, entry);
+                None
+            }
+            Item::Move(moved) => {
+                self.adopt(hub, vec![moved]);
+                None
+            }
+            Item::Crdt {
+                scope,
+                name,
+                object,
+            } => {
+                match scope.parse::<Scope>() {
+                    Ok(scope) => hub.crdt_merge(&scope, &name, object),
+                    Err(e) => {
+                        log::warn!("Shared object {} from node {} dropped: {}", name, from, e)
+                    }
+                }
+                None
+            }
+            Item::Direct {
+                tenant,
+                from,
+                to,
+                data,
+            } => {
+                hub.deliver_direct(tenant.as_deref(), &from, &to, &data, 0);
+                None
+            }
+        }
+    }
+
+    /// Merge gossiped members into ours
+    fn merge(&self, rumors: Vec<Rumor>) {
+        let mut membership = self.membership.write().unwrap();
+        let mut changed = false;
+        for rumor in rumors {
+            if rumor.node == self.config.node {
+                // refute suspicion of this node or its death
+                let me = membership.members.get_mut(&rumor.node).expect("self");
+                if rumor.state != MemberState::Alive && rumor.incarnation >= me.incarnation {
+                    me.incarnation = rumor.incarnation + 1;
+                    log::info!(
+                        "Refuting cluster rumor of this node being {:?}",
+                        rumor.state
+                    );
+                }
+                continue;
+            }
+            let newer = match membership.members.get(&rumor.node) {
+                Some(member) => {
+                    (rumor.incarnation, rumor.state) > (member.incarnation, member.state)
+                }
+                None => true,
+            };
+            if !newer {
+                continue;
+            }
+            let old = membership.members.insert(
+                rumor.node.clone(),
+                Member {
+                    url: rumor.url,
+                    incarnation: rumor.incarnation,
+                    state: rumor.state,
+                    since: Instant::now(),
+                },
+            );
+            if old.as_ref().map(|m| m.state) != Some(rumor.state) {
+                log::info!("Cluster node {} is {:?}", rumor.node, rumor.state);
+                changed = true;
+            }
+        }
+        if changed {
+            self.rebalance(&mut membership);
+        }
+    }
+
+    /// Mark member `id` suspect, after a failed probe
+    fn suspect(&self, id: &str) {
+        let mut membership = self.membership.write().unwrap();
+        if let Some(member) = membership.members.get_mut(id) {
+            if member.state == MemberState::Alive {
+                log::info!("Cluster node {} is suspect, it failed a probe", id);
+                member.state = MemberState::Suspect;
+                member.since = Instant::now();
+            }
+        }
+    }
+
+    /// Declare members dead that stayed suspect too long
+    fn expire(&self) {
+        let timeout = Duration::from_secs(self.config.suspect_timeout_secs);
+        let mut membership = self.membership.write().unwrap();
+        let mut changed = false;
+        for (id, member) in membership.members.iter_mut() {
+            if member.state == MemberState::Suspect && member.since.elapsed() >= timeout {
+                log::warn!("Cluster node {} is dead, moving its rooms", id);
+                member.state = MemberState::Dead;
+                member.since = Instant::now();
+                changed = true;
+            }
+        }
+        if changed {
+            self.rebalance(&mut membership);
+        }
+    }
+
+    /// Rebuild the ring and peers after members came or went
+    fn rebalance(&self, membership: &mut Membership) {
+        let live: Vec<&String> = membership
+            .members
+            .iter()
+            .filter(|(_, m)| m.state != MemberState::Dead)
+            .map(|(id, _)| id)
+            .collect();
+        membership.ring = Ring::new(live.iter().copied());
+        let mut peers = self.peers.write().unwrap();
+        peers.retain(|id, peer| {
+            membership.members.get(id).is_some_and(|m| {
+                m.state != MemberState::Dead && m.url.trim_end_matches('/') == peer.url
+            })
+        });
+        for id in live {
+            if *id != self.config.node && !peers.contains_key(id) {
+                peers.insert(id.clone(), Peer::new(&membership.members[id].url));
+            }
+        }
+    }
+
+    /// Next member to probe, in turn; dead ones too, at startup the nodes of
+    /// `cluster.nodes` may not be up yet
+    fn probe_target(&self) -> Option<(String, String)> {
+        let membership = self.membership.read().unwrap();
+        let others: Vec<(&String, &Member)> = membership
+            .members
+            .iter()
+            .filter(|(id, _)| **id != self.config.node)
+            .collect();
+        if others.is_empty() {
+            return None;
+        }
+        let turn = self.next_probe.fetch_add(1, Ordering::Relaxed);
+        let (id, member) = others[turn % others.len()];
+        Some((id.clone(), member.url.clone()))
+    }
+
+    /// Live members but this node and `except`, to probe another one
+    fn helpers(&self, except: &str) -> Vec<String> {
+        let membership = self.membership.read().unwrap();
+        let start = self.next_probe.load(Ordering::Relaxed);
+        let mut helpers: Vec<String> = membership
+            .members
+            .iter()
+            .filter(|(id, m)| {
+                **id != self.config.node && *id != except && m.state == MemberState::Alive
+            })
+            .map(|(_, m)| m.url.clone())
+            .collect();
+        if !helpers.is_empty() {
+            let turn = start % helpers.len();
+            helpers.rotate_left(turn);
+        }
+        helpers.truncate(INDIRECT_PROBES);
+        helpers
+    }
+
+    /// Probe members and declare failed ones dead
+    async fn gossip_round(&self, hub: &Hub) {
+        self.expire();
+        let (id, url) = match self.probe_target() {
+            Some(target) => target,
+            None => return,
+        };
+        if let Err(e) = self.probe(hub, &url).await {
+            log::debug!("Cluster node {} failed a probe: {}", id, e);
+            let mut ack = false;
+            for helper in self.helpers(&id) {
+                if self.probe_through(&helper, &id).await.unwrap_or(false) {
+                    ack = true;
+                    break;
+                }
+            }
+            if !ack {
+                self.suspect(&id);
+            }
+        }
+    }
+
+    /// Exchange member lists and moves with the node at `url`
+    async fn probe(&self, hub: &Hub, url: &str) -> Result<(), String> {
+        let gossip = Gossip {
+            node: self.config.node.clone(),
+            members: self.members(),
+            moves: self.moves(),
+        };
+        let mut res = self
+            .request(url, "gossip")
+            .send_json(&gossip)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(res.status().to_string());
+        }
+        let gossip: Gossip = res
+            .json()
+            .limit(1024 * 1024)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.merge(gossip.members);
+        self.adopt(hub, gossip.moves);
+        Ok(())
+    }
+
+    /// Ask the node at `url` to probe member `id`
+    async fn probe_through(&self, url: &str, id: &str) -> Result<bool, String> {
+        let mut res = self
+            .request(url, "probe")
+            .send_json(&Probe {
+                node: id.to_string(),
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(res.status().to_string());
+        }
+        let result: ProbeResult = res.json().await.map_err(|e| e.to_string())?;
+        Ok(result.ack)
+    }
+
+    /// Request to `/cluster/<route>` of the node at `url`, timing out within
+    /// a gossip interval
+    fn request(&self, url: &str, route: &str) -> ntex::http::client::ClientRequest {
+        let timeout = Duration::from_millis(self.config.gossip_interval_ms.max(100));
+        // clients are bound to a worker thread
+        Client::build()
+            .timeout(timeout)
+            .finish()
+            .post(format!("{}/cluster/{}", url, route))
+            .bearer_auth(&self.config.secret)
+    }
+
+    /// Start the tasks of peers that have none yet
+    fn spawn_drains(self: &Arc<Self>, hub: &Arc<Hub>) {
+        for (id, peer) in self.peers.read().unwrap().iter() {
+            if let Some(rx) = peer.rx.lock().unwrap().take() {
+                let url = format!("{}/cluster/messages", peer.url);
+                ntex::rt::spawn(drain(self.clone(), hub.clone(), id.clone(), url, rx));
+            }
+        }
+    }
+}
+
+/// Register the routes other nodes call
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/cluster/messages").route(web::post().to(post_messages)))
+        .service(web::resource("/cluster/gossip").route(web::post().to(post_gossip)))
+        .service(web::resource("/cluster/probe").route(web::post().to(post_probe)));
+}
+
+/// Check the cluster bearer token, returns error response if request is not
+/// allowed
+fn authorize<'a>(
+    req: &HttpRequest,
+    config: &Config,
+    hub: &'a Hub,
+) -> Result<&'a Arc<Cluster>, HttpResponse> {
+    match (config.cluster.as_ref(), hub.cluster()) {
+        (Some(config), Some(cluster)) => {
+            let provided = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "));
+            if !auth::secret_matches(provided, &config.secret) {
+                return Err(HttpResponse::Unauthorized().finish());
+            }
+            Ok(cluster)
+        }
+        _ => Err(HttpResponse::NotFound().finish()),
+    }
+}
+
+async fn post_messages(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    body: Bytes,
+) -> HttpResponse {
+    let cluster = match authorize(&req, &config, &hub) {
+        Ok(cluster) => cluster,
+        Err(res) => return res,
+    };
+    let batch: Batch = match serde_json::from_slice(&body) {
+        Ok(batch) => batch,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if !cluster.peers.read().unwrap().contains_key(&batch.node) {
+        return HttpResponse::BadRequest().body(format!("unknown node {}", batch.node));
+    }
+    let results = batch
+        .items
+        .into_iter()
+        .map(|item| cluster.receive(&hub, &batch.node, item))
+        .collect();
+    HttpResponse::Ok().json(&Outcomes { results })
+}
+
+/// Answer a probe with the merged member list
+async fn post_gossip(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    body: Bytes,
+) -> HttpResponse {
+    let cluster = match authorize(&req, &config, &hub) {
+        Ok(cluster) => cluster,
+        Err(res) => return res,
+    };
+    let gossip: Gossip = match serde_json::from_slice(&body) {
+        Ok(gossip) => gossip,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    cluster.merge(gossip.members);
+    cluster.adopt(&hub, gossip.moves);
+    HttpResponse::Ok().json(&Gossip {
+        node: cluster.config.node.clone(),
+        members: cluster.members(),
+        moves: cluster.moves(),
+    })
+}
+
+/// Probe a member for a node that could not reach it
+async fn post_probe(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    body: Bytes,
+) -> HttpResponse {
+    let cluster = match authorize(&req, &config, &hub) {
+        Ok(cluster) => cluster,
+        Err(res) => return res,
+    };
+    let probe: Probe = match serde_json::from_slice(&body) {
+        Ok(probe) => probe,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let url = cluster
+        .membership
+        .read()
+        .unwrap()
+        .members
+        .get(&probe.node)
+        .map(|m| m.url.clone());
+    let ack = match url {
+        Some(url) => cluster.probe(&hub, &url).await.is_ok(),
+        None => false,
+    };
+    HttpResponse::Ok().json(&ProbeResult { ack })
+}
+
+/// Gossip membership and send queued items to each peer
+pub async fn run(cluster: Arc<Cluster>, hub: Arc<Hub>) {
+    let task = TaskHandle::register("gossip", None);
+    let interval = Duration::from_millis(cluster.config.gossip_interval_ms.max(100));
+    loop {
+        cluster.spawn_drains(&hub);
+        task.set_state("sleeping");
+        time::sleep(interval).await;
+        task.set_state("probing");
+        cluster.gossip_round(&hub).await;
+    }
+}
+
+/// Send the queue of peer `id` in order, until the peer dies
+async fn drain(
+    cluster: Arc<Cluster>,
+    hub: Arc<Hub>,
+    id: String,
+    url: String,
+    mut rx: mpsc::UnboundedReceiver<Outgoing>,
+) {
+    let task = TaskHandle::register("cluster", None);
+    loop {
+        task.set_state("idle");
+        let first = match rx.next().await {
+            Some(first) => first,
+            None => return,
+        };
+        let mut queued = vec![first];
+        while queued.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(next) => queued.push(next),
+                _ => break,
+            }
+        }
+        task.set_state("sending");
+        let batch = Batch {
+            node: cluster.config.node.clone(),
+            items: queued.iter().map(|o| o.item.clone()).collect(),
+        };
+        // a forwarded publish may have been sequenced before the error
+        let attempts = match queued.iter().any(|o| o.reply.is_some()) {
+            true => 1,
+            false => ATTEMPTS,
+        };
+        let mut results = Err(String::new());
+        for attempt in 1..=attempts {
+            results = send(&url, &cluster.config.secret, &batch).await;
+            match results {
+                Err(ref e) if attempt < attempts => {
+                    log::debug!("Cannot reach cluster node {}, retrying: {}", id, e);
+                    time::sleep(Duration::from_secs(attempt as u64)).await;
+                }
+                _ => break,
+            }
+        }
+        let results = match results {
+            Ok(results) => results,
+            Err(e) => {
+                log::warn!(
+                    "Cannot send {} items to cluster node {}: {}",
+                    queued.len(),
+                    id,
+                    e
+                );
+                Vec::new()
+            }
+        };
+        let mut results = results.into_iter();
+        for outgoing in queued {
+            let result = results.next().flatten();
+            if let (Some((conn, msg_id)), Item::Publish { room, .. }) =
+                (outgoing.reply, &outgoing.item)
+            {
+                hub.send(conn, answer(room, result, msg_id.as_deref()));
+            }
+        }
+    }
+}
+
+async fn send(url: &str, secret: &str, batch: &Batch) -> Result<Vec<Option<Outcome>>, String> {
+    // clients are bound to a worker thread
+    let mut res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(url)
+        .bearer_auth(secret)
+        .send_json(batch)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(res.status().to_string());
+    }
+    let outcomes: Outcomes = res
+        .json()
+        .limit(1024 * 1024)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(outcomes.results)
+}
+
+/// Reply to a forwarded publish
+fn answer(room: &str, result: Option<Outcome>, id: Option<&str>) -> ntex::ws::Message {
+    match result {
+        Some(Outcome { seq: Some(seq), .. }) => {
+            protocol::message("room.publish", id, &json!({ "room": room, "seq": seq }))
+        }
+        Some(Outcome {
+            error: Some(error), ..
+        }) => protocol::Error::new(ErrorCode::Internal, error).to_message(id),
+        _ => protocol::Error::new(ErrorCode::Internal, "room owner unreachable").to_message(id),
+    }
+}
@@ -0,0 +1,315 @@
+//! Compression of history log lines with per room dictionaries.
+//!
+//! Chat messages are too short for DEFLATE to find much to reuse within
+//! one, but messages of a room share a lot: field names, user names,
+//! phrases. Each room gets a preset dictionary trained on its messages, the
+//! compressor is primed with it and back-references reach into it, so even
+//! a short line compresses well.
+//!
+//! Training picks the byte runs shared by the most messages, in the spirit
+//! of zstd's COVER trainer: messages are cut into segments, a segment scores
+//! by how many messages share its 8-byte substrings, and the best segments
+//! are taken greedily, substrings already covered no longer scoring. The
+//! best segments are placed last, closest to the data and cheapest to
+//! reference.
+//!
+//! A compressed line is `z:<dictionary id>:<base64 of raw DEFLATE>`, id 0
+//! for none; a line is only compressed if that is shorter.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush, TDEFLStatus,
+};
+use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+/// Prefix of compressed lines, after which come the dictionary id and data
+pub const LINE_PREFIX: &str = "z:";
+
+/// Upper bound of dictionaries, well within DEFLATE's 32 KiB window
+pub const MAX_DICTIONARY: usize = 16 * 1024;
+
+/// Max size of an inflated line
+const MAX_LINE: usize = 16 * 1024 * 1024;
+
+/// Length of the substrings segments are scored by
+const DMER: usize = 8;
+
+/// Length of the segments a dictionary is made of
+const SEGMENT: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    /// Referenced by compressed lines, unique within a room log
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Dictionary of at most `size` bytes for data like `samples`
+pub fn train(samples: &[Vec<u8>], size: usize) -> Vec<u8> {
+    let size = size.min(MAX_DICTIONARY);
+    // number of samples each substring occurs in
+    let mut freq: HashMap<&[u8], u32> = HashMap::new();
+    for sample in samples {
+        let distinct: HashSet<&[u8]> = sample.windows(DMER).collect();
+        for dmer in distinct {
+            *freq.entry(dmer).or_default() += 1;
+        }
+    }
+    let segments: Vec<&[u8]> = samples
+        .iter()
+        .flat_map(|sample| {
+            (0..sample.len().saturating_sub(DMER - 1))
+                .step_by(DMER)
+                .map(move |at| &sample[at..(at + SEGMENT).min(sample.len())])
+        })
+        .collect();
+    let mut covered: HashSet<&[u8]> = HashSet::new();
+    let score = |segment: &[u8], covered: &HashSet<&[u8]>| -> u32 {
+        let distinct: HashSet<&[u8]> = segment.windows(DMER).collect();
+        distinct
+            .into_iter()
+            .filter(|dmer| !covered.contains(dmer))
+            .map(|dmer| freq[dmer])
+            // a substring of one sample is no use to the others
+            .filter(|n| *n > 1)
+            .sum()
+    };
+    // scores only fall as substrings get covered, so a segment whose
+    // recomputed score still tops the heap is the best one
+    let mut heap: BinaryHeap<(u32, Reverse<usize>)> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| (score(segment, &covered), Reverse(i)))
+        .filter(|(score, _)| *score > 0)
+        .collect();
+    let mut chosen = Vec::new();
+    let mut len = 0;
+    while len < size {
+        let Some((_, Reverse(i))) = heap.pop() else {
+            break;
+        };
+        let now = score(segments[i], &covered);
+        if now == 0 {
+            continue;
+        }
+        if heap.peek().is_some_and(|(top, _)| *top > now) {
+            heap.push((now, Reverse(i)));
+            continue;
+        }
+        covered.extend(segments[i].windows(DMER));
+        chosen.push(segments[i]);
+        len += segments[i].len();
+    }
+    let mut dictionary: Vec<u8> = chosen.into_iter().rev().flatten().copied().collect();
+    if dictionary.len() > size {
+        dictionary.drain(..dictionary.len() - size);
+    }
+    dictionary
+}
+
+/// Raw DEFLATE of `data`, which may reference `dictionary`
+pub fn deflate(mut data: &[u8], dictionary: &[u8], level: u8) -> Vec<u8> {
+    let flags = create_comp_flags_from_zip_params(level.into(), 0, 0);
+    let mut compressor = CompressorOxide::new(flags);
+    if !dictionary.is_empty() {
+        // prime the window; a sync flush ends on a block boundary, so what
+        // follows decodes on its own once the window holds the dictionary
+        let mut primed = vec![0; dictionary.len() * 2 + 64];
+        let (status, consumed, _) =
+            compress(&mut compressor, dictionary, &mut primed, TDEFLFlush::Sync);
+        assert!(status == TDEFLStatus::Okay && consumed == dictionary.len());
+    }
+    let mut out = vec![0; (data.len() / 2).max(64)];
+    let mut pos = 0;
+    loop {
+        let (status, consumed, written) =
+            compress(&mut compressor, data, &mut out[pos..], TDEFLFlush::Finish);
+        pos += written;
+        data = &data[consumed..];
+        match status {
+            TDEFLStatus::Done => {
+                out.truncate(pos);
+                return out;
+            }
+            TDEFLStatus::Okay => {
+                if out.len() - pos < 64 {
+                    out.resize(out.len() * 2, 0);
+                }
+            }
+            status => panic!("deflate failed: {:?}", status),
+        }
+    }
+}
+
+/// Inverse of `deflate` with the same dictionary
+pub fn inflate(mut data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, String> {
+    let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    // the output buffer is the window, starting out with the dictionary
+    let mut out = dictionary.to_vec();
+    let mut pos = out.len();
+    out.resize(pos + (data.len() * 4).max(256), 0);
+    loop {
+        let (status, consumed, written) = decompress(&mut decompressor, data, &mut out, pos, flags);
+        pos += written;
+        match status {
+            TINFLStatus::Done => {
+                out.truncate(pos);
+                return Ok(out.split_off(dictionary.len()));
+            }
+            TINFLStatus::HasMoreOutput if out.len() < MAX_LINE + dictionary.len() => {
+                data = &data[consumed..];
+                let len = (out.len() * 2).min(MAX_LINE + dictionary.len());
+                out.resize(len, 0);
+            }
+            status => return Err(format!("cannot inflate: {:?}", status)),
+        }
+    }
+}
+
+/// `line` compressed with `dictionary`, or as is if that is not shorter
+pub fn pack_line(line: &str, dictionary: Option<&Dictionary>, level: u8) -> String {
+    let (id, dictionary) = dictionary.map_or((0, &[][..]), |d| (d.id, &d.data[..]));
+    let packed = format!(
+        "{}{}:{}",
+        LINE_PREFIX,
+        id,
+        STANDARD.encode(deflate(line.as_bytes(), dictionary, level))
+    );
+    match packed.len() < line.len() {
+        true => packed,
+        false => line.to_string(),
+    }
+}
+
+/// Plain text of a line that may be compressed, with the room's
+/// dictionaries by id
+pub fn unpack_line(line: &str, dictionaries: &HashMap<u32, Vec<u8>>) -> Result<String, String> {
+    let packed = match line.strip_prefix(LINE_PREFIX) {
+        Some(packed) => packed,
+        None => return Ok(line.to_string()),
+    };
+    let (id, data) = packed.split_once(':').ok_or("malformed compressed line")?;
+    let id: u32 = id.parse().map_err(|_| "malformed compressed line")?;
+    let dictionary = match id {
+        0 => &[][..],
+        id => dictionaries
+            .get(&id)
+            .ok_or_else(|| format!("unknown dictionary {}", id))?,
+    };
+    let data = STANDARD.decode(data).map_err(|e| e.to_string())?;
+    String::from_utf8(inflate(&data, dictionary)?).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(i: usize) -> String {
+        let users = ["alice", "bob", "carol", "dave"];
+        format!(
+            r#"{{"seq":{},"room":"lobby","from":"{}","type":"chat.message","payload":{{"text":"message number {} of the day"}}}}"#,
+            1000 + i,
+            users[i % users.len()],
+            i * 7
+        )
+    }
+
+    /// Deterministic bytes that DEFLATE cannot shrink
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trained_dictionary_round_trip() {
+        let samples: Vec<Vec<u8>> = (0..200).map(|i| message(i).into_bytes()).collect();
+        let data = train(&samples, 1024);
+        assert!(!data.is_empty() && data.len() <= 1024);
+        let dictionary = Dictionary { id: 3, data };
+        let dictionaries = HashMap::from([(3, dictionary.data.clone())]);
+
+        let mut plain_len = 0;
+        let mut packed_len = 0;
+        for i in 500..520 {
+            let line = message(i);
+            let packed = pack_line(&line, Some(&dictionary), 6);
+            assert!(packed.starts_with("z:3:"), "{}", packed);
+            assert_eq!(unpack_line(&packed, &dictionaries).unwrap(), line);
+            plain_len += pack_line(&line, None, 6).len();
+            packed_len += packed.len();
+        }
+        // the point of training
+        assert!(
+            packed_len * 2 < plain_len,
+            "{} vs {}",
+            packed_len,
+            plain_len
+        );
+    }
+
+    #[test]
+    fn training_respects_size() {
+        let samples: Vec<Vec<u8>> = (0..200).map(|i| message(i).into_bytes()).collect();
+        assert!(train(&samples, 100).len() <= 100);
+        assert!(train(&samples, 1 << 20).len() <= MAX_DICTIONARY);
+        assert!(train(&samples, 0).is_empty());
+        assert!(train(&[], 1024).is_empty());
+        // nothing shared, nothing worth keeping
+        assert!(train(&[noise(1000)], 1024).is_empty());
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let dictionary = train(&[message(1).into_bytes(), message(2).into_bytes()], 512);
+        let large: Vec<u8> = (0..5000).flat_map(|i| message(i).into_bytes()).collect();
+        for data in [Vec::new(), b"x".to_vec(), noise(100_000), large] {
+            for dictionary in [&[][..], &dictionary[..], &noise(MAX_DICTIONARY)[..]] {
+                for level in [1, 6, 10] {
+                    let packed = deflate(&data, dictionary, level);
+                    assert_eq!(inflate(&packed, dictionary).unwrap(), data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lines_that_do_not_shrink_stay_plain() {
+        let dictionaries = HashMap::new();
+        assert_eq!(pack_line("hi", None, 6), "hi");
+        assert_eq!(unpack_line("hi", &dictionaries).unwrap(), "hi");
+        let line = message(1).repeat(10);
+        let packed = pack_line(&line, None, 6);
+        assert!(packed.starts_with("z:0:"));
+        assert_eq!(unpack_line(&packed, &dictionaries).unwrap(), line);
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        let dictionaries = HashMap::from([(1, b"dictionary".to_vec())]);
+        for line in [
+            "z:", "z:x:AAAA", "z:2:AAAA", "z:1:!!!", "z:0:AAAA", "z:1:/w==",
+        ] {
+            assert!(unpack_line(line, &dictionaries).is_err(), "{}", line);
+        }
+        // inflated with the wrong dictionary, references land elsewhere
+        let dictionary = Dictionary {
+            id: 1,
+            data: message(1).into_bytes(),
+        };
+        let packed = pack_line(&message(2), Some(&dictionary), 6);
+        let other = HashMap::from([(1, vec![b'?'; dictionary.data.len()])]);
+        assert_ne!(unpack_line(&packed, &other).ok(), Some(message(2)));
+    }
+}
@@ -0,0 +1,1694 @@
+//! Server configuration.
+//!
+//! Loaded from the TOML file named by `WSS_CONFIG` (default `config.toml`);
+//! a missing file means all defaults. Single keys can be overridden with
+//! `--set section.key=value` and with `WSS_SECTION__KEY=value` environment
+//! variables, precedence is env > CLI > file. Values are parsed as TOML and
+//! taken as plain strings if that fails.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{fs, io, path::Path, path::PathBuf};
+
+use serde::Deserialize;
+use toml::value::{Table, Value};
+
+/// Default config file path
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Prefix of environment variables overriding config keys
+const ENV_PREFIX: &str = "WSS_";
+
+/// Separator of nested keys in environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub tls: TlsConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(rename = "static")]
+    pub static_files: StaticConfig,
+    pub log: LogConfig,
+    pub access_log: AccessLogConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub pool: PoolConfig,
+    pub hub: HubConfig,
+    pub keepalive: KeepaliveConfig,
+    pub reconnect: ReconnectConfig,
+    pub accept: AcceptConfig,
+    pub ip_filter: IpFilterConfig,
+    pub rooms: RoomsConfig,
+    pub history: HistoryConfig,
+    pub flags: FlagsConfig,
+    pub handlers: HandlersConfig,
+    pub schedule: ScheduleConfig,
+    pub kv: KvConfig,
+    pub crdt: CrdtConfig,
+    pub locks: LocksConfig,
+    /// Room messages published on cron expressions, see `jobs`
+    pub jobs: Vec<JobConfig>,
+    /// Push exporter, disabled unless configured
+    pub statsd: Option<StatsdConfig>,
+    /// Metrics labeled by room, not kept unless configured
+    pub room_metrics: Option<RoomMetricsConfig>,
+    /// Labels of connections taken at the handshake, see `labels`
+    pub labels: LabelsConfig,
+    /// Copies of a connection's frames for the admin API, see `tap`
+    pub tap: TapConfig,
+    /// Recordings of connections' frames, not made unless configured
+    pub record: Option<RecordConfig>,
+    /// Faults injected into deliveries for testing clients, see `chaos`
+    pub chaos: Option<ChaosConfig>,
+    /// Connections sent to another handler pipeline, see `pipeline`
+    pub canary: Option<CanaryConfig>,
+    /// Error reporting, disabled unless configured
+    pub sentry: Option<SentryConfig>,
+    /// Room ownership across nodes, single node unless configured
+    pub cluster: Option<ClusterConfig>,
+    /// gRPC admin API, disabled unless configured
+    pub grpc: Option<GrpcConfig>,
+    /// Sticky session token of the handshake, not sent unless configured
+    pub affinity: Option<AffinityConfig>,
+    /// Sessions, presence and resumption state, not kept unless configured
+    pub store: Option<StoreConfig>,
+    /// Push notifications of direct messages to offline users, see `push`
+    pub push: Option<PushConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: vec![
+                ListenerConfig {
+                    address: "0.0.0.0:80".to_string(),
+                    tls: false,
+                    redirect_https: false,
+                },
+                ListenerConfig {
+                    address: "0.0.0.0:443".to_string(),
+                    tls: true,
+                    redirect_https: false,
+                },
+            ],
+            tls: TlsConfig::default(),
+            cors: CorsConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            static_files: StaticConfig::default(),
+            log: LogConfig::default(),
+            access_log: AccessLogConfig::default(),
+            admin: AdminConfig::default(),
+            auth: AuthConfig::default(),
+            pool: PoolConfig::default(),
+            hub: HubConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            accept: AcceptConfig::default(),
+            ip_filter: IpFilterConfig::default(),
+            rooms: RoomsConfig::default(),
+            history: HistoryConfig::default(),
+            flags: FlagsConfig::default(),
+            handlers: HandlersConfig::default(),
+            schedule: ScheduleConfig::default(),
+            kv: KvConfig::default(),
+            crdt: CrdtConfig::default(),
+            locks: LocksConfig::default(),
+            jobs: Vec::new(),
+            statsd: None,
+            room_metrics: None,
+            labels: LabelsConfig::default(),
+            tap: TapConfig::default(),
+            record: None,
+            chaos: None,
+            canary: None,
+            sentry: None,
+            cluster: None,
+            affinity: None,
+            grpc: None,
+            store: None,
+            push: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ListenerConfig {
+    /// `host:port`, e.g. `0.0.0.0:80` or `[::]:443`
+    pub address: String,
+    /// Serve TLS with the `[tls]` certificate
+    #[serde(default)]
+    pub tls: bool,
+    /// Only redirect to the HTTPS origin instead of serving the app
+    #[serde(default)]
+    pub redirect_https: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert_path: String,
+    /// PEM RSA private key
+    pub key_path: String,
+    /// Allowed protocol versions, `1.2` and/or `1.3`
+    pub versions: Vec<String>,
+    /// Allowed cipher suites by IANA name, all supported if empty
+    pub cipher_suites: Vec<String>,
+    /// DER OCSP response stapled into handshakes, kept fresh by an external fetcher
+    pub ocsp_path: Option<String>,
+    /// How often `ocsp_path` is re-read
+    pub ocsp_refresh_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_path: "cert.pem".to_string(),
+            key_path: "key.pem".to_string(),
+            versions: vec!["1.2".to_string(), "1.3".to_string()],
+            cipher_suites: Vec::new(),
+            ocsp_path: None,
+            ocsp_refresh_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call HTTP endpoints, `*` for any; CORS is off if empty
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Allow cookies and `Authorization` on cross-origin requests
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT"].map(String::from).to_vec(),
+            allowed_headers: ["authorization", "content-type"].map(String::from).to_vec(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+/// Headers added to every HTTP response, an empty value disables one
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    /// `Strict-Transport-Security`, browsers ignore it on plain HTTP
+    pub hsts: String,
+    /// `X-Content-Type-Options`
+    pub content_type_options: String,
+    /// `Content-Security-Policy`
+    pub content_security_policy: String,
+    /// `Referrer-Policy`
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            hsts: "max-age=31536000".to_string(),
+            content_type_options: "nosniff".to_string(),
+            content_security_policy: "frame-ancestors 'self'".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StaticConfig {
+    /// Directory served at `/`
+    pub root: PathBuf,
+    pub index_file: String,
+    /// Render directory listings
+    pub listing: bool,
+    /// Serve `index_file` for unknown paths, for client side routed apps
+    pub spa: bool,
+}
+
+impl Default for StaticConfig {
+    fn default() -> Self {
+        StaticConfig {
+            root: PathBuf::from("static"),
+            index_file: "index.html".to_string(),
+            listing: false,
+            spa: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Initial filter in `env_logger` syntax, e.g. `info,ntex=debug`
+    pub filter: String,
+    /// Write to a rotated file instead of stderr
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: "ntex=trace".to_string(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// Write to a rotated file instead of stdout
+    pub file: Option<LogFileConfig>,
+}
+
+/// Log file, see `rolling`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Size a file is rotated at with `rotation = "size"`
+    #[serde(default = "default_log_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files kept besides the live one
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+fn default_log_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_log_keep() -> usize {
+    7
+}
+
+/// When a log file is rotated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    /// At `max_bytes`
+    #[default]
+    Size,
+    /// At the start of every hour, UTC
+    Hourly,
+    /// At midnight UTC
+    Daily,
+}
+
+/// Access log line format, see `accesslog`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache combined log format plus request time
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+    /// No access log
+    Off,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Bearer token required by `/admin` routes. Admin API is disabled if unset.
+    pub token: Option<String>,
+}
+
+/// Websocket handshake authentication
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Reject handshakes without credentials
+    pub required: bool,
+    /// Signed session cookie, see `auth` for the format
+    pub cookie: Option<CookieAuthConfig>,
+    /// Bearer tokens issued by an OpenID Connect provider
+    pub oidc: Option<OidcConfig>,
+    /// Connections per authenticated user
+    pub sessions: SessionsConfig,
+    /// Proof-of-work before anonymous handshakes, see `challenge`
+    pub challenge: Option<ChallengeConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ChallengeConfig {
+    /// Leading zero bits of the solution hash, each doubles the work
+    pub difficulty: u8,
+    /// Seconds a challenge can be solved and used in
+    pub ttl_secs: u64,
+    /// HMAC-SHA256 key signing challenges, random per process if unset;
+    /// with one, a cluster node takes its challenges across a restart
+    pub key: Option<String>,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        ChallengeConfig {
+            difficulty: 16,
+            ttl_secs: 60,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    pub policy: SessionPolicy,
+    /// Per tenant policies, win over `policy`
+    pub tenants: BTreeMap<String, SessionPolicy>,
+    /// Open connections per user across devices, 0 for unlimited
+    pub max_connections: usize,
+    /// What a connection over `max_connections` does
+    pub when_exceeded: WhenExceeded,
+}
+
+impl SessionsConfig {
+    pub fn policy(&self, tenant: Option<&str>) -> SessionPolicy {
+        tenant
+            .and_then(|t| self.tenants.get(t))
+            .copied()
+            .unwrap_or(self.policy)
+    }
+}
+
+/// What happens when a user who is already connected connects again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionPolicy {
+    /// Any number of connections per user
+    #[default]
+    Multiple,
+    /// Close the user's previous connections
+    Replace,
+    /// Refuse the new handshake with 409
+    Reject,
+}
+
+/// What happens when a user is over `max_connections`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhenExceeded {
+    /// Close the new connection
+    #[default]
+    RefuseNewest,
+    /// Close the user's oldest connections
+    CloseOldest,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CookieAuthConfig {
+    #[serde(default = "default_cookie_name")]
+    pub name: String,
+    /// HMAC-SHA256 key shared with the issuing app
+    pub key: String,
+}
+
+fn default_cookie_name() -> String {
+    "session".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct OidcConfig {
+    /// Expected `iss`, also the base of the discovery document
+    pub issuer: String,
+    /// Expected `aud`
+    pub audience: String,
+    /// Key set location, discovered from the issuer if unset
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+    /// Dotted path of the roles array, e.g. `realm_access.roles`
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// Dotted path of the tenant claim
+    #[serde(default)]
+    pub tenant_claim: Option<String>,
+    /// Max age of cached signing keys, seconds
+    #[serde(default = "default_jwks_refresh")]
+    pub refresh_secs: u64,
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+
+fn default_jwks_refresh() -> u64 {
+    3600
+}
+
+/// Heartbeat of websocket connections, clients may negotiate within bounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    /// Ping interval unless the client asks for another in `hello`
+    pub interval_ms: u64,
+    /// Connections silent this long are dropped
+    pub timeout_ms: u64,
+    pub min_interval_ms: u64,
+    pub max_interval_ms: u64,
+    pub max_timeout_ms: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval_ms: 5_000,
+            timeout_ms: 10_000,
+            min_interval_ms: 1_000,
+            max_interval_ms: 120_000,
+            max_timeout_ms: 300_000,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// Interval and timeout granted for those a client asked for. The
+    /// timeout is at least twice the interval, so one lost pong is not fatal.
+    pub fn negotiate(&self, interval_ms: Option<u64>, timeout_ms: Option<u64>) -> (u64, u64) {
+        let interval = interval_ms.unwrap_or(self.interval_ms).clamp(
+            self.min_interval_ms,
+            self.max_interval_ms.max(self.min_interval_ms),
+        );
+        let floor = interval * 2;
+        let timeout = timeout_ms
+            .unwrap_or(self.timeout_ms.max(floor))
+            .clamp(floor, self.max_timeout_ms.max(floor));
+        (interval, timeout)
+    }
+}
+
+/// Bounds on handlers answering later, see `inflight`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct HandlersConfig {
+    /// Time a message may take to be answered, from when it came in
+    pub timeout_ms: u64,
+    /// `timeout_ms` of single message types, e.g. `"session.resume" = 5000`
+    pub timeouts: BTreeMap<String, u64>,
+    /// Handlers a connection may have running or queued at once
+    pub max_in_flight: usize,
+}
+
+impl Default for HandlersConfig {
+    fn default() -> Self {
+        HandlersConfig {
+            timeout_ms: 30_000,
+            timeouts: BTreeMap::new(),
+            max_in_flight: 32,
+        }
+    }
+}
+
+impl HandlersConfig {
+    /// Timeout of message type `name`
+    pub fn timeout(&self, name: &str) -> Duration {
+        let ms = self.timeouts.get(name).copied().unwrap_or(self.timeout_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+/// Room messages published for later, see `schedule`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Log of scheduled messages, replayed at startup; in memory only if unset
+    pub file: Option<PathBuf>,
+    /// Furthest a message may be scheduled ahead
+    pub max_delay_secs: u64,
+    /// Scheduled messages waiting at once, further ones are refused
+    pub max_pending: usize,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            file: None,
+            max_delay_secs: 7 * 24 * 3600,
+            max_pending: 10_000,
+        }
+    }
+}
+
+/// Keys shared by the connections of a room or tenant, see `kv`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KvConfig {
+    /// Log of writes, replayed at startup; in memory only if unset
+    pub file: Option<PathBuf>,
+    /// Whether clients may write the keys of their tenant, or only the
+    /// admin API
+    pub tenant_writes: bool,
+    /// Keys of a scope, further ones are refused
+    pub max_keys: usize,
+    /// Rooms and tenants with keys, further ones are refused
+    pub max_scopes: usize,
+    /// Longest value, as JSON
+    pub max_value_bytes: usize,
+}
+
+impl Default for KvConfig {
+    fn default() -> Self {
+        KvConfig {
+            file: None,
+            tenant_writes: false,
+            max_keys: 1000,
+            max_scopes: 10_000,
+            max_value_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Counters, sets and registers shared by every node, see `crdt`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CrdtConfig {
+    /// Objects of every room and tenant, further ones are refused
+    pub max_objects: usize,
+    /// Elements of a set, removed ones included
+    pub max_set_size: usize,
+    /// Longest register value, as JSON
+    pub max_value_bytes: usize,
+}
+
+impl Default for CrdtConfig {
+    fn default() -> Self {
+        CrdtConfig {
+            max_objects: 10_000,
+            max_set_size: 10_000,
+            max_value_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Leases clients take on names, see `locks`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LocksConfig {
+    /// Lease of an acquire or renew without `ttl_ms`
+    pub default_ttl_ms: u64,
+    pub max_ttl_ms: u64,
+    /// Longest an acquire waits for a held lock
+    pub max_wait_ms: u64,
+    /// Locks held at once, further ones are refused
+    pub max_locks: usize,
+}
+
+impl Default for LocksConfig {
+    fn default() -> Self {
+        LocksConfig {
+            default_ttl_ms: 10_000,
+            max_ttl_ms: 300_000,
+            max_wait_ms: 60_000,
+            max_locks: 10_000,
+        }
+    }
+}
+
+/// Notifications of direct messages to offline users, see `push`
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    /// `log`, `webhook` or one registered with `push::register`
+    pub notifier: String,
+    /// Gateway the `webhook` notifier posts notifications to
+    pub url: Option<String>,
+    /// Bearer token sent to `url`
+    pub token: Option<String>,
+    /// Templates of a notification's text, see `push`
+    pub title: String,
+    pub body: String,
+    /// Notifications a user gets per minute, further messages are not pushed
+    pub max_per_minute: u32,
+    /// Devices a user can register, the oldest is dropped for a new one
+    pub max_devices: usize,
+    /// Time allowed to the notifier for one notification
+    pub timeout_ms: u64,
+    /// JSON file keeping registered devices over restarts, in memory only
+    /// if unset
+    pub devices_file: Option<PathBuf>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            notifier: "log".to_string(),
+            url: None,
+            token: None,
+            title: "{from}".to_string(),
+            body: "{data.text}".to_string(),
+            max_per_minute: 10,
+            max_devices: 10,
+            timeout_ms: 5000,
+            devices_file: None,
+        }
+    }
+}
+
+// keeps the token out of logs, e.g. of config reloads
+impl std::fmt::Debug for PushConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PushConfig")
+            .field("notifier", &self.notifier)
+            .field("url", &self.url)
+            .field("title", &self.title)
+            .field("body", &self.body)
+            .field("max_per_minute", &self.max_per_minute)
+            .field("max_devices", &self.max_devices)
+            .field("timeout_ms", &self.timeout_ms)
+            .field("devices_file", &self.devices_file)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Room message published by the server on a cron expression
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct JobConfig {
+    pub name: String,
+    /// `min hour day month weekday`, with an optional leading seconds
+    /// field, in UTC
+    pub cron: String,
+    pub room: String,
+    /// Template of the message, see `jobs`
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// Hints in close frames of connections the server closes, see `reconnect`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// Seconds clients should wait before reconnecting
+    pub retry_after_secs: u64,
+    /// Random seconds added per connection, spreading out the reconnects
+    pub jitter_secs: u64,
+    /// Where to reconnect instead, e.g. another node's `wss://` URL
+    pub endpoint: Option<String>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            retry_after_secs: 5,
+            jitter_secs: 10,
+            endpoint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Max number of idle buffers kept per worker
+    pub size: usize,
+    /// Initial capacity of a new buffer, bytes
+    pub buffer_capacity: usize,
+    /// Buffers grown past this are not returned to the pool, bytes
+    pub max_buffer_capacity: usize,
+    /// Fragmented messages growing past this close the connection with
+    /// 1009, bytes
+    pub max_message_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 64,
+            buffer_capacity: 16 * 1024,
+            max_buffer_capacity: 1024 * 1024,
+            max_message_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HubConfig {
+    /// Max messages waiting in a connection's outbound queue, extra are dropped
+    pub queue_size: usize,
+    /// Connection registry shards, 0 for four per CPU core
+    pub shards: usize,
+    /// Outbound data bytes per second of all connections together, 0 for
+    /// unlimited
+    pub max_bytes_per_sec: u64,
+    /// Bytes sent at once before `max_bytes_per_sec` applies
+    pub burst_bytes: u64,
+    /// Outbound data bytes per second of one connection, 0 for unlimited
+    pub conn_bytes_per_sec: u64,
+    /// Bytes a connection sends at once before `conn_bytes_per_sec` applies
+    pub conn_burst_bytes: u64,
+    /// Outbound data bytes per second of the connections of one tenant
+    /// together, those without a tenant claim counting as one, 0 for
+    /// unlimited
+    pub tenant_bytes_per_sec: u64,
+    /// Bytes a tenant sends at once before `tenant_bytes_per_sec` applies
+    pub tenant_burst_bytes: u64,
+    /// Pool running large broadcasts, off unless configured
+    pub fanout: Option<FanoutConfig>,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        HubConfig {
+            queue_size: 256,
+            shards: 0,
+            max_bytes_per_sec: 0,
+            burst_bytes: 1024 * 1024,
+            conn_bytes_per_sec: 0,
+            conn_burst_bytes: 256 * 1024,
+            tenant_bytes_per_sec: 0,
+            tenant_burst_bytes: 1024 * 1024,
+            fanout: None,
+        }
+    }
+}
+
+/// Threads fanning out large broadcasts, see `fanout`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FanoutConfig {
+    /// Pool threads, 0 for one per CPU core
+    pub workers: usize,
+    /// Connections past which a broadcast runs on the pool
+    pub threshold: usize,
+    /// Jobs waiting for a thread past which broadcasts run on the caller
+    pub max_jobs: usize,
+}
+
+impl Default for FanoutConfig {
+    fn default() -> Self {
+        FanoutConfig {
+            workers: 0,
+            threshold: 10_000,
+            max_jobs: 4096,
+        }
+    }
+}
+
+/// Websocket handshake rate limits, see `accept`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AcceptConfig {
+    /// Handshakes per second from one IP, 0 for unlimited
+    pub per_ip_per_sec: f64,
+    /// Handshakes from one IP at once before `per_ip_per_sec` applies
+    pub per_ip_burst: u32,
+    /// Handshakes per second of all clients together, 0 for unlimited
+    pub per_sec: f64,
+    /// Handshakes at once before `per_sec` applies
+    pub burst: u32,
+}
+
+impl Default for AcceptConfig {
+    fn default() -> Self {
+        AcceptConfig {
+            per_ip_per_sec: 0.0,
+            per_ip_burst: 10,
+            per_sec: 0.0,
+            burst: 100,
+        }
+    }
+}
+
+/// Client IP rules, see `ipfilter`
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct IpFilterConfig {
+    /// CIDR blocks or addresses allowed to connect, empty for all
+    pub allow: Vec<String>,
+    /// CIDR blocks or addresses refused, wins over `allow`
+    pub deny: Vec<String>,
+    /// Keeps rules added through the admin API across restarts
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RoomsConfig {
+    /// Max members of a room, 0 for unlimited
+    pub max_members: usize,
+    pub when_full: WhenFull,
+    /// Max connections waiting for a full room, further joins are rejected
+    pub max_waiting: usize,
+    /// Keep an empty room this long before destroying it, seconds
+    pub empty_ttl_secs: u64,
+    /// Destroy a room this long after creation even with members, 0 for never
+    pub ttl_secs: u64,
+    /// Rooms created at startup and never destroyed
+    pub pinned: Vec<String>,
+    /// HMAC-SHA256 key signing invites to private rooms, invites are off if unset
+    pub invite_key: Option<String>,
+    /// Per room overrides, the first matching pattern wins
+    pub limits: Vec<RoomLimit>,
+    /// Content filter of published messages, off unless configured
+    pub filter: Option<FilterConfig>,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        RoomsConfig {
+            max_members: 0,
+            when_full: WhenFull::Reject,
+            max_waiting: 100,
+            empty_ttl_secs: 0,
+            ttl_secs: 0,
+            pinned: Vec::new(),
+            invite_key: None,
+            limits: Vec::new(),
+            filter: None,
+        }
+    }
+}
+
+// keeps the invite key out of logs, e.g. of config reloads
+impl std::fmt::Debug for RoomsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomsConfig")
+            .field("max_members", &self.max_members)
+            .field("when_full", &self.when_full)
+            .field("max_waiting", &self.max_waiting)
+            .field("empty_ttl_secs", &self.empty_ttl_secs)
+            .field("ttl_secs", &self.ttl_secs)
+            .field("pinned", &self.pinned)
+            .field("limits", &self.limits)
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RoomsConfig {
+    /// Limits of `room`, those of the first matching pattern or the defaults;
+    /// a partition has those of its partitioned room
+    pub fn policy(&self, room: &str) -> RoomPolicy {
+        match self.partition_of(room) {
+            Some((partitioned, _)) => RoomPolicy {
+                partitions: None,
+                ..self.limits_of(partitioned)
+            },
+            None => self.limits_of(room),
+        }
+    }
+
+    /// Partitioned room and partition number of `room`, if it is one of its
+    /// partitions, named `<room>#<number>`
+    pub fn partition_of<'a>(&self, room: &'a str) -> Option<(&'a str, u32)> {
+        let (partitioned, number) = room.rsplit_once('#')?;
+        let partition: u32 = number.parse().ok()?;
+        let partitions = self.limits_of(partitioned).partitions?;
+        (partition < partitions && partition.to_string() == number)
+            .then_some((partitioned, partition))
+    }
+
+    fn limits_of(&self, room: &str) -> RoomPolicy {
+        let limit = self.limits.iter().find(|l| glob(&l.pattern, room));
+        let max_members = limit
+            .and_then(|l| l.max_members)
+            .unwrap_or(self.max_members);
+        let empty_ttl = limit
+            .and_then(|l| l.empty_ttl_secs)
+            .unwrap_or(self.empty_ttl_secs);
+        let ttl = limit.and_then(|l| l.ttl_secs).unwrap_or(self.ttl_secs);
+        RoomPolicy {
+            max_members: Some(max_members).filter(|max| *max > 0),
+            when_full: limit.and_then(|l| l.when_full).unwrap_or(self.when_full),
+            empty_ttl: Duration::from_secs(empty_ttl),
+            ttl: Some(ttl).filter(|ttl| *ttl > 0).map(Duration::from_secs),
+            pinned: self.pinned.iter().any(|p| p == room),
+            private: limit.and_then(|l| l.private).unwrap_or(false),
+            filtered: limit
+                .and_then(|l| l.filter)
+                .unwrap_or_else(|| self.filter.as_ref().is_some_and(|f| f.all_rooms)),
+            aggregate: limit
+                .and_then(|l| l.aggregate_ms)
+                .filter(|ms| *ms > 0)
+                .map(|ms| Aggregation {
+                    window: Duration::from_millis(ms),
+                    reducer: limit.and_then(|l| l.reducer).unwrap_or_default(),
+                }),
+            pace: match limit.map(|l| (l.throttle_ms, l.debounce_ms)) {
+                Some((Some(ms), _)) if ms > 0 => Some(Pace::Throttle(Duration::from_millis(ms))),
+                Some((_, Some(ms))) if ms > 0 => Some(Pace::Debounce(Duration::from_millis(ms))),
+                _ => None,
+            },
+            partitions: limit.and_then(|l| l.partitions).filter(|n| *n > 1),
+        }
+    }
+}
+
+/// Per room override of `[rooms]`, unset fields keep the default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RoomLimit {
+    /// Room name, `*` matches any run of characters, e.g. `lobby-*`
+    pub pattern: String,
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    #[serde(default)]
+    pub when_full: Option<WhenFull>,
+    #[serde(default)]
+    pub empty_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Matching rooms are joined with an invite only
+    #[serde(default)]
+    pub private: Option<bool>,
+    /// Run messages of matching rooms through `[rooms.filter]` or not
+    #[serde(default)]
+    pub filter: Option<bool>,
+    /// Deliver messages of matching rooms combined per window of this many
+    /// milliseconds, see `aggregate`
+    #[serde(default)]
+    pub aggregate_ms: Option<u64>,
+    /// How a window is combined, `array` by default
+    #[serde(default)]
+    pub reducer: Option<Reducer>,
+    /// At most one message of matching rooms per this many milliseconds
+    /// per member, the newest held back one following, see `pacing`
+    #[serde(default)]
+    pub throttle_ms: Option<u64>,
+    /// Deliver only the newest message once matching rooms were quiet for
+    /// this many milliseconds, unless `throttle_ms` is set
+    #[serde(default)]
+    pub debounce_ms: Option<u64>,
+    /// Split matching rooms into this many partitions, see `Rooms::route`
+    #[serde(default)]
+    pub partitions: Option<u32>,
+}
+
+/// Effective limits of one room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomPolicy {
+    /// `None` if unlimited
+    pub max_members: Option<usize>,
+    pub when_full: WhenFull,
+    /// Grace period of an empty room, zero destroys it at once
+    pub empty_ttl: Duration,
+    /// Lifetime from creation, `None` for unlimited
+    pub ttl: Option<Duration>,
+    /// Never destroyed, TTLs do not apply
+    pub pinned: bool,
+    /// Joined with an invite only
+    pub private: bool,
+    /// Published messages go through the content filter
+    pub filtered: bool,
+    /// Messages are delivered combined per window, `None` one by one
+    pub aggregate: Option<Aggregation>,
+    /// Messages are paced per member, `None` delivered as published
+    pub pace: Option<Pace>,
+    /// Number of partitions, `None` if not partitioned
+    pub partitions: Option<u32>,
+}
+
+/// How messages of a room are paced for each member, the newest winning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// At most one per interval
+    Throttle(Duration),
+    /// One once no other came for the interval
+    Debounce(Duration),
+}
+
+/// Window of a room's combined delivery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aggregation {
+    pub window: Duration,
+    pub reducer: Reducer,
+}
+
+/// How the messages of an aggregation window are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reducer {
+    /// All of them in one `room.batch`
+    #[default]
+    Array,
+    /// Only the newest one
+    Last,
+    /// One message with the data objects merged, later fields winning
+    Merge,
+}
+
+/// What happens to a join of a full room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// Answer with `room_full`
+    Reject,
+    /// Queue the connection until a member leaves
+    Wait,
+}
+
+/// Checks of room messages before they are stored and delivered
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Filter every room, else only rooms whose `limits` entry sets `filter`
+    pub all_rooms: bool,
+    /// Words masked with `*` in string values, matched case-insensitively
+    pub words: Vec<String>,
+    /// Service asked about every message after the word list, see README
+    pub url: Option<String>,
+    /// Bearer token sent to `url`
+    pub token: Option<String>,
+    /// Time allowed for the whole filter chain of one message
+    pub timeout_ms: u64,
+    pub on_error: OnError,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            all_rooms: true,
+            words: Vec::new(),
+            url: None,
+            token: None,
+            timeout_ms: 500,
+            on_error: OnError::Open,
+        }
+    }
+}
+
+// keeps the token out of logs, e.g. of config reloads
+impl std::fmt::Debug for FilterConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterConfig")
+            .field("all_rooms", &self.all_rooms)
+            .field("words", &self.words.len())
+            .field("url", &self.url)
+            .field("timeout_ms", &self.timeout_ms)
+            .field("on_error", &self.on_error)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What happens to a message when the filter fails or times out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Deliver it unfiltered
+    Open,
+    /// Reject it with a retryable `internal` error
+    Closed,
+}
+
+/// Match `name` against `pattern` where `*` matches any run of characters
+pub fn glob(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(tail) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=tail.len())
+                .filter(|i| tail.is_char_boundary(*i))
+                .any(|i| glob(rest, &tail[i..]))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Messages kept per room, 0 disables history
+    pub max_messages: usize,
+    /// Directory of the room logs, history is in memory only if unset
+    pub dir: Option<PathBuf>,
+    /// Archival of aged messages, disabled unless configured
+    pub archive: Option<ArchiveConfig>,
+    /// Encryption of room logs and archived batches, off unless configured
+    pub encryption: Option<EncryptionConfig>,
+    /// Compression of room logs, off unless configured
+    pub compression: Option<CompressionConfig>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_messages: 1000,
+            dir: None,
+            archive: None,
+            encryption: None,
+            compression: None,
+        }
+    }
+}
+
+/// DEFLATE of room log lines with a dictionary trained per room
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// 1 is fastest, 9 smallest
+    pub level: u8,
+    /// Size of trained dictionaries, at most 16 KiB
+    pub dictionary_bytes: usize,
+    /// Messages kept of a room before its dictionary is trained on them
+    pub train_after: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            level: 6,
+            dictionary_bytes: 4096,
+            train_after: 100,
+        }
+    }
+}
+
+/// S3-compatible bucket that aged history is moved to
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct ArchiveConfig {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://minio:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_archive_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to object keys
+    #[serde(default = "default_archive_prefix")]
+    pub prefix: String,
+    /// Messages older than this are archived
+    #[serde(default = "default_archive_after")]
+    pub after_secs: u64,
+    #[serde(default = "default_archive_interval")]
+    pub interval_secs: u64,
+}
+
+// keeps the secret key out of logs, e.g. of config reloads
+impl std::fmt::Debug for ArchiveConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveConfig")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("prefix", &self.prefix)
+            .field("after_secs", &self.after_secs)
+            .field("interval_secs", &self.interval_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+/// AES-256-GCM keys of stored history
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct EncryptionConfig {
+    /// Id of the key new records are encrypted with
+    pub current: String,
+    /// Base64 of 32 byte keys by id; retired keys stay listed while data
+    /// encrypted with them is still stored
+    pub keys: BTreeMap<String, String>,
+}
+
+// keeps the keys out of logs, e.g. of config reloads
+impl std::fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn default_archive_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_archive_prefix() -> String {
+    "history/".to_string()
+}
+
+fn default_archive_after() -> u64 {
+    7 * 86400
+}
+
+fn default_archive_interval() -> u64 {
+    3600
+}
+
+/// Feature flag overrides, see `flags::FLAGS` for the known flags
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FlagsConfig {
+    /// Overrides for all connections
+    pub global: BTreeMap<String, bool>,
+    /// Per tenant overrides, keyed by the `tenant` query parameter of `/ws`
+    pub tenants: BTreeMap<String, BTreeMap<String, bool>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GrpcConfig {
+    /// `host:port` of the plaintext HTTP/2 listener, e.g. `127.0.0.1:50051`
+    pub address: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StatsdConfig {
+    /// `host:port` of the statsd agent
+    pub address: String,
+    pub flavor: StatsdFlavor,
+    /// Prepended to every metric name
+    pub prefix: String,
+    /// DogStatsD tags attached to every metric
+    pub tags: BTreeMap<String, String>,
+    pub interval_secs: u64,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            address: "127.0.0.1:8125".to_string(),
+            flavor: StatsdFlavor::Statsd,
+            prefix: String::new(),
+            tags: BTreeMap::new(),
+            interval_secs: 10,
+        }
+    }
+}
+
+/// Which rooms get their own metrics, see `metrics`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RoomMetricsConfig {
+    /// Patterns of rooms labeled with their name
+    pub rooms: Vec<String>,
+    /// Patterns labeling the rooms matching them with the pattern
+    pub aggregate: Vec<String>,
+    /// Room names used as labels at most, further rooms count as `_other`
+    pub max_rooms: usize,
+}
+
+impl Default for RoomMetricsConfig {
+    fn default() -> Self {
+        RoomMetricsConfig {
+            rooms: Vec::new(),
+            aggregate: Vec::new(),
+            max_rooms: 100,
+        }
+    }
+}
+
+/// Where connection labels come from, see `labels`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LabelsConfig {
+    /// Dotted claim path of each label, e.g. `plan = "billing.plan"`
+    pub claims: BTreeMap<String, String>,
+    /// Handshake header of each label, claims win over them
+    pub headers: BTreeMap<String, String>,
+    /// Labels that are also metric dimensions
+    pub metrics: Vec<String>,
+    /// Values of a label used as metric dimension at most, further values
+    /// count as `_other`
+    pub max_values: usize,
+}
+
+impl Default for LabelsConfig {
+    fn default() -> Self {
+        LabelsConfig {
+            claims: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            metrics: Vec::new(),
+            max_values: 20,
+        }
+    }
+}
+
+/// Limits of the frame taps of the admin API, see `tap`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct TapConfig {
+    /// Taps open on one connection at most
+    pub max_taps: usize,
+    /// Bytes of a frame a tap may ask to see at most
+    pub max_bytes: usize,
+    /// JSON fields whose values copies do not show, any case
+    pub redact: Vec<String>,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        TapConfig {
+            max_taps: 2,
+            max_bytes: 64 * 1024,
+            redact: ["password", "token", "secret", "authorization"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// Where and whose frames are recorded, see `record`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RecordConfig {
+    pub dir: PathBuf,
+    /// Patterns of the users recorded from the handshake
+    pub users: Vec<String>,
+    /// Bytes of a recording past which it stops
+    pub max_bytes: u64,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        RecordConfig {
+            dir: PathBuf::from("recordings"),
+            users: Vec::new(),
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Probabilities of the faults injected, see `chaos`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Fraction of connections faults are injected into
+    pub connections: f64,
+    /// Probability a message is held back, by up to `delay_ms`
+    pub delay: f64,
+    pub delay_ms: u64,
+    /// Probability a message is not sent
+    pub drop: f64,
+    /// Probability a message is sent twice
+    pub duplicate: f64,
+    /// Probability the connection is closed instead of sending a message
+    pub disconnect: f64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            connections: 1.0,
+            delay: 0.0,
+            delay_ms: 1000,
+            drop: 0.0,
+            duplicate: 0.0,
+            disconnect: 0.0,
+        }
+    }
+}
+
+/// Which new connections get the canary pipeline, see `pipeline`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CanaryConfig {
+    /// Registered name of the pipeline
+    pub pipeline: String,
+    /// Share of new connections, 0..=100
+    #[serde(default)]
+    pub percent: f64,
+    /// Selector of the connections always sent, see `labels`
+    #[serde(default)]
+    pub labels: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFlavor {
+    /// Plain StatsD, tags are not sent
+    Statsd,
+    /// Datadog agent, supports tags
+    Dogstatsd,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SentryConfig {
+    pub dsn: String,
+    #[serde(default)]
+    pub environment: Option<String>,
+    /// Fraction of errors sent, 0.0..=1.0
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f32,
+}
+
+fn default_sample_rate() -> f32 {
+    1.0
+}
+
+/// Node token handed to load balancers on the handshake, see `affinity`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct AffinityConfig {
+    /// Token naming this node, defaults to `cluster.node`
+    pub node: Option<String>,
+    /// Cookie carrying the token, `""` for none
+    pub cookie: String,
+    /// Response header carrying the token, `""` for none
+    pub header: String,
+    pub max_age_secs: u64,
+    /// Mark the cookie `Secure`, for TLS listeners
+    pub secure: bool,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        AffinityConfig {
+            node: None,
+            cookie: "wss_node".to_string(),
+            header: "x-wss-node".to_string(),
+            max_age_secs: 86_400,
+            secure: false,
+        }
+    }
+}
+
+/// Where sessions, presence and resumption state are kept, see `store`
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub backend: StoreBackend,
+    /// `redis://host[:port][/db]` of the `redis` backend
+    pub url: String,
+    /// `AUTH` password of the `redis` backend
+    pub password: Option<String>,
+    /// Prefix of every key written to Redis
+    pub prefix: String,
+    /// Name of this instance in session keys, defaults to `cluster.node`,
+    /// random per process otherwise
+    pub instance: Option<String>,
+    /// Sessions not refreshed for this long expire, e.g. those of an
+    /// instance that died
+    pub ttl_secs: u64,
+    /// How long a closed connection can be resumed
+    pub resume_ttl_secs: u64,
+    /// How long presence read from Redis is reused, 0: always read
+    pub cache_ms: u64,
+    /// Record when users were last seen and their presence changes, off if
+    /// unset
+    pub last_seen: Option<LastSeenConfig>,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            backend: StoreBackend::Memory,
+            url: "redis://127.0.0.1:6379".to_string(),
+            password: None,
+            prefix: "wss:".to_string(),
+            instance: None,
+            ttl_secs: 60,
+            resume_ttl_secs: 300,
+            cache_ms: 1000,
+            last_seen: None,
+        }
+    }
+}
+
+/// Last-seen times and presence changes of users, see `store`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LastSeenConfig {
+    /// Latest presence changes kept per user
+    pub transitions: usize,
+    /// How long a user's record is kept after they were last seen, 0:
+    /// forever
+    pub ttl_secs: u64,
+}
+
+impl Default for LastSeenConfig {
+    fn default() -> Self {
+        LastSeenConfig {
+            transitions: 100,
+            ttl_secs: 30 * 86_400,
+        }
+    }
+}
+
+impl std::fmt::Debug for StoreConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StoreConfig")
+            .field("backend", &self.backend)
+            .field("url", &self.url)
+            .field("prefix", &self.prefix)
+            .field("instance", &self.instance)
+            .field("ttl_secs", &self.ttl_secs)
+            .field("resume_ttl_secs", &self.resume_ttl_secs)
+            .field("cache_ms", &self.cache_ms)
+            .field("last_seen", &self.last_seen)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// Process memory, lost on restart
+    #[default]
+    Memory,
+    /// Redis, shared by the instances using it, with a local copy
+    Redis,
+}
+
+/// Nodes sharing rooms, each room is owned by one of them
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct ClusterConfig {
+    /// Id of this node, unique in the cluster
+    pub node: String,
+    /// Base URL of this node's HTTP listener as other nodes reach it,
+    /// defaults to its entry of `nodes`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Base URL of the nodes' HTTP listeners by id, e.g.
+    /// `http://10.0.0.2:8080`; the nodes known at startup, others are found
+    /// through gossip
+    #[serde(default)]
+    pub nodes: BTreeMap<String, String>,
+    /// Bearer token nodes present to each other's `/cluster` routes
+    pub secret: String,
+    /// Time between probes of another node
+    #[serde(default = "default_gossip_interval")]
+    pub gossip_interval_ms: u64,
+    /// Time a node that failed a probe has to refute it before it is
+    /// declared dead and its rooms move
+    #[serde(default = "default_suspect_timeout")]
+    pub suspect_timeout_secs: u64,
+}
+
+// keeps the secret out of logs, e.g. of config reloads
+impl std::fmt::Debug for ClusterConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClusterConfig")
+            .field("node", &self.node)
+            .field("url", &self.url)
+            .field("nodes", &self.nodes)
+            .field("gossip_interval_ms", &self.gossip_interval_ms)
+            .field("suspect_timeout_secs", &self.suspect_timeout_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+fn default_gossip_interval() -> u64 {
+    1000
+}
+
+fn default_suspect_timeout() -> u64 {
+    5
+}
+
+impl Config {
+    /// Config file path, `WSS_CONFIG` or the default
+    pub fn path() -> PathBuf {
+        std::env::var_os("WSS_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+    }
+
+    /// Load config from `WSS_CONFIG` or the default path, with overrides
+    pub fn load(cli: &[String]) -> io::Result<Config> {
+        Config::read(Config::path(), cli)
+    }
+
+    /// Read config file and apply `section.key=value` CLI and `WSS_*` env overrides
+    pub fn read<P: AsRef<Path>>(path: P, cli: &[String]) -> io::Result<Config> {
+        let mut table = match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(invalid)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Table::new(),
+            Err(e) => return Err(e),
+        };
+        for entry in cli {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("`{}` is not key=value", entry)))?;
+            let path: Vec<_> = key.split('.').map(str::to_string).collect();
+            set(&mut table, &path, value).map_err(invalid)?;
+        }
+        for (name, value) in std::env::vars() {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some("CONFIG") | None => continue,
+                Some(key) => key,
+            };
+            let path: Vec<_> = key.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+            set(&mut table, &path, &value).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        }
+        Config::deserialize(Value::Table(table)).map_err(invalid)
+    }
+
+    /// Switch to `--dev` listeners: plain 8080 and self-signed TLS 8443
+    pub fn dev(mut self) -> Config {
+        self.listeners = vec![
+            ListenerConfig {
+                address: "0.0.0.0:8080".to_string(),
+                tls: false,
+                redirect_https: false,
+            },
+            ListenerConfig {
+                address: "0.0.0.0:8443".to_string(),
+                tls: true,
+                redirect_https: false,
+            },
+        ];
+        self
+    }
+
+    /// Read config file only, without overrides
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(invalid),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Set nested `path` of `table` to `value`, parsed as TOML if possible
+fn set(table: &mut Table, path: &[String], value: &str) -> Result<(), String> {
+    let (last, parents) = match path.split_last() {
+        Some((last, parents)) if !last.is_empty() => (last, parents),
+        _ => return Err("empty key".to_string()),
+    };
+    let mut table = table;
+    for key in parents {
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(t) => t,
+            _ => return Err(format!("`{}` is not a section", key)),
+        };
+    }
+    let value = toml::from_str::<Table>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+    table.insert(last.clone(), value);
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! CORS middleware for the HTTP endpoints, configured by `[cors]`.
+//!
+//! Requests from allowed origins get `Access-Control-Allow-*` headers and
+//! preflight requests are answered directly. With no allowed origins the
+//! middleware does nothing.
+
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use ntex::http::header::{self, HeaderValue};
+use ntex::http::Method;
+use ntex::service::{Service, Transform};
+use ntex::web::{HttpResponse, WebRequest, WebResponse};
+
+use crate::config::CorsConfig;
+
+#[derive(Debug)]
+struct Inner {
+    /// Empty disables CORS, `*` allows any origin
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    credentials: bool,
+    max_age: HeaderValue,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cors {
+    inner: Arc<Inner>,
+}
+
+fn header_value(config_key: &str, value: String) -> Result<HeaderValue, String> {
+    HeaderValue::try_from(value).map_err(|_| format!("cors.{} is not a valid header", config_key))
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Result<Cors, String> {
+        let any_origin = config.allowed_origins.iter().any(|o| o == "*");
+        for method in &config.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("cors.allowed_methods: invalid method `{}`", method))?;
+        }
+        Ok(Cors {
+            inner: Arc::new(Inner {
+                origins: config.allowed_origins.clone(),
+                any_origin,
+                methods: header_value("allowed_methods", config.allowed_methods.join(", "))?,
+                headers: header_value("allowed_headers", config.allowed_headers.join(", "))?,
+                credentials: config.allow_credentials,
+                max_age: header_value("max_age_secs", config.max_age_secs.to_string())?,
+            }),
+        })
+    }
+}
+
+impl Inner {
+    /// `Access-Control-Allow-Origin` for request origin, `None` if not allowed
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let allowed = self.any_origin
+            || origin
+                .to_str()
+                .is_ok_and(|o| self.origins.iter().any(|allowed| allowed == o));
+        match allowed {
+            // credentials can not be combined with a `*` origin
+            true if self.any_origin && !self.credentials => Some(HeaderValue::from_static("*")),
+            true => Some(origin.clone()),
+            false => None,
+        }
+    }
+
+    fn set_headers(&self, res: &mut WebResponse, origin: HeaderValue) {
+        let headers = res.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+impl<S> Transform<S> for Cors {
+    type Service = CorsMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        CorsMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    inner: Arc<Inner>,
+}
+
+impl<S, E> Service<WebRequest<E>> for CorsMiddleware<S>
+where
+    S: Service<WebRequest<E>, Response = WebResponse>,
+    S::Future: 'static,
+{
+    type Response = WebResponse;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: WebRequest<E>) -> Self::Future {
+        let inner = self.inner.clone();
+        let origin = match req.headers().get(header::ORIGIN) {
+            Some(origin) if !inner.origins.is_empty() => inner.allow_origin(origin),
+            _ => None,
+        };
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return Box::pin(self.service.call(req)),
+        };
+
+        // preflight, answered without calling the service
+        if req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            let res = HttpResponse::NoContent()
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, inner.methods.clone())
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, inner.headers.clone())
+                .header(header::ACCESS_CONTROL_MAX_AGE, inner.max_age.clone())
+                .finish();
+            let mut res = req.into_response(res);
+            inner.set_headers(&mut res, origin);
+            return Box::pin(async move { Ok(res) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            inner.set_headers(&mut res, origin);
+            Ok(res)
+        })
+    }
+}
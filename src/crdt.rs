@@ -0,0 +1,468 @@
+//! Counters, sets and registers shared by every node, for likes, votes or
+//! online counts.
+//!
+//! Objects belong to the scopes of `kv`, a room's or a tenant's, the latter
+//! for connections whose credential names the tenant, and have a name and a
+//! kind fixed by their first update. Clients update them with
+//! `crdt.update`, read them with `crdt.get` and follow them with
+//! `crdt.subscribe`, which sends `crdt.changed` with the new value on every
+//! change:
+//!
+//! ```json
+//! {"type": "crdt.update", "id": "1", "payload": {"room": "talk", "name": "likes", "kind": "counter", "by": 1}}
+//! {"type": "crdt.update", "id": "1", "payload": {"room": "talk", "name": "likes", "kind": "counter", "value": 42}}
+//! {"type": "crdt.update", "id": "2", "payload": {"name": "online", "kind": "set", "add": "alice"}}
+//! {"type": "crdt.update", "id": "3", "payload": {"room": "talk", "name": "slide", "kind": "register", "value": 7}}
+//! ```
+//!
+//! Each kind is a state-based CRDT, so updates made on different nodes at
+//! the same time merge to the same value everywhere, whatever order they
+//! arrive in:
+//!
+//! - a `counter` keeps what each node added and took away, its value is the
+//!   difference of the sums
+//! - a `set` of strings keeps the time of the last add or remove of each
+//!   element, the latest wins and an add wins a tie
+//! - a `register` holds the value written latest
+//!
+//! Times are unix milliseconds, ties broken by node id, so nodes' clocks
+//! should be close. In cluster mode a node sends an object's state to every
+//! other node after each of its updates, through the peer queues of
+//! `cluster`; a node that missed some, being down, catches up with the next
+//! update made elsewhere. Objects are kept in memory only.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::CrdtConfig;
+use crate::history;
+use crate::kv::Scope;
+use crate::protocol::{self, ErrorCode};
+
+/// Longest object name, bytes
+const MAX_NAME: usize = 256;
+
+/// Objects a connection may follow
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// Node id of a server outside a cluster
+const LOCAL: &str = "local";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Counter,
+    Set,
+    Register,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Kind::Counter => "counter",
+            Kind::Set => "set",
+            Kind::Register => "register",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Update of an object
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Add to a counter, take away if negative
+    Increment(i64),
+    Add(String),
+    Remove(String),
+    /// Write a register
+    Assign(Value),
+}
+
+impl Op {
+    fn kind(&self) -> Kind {
+        match self {
+            Op::Increment(_) => Kind::Counter,
+            Op::Add(_) | Op::Remove(_) => Kind::Set,
+            Op::Assign(_) => Kind::Register,
+        }
+    }
+}
+
+/// Time of a write, the later one wins
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Stamp {
+    pub at: u64,
+    pub node: String,
+}
+
+/// State of an object, as replicated to other nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Object {
+    Counter {
+        /// Added by node
+        incs: BTreeMap<String, u64>,
+        /// Taken away by node
+        decs: BTreeMap<String, u64>,
+    },
+    Set {
+        /// Last add or remove of each element, `true` for an add
+        elements: BTreeMap<String, (Stamp, bool)>,
+    },
+    Register {
+        value: Value,
+        stamp: Stamp,
+    },
+}
+
+impl Object {
+    fn new(kind: Kind) -> Object {
+        match kind {
+            Kind::Counter => Object::Counter {
+                incs: BTreeMap::new(),
+                decs: BTreeMap::new(),
+            },
+            Kind::Set => Object::Set {
+                elements: BTreeMap::new(),
+            },
+            Kind::Register => Object::Register {
+                value: Value::Null,
+                stamp: Stamp {
+                    at: 0,
+                    node: String::new(),
+                },
+            },
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Object::Counter { .. } => Kind::Counter,
+            Object::Set { .. } => Kind::Set,
+            Object::Register { .. } => Kind::Register,
+        }
+    }
+
+    /// Value clients see: an integer, the set's elements in order, or the
+    /// register's value
+    pub fn value(&self) -> Value {
+        match self {
+            Object::Counter { incs, decs } => {
+                let sum = |counts: &BTreeMap<String, u64>| {
+                    counts.values().map(|n| *n as i128).sum::<i128>()
+                };
+                let value = (sum(incs) - sum(decs)).clamp(i64::MIN as i128, i64::MAX as i128);
+                json!(value as i64)
+            }
+            Object::Set { elements } => {
+                let present = elements.iter().filter(|(_, (_, added))| *added);
+                Value::Array(present.map(|(element, _)| json!(element)).collect())
+            }
+            Object::Register { value, .. } => value.clone(),
+        }
+    }
+
+    /// Apply `op` of node `node`, of the object's kind
+    fn apply(&mut self, node: &str, op: Op) {
+        match (self, op) {
+            (Object::Counter { incs, .. }, Op::Increment(by)) if by >= 0 => {
+                let count = incs.entry(node.to_string()).or_default();
+                *count = count.saturating_add(by as u64);
+            }
+            (Object::Counter { decs, .. }, Op::Increment(by)) => {
+                let count = decs.entry(node.to_string()).or_default();
+                *count = count.saturating_add(by.unsigned_abs());
+            }
+            (Object::Set { elements }, Op::Add(element)) => mark(elements, node, element, true),
+            (Object::Set { elements }, Op::Remove(element)) => mark(elements, node, element, false),
+            (Object::Register { value, stamp: last }, Op::Assign(new)) => {
+                *last = stamp(node, Some(last.at));
+                *value = new;
+            }
+            _ => {}
+        }
+    }
+
+    /// Merge the state of the same object from another node, returns
+    /// whether ours changed
+    fn merge(&mut self, other: Object) -> bool {
+        let before = self.clone();
+        match (&mut *self, other) {
+            (Object::Counter { incs, decs }, Object::Counter { incs: i, decs: d }) => {
+                for (mine, theirs) in [(incs, i), (decs, d)] {
+                    for (node, count) in theirs {
+                        let ours = mine.entry(node).or_default();
+                        *ours = (*ours).max(count);
+                    }
+                }
+            }
+            (Object::Set { elements }, Object::Set { elements: theirs }) => {
+                for (element, (stamp, added)) in theirs {
+                    let newer = match elements.get(&element) {
+                        Some((ours, ours_added)) => (&stamp, added) > (ours, *ours_added),
+                        None => true,
+                    };
+                    if newer {
+                        elements.insert(element, (stamp, added));
+                    }
+                }
+            }
+            (Object::Register { value, stamp }, Object::Register { value: v, stamp: s }) => {
+                if s > *stamp {
+                    *value = v;
+                    *stamp = s;
+                }
+            }
+            (ours, theirs) => {
+                log::warn!(
+                    "Cannot merge a {} into a {}, keeping the {}",
+                    theirs.kind(),
+                    ours.kind(),
+                    ours.kind()
+                );
+            }
+        }
+        *self != before
+    }
+}
+
+/// Record an add or remove of `element` by `node` now
+fn mark(elements: &mut BTreeMap<String, (Stamp, bool)>, node: &str, element: String, added: bool) {
+    let after = elements.get(&element).map(|(stamp, _)| stamp.at);
+    elements.insert(element, (stamp(node, after), added));
+}
+
+/// Stamp of a write by `node` now, later than `after`
+fn stamp(node: &str, after: Option<u64>) -> Stamp {
+    let now = history::now_millis();
+    Stamp {
+        at: after.map_or(now, |after| now.max(after + 1)),
+        node: node.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrdtError {
+    BadName(String),
+    /// Object exists with another kind
+    WrongKind(Kind),
+    /// Store has `crdt.max_objects` objects
+    TooManyObjects,
+    /// Set has `crdt.max_set_size` elements
+    TooManyElements,
+    /// Register value longer than `crdt.max_value_bytes`
+    TooLarge,
+    TooManySubscriptions,
+}
+
+impl fmt::Display for CrdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrdtError::BadName(reason) => write!(f, "invalid name: {}", reason),
+            CrdtError::WrongKind(kind) => write!(f, "object is a {}", kind),
+            CrdtError::TooManyObjects => write!(f, "too many objects"),
+            CrdtError::TooManyElements => write!(f, "too many elements in the set"),
+            CrdtError::TooLarge => write!(f, "value is too large"),
+            CrdtError::TooManySubscriptions => write!(f, "too many subscriptions"),
+        }
+    }
+}
+
+impl From<CrdtError> for protocol::Error {
+    fn from(e: CrdtError) -> protocol::Error {
+        let code = match e {
+            CrdtError::BadName(_)
+            | CrdtError::WrongKind(_)
+            | CrdtError::TooManyElements
+            | CrdtError::TooLarge => ErrorCode::BadPayload,
+            CrdtError::TooManyObjects | CrdtError::TooManySubscriptions => ErrorCode::RateLimited,
+        };
+        protocol::Error::new(code, e.to_string())
+    }
+}
+
+/// Object of a scope, by name
+type Key = (Scope, String);
+
+#[derive(Debug, Default)]
+struct State {
+    objects: HashMap<Key, Object>,
+    subscribers: HashMap<Key, HashSet<u64>>,
+    /// Objects each connection follows
+    followed: HashMap<u64, HashSet<Key>>,
+}
+
+impl State {
+    fn subscribers(&self, key: &Key) -> Vec<u64> {
+        self.subscribers
+            .get(key)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Change to tell the subscribers of an object, and other nodes
+#[derive(Debug)]
+pub struct Change {
+    pub object: Object,
+    pub subscribers: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Crdts {
+    config: CrdtConfig,
+    /// Id of this node in stamps and counters
+    node: String,
+    state: Mutex<State>,
+}
+
+impl Crdts {
+    /// No objects yet; `node` is the cluster node id, if any
+    pub fn new(config: &CrdtConfig, node: Option<&str>) -> Crdts {
+        Crdts {
+            config: config.clone(),
+            node: node.unwrap_or(LOCAL).to_string(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn get(&self, scope: &Scope, name: &str) -> Option<Object> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .get(&(scope.clone(), name.to_string()))
+            .cloned()
+    }
+
+    /// Objects of every scope, by name
+    pub fn list(&self) -> BTreeMap<String, BTreeMap<String, Object>> {
+        let state = self.state.lock().unwrap();
+        let mut scopes: BTreeMap<String, BTreeMap<String, Object>> = BTreeMap::new();
+        for ((scope, name), object) in &state.objects {
+            scopes
+                .entry(scope.to_string())
+                .or_default()
+                .insert(name.clone(), object.clone());
+        }
+        scopes
+    }
+
+    /// Apply `op` to object `name` of `scope`, created of the op's kind if
+    /// it does not exist
+    pub fn update(&self, scope: &Scope, name: &str, op: Op) -> Result<Change, CrdtError> {
+        check_name(name)?;
+        let kind = op.kind();
+        if let Op::Assign(ref value) = op {
+            if value.to_string().len() > self.config.max_value_bytes {
+                return Err(CrdtError::TooLarge);
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        let key = (scope.clone(), name.to_string());
+        if !state.objects.contains_key(&key) && state.objects.len() >= self.config.max_objects {
+            return Err(CrdtError::TooManyObjects);
+        }
+        let object = state
+            .objects
+            .entry(key.clone())
+            .or_insert_with(|| Object::new(kind));
+        if object.kind() != kind {
+            return Err(CrdtError::WrongKind(object.kind()));
+        }
+        if let (Object::Set { elements }, Op::Add(element)) = (&*object, &op) {
+            if !elements.contains_key(element) && elements.len() >= self.config.max_set_size {
+                return Err(CrdtError::TooManyElements);
+            }
+        }
+        object.apply(&self.node, op);
+        let object = object.clone();
+        Ok(Change {
+            object,
+            subscribers: state.subscribers(&key),
+        })
+    }
+
+    /// Merge the state of object `name` of `scope` from another node,
+    /// `None` if it changed nothing here
+    pub fn merge(&self, scope: &Scope, name: &str, object: Object) -> Option<Change> {
+        let mut state = self.state.lock().unwrap();
+        let key = (scope.clone(), name.to_string());
+        let changed = match state.objects.get_mut(&key) {
+            Some(ours) => ours.merge(object),
+            None => {
+                state.objects.insert(key.clone(), object);
+                true
+            }
+        };
+        changed.then(|| Change {
+            object: state.objects[&key].clone(),
+            subscribers: state.subscribers(&key),
+        })
+    }
+
+    /// Follow object `name` of `scope` for connection `id`, returns it if
+    /// it exists
+    pub fn subscribe(
+        &self,
+        id: u64,
+        scope: &Scope,
+        name: &str,
+    ) -> Result<Option<Object>, CrdtError> {
+        check_name(name)?;
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let key = (scope.clone(), name.to_string());
+        let followed = state.followed.entry(id).or_default();
+        if !followed.contains(&key) && followed.len() >= MAX_SUBSCRIPTIONS {
+            return Err(CrdtError::TooManySubscriptions);
+        }
+        followed.insert(key.clone());
+        state.subscribers.entry(key.clone()).or_default().insert(id);
+        Ok(state.objects.get(&key).cloned())
+    }
+
+    pub fn unsubscribe(&self, id: u64, scope: &Scope, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        let key = (scope.clone(), name.to_string());
+        if let Some(followed) = state.followed.get_mut(&id) {
+            followed.remove(&key);
+        }
+        if let Some(ids) = state.subscribers.get_mut(&key) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                state.subscribers.remove(&key);
+            }
+        }
+    }
+
+    /// Drop the subscriptions of connection `id`, it closed
+    pub fn disconnected(&self, id: u64) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        for key in state.followed.remove(&id).unwrap_or_default() {
+            if let Some(ids) = state.subscribers.get_mut(&key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    state.subscribers.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), CrdtError> {
+    if name.is_empty() {
+        return Err(CrdtError::BadName("name is empty".to_string()));
+    }
+    if name.len() > MAX_NAME {
+        return Err(CrdtError::BadName(format!(
+            "name is over {} bytes",
+            MAX_NAME
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Encryption of stored history.
+//!
+//! Records are sealed with AES-256-GCM under the `current` key of
+//! `history.encryption`, with a random nonce and the key id as associated
+//! data: `<key id length: u8><key id><nonce: 12 bytes><ciphertext and tag>`.
+//! Lines of room logs carry that as `enc:<base64>`. Any listed key opens
+//! data, so keys are rotated by adding a new one as `current`; room logs are
+//! re-encrypted when compacted at the next startup, archived batches keep
+//! their key.
+
+use std::collections::HashMap;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::EncryptionConfig;
+
+/// Prefix of an encrypted log line
+const LINE_PREFIX: &str = "enc:";
+
+pub struct Cipher {
+    current: String,
+    keys: HashMap<String, LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    pub fn new(config: &EncryptionConfig) -> Result<Cipher, String> {
+        let mut keys = HashMap::new();
+        for (id, key) in &config.keys {
+            if id.is_empty() || id.len() > u8::MAX as usize {
+                return Err(format!("encryption key id `{}` must be 1..=255 bytes", id));
+            }
+            let bytes = STANDARD
+                .decode(key.trim())
+                .map_err(|e| format!("encryption key {}: {}", id, e))?;
+            let key = UnboundKey::new(&AES_256_GCM, &bytes)
+                .map_err(|_| format!("encryption key {} is not 32 bytes", id))?;
+            keys.insert(id.clone(), LessSafeKey::new(key));
+        }
+        if !keys.contains_key(&config.current) {
+            return Err(format!(
+                "current encryption key {} is not listed",
+                config.current
+            ));
+        }
+        Ok(Cipher {
+            current: config.current.clone(),
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypt with the current key
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let key = &self.keys[&self.current];
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).expect("system randomness");
+        let mut data = plain.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(self.current.as_bytes()),
+            &mut data,
+        )
+        .expect("within AES-GCM limits");
+        let mut out = Vec::with_capacity(1 + self.current.len() + NONCE_LEN + data.len());
+        out.push(self.current.len() as u8);
+        out.extend_from_slice(self.current.as_bytes());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// Decrypt data sealed with any listed key
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        let (&id_len, rest) = sealed.split_first().ok_or("empty ciphertext")?;
+        if rest.len() < id_len as usize + NONCE_LEN {
+            return Err("truncated ciphertext".to_string());
+        }
+        let (id, rest) = rest.split_at(id_len as usize);
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+        let id = String::from_utf8_lossy(id);
+        let key = self
+            .keys
+            .get(id.as_ref())
+            .ok_or_else(|| format!("unknown encryption key {}", id))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce length");
+        let mut data = data.to_vec();
+        let plain = key
+            .open_in_place(nonce, Aad::from(id.as_bytes()), &mut data)
+            .map_err(|_| format!("cannot decrypt with key {}", id))?;
+        Ok(plain.to_vec())
+    }
+
+    pub fn seal_line(&self, line: &str) -> String {
+        format!(
+            "{}{}",
+            LINE_PREFIX,
+            STANDARD.encode(self.seal(line.as_bytes()))
+        )
+    }
+
+    /// Plain text of a log line, which may be unencrypted
+    pub fn open_line(&self, line: &str) -> Result<String, String> {
+        let sealed = match line.strip_prefix(LINE_PREFIX) {
+            Some(sealed) => sealed,
+            None => return Ok(line.to_string()),
+        };
+        let sealed = STANDARD.decode(sealed).map_err(|e| e.to_string())?;
+        String::from_utf8(self.open(&sealed)?).map_err(|e| e.to_string())
+    }
+}
+
+/// Plain text of a log line without a cipher, encrypted lines are refused
+pub fn plain_line(line: &str) -> Result<&str, String> {
+    match line.starts_with(LINE_PREFIX) {
+        true => Err("encrypted, but history.encryption is not configured".to_string()),
+        false => Ok(line),
+    }
+}
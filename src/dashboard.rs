@@ -0,0 +1,135 @@
+//! Built-in admin dashboard at `/admin/dashboard`.
+//!
+//! The page and its `/admin/stream` websocket accept the admin token from
+//! the `wss_admin` cookie set by the login form; everything the page
+//! changes goes through the regular admin routes with a bearer token.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::ready;
+use ntex::service::{fn_factory_with_config, fn_service};
+use ntex::web::{self, types::State, ws, HttpRequest, HttpResponse};
+use ntex::{rt, time};
+use serde_json::{json, Map, Value};
+
+use crate::admin::authorize_dashboard;
+use crate::config::Config;
+use crate::hub::Hub;
+use crate::metrics::{Sample, METRICS};
+use crate::reporting;
+
+/// How often `/admin/stream` pushes a snapshot
+const STREAM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Recent errors included in a snapshot
+const SNAPSHOT_ERRORS: usize = 20;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+const LOGIN: &str = r#"<!doctype html>
+<meta charset="utf-8">
+<title>websocket-server admin</title>
+<form id="login" style="font-family: sans-serif; margin: 4em auto; width: 20em">
+  <p><label>Admin token <input type="password" name="token" autofocus></label>
+  <p><button>Sign in</button> <span id="error"></span>
+</form>
+<script>
+document.getElementById("login").onsubmit = async (e) => {
+  e.preventDefault();
+  const token = e.target.token.value;
+  const res = await fetch("/admin/tasks", { headers: { Authorization: "Bearer " + token } });
+  if (!res.ok) {
+    document.getElementById("error").textContent = "rejected";
+    return;
+  }
+  document.cookie = "wss_admin=" + encodeURIComponent(token) + "; path=/admin; SameSite=Strict";
+  location.reload();
+};
+</script>
+"#;
+
+/// `GET /admin/dashboard`
+pub async fn page(req: HttpRequest, config: State<Arc<Config>>) -> HttpResponse {
+    match authorize_dashboard(&req, &config) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(PAGE),
+        Err(res) if res.status().is_client_error() && config.admin.token.is_some() => {
+            HttpResponse::Unauthorized()
+                .content_type("text/html; charset=utf-8")
+                .body(LOGIN)
+        }
+        Err(res) => res,
+    }
+}
+
+/// Live counters, per tenant connection counts, rooms and recent errors
+fn snapshot(hub: &Hub) -> Value {
+    let mut metrics = Map::new();
+    METRICS.visit(|name, _, sample| match sample {
+        Sample::Counter(v) => {
+            metrics.insert(name.to_string(), json!(v));
+        }
+        Sample::Gauge(v) => {
+            metrics.insert(name.to_string(), json!(v));
+        }
+        Sample::Histogram(_) => (),
+    });
+    let mut tenants = BTreeMap::<String, usize>::new();
+    for conn in hub.connections() {
+        let tenant = conn.tenant.unwrap_or_else(|| "-".to_string());
+        *tenants.entry(tenant).or_default() += 1;
+    }
+    let mut errors = reporting::recent();
+    errors.truncate(SNAPSHOT_ERRORS);
+    json!({
+        "connections": hub.len(),
+        "tenants": tenants,
+        "rooms": hub.rooms().list(),
+        "metrics": metrics,
+        "errors": errors,
+    })
+}
+
+/// `GET /admin/stream`, websocket pushing a snapshot every second
+pub async fn stream(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+) -> Result<HttpResponse, web::Error> {
+    if let Err(res) = authorize_dashboard(&req, &config) {
+        return Ok(res);
+    }
+    let hub = hub.get_ref().clone();
+    ws::start(
+        req,
+        fn_factory_with_config(move |sink: ws::WsSink| {
+            rt::spawn(push(sink, hub.clone()));
+            ready(Ok::<_, web::Error>(fn_service(|frame| {
+                let reply = match frame {
+                    ws::Frame::Ping(msg) => Some(ws::Message::Pong(msg)),
+                    ws::Frame::Close(reason) => Some(ws::Message::Close(reason)),
+                    _ => None,
+                };
+                ready(Ok::<_, io::Error>(reply))
+            })))
+        }),
+    )
+    .await
+}
+
+async fn push(sink: ws::WsSink, hub: Arc<Hub>) {
+    loop {
+        let text = snapshot(&hub).to_string();
+        if sink.send(ws::Message::Text(text.into())).await.is_err() {
+            return;
+        }
+        time::sleep(STREAM_INTERVAL).await;
+        if sink.io().is_closed() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Connection lifecycle events.
+//!
+//! The session and the hub emit an `Event` when a connection opens or
+//! closes, joins or leaves a room, publishes or has a message rejected.
+//! Subsystems that react to those (metrics, the access log) subscribe here
+//! instead of being called from the frame handler.
+//!
+//! Subscribers are called synchronously on the emitting worker, sometimes
+//! under hub locks, and must be quick and must not call back into the hub;
+//! anything slow (an HTTP call, a disk write that can block) belongs on a
+//! channel drained by a task of the subscriber's own.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::hub::ConnStats;
+use crate::protocol::ErrorCode;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// Handshake done, the connection is registered with the hub
+    Connected { id: u64, stats: &'a ConnStats },
+    /// Connection closed or dropped, it is no longer registered
+    Disconnected {
+        id: u64,
+        peer: Option<SocketAddr>,
+        stats: &'a ConnStats,
+    },
+    /// Connection became a member of a room, also when promoted from the
+    /// room's queue
+    Joined { id: u64, room: &'a str },
+    /// Connection left a room, was kicked or disconnected
+    Left { id: u64, room: &'a str },
+    /// Connection published to a room; `seq` is `None` if the publish was
+    /// forwarded to the room's owner node
+    Published {
+        id: u64,
+        room: &'a str,
+        seq: Option<u64>,
+    },
+    /// A client message was answered with an error
+    Rejected { id: u64, code: ErrorCode },
+}
+
+pub trait Subscriber: Send + Sync {
+    fn event(&self, event: &Event<'_>);
+}
+
+static SUBSCRIBERS: RwLock<Vec<Arc<dyn Subscriber>>> = RwLock::new(Vec::new());
+
+/// Call `subscriber` for every event from now on
+pub fn subscribe(subscriber: Arc<dyn Subscriber>) {
+    SUBSCRIBERS.write().unwrap().push(subscriber);
+}
+
+pub fn emit(event: Event<'_>) {
+    for subscriber in SUBSCRIBERS.read().unwrap().iter() {
+        subscriber.event(&event);
+    }
+}
@@ -0,0 +1,526 @@
+//! Filter expressions of room subscriptions.
+//!
+//! A member joining with `"filter": "..."` only gets the `room.message`s
+//! whose payload the expression holds for, evaluated by the server before
+//! queueing them:
+//!
+//! ```text
+//! data.kind == 'alert' and data.level >= 3
+//! from != 12 && not (data.tags[0] in ['test', 'debug'])
+//! data.urgent
+//! ```
+//!
+//! Paths start at the payload (`room`, `seq`, `from`, `data`) and go down by
+//! `.field` and `[index]`, a missing one is `null`. Comparisons are `==`,
+//! `!=`, `<`, `<=`, `>`, `>=` and `in` a list of literals; ordering applies
+//! to two numbers or two strings and is false otherwise. A bare path holds
+//! unless it is `null` or `false`. Conditions combine with `and`/`&&`,
+//! `or`/`||`, `not`/`!` and parentheses.
+
+use serde_json::Value;
+
+/// Longest expression accepted
+pub const MAX_LEN: usize = 1024;
+
+/// Max nesting of parentheses and `not`
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    In(Operand, Vec<Value>),
+    /// A bare path or literal
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Path(Vec<Step>),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Field(String),
+    Index(usize),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        if source.len() > MAX_LEN {
+            return Err(format!("filter is longer than {} bytes", MAX_LEN));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.or(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    /// Whether the expression holds for `value`
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(value) && b.matches(value),
+            Expr::Or(a, b) => a.matches(value) || b.matches(value),
+            Expr::Not(a) => !a.matches(value),
+            Expr::Compare(a, op, b) => compare(a.get(value), *op, b.get(value)),
+            Expr::In(a, list) => {
+                let a = a.get(value);
+                list.iter().any(|item| equal(a, item))
+            }
+            Expr::Truthy(a) => !matches!(a.get(value), Value::Null | Value::Bool(false)),
+        }
+    }
+}
+
+static NULL: Value = Value::Null;
+
+impl Operand {
+    fn get<'a>(&'a self, value: &'a Value) -> &'a Value {
+        match self {
+            Operand::Literal(literal) => literal,
+            Operand::Path(steps) => steps
+                .iter()
+                .try_fold(value, |value, step| match step {
+                    Step::Field(name) => value.get(name),
+                    Step::Index(i) => value.get(i),
+                })
+                .unwrap_or(&NULL),
+        }
+    }
+}
+
+/// JSON equality, numbers by value so `1 == 1.0`
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, op: Op, b: &Value) -> bool {
+    let ordering = match (a, b) {
+        (Value::Number(_), Value::Number(_)) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    match op {
+        Op::Eq => equal(a, b),
+        Op::Ne => !equal(a, b),
+        Op::Lt => ordering.is_some_and(|o| o.is_lt()),
+        Op::Le => ordering.is_some_and(|o| o.is_le()),
+        Op::Gt => ordering.is_some_and(|o| o.is_gt()),
+        Op::Ge => ordering.is_some_and(|o| o.is_ge()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(&'static str),
+    Name(String),
+    Number(f64),
+    Str(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Punct(p) => write!(f, "`{}`", p),
+            Token::Name(name) => write!(f, "`{}`", name),
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Str(_) => write!(f, "string"),
+        }
+    }
+}
+
+const PUNCTS: [&str; 16] = [
+    "==", "!=", "<=", ">=", "&&", "||", "=", "<", ">", "!", "(", ")", "[", "]", ",", ".",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if let Some(&punct) = PUNCTS.iter().find(|p| rest.starts_with(**p)) {
+            // `=` is SQL's spelling of `==`
+            tokens.push(Token::Punct(if punct == "=" { "==" } else { punct }));
+            rest = &rest[punct.len()..];
+        } else if c == '\'' || c == '"' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, q)) if q == c => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some((_, other)) => text.push(other),
+                    None => return Err("unterminated string".to_string()),
+                }
+            };
+            tokens.push(Token::Str(text));
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() || c == '-' {
+            let end = rest[1..]
+                .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E'))
+                .map_or(rest.len(), |i| i + 1);
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| format!("bad number `{}`", &rest[..end]))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("unexpected `{}`", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of filter")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Consume `punct` or keyword `name` if next
+    fn eat(&mut self, punct: &str, name: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Punct(p)) => *p == punct,
+            Some(Token::Name(n)) => n.eq_ignore_ascii_case(name),
+            _ => false,
+        };
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(p) if p == punct => Ok(()),
+            token => Err(format!("expected `{}`, found {}", punct, token)),
+        }
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, String> {
+        let mut expr = self.and(depth)?;
+        while self.eat("||", "or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, String> {
+        let mut expr = self.not(depth)?;
+        while self.eat("&&", "and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self, depth: usize) -> Result<Expr, String> {
+        if depth > MAX_DEPTH {
+            return Err("filter is nested too deep".to_string());
+        }
+        if self.eat("!", "not") {
+            return Ok(Expr::Not(Box::new(self.not(depth + 1)?)));
+        }
+        if self.eat("(", "") {
+            let expr = self.or(depth + 1)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        self.condition()
+    }
+
+    fn condition(&mut self) -> Result<Expr, String> {
+        let a = self.operand()?;
+        if self.eat("", "in") {
+            return Ok(Expr::In(a, self.list()?));
+        }
+        let op = match self.peek() {
+            Some(Token::Punct("==")) => Op::Eq,
+            Some(Token::Punct("!=")) => Op::Ne,
+            Some(Token::Punct("<")) => Op::Lt,
+            Some(Token::Punct("<=")) => Op::Le,
+            Some(Token::Punct(">")) => Op::Gt,
+            Some(Token::Punct(">=")) => Op::Ge,
+            _ => return Ok(Expr::Truthy(a)),
+        };
+        self.pos += 1;
+        Ok(Expr::Compare(a, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next()? {
+            Token::Name(name) => match self.keyword(&name) {
+                Some(literal) => Ok(Operand::Literal(literal)),
+                None => self.path(name).map(Operand::Path),
+            },
+            token => self.literal(token).map(Operand::Literal),
+        }
+    }
+
+    fn keyword(&self, name: &str) -> Option<Value> {
+        match name {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            "null" => Some(Value::Null),
+            _ => None,
+        }
+    }
+
+    fn literal(&mut self, token: Token) -> Result<Value, String> {
+        match token {
+            Token::Str(text) => Ok(Value::String(text)),
+            Token::Number(n) => {
+                Ok(serde_json::Number::from_f64(n).map_or(Value::Null, Value::Number))
+            }
+            Token::Name(ref name) => self
+                .keyword(name)
+                .ok_or_else(|| format!("expected a literal, found {}", token)),
+            token => Err(format!("expected a value, found {}", token)),
+        }
+    }
+
+    fn path(&mut self, first: String) -> Result<Vec<Step>, String> {
+        let mut steps = vec![Step::Field(first)];
+        loop {
+            if self.eat(".", "") {
+                match self.next()? {
+                    Token::Name(name) => steps.push(Step::Field(name)),
+                    token => return Err(format!("expected a field name, found {}", token)),
+                }
+            } else if self.eat("[", "") {
+                match self.next()? {
+                    Token::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+                        steps.push(Step::Index(n as usize))
+                    }
+                    Token::Str(name) => steps.push(Step::Field(name)),
+                    token => return Err(format!("expected an index, found {}", token)),
+                }
+                self.expect("]")?;
+            } else {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>, String> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        if self.eat("]", "") {
+            return Ok(items);
+        }
+        loop {
+            let token = self.next()?;
+            items.push(self.literal(token)?);
+            if self.eat("]", "") {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn holds(source: &str, value: &Value) -> bool {
+        Expr::parse(source).unwrap().matches(value)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let value = json!({ "data": { "a": true, "b": false, "c": false } });
+        // a or (b and c)
+        assert!(holds("data.a or data.b and data.c", &value));
+        assert!(holds("data.b and data.c or data.a", &value));
+        assert!(!holds("(data.a or data.b) and data.c", &value));
+        assert_eq!(
+            Expr::parse("a || b && c").unwrap(),
+            Expr::parse("a or (b and c)").unwrap()
+        );
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        let value = json!({ "data": { "a": false, "b": true } });
+        assert!(holds("not data.a and data.b", &value));
+        assert!(!holds("not (data.a or data.b)", &value));
+        assert!(holds("!!data.b", &value));
+        // `not` takes the whole comparison after it
+        assert!(!holds("not data.b == true", &value));
+        assert!(holds("not data.b == false", &value));
+    }
+
+    #[test]
+    fn comparisons() {
+        let value = json!({ "from": 12, "data": { "level": 3, "kind": "disk", "tags": ["test"] } });
+        assert!(holds("data.level >= 3 and data.level < 4", &value));
+        assert!(holds("data.level == 3.0", &value));
+        assert!(holds("data.level = 3", &value));
+        assert!(holds("data.kind in ['disk', 'cpu']", &value));
+        assert!(holds("data.tags[0] == 'test'", &value));
+        assert!(holds("data['kind'] != \"cpu\"", &value));
+        assert!(holds("data.kind > 'abc'", &value));
+        assert!(holds("data.missing == null", &value));
+        assert!(!holds("data.missing", &value));
+        assert!(!holds("data.kind in []", &value));
+    }
+
+    #[test]
+    fn mismatched_types_do_not_match() {
+        let value = json!({ "data": { "n": 3, "s": "3", "list": [1], "t": true } });
+        assert!(!holds("data.n == '3'", &value));
+        assert!(!holds("data.s == 3", &value));
+        assert!(!holds("data.n < 'a'", &value));
+        assert!(!holds("data.s >= 1", &value));
+        assert!(!holds("data.list > 0", &value));
+        assert!(!holds("data.t > false", &value));
+        assert!(!holds("data.n.deeper == 3", &value));
+        assert!(!holds("data.s[0] == '3'", &value));
+        assert!(holds("data.n != '3'", &value));
+    }
+
+    #[test]
+    fn malformed_filters_are_errors() {
+        for source in [
+            "",
+            "data.a ==",
+            "== 3",
+            "data.a and",
+            "(data.a",
+            "data.a)",
+            "data.a in 'x'",
+            "data.a in [1,",
+            "data.a in [1 2]",
+            "data.a in [data.b]",
+            "data.",
+            "data[",
+            "data[-1]",
+            "data[1.5]",
+            "data[x]",
+            "'unterminated",
+            "'escape at end\\",
+            "data.a == 1e",
+            "-",
+            "data.a # 1",
+            "data.a === 1",
+            "data.a < > 1",
+            "3 3",
+        ] {
+            assert!(Expr::parse(source).is_err(), "{:?} parsed", source);
+        }
+    }
+
+    #[test]
+    fn limits_are_errors() {
+        let deep = format!("{}data.a{}", "(".repeat(100), ")".repeat(100));
+        assert!(Expr::parse(&deep).is_err());
+        assert!(Expr::parse(&"not ".repeat(100)).is_err());
+        assert!(Expr::parse(&"a or ".repeat(300)).is_err());
+        let nested = format!("{}data.a{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert!(Expr::parse(&nested).is_ok());
+    }
+
+    #[test]
+    fn never_panics() {
+        let pieces = [
+            "data",
+            ".",
+            "a",
+            "[",
+            "]",
+            "0",
+            "-1",
+            "1e9",
+            "99999999999999999999",
+            "(",
+            ")",
+            "==",
+            "!=",
+            "<",
+            ">=",
+            "=",
+            "!",
+            "and",
+            "or",
+            "not",
+            "in",
+            ",",
+            "'x'",
+            "\"",
+            "'",
+            "\\",
+            "null",
+            "true",
+            " ",
+            "é",
+            "😀",
+            "&&",
+            "||",
+            "&",
+            "|",
+        ];
+        let value = json!({ "data": { "a": [1, "x", null], "0": true } });
+        // deterministic pseudo-random sequences of pieces
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..20_000 {
+            let mut source = String::new();
+            for _ in 0..(seed % 12) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                source.push_str(pieces[(seed % pieces.len() as u64) as usize]);
+            }
+            if let Ok(expr) = Expr::parse(&source) {
+                expr.matches(&value);
+            }
+            seed = seed.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Thread pool fanning out large broadcasts.
+//!
+//! Queueing a broadcast for tens of thousands of connections takes a while,
+//! and on the worker that got the admin request it holds up the frames of
+//! every connection of that worker meanwhile. With `[hub.fanout]`, a
+//! broadcast while more than `threshold` connections are registered is
+//! split into a job per hub shard, run by threads of this pool instead.
+//!
+//! A thread takes a batch of jobs from the shared queue at a time, and
+//! threads that run dry steal from the others' batches, so one broadcast
+//! spreads over all threads and a slow shard holds up nothing behind it.
+//! The pool is bounded: with `max_jobs` waiting, a broadcast runs on the
+//! caller as without the pool. Broadcasts in flight at the same time may
+//! reach a connection in either order, unless they have the same ordering
+//! key: those take turns, each one starting once the one before it is
+//! queued for all its recipients, so they reach every connection in the
+//! order they were made. Broadcasts with other keys or none still overlap.
+
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::Duration;
+use std::{io, iter, thread};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use futures::channel::oneshot;
+
+use crate::config::FanoutConfig;
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+struct Pool {
+    queue: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    /// Jobs submitted and not started yet
+    waiting: AtomicUsize,
+    threshold: usize,
+    max_jobs: usize,
+    idle: Mutex<()>,
+    wake: Condvar,
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+/// Sent when a turn ends: `None` once its broadcast is queued, what it
+/// was still waiting for when it was dropped before its turn came
+#[derive(Debug)]
+struct Done(Option<oneshot::Receiver<Done>>);
+
+/// Number of a broadcast with an ordering key, and when it is done
+type Last = (u64, oneshot::Receiver<Done>);
+
+/// Last broadcast of each ordering key
+static LAST: Mutex<BTreeMap<String, Last>> = Mutex::new(BTreeMap::new());
+
+static NEXT_TURN: AtomicU64 = AtomicU64::new(0);
+
+/// Start the pool threads, before the server starts
+pub fn init(config: &FanoutConfig) -> io::Result<()> {
+    let workers = match config.workers {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let locals: Vec<Worker<Job>> = (0..workers).map(|_| Worker::new_fifo()).collect();
+    let pool = Pool {
+        queue: Injector::new(),
+        stealers: locals.iter().map(Worker::stealer).collect(),
+        waiting: AtomicUsize::new(0),
+        threshold: config.threshold,
+        max_jobs: config.max_jobs,
+        idle: Mutex::new(()),
+        wake: Condvar::new(),
+    };
+    if POOL.set(pool).is_err() {
+        return Ok(());
+    }
+    for (index, local) in locals.into_iter().enumerate() {
+        thread::Builder::new()
+            .name(format!("fanout-{}", index))
+            .spawn(move || run(index, local))?;
+    }
+    Ok(())
+}
+
+/// Whether a broadcast to `connections` goes to the pool
+pub fn wanted(connections: usize) -> bool {
+    POOL.get().is_some_and(|pool| connections > pool.threshold)
+}
+
+/// Run `jobs` on the pool; gives them back if it is not running or has
+/// `max_jobs` waiting
+pub fn submit(jobs: Vec<Job>) -> Result<(), Vec<Job>> {
+    let Some(pool) = POOL.get() else {
+        return Err(jobs);
+    };
+    if pool.waiting.load(Ordering::Relaxed) + jobs.len() > pool.max_jobs {
+        return Err(jobs);
+    }
+    pool.waiting.fetch_add(jobs.len(), Ordering::Relaxed);
+    for job in jobs {
+        pool.queue.push(job);
+    }
+    // under the lock, so a thread about to wait sees the jobs or the wakeup
+    let _idle = pool.idle.lock().unwrap();
+    pool.wake.notify_all();
+    Ok(())
+}
+
+/// Wait for the broadcasts with ordering key `key` made before this one;
+/// the next one starts once the turn is dropped. Cancel safe: dropped
+/// while waiting, the next one waits for what this one waited for
+pub async fn turn(key: &str) -> Turn {
+    let number = NEXT_TURN.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    let before = LAST.lock().unwrap().insert(key.to_string(), (number, rx));
+    let mut turn = Turn {
+        key: key.to_string(),
+        number,
+        done: Some(tx),
+        before: before.map(|(_, done)| done),
+    };
+    while let Some(before) = turn.before.as_mut() {
+        turn.before = match before.await {
+            // that one was dropped waiting, wait for its predecessor
+            Ok(Done(earlier)) => earlier,
+            Err(_) => None,
+        };
+    }
+    turn
+}
+
+/// A broadcast's turn among those with its ordering key
+#[derive(Debug)]
+pub struct Turn {
+    key: String,
+    number: u64,
+    done: Option<oneshot::Sender<Done>>,
+    /// End of the turn before, until it came
+    before: Option<oneshot::Receiver<Done>>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(Done(self.before.take()));
+        }
+        let mut last = LAST.lock().unwrap();
+        // no one is waiting for it
+        if last
+            .get(&self.key)
+            .is_some_and(|(number, _)| *number == self.number)
+        {
+            last.remove(&self.key);
+        }
+    }
+}
+
+fn run(index: usize, local: Worker<Job>) {
+    let pool = POOL.get().expect("pool is set before its threads start");
+    loop {
+        match find(pool, index, &local) {
+            Some(job) => {
+                pool.waiting.fetch_sub(1, Ordering::Relaxed);
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    log::error!("Broadcast job panicked on fanout-{}", index);
+                }
+            }
+            None => {
+                let idle = pool.idle.lock().unwrap();
+                if pool.queue.is_empty() {
+                    // jobs in another thread's batch are not announced,
+                    // look again now and then to steal them
+                    let _ = pool.wake.wait_timeout(idle, Duration::from_millis(50));
+                }
+            }
+        }
+    }
+}
+
+/// Own job, else a batch from the queue, else one stolen from another thread
+fn find(pool: &Pool, index: usize, local: &Worker<Job>) -> Option<Job> {
+    local.pop().or_else(|| {
+        iter::repeat_with(|| {
+            pool.queue.steal_batch_and_pop(local).or_else(|| {
+                pool.stealers
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != index)
+                    .map(|(_, stealer)| stealer.steal())
+                    .collect::<Steal<Job>>()
+            })
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    type Waiting = Pin<Box<dyn Future<Output = Turn>>>;
+
+    fn waiting(key: &'static str) -> Waiting {
+        Box::pin(turn(key))
+    }
+
+    fn poll(turn: &mut Waiting) -> Option<Turn> {
+        match turn
+            .as_mut()
+            .poll(&mut Context::from_waker(noop_waker_ref()))
+        {
+            Poll::Ready(turn) => Some(turn),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn turns_come_in_order() {
+        let first = poll(&mut waiting("order")).expect("nothing before it");
+        let mut second = waiting("order");
+        let mut third = waiting("order");
+        assert!(poll(&mut second).is_none());
+        assert!(poll(&mut third).is_none());
+
+        drop(first);
+        assert!(poll(&mut third).is_none());
+        let second = poll(&mut second).expect("first is done");
+        assert!(poll(&mut third).is_none());
+        drop(second);
+        assert!(poll(&mut third).is_some());
+    }
+
+    #[test]
+    fn other_keys_overlap() {
+        let _a = poll(&mut waiting("overlap-a")).expect("nothing before it");
+        assert!(poll(&mut waiting("overlap-b")).is_some());
+    }
+
+    #[test]
+    fn cancelled_turn_keeps_order() {
+        let first = poll(&mut waiting("cancel")).expect("nothing before it");
+        let mut second = waiting("cancel");
+        let mut third = waiting("cancel");
+        assert!(poll(&mut second).is_none());
+        assert!(poll(&mut third).is_none());
+
+        // the second broadcast is given up while waiting for the first
+        drop(second);
+        assert!(poll(&mut third).is_none());
+        drop(first);
+        assert!(poll(&mut third).is_some());
+    }
+
+    #[test]
+    fn cancelled_before_polled_keeps_order() {
+        let first = poll(&mut waiting("unpolled")).expect("nothing before it");
+        // registers in `LAST` when first polled, dropping it unpolled is a no-op
+        drop(waiting("unpolled"));
+        let mut third = waiting("unpolled");
+        assert!(poll(&mut third).is_none());
+        drop(first);
+        assert!(poll(&mut third).is_some());
+    }
+
+    #[test]
+    fn concurrent_broadcasts_keep_order() {
+        let mut waiting: Vec<(usize, Waiting)> = (0..8).map(|i| (i, waiting("many"))).collect();
+        let mut done = Vec::new();
+        let mut current = None;
+        for (i, turn) in waiting.iter_mut() {
+            if let Some(turn) = poll(turn) {
+                current = Some((*i, turn));
+            }
+        }
+        // some are given up while waiting
+        waiting.retain(|(i, _)| *i != 0 && i % 3 != 1);
+        while let Some((i, turn)) = current.take() {
+            done.push(i);
+            drop(turn);
+            let mut ready = Vec::new();
+            for (i, turn) in waiting.iter_mut() {
+                if let Some(turn) = poll(turn) {
+                    ready.push((*i, turn));
+                }
+            }
+            assert!(ready.len() <= 1, "one turn at a time");
+            current = ready.pop();
+            if let Some((i, _)) = current {
+                waiting.retain(|(w, _)| *w != i);
+            }
+        }
+        assert!(waiting.is_empty());
+        assert_eq!(done, vec![0, 2, 3, 5, 6]);
+    }
+}
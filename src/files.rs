@@ -0,0 +1,35 @@
+//! Static files, served from `static.root` below all other routes.
+
+use std::path::PathBuf;
+
+use futures::future::ready;
+use ntex::service::fn_service;
+use ntex::web::{self, HttpResponse, WebRequest, WebResponse};
+use ntex_files::{Files, NamedFile};
+
+use crate::config::StaticConfig;
+
+/// Register static file service, nothing is served if the root is missing
+pub fn configure(cfg: &mut web::ServiceConfig, config: &StaticConfig) {
+    // `Files` falls back to the working directory for a missing root
+    if !config.root.is_dir() {
+        return;
+    }
+    let mut files = Files::new("/", &config.root).index_file(config.index_file.clone());
+    if config.listing {
+        files = files.show_files_listing();
+    }
+    if config.spa {
+        // unknown paths get the app's index, client side routing takes it from there
+        let index: PathBuf = config.root.join(&config.index_file);
+        files = files.default_handler(fn_service(move |req: WebRequest<_>| {
+            let (req, _) = req.into_parts();
+            let res = match NamedFile::open(&index) {
+                Ok(file) => file.into_response(&req),
+                Err(_) => HttpResponse::NotFound().finish(),
+            };
+            ready(Ok(WebResponse::new(res, req)))
+        }));
+    }
+    cfg.service(files);
+}
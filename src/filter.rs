@@ -0,0 +1,244 @@
+//! Content filter of room messages.
+//!
+//! With `[rooms.filter]`, a `room.publish` to a filtered room goes through
+//! a chain of stages before the hub stores and delivers it. A stage lets
+//! the message pass, replaces its data (redaction, masking) or rejects it.
+//! The built-in stages are a word list, masking listed words in every
+//! string of the data, and an HTTP service asked about each message:
+//!
+//! ```text
+//! POST url  {"room": "..", "user": "..", "data": ..}
+//! 200       {"action": "allow"} | {"action": "replace", "data": ..}
+//!           | {"action": "reject", "reason": ".."}
+//! ```
+//!
+//! The chain has `timeout_ms` per message; if it fails or times out the
+//! message is delivered unfiltered or rejected, as `on_error` says. Stages
+//! run on the publishing connection's worker and a connection's messages
+//! are filtered one at a time, so they keep their order.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+use ntex::{http::client::Client, time};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{FilterConfig, OnError};
+use crate::metrics::METRICS;
+use crate::protocol::{self, ErrorCode};
+
+/// Message as seen by filter stages
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub room: String,
+    pub user: Option<String>,
+    pub data: Value,
+}
+
+/// What a stage decided about a message
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Verdict {
+    Allow,
+    /// Deliver `data` instead
+    Replace {
+        data: Value,
+    },
+    /// Answer the publisher with `permission_denied`
+    Reject {
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+/// One step of the filter chain; it may be checking messages of other
+/// connections at the same time
+pub trait Stage: Send + Sync {
+    fn check<'a>(&'a self, msg: &'a Message) -> LocalBoxFuture<'a, Result<Verdict, String>>;
+}
+
+pub struct ContentFilter {
+    stages: Vec<Box<dyn Stage>>,
+    timeout: Duration,
+    on_error: OnError,
+}
+
+impl std::fmt::Debug for ContentFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentFilter")
+            .field("stages", &self.stages.len())
+            .field("timeout", &self.timeout)
+            .field("on_error", &self.on_error)
+            .finish()
+    }
+}
+
+impl ContentFilter {
+    pub fn new(config: &FilterConfig) -> ContentFilter {
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+        if !config.words.is_empty() {
+            stages.push(Box::new(Words::new(&config.words)));
+        }
+        if let Some(ref url) = config.url {
+            stages.push(Box::new(HttpStage {
+                url: url.clone(),
+                token: config.token.clone(),
+                timeout: Duration::from_millis(config.timeout_ms),
+            }));
+        }
+        ContentFilter {
+            stages,
+            timeout: Duration::from_millis(config.timeout_ms),
+            on_error: config.on_error,
+        }
+    }
+
+    /// Run `msg` through every stage, returns the data to deliver or the
+    /// error to answer the publisher with
+    pub async fn run(&self, mut msg: Message) -> Result<Value, protocol::Error> {
+        let mut changed = false;
+        let chain = async {
+            for stage in &self.stages {
+                match stage.check(&msg).await? {
+                    Verdict::Allow => {}
+                    Verdict::Replace { data } => {
+                        msg.data = data;
+                        changed = true;
+                    }
+                    Verdict::Reject { reason } => return Ok(Some(reason)),
+                }
+            }
+            Ok::<_, String>(None)
+        };
+        let outcome = time::timeout(self.timeout, chain)
+            .await
+            .unwrap_or_else(|()| Err("timed out".to_string()));
+        match outcome {
+            Ok(None) => {
+                if changed {
+                    METRICS.messages_filtered_total.inc();
+                }
+                Ok(msg.data)
+            }
+            Ok(Some(reason)) => {
+                METRICS.messages_filtered_total.inc();
+                let message = match reason.as_str() {
+                    "" => "rejected by the content filter".to_string(),
+                    reason => format!("rejected by the content filter: {}", reason),
+                };
+                Err(protocol::Error::new(ErrorCode::PermissionDenied, message))
+            }
+            Err(e) => {
+                METRICS.filter_errors_total.inc();
+                log::warn!("Content filter failed for room {}: {}", msg.room, e);
+                match self.on_error {
+                    // unfiltered, as published
+                    OnError::Open => Ok(msg.data),
+                    OnError::Closed => Err(protocol::Error::new(
+                        ErrorCode::Internal,
+                        "content filter unavailable",
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Masks listed words in string values
+#[derive(Debug)]
+pub struct Words {
+    /// Lowercase
+    words: HashSet<String>,
+}
+
+impl Words {
+    pub fn new(words: &[String]) -> Words {
+        Words {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+        }
+    }
+
+    /// `text` with listed words masked, `None` if there are none
+    fn mask(&self, text: &str) -> Option<String> {
+        let mut masked = String::with_capacity(text.len());
+        let mut changed = false;
+        let mut rest = text;
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            masked.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if self.words.contains(&word.to_lowercase()) {
+                masked.push_str(&"*".repeat(word.chars().count()));
+                changed = true;
+            } else {
+                masked.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        masked.push_str(rest);
+        changed.then_some(masked)
+    }
+
+    /// Mask strings of `value` in place, returns whether any changed
+    fn mask_value(&self, value: &mut Value) -> bool {
+        match value {
+            Value::String(text) => match self.mask(text) {
+                Some(masked) => {
+                    *text = masked;
+                    true
+                }
+                None => false,
+            },
+            Value::Array(items) => items
+                .iter_mut()
+                .fold(false, |changed, item| self.mask_value(item) | changed),
+            Value::Object(fields) => fields
+                .values_mut()
+                .fold(false, |changed, field| self.mask_value(field) | changed),
+            _ => false,
+        }
+    }
+}
+
+impl Stage for Words {
+    fn check<'a>(&'a self, msg: &'a Message) -> LocalBoxFuture<'a, Result<Verdict, String>> {
+        let mut data = msg.data.clone();
+        let verdict = match self.mask_value(&mut data) {
+            true => Verdict::Replace { data },
+            false => Verdict::Allow,
+        };
+        Box::pin(async move { Ok(verdict) })
+    }
+}
+
+/// Asks an external service, see the module docs
+#[derive(Debug)]
+struct HttpStage {
+    url: String,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl Stage for HttpStage {
+    fn check<'a>(&'a self, msg: &'a Message) -> LocalBoxFuture<'a, Result<Verdict, String>> {
+        Box::pin(async move {
+            let mut req = Client::build()
+                .timeout(self.timeout)
+                .finish()
+                .post(&self.url);
+            if let Some(ref token) = self.token {
+                req = req.bearer_auth(token);
+            }
+            let mut res = req.send_json(msg).await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(res.status().to_string());
+            }
+            res.json::<Verdict>().await.map_err(|e| e.to_string())
+        })
+    }
+}
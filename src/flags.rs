@@ -0,0 +1,134 @@
+//! Runtime feature flags.
+//!
+//! Flags are declared in `FLAGS` with their built-in default. Config
+//! `[flags]` and `PUT /admin/flags` override them globally or per tenant;
+//! a tenant override wins over the global one.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use serde::Serialize;
+
+use crate::config::FlagsConfig;
+
+/// Known feature flag
+#[derive(Debug)]
+pub struct Flag {
+    pub name: &'static str,
+    /// Value unless overridden
+    pub default: bool,
+    pub doc: &'static str,
+}
+
+/// All flags consulted by the server
+pub static FLAGS: &[Flag] = &[
+    Flag {
+        name: "echo",
+        default: true,
+        doc: "Accept `echo` messages",
+    },
+    Flag {
+        name: "binary_echo",
+        default: true,
+        doc: "Echo binary frames back",
+    },
+    Flag {
+        name: "broadcast",
+        default: true,
+        doc: "Deliver broadcasts",
+    },
+];
+
+static OVERRIDES: RwLock<FlagsConfig> = RwLock::new(FlagsConfig {
+    global: BTreeMap::new(),
+    tenants: BTreeMap::new(),
+});
+
+fn flag(name: &str) -> Option<&'static Flag> {
+    FLAGS.iter().find(|f| f.name == name)
+}
+
+fn check(flags: &BTreeMap<String, bool>) -> Result<(), String> {
+    match flags.keys().find(|name| flag(name).is_none()) {
+        Some(name) => Err(format!("unknown feature flag `{}`", name)),
+        None => Ok(()),
+    }
+}
+
+/// Check that configured overrides only name known flags
+pub fn validate(config: &FlagsConfig) -> Result<(), String> {
+    check(&config.global)?;
+    for flags in config.tenants.values() {
+        check(flags)?;
+    }
+    Ok(())
+}
+
+/// Replace all overrides with the configured ones
+pub fn init(config: &FlagsConfig) -> Result<(), String> {
+    validate(config)?;
+    *OVERRIDES.write().unwrap() = config.clone();
+    Ok(())
+}
+
+/// Whether a flag `name` is declared
+pub fn known(name: &str) -> bool {
+    flag(name).is_some()
+}
+
+/// Whether flag `name` is on for `tenant`, unknown flags are off
+pub fn enabled(name: &str, tenant: Option<&str>) -> bool {
+    let overrides = OVERRIDES.read().unwrap();
+    tenant
+        .and_then(|t| overrides.tenants.get(t))
+        .and_then(|flags| flags.get(name))
+        .or_else(|| overrides.global.get(name))
+        .copied()
+        .or_else(|| flag(name).map(|f| f.default))
+        .unwrap_or(false)
+}
+
+/// Set (`Some`) or clear (`None`) overrides, globally or for one tenant
+pub fn set(tenant: Option<&str>, changes: &BTreeMap<String, Option<bool>>) -> Result<(), String> {
+    if let Some(name) = changes.keys().find(|name| flag(name).is_none()) {
+        return Err(format!("unknown feature flag `{}`", name));
+    }
+    let mut overrides = OVERRIDES.write().unwrap();
+    let flags = match tenant {
+        Some(tenant) => overrides.tenants.entry(tenant.to_string()).or_default(),
+        None => &mut overrides.global,
+    };
+    for (name, value) in changes {
+        match value {
+            Some(value) => flags.insert(name.clone(), *value),
+            None => flags.remove(name),
+        };
+    }
+    overrides.tenants.retain(|_, flags| !flags.is_empty());
+    Ok(())
+}
+
+/// Flag listing entry, for `GET /admin/flags`
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagInfo {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub default: bool,
+    /// Value for connections without tenant override
+    pub enabled: bool,
+}
+
+/// Current flags with global values, and all tenant overrides
+pub fn snapshot() -> (Vec<FlagInfo>, BTreeMap<String, BTreeMap<String, bool>>) {
+    let list = FLAGS
+        .iter()
+        .map(|f| FlagInfo {
+            name: f.name,
+            doc: f.doc,
+            default: f.default,
+            enabled: enabled(f.name, None),
+        })
+        .collect();
+    let tenants = OVERRIDES.read().unwrap().tenants.clone();
+    (list, tenants)
+}
@@ -0,0 +1,154 @@
+//! Several protocol messages in one binary websocket frame.
+//!
+//! A connection opened with `/ws?framing=packed` sends and receives its
+//! protocol messages packed: a binary frame is a run of records, each a
+//! 4-byte big-endian length followed by that many bytes of one JSON
+//! envelope. The replies to a packed frame come back packed in one frame,
+//! and the writer packs whatever is queued for the connection when it gets
+//! to write, up to `MAX_PACKED` bytes. For high-frequency small messages
+//! that saves a websocket header, a mask and a syscall per message.
+//!
+//! Text frames are still accepted from a packed connection, ping, pong and
+//! close frames are never packed.
+
+use ntex::util::{BufMut, Bytes};
+use serde::Deserialize;
+
+use crate::pool::PooledBuf;
+
+/// Size of a packed frame past which no further messages are added
+pub const MAX_PACKED: usize = 64 * 1024;
+
+/// Length prefix of a record
+const PREFIX: usize = 4;
+
+/// How a connection's protocol messages are framed, chosen at handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// A text frame per message
+    #[default]
+    Plain,
+    /// Length-prefixed records in binary frames
+    Packed,
+}
+
+/// Builds a packed frame in a pooled buffer
+#[derive(Debug, Default)]
+pub struct Packer {
+    buf: PooledBuf,
+}
+
+impl Packer {
+    pub fn new() -> Packer {
+        Packer::default()
+    }
+
+    pub fn push(&mut self, record: &[u8]) {
+        self.buf.reserve(PREFIX + record.len());
+        self.buf.put_u32(record.len() as u32);
+        self.buf.put_slice(record);
+    }
+
+    /// Bytes packed so far
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn finish(mut self) -> Bytes {
+        self.buf.take()
+    }
+}
+
+/// Records of a packed frame, `Err` once a length runs past the end
+#[derive(Debug)]
+pub struct Unpack {
+    data: Bytes,
+}
+
+impl Unpack {
+    pub fn new(data: Bytes) -> Unpack {
+        Unpack { data }
+    }
+}
+
+impl Iterator for Unpack {
+    type Item = Result<Bytes, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let Some(prefix) = self.data.get(..PREFIX) else {
+            self.data.clear();
+            return Some(Err("truncated record length"));
+        };
+        let len = u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize;
+        if self.data.len() - PREFIX < len {
+            self.data.clear();
+            return Some(Err("record longer than the frame"));
+        }
+        let record = self.data.slice(PREFIX..PREFIX + len);
+        self.data = self.data.slice(PREFIX + len..);
+        Some(Ok(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(data: &[u8]) -> Vec<Result<Bytes, &'static str>> {
+        Unpack::new(Bytes::copy_from_slice(data)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages: [&[u8]; 4] = [br#"{"type":"a"}"#, b"", &[0; 300], "é".as_bytes()];
+        let mut packer = Packer::new();
+        assert!(packer.is_empty());
+        for message in messages {
+            packer.push(message);
+        }
+        assert_eq!(packer.len(), 4 * PREFIX + 12 + 300 + 2);
+        let frame = packer.finish();
+        assert_eq!(&frame[..PREFIX + 12], b"\0\0\0\x0c{\"type\":\"a\"}");
+        let unpacked: Vec<Bytes> = Unpack::new(frame).map(Result::unwrap).collect();
+        assert_eq!(unpacked, messages);
+    }
+
+    #[test]
+    fn empty_frame_has_no_records() {
+        assert!(records(b"").is_empty());
+        assert!(Packer::new().finish().is_empty());
+    }
+
+    #[test]
+    fn truncated_frames() {
+        assert_eq!(records(b"\0\0"), [Err("truncated record length")]);
+        assert_eq!(
+            records(b"\0\0\0\x05abcd"),
+            [Err("record longer than the frame")]
+        );
+        assert_eq!(
+            records(b"\xff\xff\xff\xff"),
+            [Err("record longer than the frame")]
+        );
+        // records before the damage are still read, nothing after it
+        assert_eq!(
+            records(b"\0\0\0\x01a\0\0\0\x02b"),
+            [
+                Ok(Bytes::from_static(b"a")),
+                Err("record longer than the frame")
+            ]
+        );
+        assert_eq!(
+            records(b"\0\0\0\0\0"),
+            [Ok(Bytes::new()), Err("truncated record length")]
+        );
+    }
+}
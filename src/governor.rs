@@ -0,0 +1,121 @@
+//! Outbound bandwidth governors.
+//!
+//! A leaky bucket on the bytes of all outbound data messages: the bucket
+//! drains at `hub.max_bytes_per_sec` and holds `hub.burst_bytes`, so short
+//! bursts pass at once while a broadcast spike is spread out instead of
+//! saturating the link. Writer tasks wait for their message's turn, in the
+//! order they asked; control frames (ping, pong, close) are never held back,
+//! so heartbeats keep flowing during a spike.
+//!
+//! Each connection may have a bucket of its own too,
+//! `hub.conn_bytes_per_sec`, and share one with the other connections of its
+//! tenant, `hub.tenant_bytes_per_sec`, so a subscriber to a firehose cannot
+//! take the whole uplink. The tenant is the one the connection's credential
+//! names; connections without, e.g. anonymous ones or those only asking for
+//! a tenant with `?tenant=`, share one bucket, so they can neither dodge the
+//! cap nor drain another tenant's. A message waits for its connection's
+//! bucket, then its tenant's, then the server's; meanwhile newer messages
+//! wait in the connection's queue, and are dropped once it is full, rather
+//! than the connection being closed.
+//!
+//! Implemented as GCRA: the bucket is a single theoretical arrival time that
+//! every message pushes forward by its transmission time at the rate.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use ntex::time;
+
+use crate::config::HubConfig;
+
+#[derive(Debug)]
+pub struct Governor {
+    /// Bytes per second
+    rate: f64,
+    /// Time the burst takes at the rate
+    burst: Duration,
+    /// When the bucket is empty again given everything admitted so far
+    tat: Mutex<Instant>,
+}
+
+impl Governor {
+    /// Governor for `bytes_per_sec`, `None` if that is 0 (unlimited)
+    pub fn new(bytes_per_sec: u64, burst_bytes: u64) -> Option<Governor> {
+        if bytes_per_sec == 0 {
+            return None;
+        }
+        let rate = bytes_per_sec as f64;
+        Some(Governor {
+            rate,
+            burst: Duration::from_secs_f64(burst_bytes as f64 / rate),
+            tat: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Wait until `bytes` may be sent, returns the time waited
+    pub async fn acquire(&self, bytes: usize) -> Duration {
+        let wait = {
+            let now = Instant::now();
+            let mut tat = self.tat.lock().unwrap();
+            let start = (*tat).max(now);
+            *tat = start + Duration::from_secs_f64(bytes as f64 / self.rate);
+            // `Instant` cannot go before its epoch, e.g. for a large burst
+            // shortly after boot; nothing to wait for then
+            tat.checked_sub(self.burst)
+                .unwrap_or(now)
+                .saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+/// Governors of the server and of each tenant with open connections
+#[derive(Debug)]
+pub struct Governors {
+    server: Option<Arc<Governor>>,
+    /// `conn_bytes_per_sec` and `conn_burst_bytes`
+    conn: (u64, u64),
+    /// `tenant_bytes_per_sec` and `tenant_burst_bytes`
+    tenant: (u64, u64),
+    /// Dropped with the last connection of the tenant, `None` for the
+    /// connections without one
+    tenants: Mutex<HashMap<Option<String>, Weak<Governor>>>,
+}
+
+impl Governors {
+    pub fn new(config: &HubConfig) -> Governors {
+        Governors {
+            server: Governor::new(config.max_bytes_per_sec, config.burst_bytes).map(Arc::new),
+            conn: (config.conn_bytes_per_sec, config.conn_burst_bytes),
+            tenant: (config.tenant_bytes_per_sec, config.tenant_burst_bytes),
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Governors a new connection of `tenant`, the credential's, waits for,
+    /// in order
+    pub fn connect(&self, tenant: Option<&str>) -> Vec<Arc<Governor>> {
+        let conn = Governor::new(self.conn.0, self.conn.1).map(Arc::new);
+        let tenant = self.of_tenant(tenant);
+        conn.into_iter()
+            .chain(tenant)
+            .chain(self.server.clone())
+            .collect()
+    }
+
+    fn of_tenant(&self, tenant: Option<&str>) -> Option<Arc<Governor>> {
+        let tenant = tenant.map(str::to_string);
+        let mut tenants = self.tenants.lock().unwrap();
+        if let Some(governor) = tenants.get(&tenant).and_then(Weak::upgrade) {
+            return Some(governor);
+        }
+        let governor = Arc::new(Governor::new(self.tenant.0, self.tenant.1)?);
+        tenants.retain(|_, governor| governor.strong_count() > 0);
+        tenants.insert(tenant, Arc::downgrade(&governor));
+        Some(governor)
+    }
+}
@@ -0,0 +1,911 @@
+//! GraphQL admin API at `POST /admin/graphql`.
+//!
+//! Serves the operational data of the admin routes as one schema, so the
+//! dashboard or a script fetches what it needs in one request:
+//!
+//! ```graphql
+//! type Query {
+//!   connections(tenant: String, user: String): [Connection!]!
+//!   connection(id: ID!): Connection
+//!   rooms: [Room!]!
+//!   room(name: String!): Room
+//!   tenants: [Tenant!]!
+//!   metrics: [Metric!]!
+//! }
+//! type Mutation {
+//!   kick(id: ID!): Boolean!
+//!   ban(user: String!): Int!        # connections closed
+//!   unban(user: String!): Boolean!
+//!   publish(text: String!, metadata: [AttributeInput!], orderingKey: String): Int!  # recipients
+//! }
+//! type Connection { id: ID! tenant: String user: String connectedSecs: Int!
+//!   rttMs: Float queued: Int! metadata: [Attribute!]! }
+//! type Room { name: String! members: Int! waiting: Int! maxMembers: Int
+//!   seq: Int! ageSecs: Int! pinned: Boolean! private: Boolean! }
+//! type Tenant { name: String! connections: Int! }
+//! type Metric { name: String! help: String! kind: String! value: Float
+//!   count: Int sum: Float }
+//! type Attribute { key: String! value: String! }
+//! input AttributeInput { key: String! value: String! }
+//! ```
+//!
+//! Queries are executed by a small interpreter: operations with variables,
+//! aliases, arguments and nested selections, plus `__typename`. Fragments,
+//! directives and introspection are not supported. Resolvers build JSON
+//! objects of all fields of a type, the selection then picks from them.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ntex::web::{types::Json, types::State, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::admin;
+use crate::bans;
+use crate::config::Config;
+use crate::hub::{ConnInfo, Hub, Metadata};
+use crate::metrics::{Sample, METRICS};
+use crate::rooms::RoomInfo;
+
+/// Max nesting of selections and values
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    query: String,
+    #[serde(default, rename = "operationName")]
+    operation_name: Option<String>,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+}
+
+/// `POST /admin/graphql`
+///
+/// Body: `{"query": "...", "variables": {...}, "operationName": "..."}`,
+/// answered with `{"data": ..., "errors": [{"message": ...}]}`.
+pub async fn post(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    body: Json<Request>,
+) -> HttpResponse {
+    if let Err(res) = admin::authorize(&req, &config) {
+        return res;
+    }
+    let variables = body.variables.clone().unwrap_or_default();
+    let result = match parse(&body.query)
+        .and_then(|document| select(document, body.operation_name.as_deref()))
+    {
+        Ok(operation) => Ok(execute(&hub, &operation, &variables).await),
+        Err(e) => Err(e),
+    };
+    let response = match result {
+        Ok((data, errors)) if errors.is_empty() => json!({ "data": data }),
+        Ok((data, errors)) => json!({ "data": data, "errors": errors_json(&errors) }),
+        Err(e) => json!({ "data": null, "errors": errors_json(&[e]) }),
+    };
+    HttpResponse::Ok().json(&response)
+}
+
+fn errors_json(errors: &[String]) -> Value {
+    errors.iter().map(|e| json!({ "message": e })).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Query,
+    Mutation,
+}
+
+#[derive(Debug)]
+struct Operation {
+    kind: Kind,
+    name: Option<String>,
+    /// Default values of the declared variables
+    defaults: Map<String, Value>,
+    selection: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Input)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Argument value, variables resolved at execution
+#[derive(Debug)]
+enum Input {
+    Variable(String),
+    Value(Value),
+    List(Vec<Input>),
+    Object(Vec<(String, Input)>),
+}
+
+/// The operation to run out of a parsed document
+fn select(document: Vec<Operation>, name: Option<&str>) -> Result<Operation, String> {
+    match name {
+        Some(name) => document
+            .into_iter()
+            .find(|op| op.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("unknown operation `{}`", name)),
+        None if document.len() == 1 => Ok(document.into_iter().next().expect("one")),
+        None => Err("operationName is required with several operations".to_string()),
+    }
+}
+
+/// Run an operation, returns data and field errors
+async fn execute(
+    hub: &Arc<Hub>,
+    operation: &Operation,
+    variables: &Map<String, Value>,
+) -> (Value, Vec<String>) {
+    let mut variables = variables.clone();
+    for (name, default) in &operation.defaults {
+        variables
+            .entry(name.clone())
+            .or_insert_with(|| default.clone());
+    }
+    let mut data = Map::new();
+    let mut errors = Vec::new();
+    for field in &operation.selection {
+        let value = match arguments(field, &variables) {
+            Ok(_) if field.name == "__typename" => Ok(json!(match operation.kind {
+                Kind::Query => "Query",
+                Kind::Mutation => "Mutation",
+            })),
+            Ok(args) => match operation.kind {
+                Kind::Query => query(hub, &field.name, &args),
+                Kind::Mutation => mutation(hub, &field.name, &args).await,
+            },
+            Err(e) => Err(e),
+        };
+        let value = value.and_then(|value| project(&value, &field.selection, 0));
+        match value {
+            Ok(value) => {
+                data.insert(field.key().to_string(), value);
+            }
+            Err(e) => {
+                errors.push(format!("{}: {}", field.key(), e));
+                data.insert(field.key().to_string(), Value::Null);
+            }
+        }
+    }
+    (Value::Object(data), errors)
+}
+
+fn arguments(field: &Field, variables: &Map<String, Value>) -> Result<Map<String, Value>, String> {
+    field
+        .arguments
+        .iter()
+        .map(|(name, input)| Ok((name.clone(), resolve(input, variables)?)))
+        .collect()
+}
+
+fn resolve(input: &Input, variables: &Map<String, Value>) -> Result<Value, String> {
+    match input {
+        Input::Variable(name) => Ok(variables.get(name).cloned().unwrap_or(Value::Null)),
+        Input::Value(value) => Ok(value.clone()),
+        Input::List(items) => items.iter().map(|i| resolve(i, variables)).collect(),
+        Input::Object(fields) => fields
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), resolve(v, variables)?)))
+            .collect::<Result<Map<_, _>, String>>()
+            .map(Value::Object),
+    }
+}
+
+/// Pick the selected fields out of a resolved value
+fn project(value: &Value, selection: &[Field], depth: usize) -> Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err("selection is nested too deep".to_string());
+    }
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| project(item, selection, depth + 1))
+            .collect(),
+        Value::Object(object) => {
+            if selection.is_empty() {
+                return Err("objects need a selection of fields".to_string());
+            }
+            let mut out = Map::new();
+            for field in selection {
+                let v = object.get(&field.name).ok_or_else(|| {
+                    format!(
+                        "no field `{}` on type {}",
+                        field.name,
+                        object
+                            .get("__typename")
+                            .and_then(Value::as_str)
+                            .unwrap_or("?")
+                    )
+                })?;
+                out.insert(
+                    field.key().to_string(),
+                    project(v, &field.selection, depth + 1)?,
+                );
+            }
+            Ok(Value::Object(out))
+        }
+        scalar if selection.is_empty() => Ok(scalar.clone()),
+        _ => Err("scalars have no fields to select".to_string()),
+    }
+}
+
+fn query(hub: &Hub, name: &str, args: &Map<String, Value>) -> Result<Value, String> {
+    match name {
+        "connections" => {
+            let tenant = optional_string(args, "tenant")?;
+            let user = optional_string(args, "user")?;
+            Ok(hub
+                .connections()
+                .iter()
+                .filter(|c| tenant.is_none() || c.tenant == tenant)
+                .filter(|c| user.is_none() || c.user == user)
+                .map(connection)
+                .collect())
+        }
+        "connection" => {
+            let id = id(args)?;
+            Ok(hub
+                .connections()
+                .iter()
+                .find(|c| c.id == id)
+                .map_or(Value::Null, connection))
+        }
+        "rooms" => Ok(hub.rooms().list().iter().map(room).collect()),
+        "room" => {
+            let name = string(args, "name")?;
+            Ok(hub
+                .rooms()
+                .list()
+                .iter()
+                .find(|r| r.name == name)
+                .map_or(Value::Null, room))
+        }
+        "tenants" => {
+            let mut tenants = BTreeMap::<String, usize>::new();
+            for conn in hub.connections() {
+                let tenant = conn.tenant.unwrap_or_else(|| "-".to_string());
+                *tenants.entry(tenant).or_default() += 1;
+            }
+            Ok(tenants
+                .into_iter()
+                .map(|(name, connections)| {
+                    json!({ "__typename": "Tenant", "name": name, "connections": connections })
+                })
+                .collect())
+        }
+        "metrics" => {
+            let mut metrics = Vec::new();
+            METRICS.visit(|name, help, sample| {
+                let (value, count, sum) = match sample {
+                    Sample::Counter(v) => (json!(v), Value::Null, Value::Null),
+                    Sample::Gauge(v) => (json!(v), Value::Null, Value::Null),
+                    Sample::Histogram(h) => (Value::Null, json!(h.count()), json!(h.sum())),
+                };
+                metrics.push(json!({
+                    "__typename": "Metric",
+                    "name": name,
+                    "help": help,
+                    "kind": sample.kind(),
+                    "value": value,
+                    "count": count,
+                    "sum": sum,
+                }));
+            });
+            Ok(Value::Array(metrics))
+        }
+        name => Err(format!("no field `{}` on type Query", name)),
+    }
+}
+
+async fn mutation(hub: &Arc<Hub>, name: &str, args: &Map<String, Value>) -> Result<Value, String> {
+    match name {
+        "kick" => {
+            let id = id(args)?;
+            let kicked = admin::kick(hub, id, "kicked by admin");
+            if kicked {
+                log::info!("Connection {} kicked", id);
+            }
+            Ok(json!(kicked))
+        }
+        "ban" => Ok(json!(admin::ban_user(hub, &string(args, "user")?))),
+        "unban" => {
+            let user = string(args, "user")?;
+            let unbanned = bans::unban(&user);
+            if unbanned {
+                log::info!("User {} unbanned", user);
+            }
+            Ok(json!(unbanned))
+        }
+        "publish" => {
+            let text = string(args, "text")?;
+            let mut filter = Metadata::new();
+            if let Some(attributes) = args.get("metadata").and_then(Value::as_array) {
+                for attribute in attributes {
+                    match (attribute["key"].as_str(), attribute["value"].as_str()) {
+                        (Some(key), Some(value)) => {
+                            filter.insert(key.to_string(), value.to_string());
+                        }
+                        _ => return Err("metadata entries need a key and a value".to_string()),
+                    }
+                }
+            }
+            let key = optional_string(args, "orderingKey")?;
+            let msg = ntex::ws::Message::Text(text.into());
+            Ok(json!(hub.fan_out(msg, filter.into(), key.as_deref()).await))
+        }
+        name => Err(format!("no field `{}` on type Mutation", name)),
+    }
+}
+
+fn connection(conn: &ConnInfo) -> Value {
+    let metadata: Vec<Value> = conn
+        .metadata
+        .iter()
+        .map(|(key, value)| json!({ "__typename": "Attribute", "key": key, "value": value }))
+        .collect();
+    json!({
+        "__typename": "Connection",
+        "id": conn.id.to_string(),
+        "tenant": conn.tenant,
+        "user": conn.user,
+        "connectedSecs": conn.connected_secs,
+        "rttMs": conn.rtt_ms,
+        "queued": conn.queued,
+        "metadata": metadata,
+    })
+}
+
+fn room(room: &RoomInfo) -> Value {
+    json!({
+        "__typename": "Room",
+        "name": room.name,
+        "members": room.members,
+        "waiting": room.waiting,
+        "maxMembers": room.max_members,
+        "seq": room.seq,
+        "ageSecs": room.age_secs,
+        "pinned": room.pinned,
+        "private": room.private,
+    })
+}
+
+fn string(args: &Map<String, Value>, name: &str) -> Result<String, String> {
+    optional_string(args, name)?.ok_or_else(|| format!("argument `{}` is required", name))
+}
+
+fn optional_string(args: &Map<String, Value>, name: &str) -> Result<Option<String>, String> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("argument `{}` must be a string", name)),
+    }
+}
+
+/// `id` argument, an `ID` given as string or int
+fn id(args: &Map<String, Value>) -> Result<u64, String> {
+    match args.get("id") {
+        Some(Value::String(s)) => s.parse().map_err(|_| format!("bad id `{}`", s)),
+        Some(Value::Number(n)) => n.as_u64().ok_or_else(|| format!("bad id {}", n)),
+        _ => Err("argument `id` is required".to_string()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            // commas are insignificant in GraphQL
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {
+                chars.next();
+            }
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '!' | '$' | '=' | '@' | '|' | '&' => {
+                tokens.push(Token::Punct(c));
+                chars.next();
+            }
+            '.' => {
+                for _ in 0..3 {
+                    if chars.next() != Some('.') {
+                        return Err("unexpected `.`".to_string());
+                    }
+                }
+                tokens.push(Token::Spread);
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        None | Some('\n') => return Err("unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some('b') => s.push('\u{8}'),
+                            Some('f') => s.push('\u{c}'),
+                            Some('u') => {
+                                let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+                                let c = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or_else(|| format!("bad escape `\\u{}`", hex))?;
+                                s.push(c);
+                            }
+                            Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                            _ => return Err("bad escape in string".to_string()),
+                        },
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    number.push(c);
+                }
+                let token = match number.contains(['.', 'e', 'E']) {
+                    true => number.parse().map(Token::Float).ok(),
+                    false => number.parse().map(Token::Int).ok(),
+                };
+                tokens.push(token.ok_or_else(|| format!("bad number `{}`", number))?);
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| *c == '_' || c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected character `{}`", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+fn parse(source: &str) -> Result<Vec<Operation>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut operations = Vec::new();
+    while parser.peek().is_some() {
+        operations.push(parser.operation()?);
+    }
+    if operations.is_empty() {
+        return Err("document has no operation".to_string());
+    }
+    Ok(operations)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of document")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(c) if c == punct => Ok(()),
+            token => Err(format!("expected `{}`, found {:?}", punct, token)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn operation(&mut self) -> Result<Operation, String> {
+        let kind = match self.peek() {
+            Some(Token::Punct('{')) => {
+                return Ok(Operation {
+                    kind: Kind::Query,
+                    name: None,
+                    defaults: Map::new(),
+                    selection: self.selection(0)?,
+                })
+            }
+            Some(Token::Name(name)) if name == "query" => Kind::Query,
+            Some(Token::Name(name)) if name == "mutation" => Kind::Mutation,
+            Some(Token::Name(name)) if name == "subscription" => {
+                return Err("subscriptions are not supported".to_string())
+            }
+            Some(Token::Name(name)) if name == "fragment" => {
+                return Err("fragments are not supported".to_string())
+            }
+            token => return Err(format!("expected an operation, found {:?}", token)),
+        };
+        self.pos += 1;
+        let name = match self.peek() {
+            Some(Token::Name(_)) => Some(self.name()?),
+            _ => None,
+        };
+        let mut defaults = Map::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let variable = self.name()?;
+                self.expect(':')?;
+                self.skip_type(0)?;
+                if self.eat('=') {
+                    defaults.insert(variable, self.constant(0)?);
+                }
+            }
+        }
+        self.directives()?;
+        Ok(Operation {
+            kind,
+            name,
+            defaults,
+            selection: self.selection(0)?,
+        })
+    }
+
+    /// Variable types are not checked, resolvers check their arguments
+    fn skip_type(&mut self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("type is nested too deep".to_string());
+        }
+        if self.eat('[') {
+            self.skip_type(depth + 1)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn directives(&mut self) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Punct('@')) => Err("directives are not supported".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn selection(&mut self, depth: usize) -> Result<Vec<Field>, String> {
+        if depth > MAX_DEPTH {
+            return Err("selection is nested too deep".to_string());
+        }
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if self.peek() == Some(&Token::Spread) {
+                return Err("fragments are not supported".to_string());
+            }
+            let mut name = self.name()?;
+            let mut alias = None;
+            if self.eat(':') {
+                alias = Some(name);
+                name = self.name()?;
+            }
+            let mut arguments = Vec::new();
+            if self.eat('(') {
+                while !self.eat(')') {
+                    let argument = self.name()?;
+                    self.expect(':')?;
+                    arguments.push((argument, self.value(0)?));
+                }
+            }
+            self.directives()?;
+            let selection = match self.peek() {
+                Some(Token::Punct('{')) => self.selection(depth + 1)?,
+                _ => Vec::new(),
+            };
+            fields.push(Field {
+                alias,
+                name,
+                arguments,
+                selection,
+            });
+        }
+        Ok(fields)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Input, String> {
+        if depth > MAX_DEPTH {
+            return Err("value is nested too deep".to_string());
+        }
+        if self.eat('$') {
+            return Ok(Input::Variable(self.name()?));
+        }
+        if self.eat('[') {
+            let mut items = Vec::new();
+            while !self.eat(']') {
+                items.push(self.value(depth + 1)?);
+            }
+            return Ok(Input::List(items));
+        }
+        if self.eat('{') {
+            let mut fields = Vec::new();
+            while !self.eat('}') {
+                let name = self.name()?;
+                self.expect(':')?;
+                fields.push((name, self.value(depth + 1)?));
+            }
+            return Ok(Input::Object(fields));
+        }
+        self.scalar().map(Input::Value)
+    }
+
+    /// Value without variables, for defaults of variables
+    fn constant(&mut self, depth: usize) -> Result<Value, String> {
+        match self.value(depth)? {
+            Input::Value(value) => Ok(value),
+            input => resolve(&input, &Map::new()),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, String> {
+        Ok(match self.next()? {
+            Token::Int(n) => json!(n),
+            Token::Float(f) => json!(f),
+            Token::Str(s) => Value::String(s),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values, passed on as strings
+                _ => Value::String(name),
+            },
+            token => return Err(format!("expected a value, found {:?}", token)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(source: &str) -> Operation {
+        let document = parse(source).unwrap();
+        select(document, None).unwrap()
+    }
+
+    #[test]
+    fn shorthand_query() {
+        let op = operation("{ rooms { name members } }");
+        assert_eq!(op.kind, Kind::Query);
+        assert_eq!(op.name, None);
+        assert_eq!(op.selection.len(), 1);
+        assert_eq!(op.selection[0].name, "rooms");
+        let fields: Vec<&str> = op.selection[0].selection.iter().map(|f| f.key()).collect();
+        assert_eq!(fields, ["name", "members"]);
+    }
+
+    #[test]
+    fn aliases_arguments_and_comments() {
+        let op = operation(
+            r#"
+            # by tenant
+            query Busy {
+                acme: connections(tenant: "acme", user: null) { id }
+                room(name: "lobby\n\u00e9") { seq, ageSecs }
+            }
+            "#,
+        );
+        assert_eq!(op.name.as_deref(), Some("Busy"));
+        let acme = &op.selection[0];
+        assert_eq!(
+            (acme.alias.as_deref(), acme.name.as_str()),
+            (Some("acme"), "connections")
+        );
+        let args = arguments(acme, &Map::new()).unwrap();
+        assert_eq!(
+            Value::Object(args),
+            json!({ "tenant": "acme", "user": null })
+        );
+        let args = arguments(&op.selection[1], &Map::new()).unwrap();
+        assert_eq!(args["name"], json!("lobby\né"));
+    }
+
+    #[test]
+    fn variables_and_defaults() {
+        let op = operation(
+            r#"mutation Say($text: String!, $meta: [AttributeInput!] = [{key: "a", value: "b"}]) {
+                publish(text: $text, metadata: $meta, orderingKey: $missing)
+            }"#,
+        );
+        assert_eq!(op.kind, Kind::Mutation);
+        assert_eq!(op.defaults["meta"], json!([{ "key": "a", "value": "b" }]));
+        let mut variables = Map::new();
+        variables.insert("text".to_string(), json!("hi"));
+        variables.insert("meta".to_string(), json!([]));
+        let args = arguments(&op.selection[0], &variables).unwrap();
+        assert_eq!(
+            Value::Object(args),
+            json!({ "text": "hi", "metadata": [], "orderingKey": null })
+        );
+    }
+
+    #[test]
+    fn operation_name_selects() {
+        let source = "query A { rooms { name } } query B { tenants { name } }";
+        let b = select(parse(source).unwrap(), Some("B")).unwrap();
+        assert_eq!(b.selection[0].name, "tenants");
+        assert!(select(parse(source).unwrap(), None).is_err());
+        assert!(select(parse(source).unwrap(), Some("C")).is_err());
+    }
+
+    #[test]
+    fn projection() {
+        let op = operation("{ rooms { label: name __typename } }");
+        let value = json!([{ "__typename": "Room", "name": "lobby", "seq": 3 }]);
+        let projected = project(&value, &op.selection[0].selection, 0).unwrap();
+        assert_eq!(
+            projected,
+            json!([{ "label": "lobby", "__typename": "Room" }])
+        );
+
+        let missing = operation("{ rooms { nope } }");
+        let e = project(&value, &missing.selection[0].selection, 0).unwrap_err();
+        assert_eq!(e, "no field `nope` on type Room");
+        // objects without a type name fail the same way
+        let e = project(&json!({}), &missing.selection[0].selection, 0).unwrap_err();
+        assert_eq!(e, "no field `nope` on type ?");
+        assert!(project(&json!({ "a": 1 }), &[], 0).is_err());
+        assert!(project(&json!(1), &missing.selection[0].selection, 0).is_err());
+    }
+
+    #[test]
+    fn malformed_documents_are_errors() {
+        for source in [
+            "",
+            "# only a comment",
+            "{",
+            "}",
+            "{ rooms { name }",
+            "{ rooms { } ",
+            "query",
+            "query Q",
+            "query ($a) { rooms }",
+            "query ($a: ) { rooms }",
+            "query (a: Int) { rooms }",
+            "query ($a: [Int) { rooms }",
+            "{ room(name: ) { name } }",
+            "{ room(name \"x\") { name } }",
+            "{ room(name: \"unterminated) { name } }",
+            "{ room(name: \"bad \\q escape\") { name } }",
+            "{ room(name: \"\\u12\") { name } }",
+            "{ room(name: \"\\ud800\") { name } }",
+            "{ room(n: 1-2) { name } }",
+            "{ room(n: --) { name } }",
+            "{ a: }",
+            "{ a: b: c }",
+            "{ ..Frag }",
+            "{ ...Frag }",
+            "{ rooms @skip(if: true) { name } }",
+            "fragment F on Room { name }",
+            "subscription { rooms { name } }",
+            "mutation",
+            "{ rooms } extra",
+            "{ rooms ? }",
+            "{ room(name: [1, 2) }",
+            "{ room(name: {a 1}) }",
+        ] {
+            assert!(parse(source).is_err(), "{:?} parsed", source);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let deep = format!("{}{}", "{ a ".repeat(100), "}".repeat(100));
+        assert!(parse(&deep).is_err());
+        let list = format!("{{ a(x: {}1{}) }}", "[".repeat(100), "]".repeat(100));
+        assert!(parse(&list).is_err());
+        let ty = format!(
+            "query ($v: {}Int{}) {{ a }}",
+            "[".repeat(100),
+            "]".repeat(100)
+        );
+        assert!(parse(&ty).is_err());
+    }
+
+    #[test]
+    fn never_panics() {
+        let pieces = [
+            "{",
+            "}",
+            "(",
+            ")",
+            "[",
+            "]",
+            ":",
+            "!",
+            "$",
+            "=",
+            "@",
+            "...",
+            ".",
+            "query",
+            "mutation",
+            "a",
+            "b",
+            "\"s\"",
+            "\"",
+            "\\",
+            "1",
+            "-",
+            "1e999",
+            "9999999999999999999999",
+            "#",
+            "\n",
+            " ",
+            ",",
+            "é",
+            "null",
+        ];
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..20_000 {
+            let mut source = String::new();
+            for _ in 0..(seed % 16) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                source.push_str(pieces[(seed % pieces.len() as u64) as usize]);
+            }
+            if let Ok(document) = parse(&source) {
+                if let Ok(op) = select(document, None) {
+                    for field in &op.selection {
+                        let _ = arguments(field, &Map::new());
+                        let _ = project(&json!({ "a": [{ "b": 1 }] }), &field.selection, 0);
+                    }
+                }
+            }
+            seed = seed.wrapping_add(1);
+        }
+    }
+}
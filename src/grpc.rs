@@ -0,0 +1,549 @@
+//! gRPC admin API, the `wss.admin.v1.Admin` service of `proto/admin.proto`.
+//!
+//! Served over plaintext HTTP/2 on `grpc.address`, a listener of its own so
+//! it can stay on an internal interface. Calls carry the admin token as
+//! `authorization: Bearer <admin.token>` metadata. Only unary calls without
+//! compression are implemented, with the few protobuf messages of the
+//! service encoded by hand.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{HeaderMap, HeaderValue, Request, Response};
+use ntex::ws;
+use tokio::net::TcpListener;
+
+use crate::admin;
+use crate::auth;
+use crate::bans;
+use crate::config::Config;
+use crate::hub::{ConnInfo, Hub, Metadata};
+use crate::ipfilter;
+use crate::rpc::{self, CallError};
+use crate::tasks::TaskHandle;
+use crate::utf8;
+
+/// Max size of a request message
+const MAX_MESSAGE: usize = 4 * 1024 * 1024;
+
+const SERVICE: &str = "/wss.admin.v1.Admin/";
+
+/// gRPC status of a failed call
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    const INVALID_ARGUMENT: u32 = 3;
+    const DEADLINE_EXCEEDED: u32 = 4;
+    const NOT_FOUND: u32 = 5;
+    const PERMISSION_DENIED: u32 = 7;
+    const RESOURCE_EXHAUSTED: u32 = 8;
+    const ABORTED: u32 = 10;
+    const UNIMPLEMENTED: u32 = 12;
+    const INTERNAL: u32 = 13;
+    const UNAVAILABLE: u32 = 14;
+    const UNAUTHENTICATED: u32 = 16;
+
+    fn new<T: Into<String>>(code: u32, message: T) -> Status {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Accept connections on `listener` until the process exits
+pub async fn run(listener: std::net::TcpListener, config: Arc<Config>, hub: Arc<Hub>) {
+    let task = TaskHandle::register("grpc", None);
+    task.set_state("listening");
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Cannot serve gRPC: {}", e);
+            return;
+        }
+    };
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) if !ipfilter::allowed(peer.ip()) => {
+                log::debug!("gRPC connection from {} refused by the IP filter", peer);
+                drop(socket);
+            }
+            Ok((socket, peer)) => {
+                log::debug!("gRPC connection from {}", peer);
+                ntex::rt::spawn(serve(socket, config.clone(), hub.clone()));
+            }
+            Err(e) => log::warn!("Cannot accept gRPC connection: {}", e),
+        }
+    }
+}
+
+async fn serve(socket: tokio::net::TcpStream, config: Arc<Config>, hub: Arc<Hub>) {
+    let mut conn = match h2::server::handshake(socket).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::debug!("gRPC handshake failed: {}", e);
+            return;
+        }
+    };
+    while let Some(call) = conn.accept().await {
+        match call {
+            Ok((req, respond)) => {
+                ntex::rt::spawn(call_handler(req, respond, config.clone(), hub.clone()));
+            }
+            Err(e) => {
+                log::debug!("gRPC connection failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn call_handler(
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    config: Arc<Config>,
+    hub: Arc<Hub>,
+) {
+    let result = call(req, &config, &hub).await;
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .body(())
+        .expect("valid response");
+    let mut stream = match respond.send_response(response, false) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("Cannot answer gRPC call: {}", e);
+            return;
+        }
+    };
+    let mut trailers = HeaderMap::new();
+    match result {
+        Ok(message) => {
+            let mut frame = BytesMut::with_capacity(5 + message.len());
+            frame.extend_from_slice(&[0]);
+            frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&message);
+            if let Err(e) = stream.send_data(frame.freeze(), false) {
+                log::debug!("Cannot answer gRPC call: {}", e);
+                return;
+            }
+            trailers.insert("grpc-status", HeaderValue::from(0));
+        }
+        Err(status) => {
+            trailers.insert("grpc-status", HeaderValue::from(status.code));
+            if let Ok(message) = HeaderValue::from_str(&percent_encode(&status.message)) {
+                trailers.insert("grpc-message", message);
+            }
+        }
+    }
+    if let Err(e) = stream.send_trailers(trailers) {
+        log::debug!("Cannot answer gRPC call: {}", e);
+    }
+}
+
+/// Run a call, returns the encoded response message
+async fn call(
+    req: Request<RecvStream>,
+    config: &Config,
+    hub: &Arc<Hub>,
+) -> Result<Vec<u8>, Status> {
+    let token = match config.admin.token {
+        Some(ref token) => token,
+        None => {
+            return Err(Status::new(
+                Status::PERMISSION_DENIED,
+                "admin api is disabled",
+            ))
+        }
+    };
+    let provided = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if !auth::secret_matches(provided, token) {
+        return Err(Status::new(Status::UNAUTHENTICATED, "bad admin token"));
+    }
+    let method = match req.uri().path().strip_prefix(SERVICE) {
+        Some(method) => method.to_string(),
+        None => return Err(Status::new(Status::UNIMPLEMENTED, "unknown service")),
+    };
+    let message = read_message(req.into_body()).await?;
+    let message = Reader::new(&message);
+    match method.as_str() {
+        "ListConnections" => Ok(list_connections(hub)),
+        "Kick" => kick(hub, message),
+        "ListBans" => Ok(list_bans()),
+        "Ban" => ban(hub, message),
+        "Unban" => unban(message),
+        "Publish" => publish(hub, message).await,
+        "Call" => call_client(hub, message).await,
+        _ => Err(Status::new(
+            Status::UNIMPLEMENTED,
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+/// The single message of a unary request
+async fn read_message(mut body: RecvStream) -> Result<Vec<u8>, Status> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Status::new(Status::INTERNAL, e.to_string()))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if buf.len() + chunk.len() > 5 + MAX_MESSAGE {
+            return Err(Status::new(Status::INVALID_ARGUMENT, "request too large"));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    if buf.len() < 5 {
+        return Err(Status::new(
+            Status::INVALID_ARGUMENT,
+            "missing request message",
+        ));
+    }
+    if buf[0] != 0 {
+        return Err(Status::new(
+            Status::UNIMPLEMENTED,
+            "compressed messages are not supported",
+        ));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if buf.len() != 5 + len {
+        return Err(Status::new(
+            Status::INVALID_ARGUMENT,
+            "expected one request message",
+        ));
+    }
+    buf.drain(..5);
+    Ok(buf)
+}
+
+fn list_connections(hub: &Hub) -> Vec<u8> {
+    let mut out = Writer::default();
+    for conn in hub.connections() {
+        out.message(1, &connection(&conn));
+    }
+    out.0
+}
+
+fn connection(conn: &ConnInfo) -> Writer {
+    let mut out = Writer::default();
+    out.uint64(1, conn.id);
+    if let Some(ref tenant) = conn.tenant {
+        out.string(2, tenant);
+    }
+    if let Some(ref user) = conn.user {
+        out.string(3, user);
+    }
+    out.uint64(4, conn.connected_secs);
+    if let Some(rtt_ms) = conn.rtt_ms {
+        out.double(5, rtt_ms);
+    }
+    out.uint64(6, conn.queued as u64);
+    out.map(7, &conn.metadata);
+    out
+}
+
+fn kick(hub: &Hub, mut message: Reader) -> Result<Vec<u8>, Status> {
+    let mut id = 0;
+    while let Some((field, value)) = message.field()? {
+        if let (1, Value::Varint(v)) = (field, value) {
+            id = v;
+        }
+    }
+    if !admin::kick(hub, id, "kicked by admin") {
+        return Err(Status::new(
+            Status::NOT_FOUND,
+            format!("no connection {}", id),
+        ));
+    }
+    log::info!("Connection {} kicked", id);
+    Ok(Vec::new())
+}
+
+fn list_bans() -> Vec<u8> {
+    let mut out = Writer::default();
+    for user in bans::list() {
+        out.string(1, &user);
+    }
+    out.0
+}
+
+/// `user` field of `BanRequest` and `UnbanRequest`
+fn user(mut message: Reader) -> Result<String, Status> {
+    let mut user = String::new();
+    while let Some((field, value)) = message.field()? {
+        if let (1, Value::Bytes(v)) = (field, value) {
+            user = string(v)?;
+        }
+    }
+    match user.is_empty() {
+        true => Err(Status::new(Status::INVALID_ARGUMENT, "user is empty")),
+        false => Ok(user),
+    }
+}
+
+fn ban(hub: &Hub, message: Reader) -> Result<Vec<u8>, Status> {
+    let user = user(message)?;
+    let closed = admin::ban_user(hub, &user);
+    let mut out = Writer::default();
+    out.uint64(1, closed as u64);
+    Ok(out.0)
+}
+
+fn unban(message: Reader) -> Result<Vec<u8>, Status> {
+    let user = user(message)?;
+    if !bans::unban(&user) {
+        return Err(Status::new(
+            Status::NOT_FOUND,
+            format!("{} is not banned", user),
+        ));
+    }
+    log::info!("User {} unbanned", user);
+    Ok(Vec::new())
+}
+
+async fn publish(hub: &Arc<Hub>, mut message: Reader<'_>) -> Result<Vec<u8>, Status> {
+    let mut msg = None;
+    let mut filter = Metadata::new();
+    let mut ordering_key = None;
+    while let Some((field, value)) = message.field()? {
+        match (field, value) {
+            (1, Value::Bytes(text)) => {
+                let text = utf8::to_bytestring(ntex::util::Bytes::copy_from_slice(text))
+                    .ok_or_else(|| Status::new(Status::INVALID_ARGUMENT, "text is not utf-8"))?;
+                msg = Some(ws::Message::Text(text));
+            }
+            (2, Value::Bytes(binary)) => {
+                msg = Some(ws::Message::Binary(ntex::util::Bytes::copy_from_slice(
+                    binary,
+                )));
+            }
+            (3, Value::Bytes(entry)) => {
+                let (key, value) = map_entry(Reader::new(entry))?;
+                filter.insert(key, value);
+            }
+            (4, Value::Bytes(key)) => {
+                ordering_key = Some(string(key)?).filter(|key| !key.is_empty());
+            }
+            _ => {}
+        }
+    }
+    let msg = msg.ok_or_else(|| Status::new(Status::INVALID_ARGUMENT, "message is empty"))?;
+    let recipients = hub
+        .fan_out(msg, filter.into(), ordering_key.as_deref())
+        .await;
+    let mut out = Writer::default();
+    out.uint64(1, recipients as u64);
+    Ok(out.0)
+}
+
+/// Call a method of a client, params and result are JSON text
+async fn call_client(hub: &Arc<Hub>, mut message: Reader<'_>) -> Result<Vec<u8>, Status> {
+    let mut id = 0;
+    let mut method = String::new();
+    let mut params = serde_json::Value::Null;
+    let mut timeout = rpc::DEFAULT_TIMEOUT;
+    while let Some((field, value)) = message.field()? {
+        match (field, value) {
+            (1, Value::Varint(v)) => id = v,
+            (2, Value::Bytes(v)) => method = string(v)?,
+            (3, Value::Bytes(v)) => {
+                params = serde_json::from_slice(v)
+                    .map_err(|e| Status::new(Status::INVALID_ARGUMENT, format!("params: {}", e)))?
+            }
+            (4, Value::Varint(v)) => timeout = Duration::from_millis(v),
+            _ => {}
+        }
+    }
+    if method.is_empty() {
+        return Err(Status::new(Status::INVALID_ARGUMENT, "method is empty"));
+    }
+    let result = rpc::call(hub, id, &method, params, timeout)
+        .await
+        .map_err(|e| {
+            let code = match e {
+                CallError::NotConnected => Status::NOT_FOUND,
+                CallError::Busy => Status::RESOURCE_EXHAUSTED,
+                CallError::Disconnected => Status::UNAVAILABLE,
+                CallError::TimedOut => Status::DEADLINE_EXCEEDED,
+                CallError::Failed(_) => Status::ABORTED,
+            };
+            Status::new(code, e.to_string())
+        })?;
+    let mut out = Writer::default();
+    out.string(1, &result.to_string());
+    Ok(out.0)
+}
+
+fn map_entry(mut entry: Reader) -> Result<(String, String), Status> {
+    let (mut key, mut value) = (String::new(), String::new());
+    while let Some((field, v)) = entry.field()? {
+        match (field, v) {
+            (1, Value::Bytes(v)) => key = string(v)?,
+            (2, Value::Bytes(v)) => value = string(v)?,
+            _ => {}
+        }
+    }
+    Ok((key, value))
+}
+
+fn string(bytes: &[u8]) -> Result<String, Status> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| Status::new(Status::INVALID_ARGUMENT, "string is not utf-8"))
+}
+
+/// Percent-encoding of `grpc-message`
+fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for b in message.bytes() {
+        match b {
+            b' '..=b'~' if b != b'%' => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Protobuf encoder, default values are left out as in proto3
+#[derive(Debug, Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint64(&mut self, field: u32, v: u64) {
+        if v != 0 {
+            self.key(field, 0);
+            self.varint(v);
+        }
+    }
+
+    /// Presence is explicit for the `optional` fields this is used for
+    fn double(&mut self, field: u32, v: f64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: &Writer) {
+        self.bytes(field, &message.0);
+    }
+
+    fn map(&mut self, field: u32, map: &BTreeMap<String, String>) {
+        for (key, value) in map {
+            let mut entry = Writer::default();
+            entry.string(1, key);
+            entry.string(2, value);
+            self.message(field, &entry);
+        }
+    }
+}
+
+/// Protobuf field value by wire type
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+/// Protobuf decoder, reads the fields of a message in order
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    fn varint(&mut self) -> Result<u64, Status> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = self.buf.split_first().ok_or_else(truncated)?;
+            self.buf = rest;
+            v |= u64::from(b & 0x7f) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        Err(Status::new(Status::INVALID_ARGUMENT, "varint too long"))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Status> {
+        if self.buf.len() < n {
+            return Err(truncated());
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    /// Next field number and value, `None` at the end
+    fn field(&mut self) -> Result<Option<(u32, Value<'a>)>, Status> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed32
+            }
+            wire_type => {
+                return Err(Status::new(
+                    Status::INVALID_ARGUMENT,
+                    format!("unsupported wire type {}", wire_type),
+                ))
+            }
+        };
+        Ok(Some((field, value)))
+    }
+}
+
+fn truncated() -> Status {
+    Status::new(Status::INVALID_ARGUMENT, "truncated message")
+}
@@ -0,0 +1,36 @@
+//! Security headers added to every HTTP response, configured by
+//! `[security_headers]`. Headers set by a handler are kept.
+
+use ntex::http::header::{HeaderName, HeaderValue};
+use ntex::web::middleware::DefaultHeaders;
+
+use crate::config::SecurityHeadersConfig;
+
+fn headers(config: &SecurityHeadersConfig) -> [(&'static str, &str); 4] {
+    [
+        ("strict-transport-security", &config.hsts),
+        ("x-content-type-options", &config.content_type_options),
+        ("content-security-policy", &config.content_security_policy),
+        ("referrer-policy", &config.referrer_policy),
+    ]
+}
+
+/// Check configured values, `DefaultHeaders` panics on invalid ones
+pub fn validate(config: &SecurityHeadersConfig) -> Result<(), String> {
+    for (name, value) in headers(config) {
+        if HeaderValue::try_from(value).is_err() {
+            return Err(format!("invalid {} header value `{}`", name, value));
+        }
+    }
+    Ok(())
+}
+
+/// Middleware setting the configured headers, empty values are skipped
+pub fn middleware(config: &SecurityHeadersConfig) -> DefaultHeaders {
+    headers(config)
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .fold(DefaultHeaders::new(), |mw, (name, value)| {
+            mw.header(HeaderName::from_static(name), value)
+        })
+}
@@ -0,0 +1,864 @@
+//! Messages published to rooms, kept for edits, deletes, paging and search.
+//!
+//! The last `history.max_messages` messages of each room are kept in memory,
+//! also after the room is destroyed, so a recreated room continues its
+//! sequence numbers. A deleted message stays as a tombstone without data.
+//! Search scans these kept messages, there is no index, so it reaches no
+//! further back than `max_messages` per room.
+//!
+//! With `history.dir` set, every change is also appended to a log per room,
+//! `<dir>/<hex of room name>.jsonl` with one `{"op": "message" | "edit" |
+//! "delete" | "archived", ..}` record per line. Logs are replayed and
+//! compacted to the kept messages at startup.
+//!
+//! With `history.archive` set, messages older than `after_secs` are moved to
+//! an S3-compatible bucket, see `archive`; the room's log then starts with an
+//! `archived` record of the last archived sequence number.
+//!
+//! With `history.compression` set, log lines are compressed, see
+//! `compression`. A room's dictionary is trained in the background once it
+//! has `train_after` messages and written to its log as a `dictionary`
+//! record ahead of the lines using it. Compressed lines are read back
+//! whether or not compression is still configured.
+//!
+//! With `history.encryption` set, log lines are encrypted after compression,
+//! see `crypto`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::compression::{self, Dictionary};
+use crate::config::{CompressionConfig, HistoryConfig};
+use crate::crypto::{self, Cipher};
+
+/// Upper bound of `limit` of a search
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+/// Upper bound of `limit` of a fetch
+pub const MAX_FETCH_MESSAGES: usize = 200;
+
+/// Stored room message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    /// Connection id of the publisher
+    pub from: u64,
+    /// Authenticated publisher, `None` if anonymous
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Cluster node the publisher is connected to, `None` for this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// `null` once deleted
+    pub data: Value,
+    /// Unix milliseconds
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl Entry {
+    /// Whether the connection `id` of `user` may change this message;
+    /// messages of anonymous publishers belong to their connection
+    pub fn is_author(&self, id: u64, user: Option<&str>) -> bool {
+        match self.user {
+            Some(ref author) => user == Some(author.as_str()),
+            None => self.node.is_none() && self.from == id,
+        }
+    }
+}
+
+/// Why an edit or delete was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryError {
+    /// Not (or no longer) in history, or deleted
+    UnknownMessage,
+    NotAuthor,
+}
+
+/// Line of a room log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Message(Entry),
+    Edit {
+        seq: u64,
+        data: Value,
+        edited_at: u64,
+    },
+    Delete {
+        seq: u64,
+    },
+    /// Messages up to `seq` were moved to the archive
+    Archived {
+        seq: u64,
+    },
+    /// Compression dictionary of the lines that follow, base64
+    Dictionary {
+        id: u32,
+        data: String,
+    },
+}
+
+impl Record {
+    fn dictionary(dictionary: &Dictionary) -> Record {
+        Record::Dictionary {
+            id: dictionary.id,
+            data: STANDARD.encode(&dictionary.data),
+        }
+    }
+}
+
+/// Search request, of `history.search` and `GET /admin/history/search`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Search {
+    /// Words that must all occur in the message's text, prefixes match
+    pub query: String,
+    /// Only this room
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Published at or after, unix milliseconds
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Published before, unix milliseconds
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+    /// Matches to skip, `next_offset` of the previous page
+    #[serde(default)]
+    pub offset: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+/// Page of a room's history, of `history.fetch`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fetch {
+    pub room: String,
+    /// Messages older than this sequence number, the latest ones if neither
+    /// `before` nor `after` is set
+    #[serde(default)]
+    pub before: Option<u64>,
+    /// Messages newer than this sequence number, oldest first
+    #[serde(default)]
+    pub after: Option<u64>,
+    #[serde(default = "default_fetch_limit")]
+    pub limit: usize,
+}
+
+fn default_fetch_limit() -> usize {
+    50
+}
+
+/// Messages in sequence order, tombstones included; `more` is set if there
+/// are further messages beyond the page in the fetched direction
+#[derive(Debug, Serialize)]
+pub struct FetchPage {
+    pub room: String,
+    pub messages: Vec<Entry>,
+    pub more: bool,
+    /// Messages up to this sequence number are in the archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub room: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+/// Matches newest first, `next_offset` is set if there are more
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchHit>,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct RoomHistory {
+    entries: VecDeque<Entry>,
+    /// Last recorded sequence number, also with `max_messages` 0
+    last: u64,
+    /// Last archived sequence number, 0 if none
+    archived: u64,
+    /// Append handle of the room log, opened on first write
+    log: Option<BufWriter<File>>,
+    /// Log lines are compressed with this
+    dictionary: Option<Dictionary>,
+    /// Dictionary being trained, set once done
+    training: Option<Arc<OnceLock<Vec<u8>>>>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    max_messages: usize,
+    dir: Option<PathBuf>,
+    cipher: Option<Cipher>,
+    compression: Option<CompressionConfig>,
+    rooms: Mutex<HashMap<String, RoomHistory>>,
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl History {
+    /// Empty history, or the one replayed from `history.dir`
+    pub fn open(config: &HistoryConfig) -> io::Result<History> {
+        let cipher = match config.encryption {
+            Some(ref encryption) => Some(
+                Cipher::new(encryption)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+            None => None,
+        };
+        let mut rooms = HashMap::new();
+        if let Some(ref dir) = config.dir {
+            fs::create_dir_all(dir)?;
+            for file in fs::read_dir(dir)? {
+                let path = file?.path();
+                let room = match room_of(&path) {
+                    Some(room) => room,
+                    None => continue,
+                };
+                let (archived, entries, dictionary) =
+                    replay(&path, config.max_messages, cipher.as_ref())?;
+                // dropped with compression off, the lines are rewritten plain
+                let dictionary = dictionary.filter(|_| config.compression.is_some());
+                let level = config.compression.as_ref().map(|c| c.level);
+                compact(
+                    &path,
+                    archived,
+                    &entries,
+                    level,
+                    dictionary.as_ref(),
+                    cipher.as_ref(),
+                )?;
+                let history = RoomHistory {
+                    last: entries.back().map_or(archived, |e| e.seq),
+                    entries,
+                    archived,
+                    log: None,
+                    dictionary,
+                    training: None,
+                };
+                rooms.insert(room, history);
+            }
+            log::info!(
+                "Loaded history of {} rooms from {}",
+                rooms.len(),
+                dir.display()
+            );
+        }
+        Ok(History {
+            max_messages: config.max_messages,
+            dir: config.dir.clone(),
+            cipher,
+            compression: config.compression.clone(),
+            rooms: Mutex::new(rooms),
+        })
+    }
+
+    /// Sequence number of the last stored message of `room`, 0 if none
+    pub fn last_seq(&self, room: &str) -> u64 {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room)
+            .map_or(0, |history| history.last.max(history.archived))
+    }
+
+    /// Rooms with a message stored, ordered by name
+    pub fn rooms(&self) -> Vec<String> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut names: Vec<String> = rooms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Continue the sequence of `room` after `seq` at least, for a room
+    /// handed over by its cluster owner
+    pub fn advance(&self, room: &str, seq: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = rooms.entry(room.to_string()).or_default();
+        history.last = history.last.max(seq);
+    }
+
+    /// Store a published message, dropping the oldest one of a full room.
+    /// Messages of a room are recorded in sequence order.
+    pub fn record(&self, room: &str, entry: Entry) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = rooms.entry(room.to_string()).or_default();
+        history.last = entry.seq;
+        if self.max_messages == 0 {
+            return;
+        }
+        self.append(room, history, &Record::Message(entry.clone()));
+        if history.entries.len() >= self.max_messages {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(entry);
+        self.train(room, history);
+    }
+
+    /// Start training the dictionary of a room with enough messages and
+    /// none yet, on a thread of its own; take it up once trained
+    fn train(&self, room: &str, history: &mut RoomHistory) {
+        let compression = match self.compression {
+            Some(ref compression) if self.dir.is_some() && history.dictionary.is_none() => {
+                compression
+            }
+            _ => return,
+        };
+        match history.training {
+            None if history.entries.len() >= compression.train_after => {
+                let samples: Vec<Vec<u8>> = history
+                    .entries
+                    .iter()
+                    .rev()
+                    .take(compression.train_after)
+                    .map(|entry| encode(&Record::Message(entry.clone()), None, None, None))
+                    .map(String::into_bytes)
+                    .collect();
+                let size = compression.dictionary_bytes;
+                let trained = Arc::new(OnceLock::new());
+                history.training = Some(trained.clone());
+                std::thread::spawn(move || {
+                    let _ = trained.set(compression::train(&samples, size));
+                });
+            }
+            Some(ref trained) => {
+                if let Some(data) = trained.get() {
+                    // lines before it are compressed without a dictionary
+                    let dictionary = Dictionary {
+                        id: 1,
+                        data: data.clone(),
+                    };
+                    log::debug!(
+                        "Trained a {} byte history dictionary for room {}",
+                        dictionary.data.len(),
+                        room
+                    );
+                    history.training = None;
+                    self.append(room, history, &Record::dictionary(&dictionary));
+                    history.dictionary = Some(dictionary);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Replace data of message `seq`, by its author; returns the edit time
+    pub fn edit(
+        &self,
+        room: &str,
+        seq: u64,
+        id: u64,
+        user: Option<&str>,
+        data: Value,
+    ) -> Result<u64, HistoryError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = rooms.get_mut(room).ok_or(HistoryError::UnknownMessage)?;
+        let entry = find(&mut history.entries, seq)?;
+        if !entry.is_author(id, user) {
+            return Err(HistoryError::NotAuthor);
+        }
+        let now = now_millis();
+        entry.data = data.clone();
+        entry.edited_at = Some(now);
+        let record = Record::Edit {
+            seq,
+            data,
+            edited_at: now,
+        };
+        self.append(room, history, &record);
+        Ok(now)
+    }
+
+    /// Turn message `seq` into a tombstone, by its author
+    pub fn delete(
+        &self,
+        room: &str,
+        seq: u64,
+        id: u64,
+        user: Option<&str>,
+    ) -> Result<(), HistoryError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = rooms.get_mut(room).ok_or(HistoryError::UnknownMessage)?;
+        let entry = find(&mut history.entries, seq)?;
+        if !entry.is_author(id, user) {
+            return Err(HistoryError::NotAuthor);
+        }
+        entry.data = Value::Null;
+        entry.deleted = true;
+        self.append(room, history, &Record::Delete { seq });
+        Ok(())
+    }
+
+    /// Page of kept messages of a room
+    pub fn fetch(&self, fetch: &Fetch) -> FetchPage {
+        let rooms = self.rooms.lock().unwrap();
+        let history = rooms.get(&fetch.room);
+        let entries = history.map(|history| &history.entries);
+        let (messages, more) = page(entries.into_iter().flatten(), fetch);
+        FetchPage {
+            room: fetch.room.clone(),
+            messages,
+            more,
+            archived: history
+                .map(|history| history.archived)
+                .filter(|seq| *seq > 0),
+        }
+    }
+
+    /// Messages published before `cutoff` (unix ms), by room
+    pub fn aged(&self, cutoff: u64) -> Vec<(String, Vec<Entry>)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(room, history)| {
+                let aged = history
+                    .entries
+                    .iter()
+                    .take_while(|e| e.at < cutoff)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (room.clone(), aged)
+            })
+            .filter(|(_, aged)| !aged.is_empty())
+            .collect()
+    }
+
+    /// Drop messages up to `seq` of `room` once archived
+    pub fn archived(&self, room: &str, seq: u64) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let history = match rooms.get_mut(room) {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        while history.entries.front().is_some_and(|e| e.seq <= seq) {
+            history.entries.pop_front();
+        }
+        history.archived = history.archived.max(seq);
+        match self.dir {
+            Some(ref dir) => {
+                history.log = None;
+                compact(
+                    &dir.join(file_name(room)),
+                    history.archived,
+                    &history.entries,
+                    self.compression.as_ref().map(|c| c.level),
+                    history.dictionary.as_ref(),
+                    self.cipher.as_ref(),
+                )
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Live messages matching `search` in `rooms`, or in all rooms if `None`.
+    /// Only the kept messages are searched, not archived ones. A room's
+    /// messages in the time range are copied under the lock, one room at a
+    /// time, and matched once it is released, so publishers to the other
+    /// rooms don't wait for the scan.
+    pub fn search(&self, search: &Search, rooms: Option<&[String]>) -> SearchPage {
+        let terms = words(&search.query);
+        let limit = search.limit.clamp(1, MAX_SEARCH_RESULTS);
+        let names: Vec<String> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|room| search.room.as_ref().is_none_or(|r| r == *room))
+            .filter(|room| rooms.is_none_or(|rooms| rooms.contains(room)))
+            .cloned()
+            .collect();
+        let mut hits = Vec::new();
+        for room in names {
+            let entries: Vec<Entry> = match self.rooms.lock().unwrap().get(&room) {
+                Some(history) => history
+                    .entries
+                    .iter()
+                    .filter(|e| !e.deleted)
+                    .filter(|e| search.since.is_none_or(|since| e.at >= since))
+                    .filter(|e| search.until.is_none_or(|until| e.at < until))
+                    .cloned()
+                    .collect(),
+                None => continue,
+            };
+            hits.extend(
+                entries
+                    .into_iter()
+                    .filter(|e| matches(&terms, &e.data))
+                    .map(|entry| SearchHit {
+                        room: room.clone(),
+                        entry,
+                    }),
+            );
+        }
+        hits.sort_by(|a, b| (b.entry.at, &b.room).cmp(&(a.entry.at, &a.room)));
+        let more = hits.len() > search.offset + limit;
+        SearchPage {
+            results: hits.into_iter().skip(search.offset).take(limit).collect(),
+            next_offset: more.then_some(search.offset + limit),
+        }
+    }
+
+    /// Append `record` to the log of `room`, if persisted. A failed write
+    /// is logged, the change is still kept in memory.
+    fn append(&self, room: &str, history: &mut RoomHistory, record: &Record) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        if history.log.is_none() {
+            let path = dir.join(file_name(room));
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => history.log = Some(BufWriter::new(file)),
+                Err(e) => {
+                    log::warn!("Cannot open history log {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        let line = encode(
+            record,
+            self.compression.as_ref().map(|c| c.level),
+            history.dictionary.as_ref(),
+            self.cipher.as_ref(),
+        );
+        let log = history.log.as_mut().expect("opened above");
+        if let Err(e) = writeln!(log, "{}", line).and_then(|_| log.flush()) {
+            log::warn!("Cannot write history of room {}: {}", room, e);
+        }
+    }
+}
+
+/// Live message `seq` of a room
+fn find(entries: &mut VecDeque<Entry>, seq: u64) -> Result<&mut Entry, HistoryError> {
+    match entries.binary_search_by_key(&seq, |e| e.seq) {
+        Ok(i) if !entries[i].deleted => Ok(&mut entries[i]),
+        _ => Err(HistoryError::UnknownMessage),
+    }
+}
+
+/// Page of `entries`, in sequence order, with whether there are more
+pub(crate) fn page<'a>(
+    entries: impl DoubleEndedIterator<Item = &'a Entry>,
+    fetch: &Fetch,
+) -> (Vec<Entry>, bool) {
+    let limit = fetch.limit.clamp(1, MAX_FETCH_MESSAGES);
+    let range = entries.filter(|e| {
+        fetch.before.is_none_or(|before| e.seq < before)
+            && fetch.after.is_none_or(|after| e.seq > after)
+    });
+    let mut messages: Vec<Entry> = match fetch.after {
+        Some(_) => range.take(limit + 1).cloned().collect(),
+        None => range.rev().take(limit + 1).cloned().collect(),
+    };
+    let more = messages.len() > limit;
+    messages.truncate(limit);
+    if fetch.after.is_none() {
+        messages.reverse();
+    }
+    (messages, more)
+}
+
+/// Room name as file or object name, hex of its bytes
+pub(crate) fn encode_room(room: &str) -> String {
+    room.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn file_name(room: &str) -> String {
+    format!("{}.jsonl", encode_room(room))
+}
+
+/// Room of a log file, `None` for other files
+fn room_of(path: &Path) -> Option<String> {
+    if path.extension()? != "jsonl" {
+        return None;
+    }
+    let hex = path.file_stem()?.to_str()?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Log line of `record`, compressed at `level` if set
+fn encode(
+    record: &Record,
+    level: Option<u8>,
+    dictionary: Option<&Dictionary>,
+    cipher: Option<&Cipher>,
+) -> String {
+    let line = serde_json::to_string(record).expect("serializable record");
+    let line = match level {
+        Some(level) => compression::pack_line(&line, dictionary, level),
+        None => line,
+    };
+    match cipher {
+        Some(cipher) => cipher.seal_line(&line),
+        None => line,
+    }
+}
+
+/// Last archived sequence number, last `max` messages and last dictionary
+/// of a room log; malformed records are skipped, lines that cannot be
+/// decrypted or decompressed are an error
+fn replay(
+    path: &Path,
+    max: usize,
+    cipher: Option<&Cipher>,
+) -> io::Result<(u64, VecDeque<Entry>, Option<Dictionary>)> {
+    let mut archived = 0;
+    let mut entries = VecDeque::new();
+    let mut dictionaries = HashMap::new();
+    let mut dictionary = None;
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = match cipher {
+            Some(cipher) => cipher.open_line(&line),
+            None => crypto::plain_line(&line).map(str::to_string),
+        };
+        let line = line
+            .and_then(|line| compression::unpack_line(&line, &dictionaries))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), n + 1, e),
+                )
+            })?;
+        let record = match serde_json::from_str::<Record>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn!(
+                    "{}:{}: skipping history record: {}",
+                    path.display(),
+                    n + 1,
+                    e
+                );
+                continue;
+            }
+        };
+        match record {
+            Record::Message(entry) => {
+                if entries.len() >= max {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+            Record::Edit {
+                seq,
+                data,
+                edited_at,
+            } => {
+                if let Ok(entry) = find(&mut entries, seq) {
+                    entry.data = data;
+                    entry.edited_at = Some(edited_at);
+                }
+            }
+            Record::Delete { seq } => {
+                if let Ok(entry) = find(&mut entries, seq) {
+                    entry.data = Value::Null;
+                    entry.deleted = true;
+                }
+            }
+            Record::Archived { seq } => {
+                archived = archived.max(seq);
+                entries.retain(|e| e.seq > seq);
+            }
+            Record::Dictionary { id, data } => match STANDARD.decode(data) {
+                Ok(data) => {
+                    dictionaries.insert(id, data);
+                    dictionary = Some(id);
+                }
+                Err(e) => log::warn!(
+                    "{}:{}: skipping history dictionary: {}",
+                    path.display(),
+                    n + 1,
+                    e
+                ),
+            },
+        }
+    }
+    let dictionary = dictionary.and_then(|id| {
+        let data = dictionaries.remove(&id)?;
+        Some(Dictionary { id, data })
+    });
+    Ok((archived, entries, dictionary))
+}
+
+/// Rewrite a room log with just `entries`, after those up to `archived`,
+/// compressed at `level` if set
+fn compact(
+    path: &Path,
+    archived: u64,
+    entries: &VecDeque<Entry>,
+    level: Option<u8>,
+    dictionary: Option<&Dictionary>,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    if archived > 0 {
+        let record = Record::Archived { seq: archived };
+        writeln!(out, "{}", encode(&record, None, None, cipher))?;
+    }
+    if let Some(dictionary) = dictionary {
+        let record = Record::dictionary(dictionary);
+        writeln!(out, "{}", encode(&record, None, None, cipher))?;
+    }
+    for entry in entries {
+        let record = Record::Message(entry.clone());
+        writeln!(out, "{}", encode(&record, level, dictionary, cipher))?;
+    }
+    out.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Lowercase words of `text`
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether every term is a prefix of a word in the strings of `data`
+fn matches(terms: &[String], data: &Value) -> bool {
+    let mut text = String::new();
+    collect_text(data, &mut text);
+    let words = words(&text);
+    terms
+        .iter()
+        .all(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+}
+
+fn collect_text(value: &Value, text: &mut String) {
+    match value {
+        Value::String(s) => {
+            text.push_str(s);
+            text.push(' ');
+        }
+        Value::Array(values) => values.iter().for_each(|v| collect_text(v, text)),
+        Value::Object(map) => map.values().for_each(|v| collect_text(v, text)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(seq: u64, text: &str) -> Entry {
+        Entry {
+            seq,
+            from: 1,
+            user: None,
+            node: None,
+            data: json!({ "text": text }),
+            at: 1000 + seq,
+            edited_at: None,
+            deleted: false,
+        }
+    }
+
+    fn search(query: &str) -> Search {
+        serde_json::from_value(json!({ "query": query })).unwrap()
+    }
+
+    fn found(page: &SearchPage) -> Vec<(&str, u64)> {
+        page.results
+            .iter()
+            .map(|hit| (hit.room.as_str(), hit.entry.seq))
+            .collect()
+    }
+
+    fn history() -> History {
+        let history = History::open(&HistoryConfig {
+            max_messages: 3,
+            ..HistoryConfig::default()
+        })
+        .unwrap();
+        for (seq, text) in [
+            (1, "Hello world"),
+            (2, "help me"),
+            (3, "bye"),
+            (4, "hello again"),
+        ] {
+            history.record("lobby", entry(seq, text));
+        }
+        history.record("dev", entry(5, "hello from dev"));
+        history
+    }
+
+    #[test]
+    fn kept_messages_only() {
+        let history = history();
+        // the first message of lobby fell out of the kept window
+        let page = history.search(&search("hel"), None);
+        assert_eq!(found(&page), [("dev", 5), ("lobby", 4), ("lobby", 2)]);
+        assert_eq!(page.next_offset, None);
+        assert!(history.search(&search("world"), None).results.is_empty());
+    }
+
+    #[test]
+    fn narrowed_down() {
+        let history = history();
+        let rooms = ["lobby".to_string()];
+        assert_eq!(
+            found(&history.search(&search("hello"), Some(&rooms))),
+            [("lobby", 4)]
+        );
+        let mut dev = search("hello");
+        dev.room = Some("dev".to_string());
+        assert!(history.search(&dev, Some(&rooms)).results.is_empty());
+        let mut range = search("he");
+        range.since = Some(1002);
+        range.until = Some(1005);
+        assert_eq!(
+            found(&history.search(&range, None)),
+            [("lobby", 4), ("lobby", 2)]
+        );
+
+        history.delete("lobby", 4, 1, None).unwrap();
+        assert_eq!(found(&history.search(&search("hello"), None)), [("dev", 5)]);
+    }
+
+    #[test]
+    fn paged() {
+        let history = history();
+        let mut first = search("hel");
+        first.limit = 2;
+        let page = history.search(&first, None);
+        assert_eq!(found(&page), [("dev", 5), ("lobby", 4)]);
+        first.offset = page.next_offset.unwrap();
+        let page = history.search(&first, None);
+        assert_eq!(found(&page), [("lobby", 2)]);
+        assert_eq!(page.next_offset, None);
+    }
+}
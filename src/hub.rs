@@ -0,0 +1,1548 @@
+//! Registry of live connections and their outbound queues.
+//!
+//! Every connection gets a queue (capped at `hub.queue_size`) drained by its own writer task, so
+//! any worker thread can push messages to any connection. Broadcast messages
+//! are built once and only their `Bytes` handle is cloned per recipient.
+//! Room membership lives here too, so a closed connection leaves its rooms.
+//!
+//! Connections are spread over `hub.shards` maps by a hash of their id, each
+//! behind its own lock, so registrations and lookups on different cores
+//! rarely contend. Broadcasts lock one shard at a time.
+//!
+//! Fan-out is fair: every broadcast and room delivery starts at the next
+//! shard and member in turn instead of walking the same order to the end,
+//! so no connection is always queued first (or last). Within a shard a
+//! broadcast goes to connections with nothing queued first; a backlogged
+//! one gets to it only after its backlog anyway.
+//!
+//! A handler panicking while it holds a lock closes only its own
+//! connection, so the locks are taken over a poisoned state rather than
+//! failing every connection after it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI64, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use futures::channel::{mpsc, oneshot};
+use ntex::util::{ByteString, Bytes};
+use ntex::ws;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::acks::{AckRequest, Acks, Delivery};
+use crate::aggregate::{self, Aggregator, Window};
+use crate::bandwidth::{self, Usage};
+use crate::chaos::{Chaos, Faults};
+use crate::cluster::Cluster;
+use crate::config::{CrdtConfig, HubConfig, KvConfig, LocksConfig, RoomsConfig, ScheduleConfig};
+use crate::crdt::{Change, CrdtError, Crdts, Object, Op};
+use crate::events::{self, Event};
+use crate::expr::Expr;
+use crate::fanout;
+use crate::filter::ContentFilter;
+use crate::framing::Framing;
+use crate::governor::{Governor, Governors};
+use crate::history::{self, Entry, History};
+use crate::kv::{Kv, KvError, Scope};
+use crate::labels::{Labels, Selector};
+use crate::locks::{Locks, Notice};
+use crate::metrics::{self, METRICS};
+use crate::pacing::Pacer;
+use crate::push::{self, Push};
+use crate::record::Recorder;
+use crate::rooms::{Join, Left, Moderation, Recipients, RoomError, Rooms};
+use crate::rpc::Calls;
+use crate::schedule::{Schedule, Scheduled};
+use crate::store::Store;
+use crate::tap::Taps;
+use crate::{flags, pipeline, protocol};
+
+#[derive(Debug)]
+struct Conn {
+    tx: mpsc::UnboundedSender<(Instant, ws::Message)>,
+    queued: Arc<AtomicUsize>,
+    stats: Arc<ConnStats>,
+}
+
+/// Attributes a client sets with `hello`, e.g. `device` or `locale`
+pub type Metadata = BTreeMap<String, String>;
+
+/// Connection state shared with the hub, updated by the connection itself
+#[derive(Debug)]
+pub struct ConnStats {
+    connected_at: Instant,
+    /// Last measured round-trip time, 0 if unknown
+    rtt_us: AtomicU64,
+    /// Last estimated client clock offset, `i64::MIN` if unknown
+    skew_ms: AtomicI64,
+    tenant: Option<String>,
+    /// Whether `tenant` is the credential's, not the `tenant` query
+    /// parameter's
+    tenant_claimed: bool,
+    /// Authenticated user, `None` for anonymous connections
+    user: Option<String>,
+    /// Set at the handshake, see `labels`
+    labels: Labels,
+    /// Handler pipeline, see `pipeline`
+    pipeline: &'static str,
+    /// How protocol messages are framed, see `framing`
+    framing: Framing,
+    metadata: RwLock<Metadata>,
+    /// Filter expressions of joined rooms, by room
+    filters: RwLock<HashMap<String, Arc<Expr>>>,
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Code of the first close frame either side sent, 0 if none yet
+    close_code: AtomicU16,
+    /// Admin API copies of the frames, see `tap`
+    taps: Taps,
+    /// Recording of the frames, see `record`
+    recorder: Recorder,
+}
+
+impl Default for ConnStats {
+    fn default() -> Self {
+        ConnStats::new(None, None)
+    }
+}
+
+impl ConnStats {
+    pub fn new(tenant: Option<String>, user: Option<String>) -> ConnStats {
+        ConnStats {
+            connected_at: Instant::now(),
+            rtt_us: AtomicU64::new(0),
+            skew_ms: AtomicI64::new(i64::MIN),
+            tenant,
+            tenant_claimed: false,
+            user,
+            labels: Labels::new(),
+            pipeline: pipeline::DEFAULT,
+            framing: Framing::Plain,
+            metadata: RwLock::new(Metadata::new()),
+            filters: RwLock::new(HashMap::new()),
+            frames_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            frames_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            close_code: AtomicU16::new(0),
+            taps: Taps::default(),
+            recorder: Recorder::default(),
+        }
+    }
+
+    pub fn with_labels(mut self, labels: Labels) -> ConnStats {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: &'static str) -> ConnStats {
+        self.pipeline = pipeline;
+        self
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> ConnStats {
+        self.framing = framing;
+        self
+    }
+
+    /// Mark the tenant as the credential's
+    pub fn with_claimed_tenant(mut self, claimed: bool) -> ConnStats {
+        self.tenant_claimed = claimed;
+        self
+    }
+
+    /// Time since the handshake
+    pub fn age(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Tenant the connection belongs to, selects feature flag overrides
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Tenant of the connection's credential, `None` if anonymous or the
+    /// client only asked for one; the only one scoping what tenants keep
+    /// apart, e.g. their keys or locks
+    pub fn claimed_tenant(&self) -> Option<&str> {
+        self.tenant.as_deref().filter(|_| self.tenant_claimed)
+    }
+
+    pub fn taps(&self) -> &Taps {
+        &self.taps
+    }
+
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    /// Name of the handler pipeline
+    pub fn pipeline(&self) -> &'static str {
+        self.pipeline
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        read(&self.metadata).clone()
+    }
+
+    pub fn set_metadata(&self, metadata: Metadata) {
+        *write(&self.metadata) = metadata;
+    }
+
+    /// Whether every attribute of `filter` is set to the same value
+    pub fn matches(&self, filter: &Metadata) -> bool {
+        let metadata = read(&self.metadata);
+        filter.iter().all(|(k, v)| metadata.get(k) == Some(v))
+    }
+
+    /// Only get messages of room `name` that `filter` holds for, or all
+    pub fn set_filter(&self, name: &str, filter: Option<Expr>) {
+        let mut filters = write(&self.filters);
+        match filter {
+            Some(filter) => filters.insert(name.to_string(), Arc::new(filter)),
+            None => filters.remove(name),
+        };
+    }
+
+    /// Filter of room `name`, if set
+    pub fn filter(&self, name: &str) -> Option<Arc<Expr>> {
+        read(&self.filters).get(name).cloned()
+    }
+
+    /// Whether a `room.message` of room `name` with `payload` passes the
+    /// room's filter, if any
+    pub fn accepts(&self, name: &str, payload: &Value) -> bool {
+        self.filter(name)
+            .is_none_or(|filter| filter.matches(payload))
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        self.rtt_us.store(rtt.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    pub fn set_skew(&self, ms: i64) {
+        self.skew_ms.store(ms, Ordering::Relaxed);
+    }
+
+    /// How far the client clock is ahead of the server's, in milliseconds,
+    /// if the client sends its clock with heartbeat pongs
+    pub fn skew(&self) -> Option<i64> {
+        match self.skew_ms.load(Ordering::Relaxed) {
+            i64::MIN => None,
+            ms => Some(ms),
+        }
+    }
+
+    /// Count a frame received from the client
+    pub fn received(&self, frame: &ws::Frame) {
+        let (len, close) = match frame {
+            ws::Frame::Text(data) | ws::Frame::Binary(data) => (data.len(), None),
+            ws::Frame::Ping(data) | ws::Frame::Pong(data) => (data.len(), None),
+            ws::Frame::Continuation(item) => match item {
+                ws::Item::FirstText(data)
+                | ws::Item::FirstBinary(data)
+                | ws::Item::Continue(data)
+                | ws::Item::Last(data) => (data.len(), None),
+            },
+            ws::Frame::Close(reason) => (0, Some(reason.as_ref().map(|r| r.code))),
+        };
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+        self.taps.inbound(frame, self.framing);
+        self.recorder.inbound(frame);
+        if let Some(code) = close {
+            self.closed(code);
+        }
+    }
+
+    /// Count a message sent to the client
+    pub fn sent(&self, msg: &ws::Message) {
+        let (len, close) = match msg {
+            ws::Message::Text(text) => (text.len(), None),
+            ws::Message::Binary(data) | ws::Message::Ping(data) | ws::Message::Pong(data) => {
+                (data.len(), None)
+            }
+            ws::Message::Continuation(item) => match item {
+                ws::Item::FirstText(data)
+                | ws::Item::FirstBinary(data)
+                | ws::Item::Continue(data)
+                | ws::Item::Last(data) => (data.len(), None),
+            },
+            ws::Message::Close(reason) => (0, Some(reason.as_ref().map(|r| r.code))),
+        };
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+        self.taps.outbound(msg, self.framing);
+        self.recorder.outbound(msg);
+        if let Some(code) = close {
+            self.closed(code);
+        }
+    }
+
+    fn closed(&self, code: Option<ws::CloseCode>) {
+        // a close frame without a code means 1005, no status received
+        let code = code.map_or(1005, u16::from);
+        let _ = self
+            .close_code
+            .compare_exchange(0, code, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Frames and payload bytes received
+    pub fn received_totals(&self) -> (u64, u64) {
+        (
+            self.frames_in.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Frames and payload bytes sent
+    pub fn sent_totals(&self) -> (u64, u64) {
+        (
+            self.frames_out.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Code of the first close frame, `None` if the connection just dropped
+    pub fn close_code(&self) -> Option<u16> {
+        match self.close_code.load(Ordering::Relaxed) {
+            0 => None,
+            code => Some(code),
+        }
+    }
+}
+
+/// Connection listing entry, for `GET /admin/connections`
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnInfo {
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub connected_secs: u64,
+    pub rtt_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew_ms: Option<i64>,
+    pub queued: usize,
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub pipeline: &'static str,
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
+
+/// Connections a broadcast goes to, those whose `hello` metadata has every
+/// attribute of `metadata` and whose labels match `labels`
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub metadata: Metadata,
+    pub labels: Selector,
+}
+
+impl Target {
+    pub fn matches(&self, stats: &ConnStats) -> bool {
+        stats.matches(&self.metadata) && self.labels.matches(&stats.labels)
+    }
+}
+
+impl From<Metadata> for Target {
+    fn from(metadata: Metadata) -> Target {
+        Target {
+            metadata,
+            labels: Selector::default(),
+        }
+    }
+}
+
+/// Receiving side of a connection's outbound queue
+#[derive(Debug)]
+pub struct Outbound {
+    /// Messages with the time they were queued at
+    pub rx: mpsc::UnboundedReceiver<(Instant, ws::Message)>,
+    queued: Arc<AtomicUsize>,
+    /// Of the connection, its tenant and the server, those configured
+    governors: Vec<Arc<Governor>>,
+    /// Faults injected into the connection's deliveries, see `chaos`
+    chaos: Option<Arc<Chaos>>,
+}
+
+impl Outbound {
+    /// Wait for the bandwidth governors to let `msg` out
+    pub async fn throttle(&self, msg: &ws::Message) {
+        let bytes = match msg {
+            ws::Message::Text(text) => text.len(),
+            ws::Message::Binary(data) => data.len(),
+            ws::Message::Continuation(_) => 0,
+            // control frames are never held back
+            _ => return,
+        };
+        self.throttle_bytes(bytes).await;
+    }
+
+    /// Wait for the bandwidth governors to let `bytes` of data out
+    pub async fn throttle_bytes(&self, bytes: usize) {
+        let mut waited = Duration::ZERO;
+        for governor in &self.governors {
+            waited += governor.acquire(bytes).await;
+        }
+        if !waited.is_zero() {
+            METRICS.outbound_throttled_total.inc();
+            METRICS.outbound_throttle_seconds.observe(waited);
+        }
+    }
+
+    /// Faults to inject into `msg`, none unless the connection was picked
+    pub fn faults(&self, msg: &ws::Message) -> Faults {
+        match self.chaos {
+            Some(ref chaos) => chaos.faults(msg),
+            None => Faults::default(),
+        }
+    }
+
+    /// Mark `messages` queued messages as written, returns remaining queue
+    /// depth
+    pub fn written(&self, messages: usize) -> usize {
+        self.queued
+            .fetch_sub(messages, Ordering::Relaxed)
+            .saturating_sub(messages)
+    }
+}
+
+type Shard = RwLock<HashMap<u64, Conn>>;
+
+#[derive(Debug)]
+pub struct Hub {
+    shards: Box<[Shard]>,
+    queue_size: usize,
+    rooms: Rooms,
+    cluster: Option<Arc<Cluster>>,
+    governors: Governors,
+    filter: Option<ContentFilter>,
+    /// Where the next fan-out starts
+    turn: AtomicUsize,
+    aggregator: Aggregator,
+    pacer: Pacer,
+    /// Server to client calls waiting for their result
+    calls: Calls,
+    /// Sessions kept for presence and resumption, see `store`
+    store: Option<Arc<Store>>,
+    /// Room messages waiting for acknowledgments, see `acks`
+    acks: Acks,
+    /// Room messages published for later, see `schedule`
+    schedule: Schedule,
+    /// Keys shared by rooms and tenants, see `kv`
+    kv: Kv,
+    /// Counters, sets and registers shared by every node, see `crdt`
+    crdts: Crdts,
+    /// Leases clients take on names, see `locks`
+    locks: Locks,
+    /// Notifications of direct messages to offline users, see `push`
+    push: Option<Arc<Push>>,
+    /// Faults injected into deliveries, see `chaos`
+    chaos: Option<Arc<Chaos>>,
+}
+
+impl Hub {
+    pub fn new(
+        config: &HubConfig,
+        rooms: RoomsConfig,
+        history: History,
+        cluster: Option<Arc<Cluster>>,
+    ) -> Hub {
+        let shards = match config.shards {
+            0 => 4 * std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        Hub {
+            shards: (0..shards).map(|_| Shard::default()).collect(),
+            queue_size: config.queue_size,
+            filter: rooms.filter.as_ref().map(ContentFilter::new),
+            rooms: Rooms::new(rooms, history),
+            cluster,
+            governors: Governors::new(config),
+            turn: AtomicUsize::new(0),
+            aggregator: Aggregator::default(),
+            pacer: Pacer::default(),
+            calls: Calls::default(),
+            store: None,
+            acks: Acks::default(),
+            schedule: Schedule::new(&ScheduleConfig::default()),
+            kv: Kv::new(&KvConfig::default()),
+            crdts: Crdts::new(&CrdtConfig::default(), None),
+            locks: Locks::new(&LocksConfig::default()),
+            push: None,
+            chaos: None,
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<Store>) -> Hub {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_schedule(mut self, schedule: Schedule) -> Hub {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn with_kv(mut self, kv: Kv) -> Hub {
+        self.kv = kv;
+        self
+    }
+
+    pub fn with_crdts(mut self, crdts: Crdts) -> Hub {
+        self.crdts = crdts;
+        self
+    }
+
+    pub fn with_locks(mut self, locks: Locks) -> Hub {
+        self.locks = locks;
+        self
+    }
+
+    pub fn with_push(mut self, push: Push) -> Hub {
+        self.push = Some(Arc::new(push));
+        self
+    }
+
+    pub fn with_chaos(mut self, chaos: Chaos) -> Hub {
+        self.chaos = Some(Arc::new(chaos));
+        self
+    }
+
+    /// Shard of connection `id`; ids are sequential, so they are mixed first
+    fn shard(&self, id: u64) -> &Shard {
+        let hash = id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// Stats of connection `id`, if registered
+    pub fn stats(&self, id: u64) -> Option<Arc<ConnStats>> {
+        self.with_conn(id, |conn| conn.stats.clone())
+    }
+
+    /// Run `f` on connection `id`, if registered
+    fn with_conn<T>(&self, id: u64, f: impl FnOnce(&Conn) -> T) -> Option<T> {
+        read(self.shard(id)).get(&id).map(f)
+    }
+
+    /// Register connection, returns its outbound queue
+    pub fn register(&self, id: u64, stats: Arc<ConnStats>) -> Outbound {
+        let (tx, rx) = mpsc::unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let governors = self.governors.connect(stats.claimed_tenant());
+        write(self.shard(id)).insert(
+            id,
+            Conn {
+                tx,
+                queued: queued.clone(),
+                stats,
+            },
+        );
+        let chaos = self.chaos.clone().filter(|chaos| chaos.picks());
+        Outbound {
+            rx,
+            queued,
+            governors,
+            chaos,
+        }
+    }
+
+    /// Remove connection, its writer task stops once the queue is drained
+    pub fn unregister(&self, id: u64) {
+        if let Some(conn) = write(self.shard(id)).remove(&id) {
+            bandwidth::closed(&conn.stats);
+            conn.stats.taps.close();
+            if let Some(summary) = conn.stats.recorder.stop() {
+                log::info!(
+                    "Recorded {} frames of connection {} to {}",
+                    summary.frames,
+                    id,
+                    summary.file.display()
+                );
+            }
+        }
+        self.pacer.forget(id);
+        self.calls.disconnected(id);
+        self.kv.disconnected(id);
+        self.crdts.disconnected(id);
+        self.lock_notices(self.locks.disconnected(id));
+        let deliveries = self.acks.closed(id);
+        let mut names = Vec::new();
+        for (name, left) in self.rooms.leave_all(id) {
+            events::emit(Event::Left { id, room: &name });
+            self.notify_left(&name, left);
+            names.push(name);
+        }
+        if let Some(ref store) = self.store {
+            let rooms = names
+                .into_iter()
+                .map(|name| {
+                    let seq = self.rooms.last_seq(&name);
+                    (name, seq)
+                })
+                .collect();
+            store.close(id, rooms, deliveries);
+        }
+    }
+
+    /// Number of registered connections
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| read(s).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Snapshot of all connections, ordered by id
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        let mut list = Vec::new();
+        for shard in self.shards.iter() {
+            list.extend(read(shard).iter().map(|(id, c)| ConnInfo {
+                id: *id,
+                tenant: c.stats.tenant.clone(),
+                user: c.stats.user.clone(),
+                connected_secs: c.stats.connected_at.elapsed().as_secs(),
+                rtt_ms: c.stats.rtt().map(|d| d.as_secs_f64() * 1000.0),
+                clock_skew_ms: c.stats.skew(),
+                queued: c.queued.load(Ordering::Relaxed),
+                usage: Usage::of(&c.stats),
+                labels: c.stats.labels.clone(),
+                pipeline: c.stats.pipeline,
+                metadata: c.stats.metadata(),
+            }));
+        }
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    /// Ids of the connections authenticated as `user`
+    pub fn user_connections(&self, user: &str) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for shard in self.shards.iter() {
+            let conns = read(shard);
+            ids.extend(
+                conns
+                    .iter()
+                    .filter(|(_, c)| c.stats.user() == Some(user))
+                    .map(|(id, _)| *id),
+            );
+        }
+        ids
+    }
+
+    /// User connection `id` authenticated as
+    pub fn user(&self, id: u64) -> Option<String> {
+        self.with_conn(id, |conn| conn.stats.user().map(str::to_string))
+            .flatten()
+    }
+
+    /// Queue close frame for one connection, even if its queue is full
+    pub fn close(&self, id: u64, reason: ws::CloseReason) -> bool {
+        self.with_conn(id, |conn| {
+            let msg = ws::Message::Close(Some(reason));
+            if conn.tx.unbounded_send((Instant::now(), msg)).is_err() {
+                return false;
+            }
+            conn.queued.fetch_add(1, Ordering::Relaxed);
+            true
+        })
+        .unwrap_or(false)
+    }
+
+    pub fn is_connected(&self, id: u64) -> bool {
+        self.with_conn(id, |_| ()).is_some()
+    }
+
+    /// Queue message for one connection
+    pub fn send(&self, id: u64, msg: ws::Message) -> bool {
+        self.with_conn(id, |conn| push(conn, msg, self.queue_size))
+            .unwrap_or(false)
+    }
+
+    /// Whether connection `id` has half of `hub.queue_size` or more queued,
+    /// `false` if it is gone
+    pub fn backlogged(&self, id: u64) -> bool {
+        self.with_conn(id, |conn| {
+            conn.queued.load(Ordering::Relaxed) >= self.queue_size / 2
+        })
+        .unwrap_or(false)
+    }
+
+    /// Queue message for every connection, returns number of recipients.
+    /// Tenants with the `broadcast` flag off are skipped.
+    ///
+    /// `msg` must be cheap to clone, i.e. carry `Bytes`/`ByteString` payload.
+    pub fn broadcast(&self, msg: ws::Message) -> usize {
+        self.broadcast_to(msg, &Target::default())
+    }
+
+    /// Broadcast to the connections of `target`
+    pub fn broadcast_to(&self, msg: ws::Message, target: &Target) -> usize {
+        let start = Instant::now();
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        let delivered = (0..self.shards.len())
+            .map(|i| self.broadcast_shard(turn + i, turn, &msg, target))
+            .sum();
+        broadcasted(start, delivered);
+        delivered
+    }
+
+    /// Broadcast like `broadcast_to`, on the fan-out pool if configured and
+    /// there are enough connections; resolves once queued for all. With an
+    /// ordering `key`, it waits for the broadcasts with that key before it.
+    pub async fn fan_out(
+        self: &Arc<Hub>,
+        msg: ws::Message,
+        target: Target,
+        key: Option<&str>,
+    ) -> usize {
+        let ordered = match key {
+            Some(key) => Some(fanout::turn(key).await),
+            None => None,
+        };
+        if !fanout::wanted(self.len()) {
+            return self.broadcast_to(msg, &target);
+        }
+        let start = Instant::now();
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        let target = Arc::new(target);
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let remaining = Arc::new(AtomicUsize::new(self.shards.len()));
+        let (tx, rx) = oneshot::channel();
+        // given up by the last job, even if this future is dropped first
+        let tx = Arc::new(Mutex::new(Some((tx, ordered))));
+        let jobs: Vec<fanout::Job> = (0..self.shards.len())
+            .map(|i| {
+                let (hub, msg, target) = (self.clone(), msg.clone(), target.clone());
+                let (delivered, remaining, tx) = (delivered.clone(), remaining.clone(), tx.clone());
+                Box::new(move || {
+                    let n = hub.broadcast_shard(turn + i, turn, &msg, &target);
+                    let total = delivered.fetch_add(n, Ordering::Relaxed) + n;
+                    if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                        if let Some((tx, ordered)) = lock(&tx).take() {
+                            let _ = tx.send(total);
+                            drop(ordered);
+                        }
+                    }
+                }) as fanout::Job
+            })
+            .collect();
+        if let Err(jobs) = fanout::submit(jobs) {
+            // pool is full
+            for job in jobs {
+                job();
+            }
+        }
+        let delivered = rx.await.unwrap_or(0);
+        broadcasted(start, delivered);
+        delivered
+    }
+
+    /// Queue `msg` for the matching connections of shard `index`, those
+    /// with nothing queued first; returns number of recipients
+    fn broadcast_shard(
+        &self,
+        index: usize,
+        turn: usize,
+        msg: &ws::Message,
+        target: &Target,
+    ) -> usize {
+        let shard = read(&self.shards[index % self.shards.len()]);
+        let len = shard.len();
+        let (idle, busy): (Vec<&Conn>, Vec<&Conn>) = shard
+            .values()
+            .cycle()
+            .skip(turn % len.max(1))
+            .take(len)
+            .filter(|c| flags::enabled("broadcast", c.stats.tenant()))
+            .filter(|c| target.matches(&c.stats))
+            .partition(|c| c.queued.load(Ordering::Relaxed) == 0);
+        idle.into_iter()
+            .chain(busy)
+            .filter(|c| push(c, msg.clone(), self.queue_size))
+            .count()
+    }
+
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
+    /// Content filter that messages published to `room` go through, if any
+    pub fn content_filter(&self, room: &str) -> Option<&ContentFilter> {
+        self.filter
+            .as_ref()
+            .filter(|_| self.rooms.policy(room).filtered)
+    }
+
+    pub fn cluster(&self) -> Option<&Arc<Cluster>> {
+        self.cluster.as_ref()
+    }
+
+    pub fn calls(&self) -> &Calls {
+        &self.calls
+    }
+
+    /// Deliveries awaiting acknowledgement, see `acks`
+    pub fn acks(&self) -> &Acks {
+        &self.acks
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn kv(&self) -> &Kv {
+        &self.kv
+    }
+
+    pub fn crdts(&self) -> &Crdts {
+        &self.crdts
+    }
+
+    pub fn locks(&self) -> &Locks {
+        &self.locks
+    }
+
+    /// Send lock grants, timeouts and losses to their connections
+    pub fn lock_notices(&self, notices: Vec<Notice>) {
+        for notice in notices {
+            self.send(notice.conn(), notice.to_message());
+        }
+    }
+
+    /// Session store, `None` without `[store]`
+    pub fn store(&self) -> Option<&Arc<Store>> {
+        self.store.as_ref()
+    }
+
+    /// Push notifications, `None` without `[push]`
+    pub fn push(&self) -> Option<&Arc<Push>> {
+        self.push.as_ref()
+    }
+
+    /// Send direct message `data` of `from` to the connections of user `to`
+    /// whose credential names `tenant`, on every node, but connection
+    /// `except`; pushed to the user's devices if they have none and are not
+    /// the sender. Returns the connections of this node it was queued for
+    pub fn direct(
+        &self,
+        tenant: Option<&str>,
+        from: &str,
+        to: &str,
+        data: Value,
+        except: u64,
+    ) -> usize {
+        let delivered = self.deliver_direct(tenant, from, to, &data, except);
+        if let Some(ref cluster) = self.cluster {
+            cluster.replicate_direct(tenant, from, to, &data);
+        }
+        if let Some(push) = self.push.clone().filter(|_| delivered == 0 && from != to) {
+            let msg = push::Message {
+                tenant: tenant.map(str::to_string),
+                from: from.to_string(),
+                to: to.to_string(),
+                data,
+            };
+            ntex::rt::spawn(push::notify(push, self.store.clone(), msg));
+        }
+        delivered
+    }
+
+    /// Send a direct message to the connections of user `to` whose
+    /// credential names `tenant`, on this node, but connection `except`;
+    /// returns their number
+    pub fn deliver_direct(
+        &self,
+        tenant: Option<&str>,
+        from: &str,
+        to: &str,
+        data: &Value,
+        except: u64,
+    ) -> usize {
+        let msg = protocol::message("user.message", None, &json!({ "from": from, "data": data }));
+        let mut delivered = 0;
+        for id in self.user_connections(to) {
+            let same_tenant = self
+                .with_conn(id, |conn| conn.stats.claimed_tenant() == tenant)
+                .unwrap_or(false);
+            if id != except && same_tenant && self.send(id, msg.clone()) {
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+
+    /// Join room `name`, or queue for it if full
+    pub fn join(
+        &self,
+        id: u64,
+        name: &str,
+        invite: Option<&str>,
+        private: bool,
+    ) -> Result<Join, RoomError> {
+        let stats = self.with_conn(id, |c| c.stats.clone());
+        let user = stats.as_ref().and_then(|s| s.user());
+        let join = self.rooms.join(id, user, name, invite, private)?;
+        if let Join::Member(_) = join {
+            events::emit(Event::Joined { id, room: name });
+        }
+        Ok(join)
+    }
+
+    /// Moderate room `name`, `by` a moderator or the admin API if `None`;
+    /// kicked connections get `room.closed`
+    pub fn moderate(
+        &self,
+        by: Option<&str>,
+        name: &str,
+        action: Moderation,
+    ) -> Result<(), RoomError> {
+        let moderated = self.rooms.moderate(by, name, action)?;
+        let msg = protocol::message(
+            "room.closed",
+            None,
+            &json!({ "room": name, "reason": "kicked" }),
+        );
+        for id in moderated.kicked {
+            events::emit(Event::Left { id, room: name });
+            self.send(id, msg.clone());
+        }
+        for left in moderated.left {
+            self.notify_left(name, left);
+        }
+        Ok(())
+    }
+
+    /// Leave room `name`, returns `false` if not a member or waiting
+    pub fn leave(&self, id: u64, name: &str) -> bool {
+        match self.rooms.leave(id, name) {
+            Some(left) => {
+                events::emit(Event::Left { id, room: name });
+                self.notify_left(name, left);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send `data` as `room.message` to the other members of `name`,
+    /// returns its sequence number. In cluster mode, a publish to a room
+    /// owned by another node is forwarded there and `None` is returned; the
+    /// connection gets the owner's answer as reply to message `reply_id`.
+    ///
+    /// With `ack` the members are asked to acknowledge the message, see
+    /// `acks`; the room must be owned by this node then.
+    pub fn publish(
+        &self,
+        id: u64,
+        name: &str,
+        data: &Value,
+        reply_id: Option<&str>,
+        ack: Option<&AckRequest>,
+    ) -> Result<Option<u64>, RoomError> {
+        let cluster = match self.cluster {
+            Some(ref cluster) => cluster,
+            None => {
+                let (seq, members) = self.rooms.publish(id, name, data, |_| ())?;
+                self.send_to(name, id, data, seq, members, ack);
+                self.published(id, name, Some(seq));
+                return Ok(Some(seq));
+            }
+        };
+        let _sequencing = cluster.sequencing();
+        let owner = cluster.owner(name);
+        if owner != cluster.node() {
+            if ack.is_some() {
+                return Err(RoomError::NotOwner);
+            }
+            let user = self.rooms.authorize_publish(id, name)?;
+            if !cluster.forward(&owner, name, id, user, data, reply_id) {
+                return Err(RoomError::OwnerUnreachable);
+            }
+            self.published(id, name, None);
+            return Ok(None);
+        }
+        let (seq, members) = self
+            .rooms
+            .publish(id, name, data, |entry| cluster.replicate(name, entry))?;
+        self.send_to(name, id, data, seq, members, ack);
+        self.published(id, name, Some(seq));
+        Ok(Some(seq))
+    }
+
+    /// Publish `messages` to their rooms as `publish` does, all or none;
+    /// returns their sequence numbers, or the index of the first that may
+    /// not be published. In cluster mode all rooms must be owned here.
+    pub fn publish_batch(
+        &self,
+        id: u64,
+        messages: &[(String, Value)],
+    ) -> Result<Vec<u64>, (usize, RoomError)> {
+        let _sequencing = match self.cluster {
+            Some(ref cluster) => {
+                let sequencing = cluster.sequencing();
+                if let Some(i) = messages
+                    .iter()
+                    .position(|(name, _)| cluster.owner(name) != cluster.node())
+                {
+                    return Err((i, RoomError::NotOwner));
+                }
+                Some(sequencing)
+            }
+            None => None,
+        };
+        let published = self.rooms.publish_all(id, messages, |name, entry| {
+            if let Some(ref cluster) = self.cluster {
+                cluster.replicate(name, entry);
+            }
+        })?;
+        let mut seqs = Vec::with_capacity(published.len());
+        for ((name, data), (seq, members)) in messages.iter().zip(published) {
+            self.send_message(name, id, None, data, seq, members);
+            self.published(id, name, Some(seq));
+            seqs.push(seq);
+        }
+        Ok(seqs)
+    }
+
+    /// Send `data` to the members of existing room `name` as the server,
+    /// from connection 0, like `publish` otherwise
+    pub fn post(
+        &self,
+        name: &str,
+        data: &Value,
+        ack: Option<&AckRequest>,
+    ) -> Result<u64, RoomError> {
+        if !self.rooms.names().iter().any(|room| room == name) {
+            return Err(RoomError::NotFound);
+        }
+        let _sequencing = match self.cluster {
+            Some(ref cluster) if cluster.owner(name) != cluster.node() => {
+                return Err(RoomError::NotOwner)
+            }
+            Some(ref cluster) => Some(cluster.sequencing()),
+            None => None,
+        };
+        let entry = Entry {
+            seq: 0,
+            from: 0,
+            user: None,
+            node: None,
+            data: data.clone(),
+            at: history::now_millis(),
+            edited_at: None,
+            deleted: false,
+        };
+        let (entry, members) = self.rooms.sequence(name, entry, |entry| {
+            if let Some(ref cluster) = self.cluster {
+                cluster.replicate(name, entry);
+            }
+        });
+        self.send_to(name, 0, data, entry.seq, members, ack);
+        self.published(0, name, Some(entry.seq));
+        Ok(entry.seq)
+    }
+
+    /// Publish scheduled message `msg` now that it is due, through the
+    /// room's owner if another cluster node owns it by now
+    pub fn publish_scheduled(&self, msg: &Scheduled) {
+        let entry = Entry {
+            seq: 0,
+            from: msg.from,
+            user: msg.user.clone(),
+            node: None,
+            data: msg.data.clone(),
+            at: history::now_millis(),
+            edited_at: None,
+            deleted: false,
+        };
+        let _sequencing = match self.cluster {
+            Some(ref cluster) if cluster.owner(&msg.room) != cluster.node() => {
+                let owner = cluster.owner(&msg.room);
+                let user = msg.user.clone();
+                if !cluster.forward(&owner, &msg.room, msg.from, user, &msg.data, None) {
+                    log::warn!(
+                        "Scheduled message {} to {} dropped, its owner is unreachable",
+                        msg.id,
+                        msg.room
+                    );
+                }
+                return self.published(msg.from, &msg.room, None);
+            }
+            Some(ref cluster) => Some(cluster.sequencing()),
+            None => None,
+        };
+        let seq = self.sequence(&msg.room, entry);
+        self.published(msg.from, &msg.room, Some(seq));
+    }
+
+    fn published(&self, id: u64, name: &str, seq: Option<u64>) {
+        events::emit(Event::Published {
+            id,
+            room: name,
+            seq,
+        });
+    }
+
+    /// Store and send a message of another cluster node's connection, as the
+    /// room's owner; returns its sequence number
+    pub fn sequence(&self, name: &str, entry: Entry) -> u64 {
+        let (entry, members) = self.rooms.sequence(name, entry, |entry| {
+            if let Some(ref cluster) = self.cluster {
+                cluster.replicate(name, entry);
+            }
+        });
+        let node = entry.node.as_deref();
+        self.send_message(name, entry.from, node, &entry.data, entry.seq, members);
+        entry.seq
+    }
+
+    /// Store and send a message sequenced by the room's cluster owner
+    pub fn deliver(&self, name: &str, entry: Entry) {
+        let members = self.rooms.deliver(name, entry.clone());
+        let node = entry.node.as_deref();
+        self.send_message(name, entry.from, node, &entry.data, entry.seq, members);
+    }
+
+    /// Send `room.message` of this node's connection `from` to `members`,
+    /// asking them to acknowledge it with `ack`
+    fn send_to(
+        &self,
+        name: &str,
+        from: u64,
+        data: &Value,
+        seq: u64,
+        members: Recipients,
+        ack: Option<&AckRequest>,
+    ) {
+        let Some(ack) = ack else {
+            return self.send_message(name, from, None, data, seq, members);
+        };
+        // neither aggregated nor paced, each one is acknowledged on its own
+        let mut payload =
+            json!({ "room": name, "seq": seq, "from": from, "data": data, "ack": true });
+        let members: Vec<u64> = self
+            .pick(name, members, &payload)
+            .into_iter()
+            .filter(|id| {
+                self.with_conn(*id, |conn| conn.stats.accepts(name, &payload))
+                    .unwrap_or(false)
+            })
+            .collect();
+        self.acks.track(name, seq, &members, ack.quorum);
+        let to = match ack.exactly_once {
+            true => {
+                payload["exactly_once"] = json!(true);
+                let delivery = Delivery {
+                    room: name.to_string(),
+                    seq,
+                    from,
+                    data: data.clone(),
+                    received: false,
+                };
+                self.acks.deliver(&members, &delivery)
+            }
+            false => members,
+        };
+        let msg = protocol::message("room.message", None, &payload);
+        self.send_room(name, &to, msg, |_, _| true);
+    }
+
+    /// Send connection `id` the unfinished exactly-once messages of the
+    /// session it resumed again, or their release if it received them
+    pub fn redeliver(&self, id: u64, deliveries: Vec<Delivery>) {
+        self.acks.resumed(id, &deliveries);
+        for delivery in deliveries {
+            let msg = match delivery.received {
+                true => protocol::message(
+                    "room.release",
+                    None,
+                    &json!({ "room": delivery.room, "seq": delivery.seq }),
+                ),
+                false => {
+                    let payload = json!({
+                        "room": delivery.room,
+                        "seq": delivery.seq,
+                        "from": delivery.from,
+                        "data": delivery.data,
+                        "ack": true,
+                        "exactly_once": true,
+                        "dup": true,
+                    });
+                    protocol::message("room.message", None, &payload)
+                }
+            };
+            METRICS.redeliveries_total.inc();
+            self.send(id, msg);
+        }
+    }
+
+    /// Send `room.message` to `members`, `from` a connection of cluster
+    /// node `node` if not this one
+    fn send_message(
+        &self,
+        name: &str,
+        from: u64,
+        node: Option<&str>,
+        data: &Value,
+        seq: u64,
+        members: Recipients,
+    ) {
+        let mut payload = json!({ "room": name, "seq": seq, "from": from, "data": data });
+        if let Some(node) = node {
+            payload["node"] = json!(node);
+        }
+        let members = self.pick(name, members, &payload);
+        let policy = self.rooms.policy(name);
+        if let Some(aggregation) = policy.aggregate {
+            if let Some(window) = self.aggregator.add(name, payload, members, aggregation) {
+                self.flush(name, window);
+            }
+            return;
+        }
+        let msg = protocol::message("room.message", None, &payload);
+        self.send_room(name, &members, msg.clone(), |id, stats| {
+            stats.accepts(name, &payload)
+                && policy
+                    .pace
+                    .is_none_or(|pace| self.pacer.offer(id, name, &msg, pace))
+        });
+    }
+
+    /// Members to send room message `payload` to: those in no group, and of
+    /// each consumer group the first in turn that is connected, has room in
+    /// its queue and whose filter passes the message
+    fn pick(&self, name: &str, recipients: Recipients, payload: &Value) -> Vec<u64> {
+        let Recipients {
+            mut members,
+            groups,
+        } = recipients;
+        for group in groups {
+            let eligible = group.into_iter().find(|id| {
+                self.with_conn(*id, |conn| {
+                    conn.queued.load(Ordering::Relaxed) < self.queue_size
+                        && conn.stats.accepts(name, payload)
+                })
+                .unwrap_or(false)
+            });
+            members.extend(eligible);
+        }
+        members
+    }
+
+    pub fn pacer(&self) -> &Pacer {
+        &self.pacer
+    }
+
+    pub fn aggregator(&self) -> &Aggregator {
+        &self.aggregator
+    }
+
+    /// Send the messages of an aggregation window of room `name`, combined;
+    /// members with a filter get theirs combined of the messages it passes
+    pub fn flush(&self, name: &str, window: Window) {
+        let all: Vec<&Value> = window.payloads.iter().collect();
+        let Some(msg) = aggregate::reduce(name, window.reducer, &all) else {
+            return;
+        };
+        self.send_room(name, &window.members, msg, |_, stats| {
+            stats.filter(name).is_none()
+        });
+        for member in &window.members {
+            let filter = self.with_conn(*member, |conn| conn.stats.filter(name));
+            let Some(filter) = filter.flatten() else {
+                continue;
+            };
+            let passed: Vec<&Value> = all.iter().copied().filter(|p| filter.matches(p)).collect();
+            if let Some(msg) = aggregate::reduce(name, window.reducer, &passed) {
+                self.send(*member, msg);
+            }
+        }
+    }
+
+    /// Queue `msg` for each of `members`, starting at the next one in turn;
+    /// returns number of recipients
+    fn send_all(&self, members: &[u64], msg: ws::Message) -> usize {
+        self.send_where(members, msg, |_, _| true)
+    }
+
+    /// `send_all` to the members `accept` holds for
+    fn send_where(
+        &self,
+        members: &[u64],
+        msg: ws::Message,
+        accept: impl Fn(u64, &ConnStats) -> bool,
+    ) -> usize {
+        self.send_counted(members, msg, accept).0
+    }
+
+    /// `send_where` for a message of room `name`, counted in the room's
+    /// metrics
+    fn send_room(
+        &self,
+        name: &str,
+        members: &[u64],
+        msg: ws::Message,
+        accept: impl Fn(u64, &ConnStats) -> bool,
+    ) -> usize {
+        let start = Instant::now();
+        let (sent, dropped) = self.send_counted(members, msg, accept);
+        metrics::room_fanout(name, start.elapsed(), dropped);
+        sent
+    }
+
+    /// `send_where`, returns the number of recipients and of members
+    /// accepted that it could not be queued for
+    fn send_counted(
+        &self,
+        members: &[u64],
+        msg: ws::Message,
+        accept: impl Fn(u64, &ConnStats) -> bool,
+    ) -> (usize, usize) {
+        if members.is_empty() {
+            return (0, 0);
+        }
+        let start = self.turn.fetch_add(1, Ordering::Relaxed) % members.len();
+        let mut dropped = 0;
+        let sent = members[start..]
+            .iter()
+            .chain(&members[..start])
+            .filter_map(|member| {
+                self.with_conn(*member, |conn| {
+                    if !accept(*member, &conn.stats) {
+                        return false;
+                    }
+                    let sent = push(conn, msg.clone(), self.queue_size);
+                    dropped += usize::from(!sent);
+                    sent
+                })
+            })
+            .filter(|sent| *sent)
+            .count();
+        (sent, dropped)
+    }
+
+    /// Send `data` as `room.ephemeral` to the other members of `name` that
+    /// have nothing queued, returns number of recipients
+    pub fn ephemeral(&self, id: u64, name: &str, data: &Value) -> Result<usize, RoomError> {
+        let members = self.rooms.ephemeral(id, name)?;
+        let msg = protocol::message(
+            "room.ephemeral",
+            None,
+            &json!({ "room": name, "from": id, "data": data }),
+        );
+        let delivered = members
+            .iter()
+            .filter_map(|member| {
+                self.with_conn(*member, |conn| {
+                    conn.queued.load(Ordering::Relaxed) == 0
+                        && push(conn, msg.clone(), self.queue_size)
+                })
+            })
+            .filter(|delivered| *delivered)
+            .count();
+        Ok(delivered)
+    }
+
+    /// Replace data of message `seq` by its author `id` and send it on to the
+    /// other members as `message.edit`; returns the edit time
+    pub fn edit(&self, id: u64, name: &str, seq: u64, data: &Value) -> Result<u64, RoomError> {
+        let (edited_at, members) = self.rooms.edit(id, name, seq, data.clone())?;
+        let msg = protocol::message(
+            "message.edit",
+            None,
+            &json!({ "room": name, "seq": seq, "data": data, "edited_at": edited_at }),
+        );
+        self.send_all(&members, msg);
+        Ok(edited_at)
+    }
+
+    /// Delete message `seq` by its author `id` and send the tombstone to the
+    /// other members as `message.delete`
+    pub fn delete(&self, id: u64, name: &str, seq: u64) -> Result<(), RoomError> {
+        let members = self.rooms.delete(id, name, seq)?;
+        let msg = protocol::message("message.delete", None, &json!({ "room": name, "seq": seq }));
+        self.send_all(&members, msg);
+        Ok(())
+    }
+
+    /// Record a read receipt of member `id` and pass it on to the other
+    /// members as `room.read` if it moved; returns the user's read position
+    pub fn read(&self, id: u64, user: &str, name: &str, seq: u64) -> Result<u64, RoomError> {
+        let (read, moved, members) = self.rooms.read(id, name, seq)?;
+        if moved {
+            let msg = protocol::message(
+                "room.read",
+                None,
+                &json!({ "room": name, "user": user, "seq": read }),
+            );
+            self.send_all(&members, msg);
+        }
+        Ok(read)
+    }
+
+    /// Set `key` of `scope` if it is at `if_version` and send it to its
+    /// subscribers as `kv.changed`; returns its version
+    pub fn kv_set(
+        &self,
+        scope: &Scope,
+        key: &str,
+        value: Value,
+        if_version: Option<u64>,
+    ) -> Result<u64, KvError> {
+        let (version, subscribers) = self.kv.set(scope, key, value.clone(), if_version)?;
+        self.kv_changed(scope, key, &value, version, &subscribers);
+        Ok(version)
+    }
+
+    /// Delete `key` of `scope` if it is at `if_version` and tell its
+    /// subscribers; returns whether it was there
+    pub fn kv_delete(
+        &self,
+        scope: &Scope,
+        key: &str,
+        if_version: Option<u64>,
+    ) -> Result<bool, KvError> {
+        let Some((version, subscribers)) = self.kv.delete(scope, key, if_version)? else {
+            return Ok(false);
+        };
+        self.kv_changed(scope, key, &Value::Null, version, &subscribers);
+        Ok(true)
+    }
+
+    fn kv_changed(&self, scope: &Scope, key: &str, value: &Value, version: u64, to: &[u64]) {
+        let mut msg = json!({ "key": key, "value": value, "version": version });
+        if let Scope::Room(ref room) = scope {
+            msg["room"] = json!(room);
+        }
+        self.send_scoped(scope, to, protocol::message("kv.changed", None, &msg));
+    }
+
+    /// Apply `op` to object `name` of `scope`, send its new value to its
+    /// subscribers as `crdt.changed` and its state to the other cluster
+    /// nodes; returns the new value
+    pub fn crdt_update(&self, scope: &Scope, name: &str, op: Op) -> Result<Value, CrdtError> {
+        let change = self.crdts.update(scope, name, op)?;
+        if let Some(ref cluster) = self.cluster {
+            cluster.replicate_crdt(scope, name, &change.object);
+        }
+        self.crdt_changed(scope, name, &change);
+        Ok(change.object.value())
+    }
+
+    /// Merge the state of object `name` of `scope` from another cluster
+    /// node, telling its subscribers if it changed
+    pub fn crdt_merge(&self, scope: &Scope, name: &str, object: Object) {
+        if let Some(change) = self.crdts.merge(scope, name, object) {
+            self.crdt_changed(scope, name, &change);
+        }
+    }
+
+    fn crdt_changed(&self, scope: &Scope, name: &str, change: &Change) {
+        let mut msg = json!({
+            "name": name,
+            "kind": change.object.kind(),
+            "value": change.object.value(),
+        });
+        if let Scope::Room(ref room) = scope {
+            msg["room"] = json!(room);
+        }
+        let msg = protocol::message("crdt.changed", None, &msg);
+        self.send_scoped(scope, &change.subscribers, msg);
+    }
+
+    /// Send `msg` to the connections of `to` still allowed to see `scope`,
+    /// those of a room that have not left it
+    fn send_scoped(&self, scope: &Scope, to: &[u64], msg: ws::Message) {
+        for id in to {
+            match scope {
+                Scope::Room(room) if !self.rooms.is_member(*id, room) => {}
+                _ => {
+                    self.send(*id, msg.clone());
+                }
+            }
+        }
+    }
+
+    /// Tell a promoted connection it joined and the others their new position
+    fn notify_left(&self, name: &str, left: Left) {
+        if let Some((id, members)) = left.promoted {
+            events::emit(Event::Joined { id, room: name });
+            let msg = json!({ "room": name, "members": members });
+            self.send(id, protocol::message("room.join", None, &msg));
+        }
+        for (id, position) in left.moved {
+            let msg = json!({ "room": name, "position": position });
+            self.send(id, protocol::message("room.waiting", None, &msg));
+        }
+    }
+
+    /// Broadcast text payload, shared by all recipients
+    pub fn broadcast_text(&self, text: ByteString) -> usize {
+        self.broadcast(ws::Message::Text(text))
+    }
+
+    /// Broadcast binary payload, shared by all recipients
+    pub fn broadcast_binary(&self, bin: Bytes) -> usize {
+        self.broadcast(ws::Message::Binary(bin))
+    }
+}
+
+// guards also over a poisoned state, see the module doc
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn broadcasted(start: Instant, delivered: usize) {
+    METRICS.broadcast_fanout_seconds.observe(start.elapsed());
+    METRICS.broadcasts_total.inc();
+    METRICS.broadcast_recipients_total.add(delivered as u64);
+}
+
+fn push(conn: &Conn, msg: ws::Message, limit: usize) -> bool {
+    // slow consumer, drop instead of blocking the sender
+    if conn.queued.load(Ordering::Relaxed) >= limit {
+        METRICS.messages_dropped_total.inc();
+        return false;
+    }
+    if conn.tx.unbounded_send((Instant::now(), msg)).is_err() {
+        return false;
+    }
+    conn.queued.fetch_add(1, Ordering::Relaxed);
+    true
+}
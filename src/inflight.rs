@@ -0,0 +1,160 @@
+//! Bounds on the handlers of a connection that answer later.
+//!
+//! Most messages are answered before the next frame is read. Those that
+//! wait on something else, `session.resume` on the session store,
+//! `room.publish` to a room with a content filter and replies streamed in
+//! parts, run on their own and hold a `Ticket` while they do. `[handlers]`
+//! gives each message type a timeout, counted from when the message came
+//! in, and caps how many such handlers a connection has running or queued;
+//! more are refused with `rate_limited`.
+//!
+//! A client gives up on a message with `$cancel` naming its `id`:
+//!
+//! ```json
+//! {"type": "$cancel", "id": "8", "payload": {"id": "7"}}
+//! {"type": "$cancel", "id": "8", "payload": {"id": "7", "cancelled": 1}}
+//! {"type": "error", "id": "7", "code": "cancelled", "message": "...", "retryable": false}
+//! ```
+//!
+//! The handler is dropped where it waits, and its message answered with
+//! `cancelled`, or with a retryable `timeout` once its time is up.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use ntex::time;
+
+use crate::config::HandlersConfig;
+use crate::metrics::METRICS;
+use crate::protocol::{self, Direction, ErrorCode};
+
+/// Check `[handlers]` names message types clients send
+pub fn validate(config: &HandlersConfig) -> Result<(), String> {
+    if config.max_in_flight == 0 {
+        return Err("handlers.max_in_flight must be at least 1".to_string());
+    }
+    for name in config.timeouts.keys() {
+        match protocol::message_type(name) {
+            Some(ty) if ty.direction != Direction::ServerToClient => {}
+            _ => {
+                return Err(format!(
+                    "handlers.timeouts: `{}` is not a message type clients send",
+                    name
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handler holding a ticket, and the `id` of its message
+type Running = (Option<String>, AbortHandle);
+
+/// Handlers of one connection that answer later
+#[derive(Debug, Clone)]
+pub struct Inflight {
+    config: Rc<HandlersConfig>,
+    next: Rc<Cell<u64>>,
+    running: Rc<RefCell<HashMap<u64, Running>>>,
+}
+
+impl Inflight {
+    pub fn new(config: HandlersConfig) -> Inflight {
+        Inflight {
+            config: Rc::new(config),
+            next: Rc::new(Cell::new(0)),
+            running: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Ticket for handling message `name` with `id` that just came in,
+    /// refused with `rate_limited` if the connection has too many running
+    pub fn start(&self, name: &'static str, id: Option<&str>) -> Result<Ticket, protocol::Error> {
+        let mut running = self.running.borrow_mut();
+        if running.len() >= self.config.max_in_flight {
+            return Err(protocol::Error::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "{} messages are in flight, wait for their answers",
+                    running.len()
+                ),
+            ));
+        }
+        let key = self.next.get();
+        self.next.set(key + 1);
+        let (handle, registration) = AbortHandle::new_pair();
+        running.insert(key, (id.map(str::to_string), handle));
+        let timeout = self.config.timeout(name);
+        Ok(Ticket {
+            running: self.running.clone(),
+            key,
+            name,
+            timeout,
+            deadline: Instant::now() + timeout,
+            registration: Some(registration),
+        })
+    }
+
+    /// Abort the handlers of messages with `id`, returns how many there were
+    pub fn cancel(&self, id: &str) -> usize {
+        let running = self.running.borrow();
+        let mut cancelled = 0;
+        for (msg, handle) in running.values() {
+            if msg.as_deref() == Some(id) {
+                handle.abort();
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+}
+
+/// A handler's place among those in flight, given up once dropped
+#[derive(Debug)]
+pub struct Ticket {
+    running: Rc<RefCell<HashMap<u64, Running>>>,
+    key: u64,
+    name: &'static str,
+    timeout: Duration,
+    deadline: Instant,
+    registration: Option<AbortRegistration>,
+}
+
+impl Ticket {
+    /// Run `handler` until it is done, cancelled or its message's time is up
+    pub async fn run<F: Future>(mut self, handler: F) -> Result<F::Output, protocol::Error> {
+        let registration = self.registration.take().expect("ticket runs once");
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        match time::timeout(left, Abortable::new(handler, registration)).await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(_)) => {
+                METRICS.handlers_cancelled_total.inc();
+                Err(protocol::Error::new(
+                    ErrorCode::Cancelled,
+                    format!("`{}` was cancelled", self.name),
+                ))
+            }
+            Err(()) => {
+                METRICS.handler_timeouts_total.inc();
+                Err(protocol::Error::new(
+                    ErrorCode::Timeout,
+                    format!(
+                        "`{}` timed out after {} ms",
+                        self.name,
+                        self.timeout.as_millis()
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.running.borrow_mut().remove(&self.key);
+    }
+}
@@ -0,0 +1,62 @@
+//! Signed invitations to private rooms.
+//!
+//! An invite is signed like the session cookie, with `rooms.invite_key`:
+//! `<base64url(claims)>.<base64url(HMAC-SHA256(key, first part))>` and claims
+//! `{"room", "exp", "max_uses", "jti"}`. Uses are counted in memory by `jti`,
+//! so a restart resets them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, AuthError};
+
+/// Invite id generator, unique together with the mint time
+static NEXT_INVITE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub room: String,
+    /// Unix seconds the invite expires
+    pub exp: u64,
+    /// Joins allowed with this invite
+    pub max_uses: u32,
+    /// Invite id, to count uses
+    pub jti: String,
+}
+
+impl Invite {
+    pub fn new(room: &str, ttl: Duration, max_uses: u32) -> Invite {
+        let now = auth::now_secs();
+        Invite {
+            room: room.to_string(),
+            exp: now + ttl.as_secs(),
+            max_uses,
+            jti: format!(
+                "{:x}-{:x}",
+                now,
+                NEXT_INVITE.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.exp <= auth::now_secs()
+    }
+
+    pub fn sign(&self, key: &[u8]) -> String {
+        auth::sign(&serde_json::to_vec(self).expect("serializable invite"), key)
+    }
+
+    /// Check signature and expiry of an invite token
+    pub fn verify(token: &str, key: &[u8]) -> Result<Invite, AuthError> {
+        let claims = auth::verify_signed(token, key)?;
+        let invite: Invite =
+            serde_json::from_slice(&claims).map_err(|e| AuthError::Invalid(e.to_string()))?;
+        if invite.is_expired() {
+            return Err(AuthError::Expired);
+        }
+        Ok(invite)
+    }
+}
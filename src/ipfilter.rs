@@ -0,0 +1,415 @@
+//! Client IP allow and deny lists.
+//!
+//! Rules are CIDR blocks (`10.0.0.0/8`, `2001:db8::/32`) or single
+//! addresses. A client matching a deny rule is refused; if there are allow
+//! rules, so is a client matching none of them. Checked for every HTTP
+//! request before routing, so before the websocket upgrade too, and for
+//! gRPC connections when accepted.
+//!
+//! Rules from `[ip_filter]` are re-read on config reload. Rules added
+//! through the admin API are kept apart from them and saved to
+//! `ip_filter.file` if set, so they survive a restart.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use std::{fs, future::Future, io, pin::Pin};
+
+use ntex::service::{Service, Transform};
+use ntex::web::{HttpResponse, WebRequest, WebResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::config::IpFilterConfig;
+
+/// Address block, the address has no bits set past the prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(ip).into(), 32, self.prefix) == u32::from(net).into()
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(ip), 128, self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Clear the bits of a `bits` wide address past `prefix`
+fn mask(addr: u128, bits: u8, prefix: u8) -> u128 {
+    match bits - prefix {
+        0 => addr,
+        host if host >= 128 => 0,
+        host => addr >> host << host,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim())
+            .map_err(|_| format!("`{}` is not an IP address or CIDR block", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => return Err(format!("`{}` has an invalid prefix length", s)),
+            },
+            None => bits,
+        };
+        // clients are matched by their canonical address, so within
+        // `::ffff:0:0/96` a block is one of IPv4
+        let (addr, prefix) = match addr.to_canonical() {
+            IpAddr::V4(_) if addr.is_ipv6() && prefix < 96 => (addr, prefix),
+            IpAddr::V4(v4) if addr.is_ipv6() => (IpAddr::V4(v4), prefix - 96),
+            addr => (addr, prefix),
+        };
+        let addr = match addr {
+            IpAddr::V4(v4) => {
+                IpAddr::V4(Ipv4Addr::from(mask(u32::from(v4).into(), 32, prefix) as u32))
+            }
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(mask(u128::from(v6), 128, prefix))),
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which list a rule is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum List {
+    Allow,
+    Deny,
+}
+
+/// Rules of both lists
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Rules {
+    fn parse(config: &IpFilterConfig) -> Result<Rules, String> {
+        let parse = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<Vec<Cidr>, String>>()
+        };
+        Ok(Rules {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+        })
+    }
+
+    fn list(&mut self, list: List) -> &mut Vec<Cidr> {
+        match list {
+            List::Allow => &mut self.allow,
+            List::Deny => &mut self.deny,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Filter {
+    /// From the config file
+    configured: Rules,
+    /// Added through the admin API
+    runtime: Rules,
+    file: Option<PathBuf>,
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    configured: Rules {
+        allow: Vec::new(),
+        deny: Vec::new(),
+    },
+    runtime: Rules {
+        allow: Vec::new(),
+        deny: Vec::new(),
+    },
+    file: None,
+});
+
+/// Check configured rules
+pub fn validate(config: &IpFilterConfig) -> Result<(), String> {
+    Rules::parse(config).map(|_| ())
+}
+
+/// Set configured rules and load saved runtime rules
+pub fn init(config: &IpFilterConfig) -> Result<(), String> {
+    let configured = Rules::parse(config)?;
+    let runtime = match config.file {
+        Some(ref file) => load(file)?,
+        None => Rules::default(),
+    };
+    *FILTER.write().unwrap() = Filter {
+        configured,
+        runtime,
+        file: config.file.clone(),
+    };
+    Ok(())
+}
+
+/// Replace configured rules, e.g. on reload; runtime rules are kept
+pub fn set_configured(config: &IpFilterConfig) -> Result<(), String> {
+    let configured = Rules::parse(config)?;
+    FILTER.write().unwrap().configured = configured;
+    Ok(())
+}
+
+fn load(file: &Path) -> Result<Rules, String> {
+    match fs::read(file) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| format!("ip_filter.file {}: {}", file.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Rules::default()),
+        Err(e) => Err(format!("ip_filter.file {}: {}", file.display(), e)),
+    }
+}
+
+fn save(file: &Path, rules: &Rules) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(rules)?;
+    // replace atomically, a crash must not leave half a file
+    let tmp = file.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, file)
+}
+
+/// Whether a client at `ip` may connect
+pub fn allowed(ip: IpAddr) -> bool {
+    let filter = FILTER.read().unwrap();
+    let (configured, runtime) = (&filter.configured, &filter.runtime);
+    let matches = |rules: &[Cidr]| rules.iter().any(|rule| rule.contains(ip));
+    if matches(&configured.deny) || matches(&runtime.deny) {
+        return false;
+    }
+    let open = configured.allow.is_empty() && runtime.allow.is_empty();
+    open || matches(&configured.allow) || matches(&runtime.allow)
+}
+
+/// Configured and runtime rules
+pub fn list() -> (Rules, Rules) {
+    let filter = FILTER.read().unwrap();
+    (filter.configured.clone(), filter.runtime.clone())
+}
+
+/// Change the runtime rules with `f` and save them; nothing changes if
+/// they cannot be saved
+fn update<F: FnOnce(&mut Rules) -> bool>(f: F) -> io::Result<bool> {
+    let mut filter = FILTER.write().unwrap();
+    let mut runtime = filter.runtime.clone();
+    if !f(&mut runtime) {
+        return Ok(false);
+    }
+    if let Some(ref file) = filter.file {
+        save(file, &runtime)?;
+    }
+    filter.runtime = runtime;
+    Ok(true)
+}
+
+/// Add a runtime rule, `false` if it is there already
+pub fn add(list: List, cidr: Cidr) -> io::Result<bool> {
+    update(|rules| {
+        let rules = rules.list(list);
+        if rules.contains(&cidr) {
+            return false;
+        }
+        rules.push(cidr);
+        true
+    })
+}
+
+/// Remove a runtime rule, `false` if there is none
+pub fn remove(list: List, cidr: Cidr) -> io::Result<bool> {
+    update(|rules| {
+        let rules = rules.list(list);
+        let len = rules.len();
+        rules.retain(|rule| *rule != cidr);
+        rules.len() != len
+    })
+}
+
+/// Middleware refusing requests from filtered IPs with 403
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpFilter;
+
+impl<S> Transform<S> for IpFilter {
+    type Service = IpFilterMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        IpFilterMiddleware { service }
+    }
+}
+
+pub struct IpFilterMiddleware<S> {
+    service: S,
+}
+
+impl<S, E> Service<WebRequest<E>> for IpFilterMiddleware<S>
+where
+    S: Service<WebRequest<E>, Response = WebResponse>,
+    S::Future: 'static,
+{
+    type Response = WebResponse;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: WebRequest<E>) -> Self::Future {
+        match req.peer_addr() {
+            Some(peer) if !allowed(peer.ip()) => {
+                log::debug!("Request from {} refused by the IP filter", peer.ip());
+                let res = req.into_response(HttpResponse::Forbidden().body("address not allowed"));
+                Box::pin(async move { Ok(res) })
+            }
+            _ => Box::pin(self.service.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let any = cidr("0.0.0.0/0");
+        assert!(any.contains(ip("0.0.0.0")));
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(!any.contains(ip("::1")));
+
+        let block = cidr("10.1.2.3/8");
+        assert_eq!(block.to_string(), "10.0.0.0/8");
+        assert!(block.contains(ip("10.0.0.0")));
+        assert!(block.contains(ip("10.255.255.255")));
+        assert!(!block.contains(ip("9.255.255.255")));
+        assert!(!block.contains(ip("11.0.0.0")));
+
+        let odd = cidr("192.168.1.128/25");
+        assert!(odd.contains(ip("192.168.1.128")));
+        assert!(odd.contains(ip("192.168.1.255")));
+        assert!(!odd.contains(ip("192.168.1.127")));
+
+        let host = cidr("192.168.1.1");
+        assert_eq!(host, cidr("192.168.1.1/32"));
+        assert!(host.contains(ip("192.168.1.1")));
+        assert!(!host.contains(ip("192.168.1.0")));
+        assert!(!host.contains(ip("192.168.1.2")));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let any = cidr("::/0");
+        assert!(any.contains(ip("::")));
+        assert!(any.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!any.contains(ip("1.2.3.4")));
+
+        let block = cidr("2001:db8:ffff::1/32");
+        assert_eq!(block.to_string(), "2001:db8::/32");
+        assert!(block.contains(ip("2001:db8::")));
+        assert!(block.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!block.contains(ip("2001:db9::")));
+        assert!(!block.contains(ip("2001:db7:ffff:ffff:ffff:ffff:ffff:ffff")));
+
+        let high = cidr("2001:db8::/127");
+        assert!(high.contains(ip("2001:db8::1")));
+        assert!(!high.contains(ip("2001:db8::2")));
+
+        let host = cidr("2001:db8::1");
+        assert_eq!(host, cidr("2001:db8::1/128"));
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        // a dual-stack listener sees IPv4 clients as mapped addresses
+        let block = cidr("10.0.0.0/8");
+        assert!(block.contains(ip("::ffff:10.1.2.3")));
+        assert!(!block.contains(ip("::ffff:11.1.2.3")));
+
+        let mapped = cidr("::ffff:10.0.0.0/104");
+        assert_eq!(mapped, block);
+        assert!(mapped.contains(ip("10.200.0.1")));
+        assert_eq!(cidr("::ffff:192.168.1.1"), cidr("192.168.1.1/32"));
+        assert_eq!(cidr("::ffff:0.0.0.0/96"), cidr("0.0.0.0/0"));
+
+        // wider than the mapped range, an IPv6 block
+        let wide = cidr("::ffff:10.0.0.0/80");
+        assert_eq!(wide.to_string(), "::/80");
+        assert!(wide.contains(ip("::1")));
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/a",
+            "10.0.0/8",
+            "10.0.0.256",
+            "2001:db8::g/32",
+            "::ffff:10.0.0.0/129",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?} parsed", s);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! Room messages the server publishes on cron expressions.
+//!
+//! Jobs come from `[[jobs]]`, re-read on config reload, and from
+//! `PUT /admin/jobs/{name}`, kept apart from them in memory only. Each names
+//! a room, a cron expression and the message's `data`, an announcement or
+//! a signal for clients to drop their caches:
+//!
+//! ```toml
+//! [[jobs]]
+//! name = "cache-bust"
+//! cron = "*/15 * * * *"
+//! room = "app"
+//! data = { cmd = "refresh", run = "{{run}}", at = "{{now}}" }
+//! ```
+//!
+//! A cron expression has the fields `minute hour day month weekday`, or a
+//! leading `second` field too, in UTC; each is `*`, a value, a range `a-b`,
+//! a step `*/n`, `a-b/n` or `a/n`, or a list of them. Weekdays run from 0,
+//! Sunday, to 7, Sunday again. With both day and weekday restricted a time
+//! matching either runs the job.
+//!
+//! In the strings of `data`, `{{job}}`, `{{run}}` (its count, from 1) and
+//! `{{now}}` (unix milliseconds) are replaced; a string that is just
+//! `{{run}}` or `{{now}}` becomes the number. The message is sent as from
+//! the server, like `POST /admin/rooms/{room}/publish`, to a room that
+//! exists; in cluster mode only by the room's owner.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ntex::time;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::config::JobConfig;
+use crate::history::now_millis;
+use crate::hub::Hub;
+use crate::logging::DateTime;
+use crate::metrics::METRICS;
+use crate::rooms::RoomError;
+
+/// Seconds missed while the server was busy that are still run, more mean
+/// the clock jumped
+const MAX_CATCH_UP: u64 = 60;
+
+/// Times a job runs, a bit per value of each field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day and weekday are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let (seconds, fields) = match fields.len() {
+            5 => ("0", &fields[..]),
+            6 => (fields[0], &fields[1..]),
+            _ => return Err(format!("`{}` is not a cron expression of 5 or 6 fields", s)),
+        };
+        let weekdays = field(fields[4], 0, 7, "weekday")?;
+        Ok(Cron {
+            seconds: field(seconds, 0, 59, "second")?,
+            minutes: field(fields[0], 0, 59, "minute")?,
+            hours: field(fields[1], 0, 23, "hour")?,
+            days: field(fields[2], 1, 31, "day")?,
+            months: field(fields[3], 1, 12, "month")?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    /// Whether the job runs at unix second `secs`
+    pub fn matches(&self, secs: u64) -> bool {
+        let t = DateTime::from_unix(secs);
+        // 1970-01-01 was a Thursday
+        let weekday = (secs / 86400 + 4) % 7;
+        let day = self.days & 1 << t.day != 0;
+        let weekday = self.weekdays & 1 << weekday != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.seconds & 1 << t.second != 0
+            && self.minutes & 1 << t.minute != 0
+            && self.hours & 1 << t.hour != 0
+            && self.months & 1 << t.month != 0
+    }
+}
+
+/// Bits of the values of cron field `s`, within `min..=max`
+fn field(s: &str, min: u64, max: u64, what: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid cron {} `{}`", what, s);
+    let number = |n: &str| n.parse::<u64>().map_err(|_| invalid());
+    let mut bits = 0;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match number(step)? {
+                0 => return Err(invalid()),
+                step => (range, Some(step)),
+            },
+            None => (part, None),
+        };
+        let (lo, hi) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((lo, hi)) => (number(lo)?, number(hi)?),
+            // `a/n` runs from `a` to the end
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+        for value in (lo..=hi).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// `data` with the placeholders of its strings replaced
+fn render(data: &Value, job: &str, run: u64, now: u64) -> Value {
+    match data {
+        Value::String(s) => match s.as_str() {
+            "{{run}}" => json!(run),
+            "{{now}}" => json!(now),
+            s => Value::String(
+                s.replace("{{job}}", job)
+                    .replace("{{run}}", &run.to_string())
+                    .replace("{{now}}", &now.to_string()),
+            ),
+        },
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render(item, job, run, now))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), render(value, job, run, now)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobError {
+    Invalid(String),
+    /// A job of that name comes from the config
+    Configured,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Invalid(e) => write!(f, "{}", e),
+            JobError::Configured => write!(f, "job is configured, change it in the config"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    config: JobConfig,
+    cron: Cron,
+    runs: u64,
+    /// Unix milliseconds
+    last_run: Option<u64>,
+}
+
+impl Job {
+    fn new(config: JobConfig) -> Result<Job, String> {
+        if config.name.is_empty() {
+            return Err("jobs: name must not be empty".to_string());
+        }
+        let cron = config
+            .cron
+            .parse()
+            .map_err(|e| format!("jobs.{}: {}", config.name, e))?;
+        Ok(Job {
+            config,
+            cron,
+            runs: 0,
+            last_run: None,
+        })
+    }
+
+    fn info(&self) -> JobInfo {
+        JobInfo {
+            name: self.config.name.clone(),
+            cron: self.config.cron.clone(),
+            room: self.config.room.clone(),
+            data: self.config.data.clone(),
+            runs: self.runs,
+            last_run: self.last_run,
+        }
+    }
+}
+
+/// A job as the admin API lists it
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub cron: String,
+    pub room: String,
+    pub data: Value,
+    /// Times it ran since it was set
+    pub runs: u64,
+    pub last_run: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Jobs {
+    configured: BTreeMap<String, Job>,
+    runtime: BTreeMap<String, Job>,
+}
+
+static JOBS: Mutex<Jobs> = Mutex::new(Jobs {
+    configured: BTreeMap::new(),
+    runtime: BTreeMap::new(),
+});
+
+/// Check configured jobs
+pub fn validate(jobs: &[JobConfig]) -> Result<(), String> {
+    parse(jobs).map(|_| ())
+}
+
+fn parse(jobs: &[JobConfig]) -> Result<BTreeMap<String, Job>, String> {
+    let mut names = BTreeSet::new();
+    let mut parsed = BTreeMap::new();
+    for job in jobs {
+        if !names.insert(job.name.as_str()) {
+            return Err(format!("jobs: `{}` is named twice", job.name));
+        }
+        parsed.insert(job.name.clone(), Job::new(job.clone())?);
+    }
+    Ok(parsed)
+}
+
+/// Replace configured jobs; the runs of those that did not change are kept
+pub fn set_configured(jobs: &[JobConfig]) -> Result<(), String> {
+    let mut configured = parse(jobs)?;
+    let mut jobs = JOBS.lock().unwrap();
+    for (name, job) in configured.iter_mut() {
+        if let Some(old) = jobs
+            .configured
+            .get(name)
+            .filter(|old| old.config == job.config)
+        {
+            job.runs = old.runs;
+            job.last_run = old.last_run;
+        }
+    }
+    jobs.configured = configured;
+    Ok(())
+}
+
+/// Configured and runtime jobs, by name
+pub fn list() -> (Vec<JobInfo>, Vec<JobInfo>) {
+    let jobs = JOBS.lock().unwrap();
+    (
+        jobs.configured.values().map(Job::info).collect(),
+        jobs.runtime.values().map(Job::info).collect(),
+    )
+}
+
+/// Add or replace runtime job `config`, returns `true` if added
+pub fn put(config: JobConfig) -> Result<bool, JobError> {
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.configured.contains_key(&config.name) {
+        return Err(JobError::Configured);
+    }
+    let job = Job::new(config).map_err(JobError::Invalid)?;
+    Ok(jobs.runtime.insert(job.config.name.clone(), job).is_none())
+}
+
+/// Remove runtime job `name`, returns `false` if there is none
+pub fn remove(name: &str) -> bool {
+    JOBS.lock().unwrap().runtime.remove(name).is_some()
+}
+
+/// Name, room and message of the jobs running at unix second `secs`
+fn due(secs: u64) -> Vec<(String, String, Value)> {
+    let mut jobs = JOBS.lock().unwrap();
+    let Jobs {
+        configured,
+        runtime,
+    } = &mut *jobs;
+    let now = secs * 1000;
+    configured
+        .values_mut()
+        .chain(runtime.values_mut())
+        .filter(|job| job.cron.matches(secs))
+        .map(|job| {
+            job.runs += 1;
+            job.last_run = Some(now);
+            let name = &job.config.name;
+            let data = render(&job.config.data, name, job.runs, now);
+            (name.clone(), job.config.room.clone(), data)
+        })
+        .collect()
+}
+
+/// Run the jobs due each second
+pub async fn run(hub: Arc<Hub>) {
+    let mut last = now_millis() / 1000;
+    loop {
+        let ms = now_millis();
+        time::sleep(Duration::from_millis(1000 - ms % 1000)).await;
+        let now = now_millis() / 1000;
+        if now == last {
+            continue;
+        }
+        let first = match now.checked_sub(last) {
+            Some(missed) if missed <= MAX_CATCH_UP => last + 1,
+            _ => now,
+        };
+        last = now;
+        for secs in first..=now {
+            for (name, room, data) in due(secs) {
+                match hub.post(&room, &data, None) {
+                    Ok(seq) => {
+                        METRICS.job_runs_total.inc();
+                        log::debug!("Job {} published {} {}", name, room, seq);
+                    }
+                    // the owner publishes it
+                    Err(RoomError::NotOwner) => {}
+                    Err(e) => log::debug!("Job {} not published to {}: {:?}", name, room, e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00:00 UTC, a Monday
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(day: u64, hour: u64, minute: u64, second: u64) -> u64 {
+        MONDAY + ((day * 24 + hour) * 60 + minute) * 60 + second
+    }
+
+    fn bits(values: &[u64]) -> u64 {
+        values.iter().fold(0, |bits, v| bits | 1 << v)
+    }
+
+    #[test]
+    fn ranges_steps_and_lists() {
+        assert_eq!(
+            field("*", 1, 12, "month"),
+            Ok(bits(&(1..=12).collect::<Vec<_>>()))
+        );
+        assert_eq!(field("5", 0, 59, "minute"), Ok(bits(&[5])));
+        assert_eq!(field("9-12", 0, 23, "hour"), Ok(bits(&[9, 10, 11, 12])));
+        assert_eq!(field("*/15", 0, 59, "minute"), Ok(bits(&[0, 15, 30, 45])));
+        assert_eq!(field("10-20/5", 0, 59, "minute"), Ok(bits(&[10, 15, 20])));
+        assert_eq!(field("50/4", 0, 59, "minute"), Ok(bits(&[50, 54, 58])));
+        assert_eq!(field("1,3,5-6", 0, 7, "weekday"), Ok(bits(&[1, 3, 5, 6])));
+        assert_eq!(
+            field("0-1,*/20", 0, 59, "second"),
+            Ok(bits(&[0, 1, 20, 40]))
+        );
+        assert_eq!(field("59", 0, 59, "second"), Ok(bits(&[59])));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for (s, min, max) in [
+            ("60", 0, 59),
+            ("0", 1, 31),
+            ("13", 1, 12),
+            ("5-3", 0, 59),
+            ("*/0", 0, 59),
+            ("1-", 0, 59),
+            ("-1", 0, 59),
+            ("a", 0, 59),
+            ("1,,2", 0, 59),
+            ("", 0, 59),
+            ("*/", 0, 59),
+            ("1/2/3", 0, 59),
+            ("*-5", 0, 59),
+            ("99999999999999999999", 0, 59),
+        ] {
+            assert!(field(s, min, max, "field").is_err(), "{:?} parsed", s);
+        }
+        for s in [
+            "",
+            "* * * *",
+            "* * * * * * *",
+            "* * * 13 *",
+            "* 24 * * *",
+            "* * * * 8",
+        ] {
+            assert!(s.parse::<Cron>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn matches_times() {
+        let cron: Cron = "*/15 9-17 * * 1-5".parse().unwrap();
+        assert!(cron.matches(at(0, 9, 0, 0)));
+        assert!(cron.matches(at(0, 17, 45, 0)));
+        assert!(!cron.matches(at(0, 9, 0, 1)), "second 0 only with 5 fields");
+        assert!(!cron.matches(at(0, 9, 10, 0)));
+        assert!(!cron.matches(at(0, 18, 0, 0)));
+        // Saturday
+        assert!(!cron.matches(at(5, 9, 0, 0)));
+
+        let cron: Cron = "30 0 0 * * *".parse().unwrap();
+        assert!(cron.matches(at(3, 0, 0, 30)));
+        assert!(!cron.matches(at(3, 0, 0, 0)));
+    }
+
+    #[test]
+    fn weekday_seven_is_sunday() {
+        let sunday = at(6, 12, 0, 0);
+        assert!("0 12 * * 7".parse::<Cron>().unwrap().matches(sunday));
+        assert!("0 12 * * 0".parse::<Cron>().unwrap().matches(sunday));
+        assert!(!"0 12 * * 7"
+            .parse::<Cron>()
+            .unwrap()
+            .matches(at(0, 12, 0, 0)));
+    }
+
+    #[test]
+    fn day_or_weekday() {
+        // the 15th, or any Monday
+        let cron: Cron = "0 0 15 * 1".parse().unwrap();
+        assert!(cron.matches(at(7, 0, 0, 0)));
+        // 2024-02-15, a Thursday
+        assert!(cron.matches(at(45, 0, 0, 0)));
+        assert!(!cron.matches(at(1, 0, 0, 0)));
+        // only the day restricted
+        let cron: Cron = "0 0 2 * *".parse().unwrap();
+        assert!(cron.matches(at(1, 0, 0, 0)));
+        assert!(!cron.matches(at(7, 0, 0, 0)));
+    }
+}
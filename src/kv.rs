@@ -0,0 +1,551 @@
+//! Shared key-value state, for what needs no datastore of its own.
+//!
+//! Keys belong to a scope: a room's, shared by its members, or a tenant's,
+//! shared by the connections whose credential names the tenant; the admin
+//! API also has keys of no tenant. A message with a `room` is about the
+//! keys of that room, for its members only, one without about those of the
+//! connection's tenant, `permission_denied` for connections that are
+//! anonymous or only asked for a tenant with `?tenant=`:
+//!
+//! ```json
+//! {"type": "kv.set", "id": "1", "payload": {"room": "lobby", "key": "topic", "value": "standup"}}
+//! {"type": "kv.set", "id": "1", "payload": {"room": "lobby", "key": "topic", "version": 12}}
+//! {"type": "kv.get", "id": "2", "payload": {"key": "theme"}}
+//! {"type": "kv.get", "id": "2", "payload": {"key": "theme", "value": "dark", "version": 3}}
+//! ```
+//!
+//! Every write gets the next version of the store. A `kv.set` or
+//! `kv.delete` with `if_version` only applies if the key is at that version,
+//! 0 for a missing key, and is answered with `conflict` otherwise.
+//! `kv.subscribe` to a key, or without one to every key of the scope, is
+//! answered with their entries and sends `kv.changed` on each write from
+//! then on, `value` null for a delete. Tenant keys are set through the admin
+//! API, by clients too with `kv.tenant_writes`.
+//!
+//! With `kv.file` set every write is also appended to it, replayed and
+//! compacted at startup. Each node keeps keys of its own, they are not
+//! shared across a cluster.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::KvConfig;
+use crate::protocol::{self, ErrorCode};
+
+/// Longest key, bytes
+const MAX_KEY: usize = 256;
+
+/// Subscriptions a connection may have, a subscription to a scope counts
+/// as one
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// Keys shared by the same connections
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Scope {
+    Room(String),
+    /// `None` for state of no tenant, only the admin API reaches it
+    Tenant(Option<String>),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Room(room) => write!(f, "room:{}", room),
+            Scope::Tenant(Some(tenant)) => write!(f, "tenant:{}", tenant),
+            Scope::Tenant(None) => write!(f, "tenant"),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    /// `room:<name>`, `tenant:<name>` or `tenant`
+    fn from_str(s: &str) -> Result<Scope, String> {
+        match s.split_once(':') {
+            Some(("room", room)) if !room.is_empty() => Ok(Scope::Room(room.to_string())),
+            Some(("tenant", tenant)) if !tenant.is_empty() => {
+                Ok(Scope::Tenant(Some(tenant.to_string())))
+            }
+            None if s == "tenant" => Ok(Scope::Tenant(None)),
+            _ => Err(format!(
+                "invalid scope `{}`, expected `room:<name>`, `tenant:<name>` or `tenant`",
+                s
+            )),
+        }
+    }
+}
+
+/// Value of a key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub value: Value,
+    pub version: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvError {
+    BadKey(String),
+    /// Value longer than `kv.max_value_bytes`
+    TooLarge,
+    /// Scope has `kv.max_keys` keys
+    TooManyKeys,
+    /// Store has `kv.max_scopes` scopes
+    TooManyScopes,
+    TooManySubscriptions,
+    /// Key is not at `if_version`, but at this one
+    Conflict(u64),
+    /// Tenant keys are not written by clients
+    ReadOnly,
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::BadKey(reason) => write!(f, "invalid key: {}", reason),
+            KvError::TooLarge => write!(f, "value is too large"),
+            KvError::TooManyKeys => write!(f, "too many keys in the scope"),
+            KvError::TooManyScopes => write!(f, "too many scopes"),
+            KvError::TooManySubscriptions => write!(f, "too many subscriptions"),
+            KvError::Conflict(version) => write!(f, "key is at version {}", version),
+            KvError::ReadOnly => write!(f, "tenant keys are read-only"),
+        }
+    }
+}
+
+impl From<KvError> for protocol::Error {
+    fn from(e: KvError) -> protocol::Error {
+        let code = match e {
+            KvError::BadKey(_) | KvError::TooLarge => ErrorCode::BadPayload,
+            KvError::TooManyKeys | KvError::TooManyScopes | KvError::TooManySubscriptions => {
+                ErrorCode::RateLimited
+            }
+            KvError::Conflict(_) => ErrorCode::Conflict,
+            KvError::ReadOnly => ErrorCode::PermissionDenied,
+        };
+        protocol::Error::new(code, e.to_string())
+    }
+}
+
+/// Line of the store log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Set {
+        scope: String,
+        key: String,
+        value: Value,
+        version: u64,
+    },
+    Delete {
+        scope: String,
+        key: String,
+        version: u64,
+    },
+    /// Last version written, kept by compaction when the key it went to is
+    /// gone
+    Version { version: u64 },
+}
+
+/// Keys a connection follows in a scope
+#[derive(Debug)]
+enum Subscription {
+    All,
+    Keys(BTreeSet<String>),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    scopes: HashMap<Scope, BTreeMap<String, Entry>>,
+    /// Version of the last write
+    version: u64,
+    /// By scope and connection
+    subscriptions: HashMap<Scope, HashMap<u64, Subscription>>,
+    /// Scopes each connection follows keys of
+    followed: HashMap<u64, HashSet<Scope>>,
+}
+
+impl State {
+    /// Connections following `key` of `scope`
+    fn subscribers(&self, scope: &Scope, key: &str) -> Vec<u64> {
+        let Some(subscriptions) = self.subscriptions.get(scope) else {
+            return Vec::new();
+        };
+        subscriptions
+            .iter()
+            .filter(|(_, sub)| match sub {
+                Subscription::All => true,
+                Subscription::Keys(keys) => keys.contains(key),
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn check_version(
+        &self,
+        scope: &Scope,
+        key: &str,
+        if_version: Option<u64>,
+    ) -> Result<(), KvError> {
+        let current = self
+            .scopes
+            .get(scope)
+            .and_then(|keys| keys.get(key))
+            .map_or(0, |entry| entry.version);
+        match if_version {
+            Some(expected) if expected != current => Err(KvError::Conflict(current)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Kv {
+    config: KvConfig,
+    state: Mutex<State>,
+    log: Mutex<Option<BufWriter<File>>>,
+}
+
+impl Kv {
+    /// Empty store kept in memory only
+    pub fn new(config: &KvConfig) -> Kv {
+        Kv {
+            config: config.clone(),
+            state: Mutex::new(State::default()),
+            log: Mutex::new(None),
+        }
+    }
+
+    /// Empty store, or the one replayed from `kv.file`
+    pub fn open(config: &KvConfig) -> io::Result<Kv> {
+        let kv = Kv::new(config);
+        if let Some(ref path) = config.file {
+            let (scopes, version) = match path.exists() {
+                true => replay(path)?,
+                false => (HashMap::new(), 0),
+            };
+            compact(path, &scopes, version)?;
+            log::info!(
+                "Loaded {} keys from {}",
+                scopes.values().map(BTreeMap::len).sum::<usize>(),
+                path.display()
+            );
+            let mut state = kv.state.lock().unwrap();
+            state.scopes = scopes;
+            state.version = version;
+            drop(state);
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            *kv.log.lock().unwrap() = Some(BufWriter::new(file));
+        }
+        Ok(kv)
+    }
+
+    /// Whether clients may write the keys of `scope`
+    pub fn writable(&self, scope: &Scope) -> bool {
+        matches!(scope, Scope::Room(_)) || self.config.tenant_writes
+    }
+
+    pub fn get(&self, scope: &Scope, key: &str) -> Option<Entry> {
+        let state = self.state.lock().unwrap();
+        state.scopes.get(scope)?.get(key).cloned()
+    }
+
+    /// Every key of `scope`
+    pub fn entries(&self, scope: &Scope) -> BTreeMap<String, Entry> {
+        let state = self.state.lock().unwrap();
+        state.scopes.get(scope).cloned().unwrap_or_default()
+    }
+
+    /// Scopes with their number of keys
+    pub fn scopes(&self) -> BTreeMap<String, usize> {
+        let state = self.state.lock().unwrap();
+        state
+            .scopes
+            .iter()
+            .map(|(scope, keys)| (scope.to_string(), keys.len()))
+            .collect()
+    }
+
+    /// Set `key` of `scope` if it is at `if_version`, returns its version
+    /// and the connections to tell
+    pub fn set(
+        &self,
+        scope: &Scope,
+        key: &str,
+        value: Value,
+        if_version: Option<u64>,
+    ) -> Result<(u64, Vec<u64>), KvError> {
+        check_key(key)?;
+        if value.to_string().len() > self.config.max_value_bytes {
+            return Err(KvError::TooLarge);
+        }
+        let mut state = self.state.lock().unwrap();
+        state.check_version(scope, key, if_version)?;
+        match state.scopes.get(scope) {
+            Some(keys) if !keys.contains_key(key) && keys.len() >= self.config.max_keys => {
+                return Err(KvError::TooManyKeys)
+            }
+            None if state.scopes.len() >= self.config.max_scopes => {
+                return Err(KvError::TooManyScopes)
+            }
+            _ => {}
+        }
+        state.version += 1;
+        let version = state.version;
+        self.append(&Record::Set {
+            scope: scope.to_string(),
+            key: key.to_string(),
+            value: value.clone(),
+            version,
+        });
+        state
+            .scopes
+            .entry(scope.clone())
+            .or_default()
+            .insert(key.to_string(), Entry { value, version });
+        Ok((version, state.subscribers(scope, key)))
+    }
+
+    /// Remove `key` of `scope` if it is at `if_version`, returns the version
+    /// of the delete and the connections to tell, `None` if it was missing
+    pub fn delete(
+        &self,
+        scope: &Scope,
+        key: &str,
+        if_version: Option<u64>,
+    ) -> Result<Option<(u64, Vec<u64>)>, KvError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_version(scope, key, if_version)?;
+        let Some(keys) = state.scopes.get_mut(scope) else {
+            return Ok(None);
+        };
+        if keys.remove(key).is_none() {
+            return Ok(None);
+        }
+        if keys.is_empty() {
+            state.scopes.remove(scope);
+        }
+        state.version += 1;
+        let version = state.version;
+        self.append(&Record::Delete {
+            scope: scope.to_string(),
+            key: key.to_string(),
+            version,
+        });
+        Ok(Some((version, state.subscribers(scope, key))))
+    }
+
+    /// Follow `key` of `scope` for connection `id`, every key without one;
+    /// returns the entries followed
+    pub fn subscribe(
+        &self,
+        id: u64,
+        scope: &Scope,
+        key: Option<&str>,
+    ) -> Result<BTreeMap<String, Entry>, KvError> {
+        if let Some(key) = key {
+            check_key(key)?;
+        }
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let followed = state.followed.entry(id).or_default();
+        let count: usize = followed
+            .iter()
+            .filter_map(|scope| state.subscriptions.get(scope)?.get(&id))
+            .map(|sub| match sub {
+                Subscription::All => 1,
+                Subscription::Keys(keys) => keys.len(),
+            })
+            .sum();
+        if count >= MAX_SUBSCRIPTIONS {
+            return Err(KvError::TooManySubscriptions);
+        }
+        followed.insert(scope.clone());
+        let subscription = state
+            .subscriptions
+            .entry(scope.clone())
+            .or_default()
+            .entry(id)
+            .or_insert_with(|| Subscription::Keys(BTreeSet::new()));
+        let entries = state.scopes.get(scope);
+        match key {
+            Some(key) => {
+                if let Subscription::Keys(keys) = subscription {
+                    keys.insert(key.to_string());
+                }
+                let entry = entries.and_then(|keys| keys.get(key));
+                Ok(entry
+                    .map(|entry| (key.to_string(), entry.clone()))
+                    .into_iter()
+                    .collect())
+            }
+            None => {
+                *subscription = Subscription::All;
+                Ok(entries.cloned().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Stop following `key` of `scope` for connection `id`, every key
+    /// without one
+    pub fn unsubscribe(&self, id: u64, scope: &Scope, key: Option<&str>) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Some(subscriptions) = state.subscriptions.get_mut(scope) else {
+            return;
+        };
+        let gone = match (subscriptions.get_mut(&id), key) {
+            (Some(Subscription::Keys(keys)), Some(key)) => {
+                keys.remove(key);
+                keys.is_empty()
+            }
+            (Some(_), _) => true,
+            (None, _) => false,
+        };
+        if gone {
+            subscriptions.remove(&id);
+            if subscriptions.is_empty() {
+                state.subscriptions.remove(scope);
+            }
+            if let Some(followed) = state.followed.get_mut(&id) {
+                followed.remove(scope);
+            }
+        }
+    }
+
+    /// Drop the subscriptions of connection `id`, it closed
+    pub fn disconnected(&self, id: u64) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        for scope in state.followed.remove(&id).unwrap_or_default() {
+            if let Some(subscriptions) = state.subscriptions.get_mut(&scope) {
+                subscriptions.remove(&id);
+                if subscriptions.is_empty() {
+                    state.subscriptions.remove(&scope);
+                }
+            }
+        }
+    }
+
+    fn append(&self, record: &Record) {
+        let mut log = self.log.lock().unwrap();
+        let Some(ref mut out) = *log else {
+            return;
+        };
+        let line = serde_json::to_string(record).expect("record serializes");
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            log::error!("Failed to write the key-value log: {}", e);
+        }
+    }
+}
+
+fn check_key(key: &str) -> Result<(), KvError> {
+    if key.is_empty() {
+        return Err(KvError::BadKey("key is empty".to_string()));
+    }
+    if key.len() > MAX_KEY {
+        return Err(KvError::BadKey(format!("key is over {} bytes", MAX_KEY)));
+    }
+    Ok(())
+}
+
+type Scopes = HashMap<Scope, BTreeMap<String, Entry>>;
+
+/// Keys of the store log with the last version written; malformed records
+/// are skipped
+fn replay(path: &Path) -> io::Result<(Scopes, u64)> {
+    let mut scopes = Scopes::new();
+    let mut last = 0;
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let skip = |e: &dyn fmt::Display| {
+            log::warn!(
+                "{}:{}: skipping key-value record: {}",
+                path.display(),
+                n + 1,
+                e
+            )
+        };
+        let record = match serde_json::from_str::<Record>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                skip(&e);
+                continue;
+            }
+        };
+        // a delete is a write without an entry
+        let (scope, key, version, entry) = match record {
+            Record::Set {
+                scope,
+                key,
+                value,
+                version,
+            } => (scope, key, version, Some(Entry { value, version })),
+            Record::Delete {
+                scope,
+                key,
+                version,
+            } => (scope, key, version, None),
+            Record::Version { version } => {
+                last = last.max(version);
+                continue;
+            }
+        };
+        let scope = match scope.parse::<Scope>() {
+            Ok(scope) => scope,
+            Err(e) => {
+                skip(&e);
+                continue;
+            }
+        };
+        last = last.max(version);
+        match entry {
+            Some(entry) => {
+                scopes.entry(scope).or_default().insert(key, entry);
+            }
+            None => {
+                if let Some(keys) = scopes.get_mut(&scope) {
+                    keys.remove(&key);
+                    if keys.is_empty() {
+                        scopes.remove(&scope);
+                    }
+                }
+            }
+        }
+    }
+    Ok((scopes, last))
+}
+
+/// Rewrite the store log with just the keys of `scopes` and the last
+/// `version` written
+fn compact(path: &Path, scopes: &Scopes, version: u64) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    for (scope, keys) in scopes {
+        for (key, entry) in keys {
+            let record = Record::Set {
+                scope: scope.to_string(),
+                key: key.clone(),
+                value: entry.value.clone(),
+                version: entry.version,
+            };
+            let line = serde_json::to_string(&record).expect("record serializes");
+            writeln!(out, "{}", line)?;
+        }
+    }
+    let line = serde_json::to_string(&Record::Version { version }).expect("record serializes");
+    writeln!(out, "{}", line)?;
+    out.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,168 @@
+//! Labels of connections, set at the handshake.
+//!
+//! `[labels]` names where each label comes from, a claim of the credential
+//! or a header of the handshake; a claim wins over a header, as headers are
+//! whatever the client sends:
+//!
+//! ```toml
+//! [labels]
+//! claims = { plan = "billing.plan" }
+//! headers = { region = "x-region" }
+//! metrics = ["plan"]
+//! ```
+//!
+//! `POST /admin/broadcast?labels=plan=pro,region!=eu` sends to the
+//! connections whose labels match a selector of comma separated
+//! requirements: `key=value`, `key!=value`, `key in (a,b)`,
+//! `key notin (a,b)`, `key` to have the label and `!key` not to. Labels named
+//! in `metrics` are also metric dimensions, see `metrics`.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use ntex::http::header::HeaderName;
+use ntex::web::HttpRequest;
+use serde_json::Value;
+
+use crate::auth::Identity;
+use crate::config::LabelsConfig;
+use crate::oidc::claim;
+
+/// Labels of a connection, by name
+pub type Labels = BTreeMap<String, String>;
+
+/// Longest label value kept, longer ones are cut
+const MAX_VALUE: usize = 128;
+
+/// Check the label config
+pub fn validate(config: &LabelsConfig) -> Result<(), String> {
+    for (label, name) in &config.headers {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!(
+                "labels.headers.{}: invalid header `{}`",
+                label, name
+            ));
+        }
+    }
+    for label in &config.metrics {
+        if !config.claims.contains_key(label) && !config.headers.contains_key(label) {
+            return Err(format!("labels.metrics: `{}` is not a label", label));
+        }
+    }
+    Ok(())
+}
+
+/// Labels of a connection of `identity` opened by `req`
+pub fn of(config: &LabelsConfig, identity: Option<&Identity>, req: &HttpRequest) -> Labels {
+    let mut labels = Labels::new();
+    for (label, name) in &config.headers {
+        if let Some(value) = req.headers().get(name).and_then(|v| v.to_str().ok()) {
+            labels.insert(label.clone(), cut(value));
+        }
+    }
+    let Some(identity) = identity else {
+        return labels;
+    };
+    for (label, path) in &config.claims {
+        let value = match claim(&identity.claims, path) {
+            Some(Value::String(s)) => cut(s),
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+            _ => continue,
+        };
+        labels.insert(label.clone(), value);
+    }
+    labels
+}
+
+fn cut(value: &str) -> String {
+    match value.char_indices().nth(MAX_VALUE) {
+        Some((end, _)) => value[..end].to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    Missing(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+            Requirement::NotIn(key, values) => labels.get(key).is_none_or(|v| !values.contains(v)),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::Missing(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// Which labels a connection must have, matches every one if empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector(Vec<Requirement>);
+
+impl Selector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.0.iter().all(|requirement| requirement.matches(labels))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Selector, String> {
+        let mut requirements = Vec::new();
+        let mut rest = s.trim();
+        while !rest.is_empty() {
+            // a set has commas of its own
+            let end = match rest.find('(') {
+                Some(open) if rest.find(',').is_none_or(|comma| open < comma) => rest[open..]
+                    .find(')')
+                    .map(|close| open + close + 1)
+                    .ok_or_else(|| format!("unclosed set in `{}`", s))?,
+                _ => rest.find(',').unwrap_or(rest.len()),
+            };
+            requirements.push(requirement(rest[..end].trim())?);
+            rest = rest[end..].trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        }
+        Ok(Selector(requirements))
+    }
+}
+
+fn requirement(s: &str) -> Result<Requirement, String> {
+    let key = |key: &str| match key.trim() {
+        "" => Err(format!("requirement `{}` has no label", s)),
+        key => Ok(key.to_string()),
+    };
+    if let Some((k, v)) = s.split_once("!=") {
+        return Ok(Requirement::NotIn(key(k)?, vec![v.trim().to_string()]));
+    }
+    if let Some((k, v)) = s.split_once('=') {
+        return Ok(Requirement::In(key(k)?, vec![v.trim().to_string()]));
+    }
+    if let Some(k) = s.strip_prefix('!') {
+        return Ok(Requirement::Missing(key(k)?));
+    }
+    let set = |values: &str| {
+        values
+            .trim()
+            .strip_prefix('(')
+            .and_then(|values| values.strip_suffix(')'))
+            .map(|values| values.split(',').map(|v| v.trim().to_string()).collect())
+            .ok_or_else(|| format!("invalid set in `{}`", s))
+    };
+    if let Some((k, values)) = s.split_once(" notin ") {
+        return Ok(Requirement::NotIn(key(k)?, set(values)?));
+    }
+    if let Some((k, values)) = s.split_once(" in ") {
+        return Ok(Requirement::In(key(k)?, set(values)?));
+    }
+    match s.contains(char::is_whitespace) {
+        true => Err(format!("invalid requirement `{}`", s)),
+        false => Ok(Requirement::Exists(key(s)?)),
+    }
+}
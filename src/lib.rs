@@ -0,0 +1,73 @@
+//! Websocket server internals, shared by the binary and benchmarks.
+
+pub mod accept;
+pub mod accesslog;
+pub mod acks;
+pub mod admin;
+pub mod affinity;
+pub mod aggregate;
+pub mod archive;
+pub mod asyncapi;
+pub mod auth;
+pub mod bandwidth;
+pub mod bans;
+pub mod challenge;
+pub mod channels;
+pub mod chaos;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod crdt;
+pub mod crypto;
+pub mod dashboard;
+pub mod events;
+pub mod expr;
+pub mod fanout;
+pub mod files;
+pub mod filter;
+pub mod flags;
+pub mod framing;
+pub mod governor;
+pub mod graphql;
+pub mod grpc;
+pub mod headers;
+pub mod history;
+pub mod hub;
+pub mod inflight;
+pub mod invites;
+pub mod ipfilter;
+pub mod jobs;
+pub mod kv;
+pub mod labels;
+pub mod locks;
+pub mod logging;
+pub mod metrics;
+pub mod oidc;
+pub mod pacing;
+pub mod pipeline;
+pub mod pool;
+pub mod profiling;
+pub mod protocol;
+pub mod push;
+pub mod reconnect;
+pub mod record;
+pub mod redirect;
+pub mod redis;
+pub mod reload;
+pub mod reporting;
+pub mod rolling;
+pub mod rooms;
+pub mod rpc;
+pub mod s3;
+pub mod schedule;
+pub mod schema;
+pub mod session;
+pub mod statsd;
+pub mod store;
+pub mod stream;
+pub mod tap;
+pub mod tasks;
+pub mod tls;
+pub mod typescript;
+pub mod utf8;
@@ -0,0 +1,471 @@
+//! Leases on names, so that one client at a time drives a device or leads
+//! the others.
+//!
+//! Locks belong to the scopes of `kv`, a room's, for its members, or a
+//! tenant's, for connections whose credential names it. `lock.acquire`
+//! takes a lock for `ttl_ms` and is answered with a fencing token, higher
+//! for every grant, that the holder can hand to what it drives so that
+//! requests of an earlier holder are told apart:
+//!
+//! ```json
+//! {"type": "lock.acquire", "id": "1", "payload": {"room": "plant", "name": "pump-3", "ttl_ms": 10000, "wait_ms": 30000}}
+//! {"type": "lock.acquire", "id": "1", "payload": {"room": "plant", "name": "pump-3", "token": 17, "expires_at": 1700000010000}}
+//! {"type": "lock.renew", "id": "2", "payload": {"room": "plant", "name": "pump-3", "ttl_ms": 10000}}
+//! {"type": "lock.release", "id": "3", "payload": {"room": "plant", "name": "pump-3"}}
+//! ```
+//!
+//! A held lock is refused with `conflict`, unless the acquire has a
+//! `wait_ms`: it is then answered once the lock is granted to it, in the
+//! order of the waits, or with `timeout` when the wait is over. The holder
+//! renews the lease before it expires; a lease that expires, or that an
+//! admin releases, is taken away with `lock.lost`. Locks of a connection
+//! are released when it closes, and its waits dropped.
+//!
+//! Locks are kept by each node, they are not shared across a cluster.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ntex::{time, ws};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::config::LocksConfig;
+use crate::history::now_millis;
+use crate::hub::Hub;
+use crate::kv::Scope;
+use crate::protocol::{self, ErrorCode};
+
+/// Longest lock name, bytes
+const MAX_NAME: usize = 256;
+
+/// Connections waiting for a lock at once
+const MAX_WAITERS: usize = 64;
+
+/// How often expired leases and waits are looked for
+const TICK: Duration = Duration::from_millis(100);
+
+/// Lease of a lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Grant {
+    /// Higher for every grant of the store
+    pub token: u64,
+    /// Unix ms
+    pub expires_at: u64,
+}
+
+/// Holder of a lock, as listed by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct Holder {
+    pub conn: u64,
+    #[serde(flatten)]
+    pub grant: Grant,
+    pub waiting: usize,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    conn: u64,
+    /// Of the `lock.acquire` to answer
+    id: Option<String>,
+    ttl_ms: u64,
+    /// End of the wait, unix ms
+    until: u64,
+}
+
+#[derive(Debug)]
+struct Lock {
+    conn: u64,
+    grant: Grant,
+    waiters: VecDeque<Waiter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockError {
+    BadName(String),
+    BadTtl(String),
+    /// Held by another connection, and the acquire does not wait
+    Held,
+    /// Renewed or released by a connection not holding it
+    NotHolder,
+    /// Store has `locks.max_locks` locks
+    TooManyLocks,
+    TooManyWaiters,
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::BadName(reason) => write!(f, "invalid name: {}", reason),
+            LockError::BadTtl(reason) => f.write_str(reason),
+            LockError::Held => write!(f, "lock is held by another connection"),
+            LockError::NotHolder => write!(f, "lock is not held by this connection"),
+            LockError::TooManyLocks => write!(f, "too many locks"),
+            LockError::TooManyWaiters => write!(f, "too many connections wait for the lock"),
+        }
+    }
+}
+
+impl From<LockError> for protocol::Error {
+    fn from(e: LockError) -> protocol::Error {
+        let code = match e {
+            LockError::BadName(_) | LockError::BadTtl(_) => ErrorCode::BadPayload,
+            LockError::Held | LockError::NotHolder => ErrorCode::Conflict,
+            LockError::TooManyLocks | LockError::TooManyWaiters => ErrorCode::RateLimited,
+        };
+        protocol::Error::new(code, e.to_string())
+    }
+}
+
+/// What a connection is told about a lock outside of the answers to its
+/// messages
+#[derive(Debug)]
+pub enum Notice {
+    /// Waited for and granted, answers its `lock.acquire`
+    Granted {
+        conn: u64,
+        id: Option<String>,
+        scope: Scope,
+        name: String,
+        grant: Grant,
+    },
+    /// Not granted within the wait
+    TimedOut {
+        conn: u64,
+        id: Option<String>,
+        scope: Scope,
+        name: String,
+    },
+    /// Lease expired or was released by an admin
+    Lost {
+        conn: u64,
+        scope: Scope,
+        name: String,
+        reason: &'static str,
+    },
+}
+
+impl Notice {
+    pub fn conn(&self) -> u64 {
+        match self {
+            Notice::Granted { conn, .. }
+            | Notice::TimedOut { conn, .. }
+            | Notice::Lost { conn, .. } => *conn,
+        }
+    }
+
+    pub fn to_message(&self) -> ws::Message {
+        match self {
+            Notice::Granted {
+                id,
+                scope,
+                name,
+                grant,
+                ..
+            } => protocol::message(
+                "lock.acquire",
+                id.as_deref(),
+                &payload(
+                    scope,
+                    name,
+                    json!({ "token": grant.token, "expires_at": grant.expires_at }),
+                ),
+            ),
+            Notice::TimedOut { id, name, .. } => protocol::Error::new(
+                ErrorCode::Timeout,
+                format!("lock `{}` was not granted in time", name),
+            )
+            .to_message(id.as_deref()),
+            Notice::Lost {
+                scope,
+                name,
+                reason,
+                ..
+            } => protocol::message(
+                "lock.lost",
+                None,
+                &payload(scope, name, json!({ "reason": reason })),
+            ),
+        }
+    }
+}
+
+/// Payload about lock `name` of `scope`, `fields` with its name and room
+pub fn payload(scope: &Scope, name: &str, mut fields: Value) -> Value {
+    fields["name"] = json!(name);
+    if let Scope::Room(room) = scope {
+        fields["room"] = json!(room);
+    }
+    fields
+}
+
+#[derive(Debug, Default)]
+struct State {
+    locks: HashMap<(Scope, String), Lock>,
+    /// Token of the last grant
+    token: u64,
+}
+
+impl State {
+    fn grant(&mut self, ttl_ms: u64, now: u64) -> Grant {
+        self.token += 1;
+        Grant {
+            token: self.token,
+            expires_at: now + ttl_ms,
+        }
+    }
+
+    /// Hand lock `key` to its next waiter still waiting, removing it if
+    /// there is none
+    fn hand_over(&mut self, key: (Scope, String), now: u64, notices: &mut Vec<Notice>) {
+        let Some(mut lock) = self.locks.remove(&key) else {
+            return;
+        };
+        while let Some(waiter) = lock.waiters.pop_front() {
+            if waiter.until < now {
+                let (scope, name) = key.clone();
+                notices.push(Notice::TimedOut {
+                    conn: waiter.conn,
+                    id: waiter.id,
+                    scope,
+                    name,
+                });
+                continue;
+            }
+            let grant = self.grant(waiter.ttl_ms, now);
+            let (scope, name) = key.clone();
+            notices.push(Notice::Granted {
+                conn: waiter.conn,
+                id: waiter.id,
+                scope,
+                name,
+                grant,
+            });
+            lock.conn = waiter.conn;
+            lock.grant = grant;
+            self.locks.insert(key, lock);
+            return;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Locks {
+    config: LocksConfig,
+    state: Mutex<State>,
+}
+
+impl Locks {
+    pub fn new(config: &LocksConfig) -> Locks {
+        Locks {
+            config: config.clone(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Lease length of `ttl_ms`, the default if unset
+    fn ttl(&self, ttl_ms: Option<u64>) -> Result<u64, LockError> {
+        match ttl_ms.unwrap_or(self.config.default_ttl_ms) {
+            0 => Err(LockError::BadTtl("`ttl_ms` must be positive".to_string())),
+            ttl if ttl > self.config.max_ttl_ms => Err(LockError::BadTtl(format!(
+                "`ttl_ms` must be at most {}",
+                self.config.max_ttl_ms
+            ))),
+            ttl => Ok(ttl),
+        }
+    }
+
+    /// Take lock `name` of `scope` for connection `conn`, renewing it if
+    /// `conn` holds it. `None` if it waits for the lock, `wait` being the
+    /// id of its `lock.acquire` and how long it waits.
+    pub fn acquire(
+        &self,
+        conn: u64,
+        scope: &Scope,
+        name: &str,
+        ttl_ms: Option<u64>,
+        wait: Option<(Option<&str>, u64)>,
+    ) -> Result<Option<Grant>, LockError> {
+        check_name(name)?;
+        let ttl_ms = self.ttl(ttl_ms)?;
+        let now = now_millis();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let key = (scope.clone(), name.to_string());
+        if !state.locks.contains_key(&key) {
+            if state.locks.len() >= self.config.max_locks {
+                return Err(LockError::TooManyLocks);
+            }
+            let grant = state.grant(ttl_ms, now);
+            let lock = Lock {
+                conn,
+                grant,
+                waiters: VecDeque::new(),
+            };
+            state.locks.insert(key, lock);
+            return Ok(Some(grant));
+        }
+        let lock = state.locks.get_mut(&key).expect("lock is held");
+        if lock.conn == conn {
+            lock.grant.expires_at = now + ttl_ms;
+            return Ok(Some(lock.grant));
+        }
+        let Some((id, wait_ms)) = wait else {
+            return Err(LockError::Held);
+        };
+        if lock.waiters.len() >= MAX_WAITERS {
+            return Err(LockError::TooManyWaiters);
+        }
+        lock.waiters.push_back(Waiter {
+            conn,
+            id: id.map(str::to_string),
+            ttl_ms,
+            until: now + wait_ms.min(self.config.max_wait_ms),
+        });
+        Ok(None)
+    }
+
+    /// Extend the lease of connection `conn` on lock `name` of `scope`
+    pub fn renew(
+        &self,
+        conn: u64,
+        scope: &Scope,
+        name: &str,
+        ttl_ms: Option<u64>,
+    ) -> Result<Grant, LockError> {
+        let ttl_ms = self.ttl(ttl_ms)?;
+        let mut state = self.state.lock().unwrap();
+        match state.locks.get_mut(&(scope.clone(), name.to_string())) {
+            Some(lock) if lock.conn == conn => {
+                lock.grant.expires_at = now_millis() + ttl_ms;
+                Ok(lock.grant)
+            }
+            _ => Err(LockError::NotHolder),
+        }
+    }
+
+    /// Give up lock `name` of `scope` held by connection `conn`, handing it
+    /// to its next waiter
+    pub fn release(&self, conn: u64, scope: &Scope, name: &str) -> Result<Vec<Notice>, LockError> {
+        let mut state = self.state.lock().unwrap();
+        let key = (scope.clone(), name.to_string());
+        match state.locks.get(&key) {
+            Some(lock) if lock.conn == conn => {}
+            _ => return Err(LockError::NotHolder),
+        }
+        let mut notices = Vec::new();
+        state.hand_over(key, now_millis(), &mut notices);
+        Ok(notices)
+    }
+
+    /// Take lock `name` of `scope` away from its holder, `None` if it is
+    /// not held
+    pub fn force_release(&self, scope: &Scope, name: &str) -> Option<Vec<Notice>> {
+        let mut state = self.state.lock().unwrap();
+        let key = (scope.clone(), name.to_string());
+        let conn = state.locks.get(&key)?.conn;
+        let mut notices = vec![Notice::Lost {
+            conn,
+            scope: scope.clone(),
+            name: name.to_string(),
+            reason: "released",
+        }];
+        state.hand_over(key, now_millis(), &mut notices);
+        Some(notices)
+    }
+
+    /// Release the locks of connection `conn` and drop its waits, it closed
+    pub fn disconnected(&self, conn: u64) -> Vec<Notice> {
+        let mut state = self.state.lock().unwrap();
+        let mut held = Vec::new();
+        for (key, lock) in state.locks.iter_mut() {
+            lock.waiters.retain(|waiter| waiter.conn != conn);
+            if lock.conn == conn {
+                held.push(key.clone());
+            }
+        }
+        let mut notices = Vec::new();
+        let now = now_millis();
+        for key in held {
+            state.hand_over(key, now, &mut notices);
+        }
+        notices
+    }
+
+    /// Take away the leases that expired and end the waits that are over
+    fn expire(&self, now: u64) -> Vec<Notice> {
+        let mut state = self.state.lock().unwrap();
+        let mut notices = Vec::new();
+        let mut expired = Vec::new();
+        for (key, lock) in state.locks.iter_mut() {
+            if lock.grant.expires_at <= now {
+                expired.push(key.clone());
+                continue;
+            }
+            while let Some(i) = lock.waiters.iter().position(|waiter| waiter.until < now) {
+                let waiter = lock.waiters.remove(i).expect("position is in the queue");
+                let (scope, name) = key.clone();
+                notices.push(Notice::TimedOut {
+                    conn: waiter.conn,
+                    id: waiter.id,
+                    scope,
+                    name,
+                });
+            }
+        }
+        for key in expired {
+            let conn = state.locks[&key].conn;
+            let (scope, name) = key.clone();
+            notices.push(Notice::Lost {
+                conn,
+                scope,
+                name,
+                reason: "expired",
+            });
+            state.hand_over(key, now, &mut notices);
+        }
+        notices
+    }
+
+    /// Held locks by scope and name
+    pub fn list(&self) -> BTreeMap<String, BTreeMap<String, Holder>> {
+        let state = self.state.lock().unwrap();
+        let mut scopes: BTreeMap<String, BTreeMap<String, Holder>> = BTreeMap::new();
+        for ((scope, name), lock) in &state.locks {
+            let holder = Holder {
+                conn: lock.conn,
+                grant: lock.grant,
+                waiting: lock.waiters.len(),
+            };
+            scopes
+                .entry(scope.to_string())
+                .or_default()
+                .insert(name.clone(), holder);
+        }
+        scopes
+    }
+}
+
+fn check_name(name: &str) -> Result<(), LockError> {
+    if name.is_empty() {
+        return Err(LockError::BadName("name is empty".to_string()));
+    }
+    if name.len() > MAX_NAME {
+        return Err(LockError::BadName(format!(
+            "name is over {} bytes",
+            MAX_NAME
+        )));
+    }
+    Ok(())
+}
+
+/// Expire leases and waits as their time comes
+pub async fn run(hub: Arc<Hub>) {
+    loop {
+        time::sleep(TICK).await;
+        let notices = hub.locks().expire(now_millis());
+        hub.lock_notices(notices);
+    }
+}
@@ -0,0 +1,157 @@
+//! Reloadable logger.
+//!
+//! Wraps `env_logger` so the filter can be replaced at runtime
+//! (see `PUT /admin/log-level`).
+
+use std::collections::BTreeMap;
+use std::{fmt, str::FromStr, sync::OnceLock, sync::RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::config::LogConfig;
+use crate::rolling::SharedFile;
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+/// Default level plus per-module overrides
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLevels {
+    pub level: LevelFilter,
+    #[serde(default)]
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+impl LogLevels {
+    /// Parse `env_logger` style spec: `level,module=level,...`
+    pub fn parse(spec: &str) -> Result<LogLevels, String> {
+        let mut levels = LogLevels {
+            level: LevelFilter::Error,
+            modules: BTreeMap::new(),
+        };
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => {
+                    let level = LevelFilter::from_str(level.trim())
+                        .map_err(|_| format!("invalid level in `{}`", part))?;
+                    levels.modules.insert(module.trim().to_string(), level);
+                }
+                None => match LevelFilter::from_str(part) {
+                    Ok(level) => levels.level = level,
+                    // bare module name enables everything for it
+                    Err(_) => {
+                        levels.modules.insert(part.to_string(), LevelFilter::Trace);
+                    }
+                },
+            }
+        }
+        Ok(levels)
+    }
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level.to_string().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+struct ReloadableLogger {
+    inner: RwLock<(LogLevels, env_logger::Logger)>,
+    /// Log file instead of stderr, if configured
+    file: Option<SharedFile>,
+}
+
+fn build(levels: &LogLevels, file: Option<&SharedFile>) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&levels.to_string());
+    if let Some(file) = file {
+        builder.target(env_logger::Target::Pipe(Box::new(file.clone())));
+    }
+    builder.build()
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().1.log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().1.flush()
+    }
+}
+
+/// Install global logger with initial filter and output
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let levels = LogLevels::parse(&config.filter)?;
+    let file = match config.file {
+        Some(ref file) => Some(SharedFile::open(file).map_err(|e| format!("log.file: {}", e))?),
+        None => None,
+    };
+    let logger = build(&levels, file.as_ref());
+    let max = logger.filter();
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: RwLock::new((levels, logger)),
+        file,
+    });
+    log::set_logger(logger).map_err(|e| e.to_string())?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+/// Current log levels
+pub fn levels() -> Option<LogLevels> {
+    LOGGER.get().map(|l| l.inner.read().unwrap().0.clone())
+}
+
+/// Replace active log levels
+pub fn set_levels(levels: LogLevels) {
+    if let Some(l) = LOGGER.get() {
+        let logger = build(&levels, l.file.as_ref());
+        log::set_max_level(logger.filter());
+        *l.inner.write().unwrap() = (levels, logger);
+    }
+}
+
+/// UTC calendar time, for timestamps in log lines and requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12
+    pub month: i64,
+    pub day: i64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+impl DateTime {
+    pub fn from_unix(secs: u64) -> DateTime {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // civil date from days since the epoch, after Howard Hinnant
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        DateTime {
+            year: yoe + era * 400 + i64::from(month <= 2),
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem % 3600 / 60,
+            second: rem % 60,
+        }
+    }
+}
@@ -1,138 +1,245 @@
 //! Simple echo websocket server.
 //! Open `http://localhost:8080/ws/index.html` in browser
 
-use std::{cell::RefCell, io, rc::Rc, time::Duration, time::Instant};
-use std::fs::File;
-use std::io::BufReader;
-
-// use ntex_files::Files;
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, rsa_private_keys};
-
-use futures::future::{ready, select, Either};
-use ntex::service::{fn_factory_with_config, fn_service, Service};
-use ntex::web::{self, middleware, ws, App, Error, HttpRequest, HttpResponse};
-use ntex::{channel::oneshot, rt, time, util::Bytes};
-use ntex_files as fs;
-
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-
-struct WsState {
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
-    /// otherwise we drop connection.
-    hb: Instant,
-}
-
-/// WebSockets service factory
-async fn ws_service(
-    sink: ws::WsSink,
-) -> Result<
-    impl Service<ws::Frame, Response = Option<ws::Message>, Error = io::Error>,
-    web::Error,
-> {
-    let state = Rc::new(RefCell::new(WsState { hb: Instant::now() }));
-
-    // disconnect notification
-    let (tx, rx) = oneshot::channel();
-
-    // start heartbeat task
-    rt::spawn(heartbeat(state.clone(), sink, rx));
-
-    // websockets handler service
-    Ok(fn_service(move |frame| {
-        println!("WS Frame: {:?}", frame);
-
-        let item = match frame {
-            ws::Frame::Ping(msg) => {
-                (*state.borrow_mut()).hb = Instant::now();
-                ws::Message::Pong(msg)
-            }
-            ws::Frame::Text(text) => ws::Message::Text(
-                String::from_utf8(Vec::from(text.as_ref())).unwrap().into(),
-            ),
-            ws::Frame::Binary(bin) => ws::Message::Binary(bin),
-            ws::Frame::Close(reason) => ws::Message::Close(reason),
-            _ => ws::Message::Close(None),
-        };
-        ready(Ok(Some(item)))
-    })
-    // on_shutdown callback is being called when service get shutdowned by dispatcher
-    // in this case when connection get dropped
-    .on_shutdown(move || {
-        let _ = tx.send(());
-    }))
-}
-
-/// helper method that sends ping to client every heartbeat interval
-async fn heartbeat(
-    state: Rc<RefCell<WsState>>,
-    sink: ws::WsSink,
-    mut rx: oneshot::Receiver<()>,
-) {
-    loop {
-        match select(Box::pin(time::sleep(HEARTBEAT_INTERVAL)), &mut rx).await {
-            Either::Left(_) => {
-                // check client heartbeats
-                if Instant::now().duration_since(state.borrow().hb) > CLIENT_TIMEOUT {
-                    // heartbeat timed out
-                    println!("Websocket Client heartbeat failed, disconnecting!");
-                    return;
-                }
-
-                // send ping
-                if sink.send(ws::Message::Ping(Bytes::new())).await.is_err() {
-                    return;
-                }
-            }
-            Either::Right(_) => {
-                println!("Connection is dropped, stop heartbeat task");
-                return;
-            }
-        }
-    }
-}
-
-/// do websocket handshake and start web sockets service
-async fn ws_index(req: HttpRequest) -> Result<HttpResponse, Error> {
-    ws::start(req, fn_factory_with_config(ws_service)).await
-}
+use std::io;
+use std::sync::Arc;
+
+use ntex::web::{self, App};
+
+use websocket_server::accept::AcceptLimiter;
+use websocket_server::archive::Archive;
+use websocket_server::auth::Authenticator;
+use websocket_server::chaos::Chaos;
+use websocket_server::cluster::Cluster;
+use websocket_server::config::Config;
+use websocket_server::cors::Cors;
+use websocket_server::crdt::Crdts;
+use websocket_server::history::History;
+use websocket_server::hub::Hub;
+use websocket_server::kv::Kv;
+use websocket_server::locks::Locks;
+use websocket_server::push::{self, Push};
+use websocket_server::redirect::Redirect;
+use websocket_server::schedule::Schedule;
+use websocket_server::session::ws_index;
+use websocket_server::store::Store;
+use websocket_server::{
+    accesslog, admin, affinity, aggregate, archive, asyncapi, challenge, channels, chaos, cluster,
+    events, fanout, files, flags, grpc, headers, inflight, ipfilter, jobs, labels, locks, logging,
+    metrics, oidc, pacing, pipeline, pool, reconnect, reload, reporting, rooms, schedule, statsd,
+    store, tls, typescript,
+};
 
 #[ntex::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "ntex=trace");
-    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--export-asyncapi") {
+        let doc = serde_json::to_string_pretty(&asyncapi::document())?;
+        println!("{}", doc);
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--export-typescript") {
+        print!("{}", typescript::module());
+        return Ok(());
+    }
 
-    // load ssl keys
-    let key_file = &mut BufReader::new(File::open("key.pem").unwrap());
-    let key = PrivateKey(rsa_private_keys(key_file).unwrap().remove(0));
-    let cert_file = &mut BufReader::new(File::open("cert.pem").unwrap());
-    let cert_chain = certs(cert_file)
-        .unwrap()
+    // `--set section.key=value` config overrides
+    let overrides: Vec<String> = args
         .iter()
-        .map(|c| Certificate(c.to_vec()))
+        .zip(args.iter().skip(1))
+        .filter(|(flag, _)| *flag == "--set")
+        .map(|(_, value)| value.clone())
         .collect();
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)
-        .unwrap();
-
-    web::server(|| {
+    let loaded = Config::load(&overrides)?;
+    // `--dev`: high ports and a self-signed certificate, no PEM files needed
+    let dev = args.iter().any(|arg| arg == "--dev");
+    let config = match dev {
+        true => Arc::new(loaded.clone().dev()),
+        false => Arc::new(loaded.clone()),
+    };
+    logging::init(&config.log).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    accesslog::init(&config.access_log)?;
+    metrics::init_rooms(config.room_metrics.as_ref());
+    labels::validate(&config.labels).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    metrics::init_labels(&config.labels);
+    events::subscribe(Arc::new(metrics::MetricsEvents));
+    events::subscribe(Arc::new(accesslog::SessionLog));
+    flags::init(&config.flags).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    ipfilter::init(&config.ip_filter)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    jobs::set_configured(&config.jobs)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let _sentry = config.sentry.as_ref().map(reporting::init);
+    pool::init(config.pool.clone());
+    channels::init();
+    if let Some(ref fanout) = config.hub.fanout {
+        fanout::init(fanout)?;
+    }
+    let cluster = match config.cluster {
+        Some(ref cluster) => {
+            let cluster = Cluster::new(cluster.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Some(Arc::new(cluster))
+        }
+        None => None,
+    };
+    let hub = Hub::new(
+        &config.hub,
+        config.rooms.clone(),
+        History::open(&config.history)?,
+        cluster.clone(),
+    )
+    .with_schedule(Schedule::open(&config.schedule)?)
+    .with_kv(Kv::open(&config.kv)?)
+    .with_crdts(Crdts::new(
+        &config.crdt,
+        config.cluster.as_ref().map(|c| c.node.as_str()),
+    ))
+    .with_locks(Locks::new(&config.locks));
+    let hub = match config.store {
+        Some(ref store) => {
+            let node = config.cluster.as_ref().map(|c| c.node.as_str());
+            let store = Store::new(store.clone(), node)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let store = Arc::new(store);
+            ntex::rt::spawn(store::run(store.clone()));
+            hub.with_store(store)
+        }
+        None => hub,
+    };
+    if let Some(ref canary) = config.canary {
+        pipeline::validate(canary).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    push::validate(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let hub = match config.push {
+        Some(ref push) => {
+            let push =
+                Push::open(push).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            hub.with_push(push)
+        }
+        None => hub,
+    };
+    let hub = match config.chaos {
+        Some(ref config) => {
+            chaos::validate(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            log::warn!("Chaos mode: injecting faults into deliveries, {:?}", config);
+            hub.with_chaos(Chaos::new(config.clone()))
+        }
+        None => hub,
+    };
+    let hub = Arc::new(hub);
+    ntex::rt::spawn(rooms::run(hub.clone()));
+    ntex::rt::spawn(aggregate::run(hub.clone()));
+    ntex::rt::spawn(pacing::run(hub.clone()));
+    ntex::rt::spawn(schedule::run(hub.clone()));
+    ntex::rt::spawn(locks::run(hub.clone()));
+    ntex::rt::spawn(jobs::run(hub.clone()));
+    if let Some(cluster) = cluster {
+        ntex::rt::spawn(cluster::run(cluster, hub.clone()));
+    }
+    let archive = match config.history.archive {
+        Some(ref archive) => {
+            let archive = Archive::new(archive.clone(), config.history.encryption.as_ref())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let archive = Arc::new(archive);
+            ntex::rt::spawn(archive::run(archive.clone(), hub.clone()));
+            Some(archive)
+        }
+        None => None,
+    };
+    let auth = Arc::new(Authenticator::new(
+        &config.auth,
+        config.cluster.as_ref().map(|c| c.node.as_str()),
+    ));
+    let limiter = Arc::new(AcceptLimiter::new(&config.accept));
+    if let Some(oidc) = auth.oidc() {
+        ntex::rt::spawn(oidc::refresh_keys(oidc));
+    }
+    if let Some(ref statsd) = config.statsd {
+        ntex::rt::spawn(statsd::run(statsd.clone()));
+    }
+    if let Some(ref grpc) = config.grpc {
+        let listener = std::net::TcpListener::bind(&grpc.address)?;
+        log::info!("Serving the gRPC admin API on {}", grpc.address);
+        ntex::rt::spawn(grpc::run(listener, config.clone(), hub.clone()));
+    }
+    affinity::validate(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    reconnect::validate(&config.reconnect)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    inflight::validate(&config.handlers)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    ntex::rt::spawn(reconnect::on_shutdown(
+        hub.clone(),
+        config.reconnect.clone(),
+    ));
+    ntex::rt::spawn(reload::run(Config::path(), overrides, loaded));
+
+    let listeners = config.listeners.clone();
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no listeners configured",
+        ));
+    }
+    // load ssl keys, only needed by tls listeners
+    let tls_config = if listeners.iter().any(|l| l.tls) {
+        let (tls_config, resolver) = if dev {
+            log::warn!("Development mode, serving a self-signed certificate");
+            tls::self_signed(&config.tls)?
+        } else {
+            tls::server_config(&config.tls)?
+        };
+        ntex::rt::spawn(tls::refresh_ocsp(resolver, config.tls.clone()));
+        Some(tls_config)
+    } else {
+        None
+    };
+
+    if !config.static_files.root.is_dir() {
+        log::warn!(
+            "Static root {} is not a directory, not serving files",
+            config.static_files.root.display()
+        );
+    }
+    let cors =
+        Cors::new(&config.cors).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    headers::validate(&config.security_headers)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let redirect =
+        Redirect::new(&listeners).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut server = web::server(move || {
         App::new()
-            // enable logger
-            .wrap(middleware::Logger::default())
+            .state(config.clone())
+            .state(hub.clone())
+            .state(auth.clone())
+            .state(limiter.clone())
+            .state(archive.clone())
+            .wrap(headers::middleware(&config.security_headers))
+            .wrap(cors.clone())
+            // plaintext listeners with `redirect_https`
+            .wrap(redirect.clone())
+            .wrap(ipfilter::IpFilter)
+            .wrap(accesslog::AccessLog)
+            // admin api
+            .configure(admin::configure)
+            // calls of other cluster nodes
+            .configure(cluster::configure)
+            // prometheus metrics
+            .service(web::resource("/metrics").route(web::get().to(metrics::index)))
             // websocket route
             .service(web::resource("/ws").route(web::get().to(ws_index)))
+            .service(web::resource("/ws/challenge").route(web::get().to(challenge::index)))
             // static files
-            .service(fs::Files::new("/", "./").index_file("index.html").show_files_listing())
-            // .service(Files::new("/static", "static"))
-    })
-    // start http server on 127.0.0.1:8080
-    .bind("0.0.0.0:80")?
-    .bind_rustls("0.0.0.0:443", config)?
-    .run()
-    .await
+            .configure(|cfg| files::configure(cfg, &config.static_files))
+    });
+    for listener in &listeners {
+        server = match tls_config {
+            Some(ref tls_config) if listener.tls => {
+                server.bind_rustls(&listener.address, tls_config.clone())?
+            }
+            _ => server.bind(&listener.address)?,
+        };
+    }
+    server.run().await
 }
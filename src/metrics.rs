@@ -0,0 +1,950 @@
+//! Process-wide metrics, rendered in Prometheus text format at `GET /metrics`.
+//!
+//! With `[room_metrics]` there are also metrics labeled by `room`: members,
+//! messages published and dropped, and the time to queue a room message for
+//! its members. To keep the number of series bounded, rooms matching a
+//! `rooms` pattern are labeled with their name, up to `max_rooms` of them;
+//! rooms matching an `aggregate` pattern with the pattern, and all others
+//! with `_other`. A label stays once used, until restart.
+//!
+//! Connection labels named in `labels.metrics` label metrics by `label` and
+//! `value`: open connections, handshakes, and the bytes received and sent
+//! by connections, counted when they close. The first `labels.max_values`
+//! values of each label are kept, further ones count as `_other`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ntex::web::HttpResponse;
+
+use crate::config::{self, LabelsConfig, RoomMetricsConfig};
+use crate::events::{Event, Subscriber};
+use crate::hub::ConnStats;
+
+/// Latency buckets, seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets for in-process work measured in microseconds, seconds
+const FAST_BUCKETS: &[f64] = &[
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1,
+    0.5, 1.0,
+];
+
+/// Quantiles exported for every histogram
+const QUANTILES: &[f64] = &[0.5, 0.95, 0.99];
+
+/// Max number of buckets a histogram can have
+const MAX_BUCKETS: usize = 16;
+
+/// Global metrics
+pub static METRICS: Metrics = Metrics {
+    connections_total: Counter::new(),
+    connections_active: Gauge::new(),
+    handshakes_throttled_total: Counter::new(),
+    affinity_misses_total: Counter::new(),
+    store_errors_total: Counter::new(),
+    frames_received_total: Counter::new(),
+    handler_panics_total: Counter::new(),
+    handler_timeouts_total: Counter::new(),
+    handlers_cancelled_total: Counter::new(),
+    messages_rejected_total: Counter::new(),
+    pool_hits_total: Counter::new(),
+    redeliveries_total: Counter::new(),
+    scheduled_total: Counter::new(),
+    job_runs_total: Counter::new(),
+    pool_misses_total: Counter::new(),
+    pool_discarded_total: Counter::new(),
+    broadcasts_total: Counter::new(),
+    broadcast_recipients_total: Counter::new(),
+    messages_dropped_total: Counter::new(),
+    rooms_expired_total: Counter::new(),
+    messages_archived_total: Counter::new(),
+    outbound_throttled_total: Counter::new(),
+    messages_filtered_total: Counter::new(),
+    filter_errors_total: Counter::new(),
+    pushes_sent_total: Counter::new(),
+    pushes_failed_total: Counter::new(),
+    pushes_rate_limited_total: Counter::new(),
+    chaos_delayed_total: Counter::new(),
+    chaos_dropped_total: Counter::new(),
+    chaos_duplicated_total: Counter::new(),
+    chaos_disconnects_total: Counter::new(),
+    rtt_seconds: Histogram::new(LATENCY_BUCKETS),
+    broadcast_fanout_seconds: Histogram::new(FAST_BUCKETS),
+    handler_seconds: Histogram::new(FAST_BUCKETS),
+    queue_wait_seconds: Histogram::new(LATENCY_BUCKETS),
+    outbound_throttle_seconds: Histogram::new(LATENCY_BUCKETS),
+};
+
+/// Monotonic counter
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of durations over fixed buckets
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Non-cumulative count per bucket, last one is `+Inf`
+    buckets: [AtomicU64; MAX_BUCKETS],
+    /// Sum of observations, microseconds
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// `bounds` are bucket upper bounds in seconds, ascending
+    pub const fn new(bounds: &'static [f64]) -> Histogram {
+        assert!(bounds.len() < MAX_BUCKETS);
+        Histogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; MAX_BUCKETS],
+            sum_us: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(self.bounds.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of observations, seconds
+    pub fn sum(&self) -> f64 {
+        self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    }
+
+    /// Estimate quantile `q` (0..=1) by linear interpolation within buckets,
+    /// `None` if nothing was observed
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let buckets = self.cumulative();
+        let total = buckets.last().map_or(0, |b| b.1);
+        if total == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * total as f64;
+        let mut lower = (0.0, 0);
+        for (le, count) in buckets {
+            if count as f64 >= rank {
+                if le.is_infinite() {
+                    // no upper bound, report the largest finite one
+                    return Some(lower.0);
+                }
+                let in_bucket = (count - lower.1) as f64;
+                let frac = if in_bucket > 0.0 {
+                    (rank - lower.1 as f64) / in_bucket
+                } else {
+                    1.0
+                };
+                return Some(lower.0 + (le - lower.0) * frac);
+            }
+            lower = (le, count);
+        }
+        Some(lower.0)
+    }
+
+    /// Upper bounds with cumulative counts, `+Inf` bucket last
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        (0..=self.bounds.len())
+            .map(|idx| {
+                total += self.buckets[idx].load(Ordering::Relaxed);
+                (self.bounds.get(idx).copied().unwrap_or(f64::INFINITY), total)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub connections_total: Counter,
+    pub connections_active: Gauge,
+    /// Handshakes refused by the accept rate limits
+    pub handshakes_throttled_total: Counter,
+    /// Handshakes with the affinity token of another node
+    pub affinity_misses_total: Counter,
+    /// Redis batches of the session store that failed
+    pub store_errors_total: Counter,
+    pub frames_received_total: Counter,
+    /// Frame handler panics, each closed its connection
+    pub handler_panics_total: Counter,
+    /// Messages whose handler ran out of time
+    pub handler_timeouts_total: Counter,
+    /// Messages whose handler the client cancelled
+    pub handlers_cancelled_total: Counter,
+    /// Client messages answered with an error
+    pub messages_rejected_total: Counter,
+    /// Exactly-once messages or releases sent again to a resumed session
+    pub redeliveries_total: Counter,
+    /// Room messages published for later
+    pub scheduled_total: Counter,
+    /// Room messages published by cron jobs
+    pub job_runs_total: Counter,
+    /// Buffer pool requests served from the free list
+    pub pool_hits_total: Counter,
+    /// Buffer pool requests that had to allocate
+    pub pool_misses_total: Counter,
+    /// Buffers dropped instead of returned to a full pool
+    pub pool_discarded_total: Counter,
+    pub broadcasts_total: Counter,
+    /// Sum of recipients over all broadcasts
+    pub broadcast_recipients_total: Counter,
+    /// Messages dropped because a connection queue was full
+    pub messages_dropped_total: Counter,
+    /// Rooms destroyed because a TTL passed
+    pub rooms_expired_total: Counter,
+    /// Room messages moved to the history archive
+    pub messages_archived_total: Counter,
+    /// Outbound messages held back by a bandwidth governor
+    pub outbound_throttled_total: Counter,
+    /// Room messages the content filter rejected or rewrote
+    pub messages_filtered_total: Counter,
+    /// Content filter runs that failed or timed out
+    pub filter_errors_total: Counter,
+    /// Notifications of direct messages to offline users, see `push`
+    pub pushes_sent_total: Counter,
+    pub pushes_failed_total: Counter,
+    pub pushes_rate_limited_total: Counter,
+    /// Faults injected by `[chaos]`
+    pub chaos_delayed_total: Counter,
+    pub chaos_dropped_total: Counter,
+    pub chaos_duplicated_total: Counter,
+    pub chaos_disconnects_total: Counter,
+    /// Heartbeat round-trip time
+    pub rtt_seconds: Histogram,
+    /// Time to queue one broadcast for all recipients
+    pub broadcast_fanout_seconds: Histogram,
+    /// Time spent handling one incoming frame
+    pub handler_seconds: Histogram,
+    /// Time a message waits in a connection queue before it is written
+    pub queue_wait_seconds: Histogram,
+    /// Time a held back message waited for the bandwidth governors
+    pub outbound_throttle_seconds: Histogram,
+}
+
+/// Counts connections and rejected messages from lifecycle events
+#[derive(Debug, Default)]
+pub struct MetricsEvents;
+
+impl Subscriber for MetricsEvents {
+    fn event(&self, event: &Event<'_>) {
+        match event {
+            Event::Connected { stats, .. } => {
+                METRICS.connections_total.inc();
+                METRICS.connections_active.inc();
+                let pipeline = pipeline_series(stats.pipeline());
+                pipeline.connections.inc();
+                pipeline.connections_total.inc();
+                for series in label_series(stats) {
+                    series.connections.inc();
+                    series.connections_total.inc();
+                }
+            }
+            Event::Disconnected { stats, .. } => {
+                METRICS.connections_active.dec();
+                pipeline_series(stats.pipeline()).connections.dec();
+                let (_, bytes_in) = stats.received_totals();
+                let (_, bytes_out) = stats.sent_totals();
+                for series in label_series(stats) {
+                    series.connections.dec();
+                    series.bytes_in.add(bytes_in);
+                    series.bytes_out.add(bytes_out);
+                }
+            }
+            Event::Rejected { .. } => METRICS.messages_rejected_total.inc(),
+            Event::Joined { room, .. } => {
+                if let Some(series) = room_series(room) {
+                    series.members.inc();
+                }
+            }
+            Event::Left { room, .. } => {
+                if let Some(series) = room_series(room) {
+                    series.members.dec();
+                }
+            }
+            Event::Published { room, .. } => {
+                if let Some(series) = room_series(room) {
+                    series.published.inc();
+                }
+            }
+        }
+    }
+}
+
+/// Current value of a metric
+#[derive(Debug)]
+pub enum Sample<'a> {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(&'a Histogram),
+}
+
+impl Sample<'_> {
+    /// Prometheus metric type, e.g. `counter`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Sample::Counter(_) => "counter",
+            Sample::Gauge(_) => "gauge",
+            Sample::Histogram(_) => "histogram",
+        }
+    }
+}
+
+impl Metrics {
+    /// Call `f` with name, help and current value of every metric
+    pub fn visit<F: FnMut(&str, &str, Sample<'_>)>(&self, mut f: F) {
+        f(
+            "wss_connections_total",
+            "Accepted websocket connections",
+            Sample::Counter(self.connections_total.get()),
+        );
+        f(
+            "wss_connections_active",
+            "Currently open websocket connections",
+            Sample::Gauge(self.connections_active.get()),
+        );
+        f(
+            "wss_handshakes_throttled_total",
+            "Websocket handshakes refused by the accept rate limits",
+            Sample::Counter(self.handshakes_throttled_total.get()),
+        );
+        f(
+            "wss_affinity_misses_total",
+            "Websocket handshakes with the affinity token of another node",
+            Sample::Counter(self.affinity_misses_total.get()),
+        );
+        f(
+            "wss_store_errors_total",
+            "Redis batches of the session store that failed",
+            Sample::Counter(self.store_errors_total.get()),
+        );
+        f(
+            "wss_frames_received_total",
+            "Websocket frames received from clients",
+            Sample::Counter(self.frames_received_total.get()),
+        );
+        f(
+            "wss_handler_panics_total",
+            "Frame handler panics, each closed its connection",
+            Sample::Counter(self.handler_panics_total.get()),
+        );
+        f(
+            "wss_handler_timeouts_total",
+            "Messages whose handler ran out of time",
+            Sample::Counter(self.handler_timeouts_total.get()),
+        );
+        f(
+            "wss_handlers_cancelled_total",
+            "Messages whose handler the client cancelled",
+            Sample::Counter(self.handlers_cancelled_total.get()),
+        );
+        f(
+            "wss_redeliveries_total",
+            "Exactly-once messages or releases sent again to a resumed session",
+            Sample::Counter(self.redeliveries_total.get()),
+        );
+        f(
+            "wss_scheduled_total",
+            "Room messages published for later",
+            Sample::Counter(self.scheduled_total.get()),
+        );
+        f(
+            "wss_job_runs_total",
+            "Room messages published by cron jobs",
+            Sample::Counter(self.job_runs_total.get()),
+        );
+        f(
+            "wss_messages_rejected_total",
+            "Client messages answered with an error",
+            Sample::Counter(self.messages_rejected_total.get()),
+        );
+        f(
+            "wss_pool_hits_total",
+            "Payload buffers reused from the pool",
+            Sample::Counter(self.pool_hits_total.get()),
+        );
+        f(
+            "wss_pool_misses_total",
+            "Payload buffers allocated because the pool was empty",
+            Sample::Counter(self.pool_misses_total.get()),
+        );
+        f(
+            "wss_pool_discarded_total",
+            "Payload buffers dropped instead of returned to the pool",
+            Sample::Counter(self.pool_discarded_total.get()),
+        );
+        f(
+            "wss_broadcasts_total",
+            "Broadcast messages published",
+            Sample::Counter(self.broadcasts_total.get()),
+        );
+        f(
+            "wss_broadcast_recipients_total",
+            "Connections a broadcast message was queued for",
+            Sample::Counter(self.broadcast_recipients_total.get()),
+        );
+        f(
+            "wss_messages_dropped_total",
+            "Outbound messages dropped because the connection queue was full",
+            Sample::Counter(self.messages_dropped_total.get()),
+        );
+        f(
+            "wss_rooms_expired_total",
+            "Rooms destroyed because their TTL or empty grace period passed",
+            Sample::Counter(self.rooms_expired_total.get()),
+        );
+        f(
+            "wss_messages_archived_total",
+            "Room messages moved from history to the archive bucket",
+            Sample::Counter(self.messages_archived_total.get()),
+        );
+        f(
+            "wss_outbound_throttled_total",
+            "Outbound messages held back by a bandwidth governor",
+            Sample::Counter(self.outbound_throttled_total.get()),
+        );
+        f(
+            "wss_messages_filtered_total",
+            "Room messages rejected or rewritten by the content filter",
+            Sample::Counter(self.messages_filtered_total.get()),
+        );
+        f(
+            "wss_filter_errors_total",
+            "Content filter runs that failed or timed out",
+            Sample::Counter(self.filter_errors_total.get()),
+        );
+        f(
+            "wss_pushes_sent_total",
+            "Direct messages to offline users handed to the push notifier",
+            Sample::Counter(self.pushes_sent_total.get()),
+        );
+        f(
+            "wss_pushes_failed_total",
+            "Push notifications that failed or timed out",
+            Sample::Counter(self.pushes_failed_total.get()),
+        );
+        f(
+            "wss_pushes_rate_limited_total",
+            "Direct messages to offline users not pushed because of push.max_per_minute",
+            Sample::Counter(self.pushes_rate_limited_total.get()),
+        );
+        f(
+            "wss_chaos_delayed_total",
+            "Outbound messages held back by [chaos]",
+            Sample::Counter(self.chaos_delayed_total.get()),
+        );
+        f(
+            "wss_chaos_dropped_total",
+            "Outbound messages dropped by [chaos]",
+            Sample::Counter(self.chaos_dropped_total.get()),
+        );
+        f(
+            "wss_chaos_duplicated_total",
+            "Outbound messages sent twice by [chaos]",
+            Sample::Counter(self.chaos_duplicated_total.get()),
+        );
+        f(
+            "wss_chaos_disconnects_total",
+            "Connections closed by [chaos]",
+            Sample::Counter(self.chaos_disconnects_total.get()),
+        );
+        f(
+            "wss_rtt_seconds",
+            "Heartbeat ping/pong round-trip time",
+            Sample::Histogram(&self.rtt_seconds),
+        );
+        f(
+            "wss_broadcast_fanout_seconds",
+            "Time to queue a broadcast for all recipients",
+            Sample::Histogram(&self.broadcast_fanout_seconds),
+        );
+        f(
+            "wss_handler_seconds",
+            "Time spent handling an incoming frame",
+            Sample::Histogram(&self.handler_seconds),
+        );
+        f(
+            "wss_queue_wait_seconds",
+            "Time an outbound message waits in the connection queue",
+            Sample::Histogram(&self.queue_wait_seconds),
+        );
+        f(
+            "wss_outbound_throttle_seconds",
+            "Time a held back message waited for the bandwidth governors",
+            Sample::Histogram(&self.outbound_throttle_seconds),
+        );
+    }
+
+    /// Render in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.visit(|name, help, sample| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, sample.kind());
+            match sample {
+                Sample::Counter(v) => {
+                    let _ = writeln!(out, "{} {}", name, v);
+                }
+                Sample::Gauge(v) => {
+                    let _ = writeln!(out, "{} {}", name, v);
+                }
+                Sample::Histogram(h) => {
+                    for (le, count) in h.cumulative() {
+                        if le.is_infinite() {
+                            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+                        } else {
+                            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+                        }
+                    }
+                    let _ = writeln!(out, "{}_sum {}", name, h.sum());
+                    let _ = writeln!(out, "{}_count {}", name, h.count());
+
+                    // estimated tail latency, as a separate gauge family
+                    let _ = writeln!(out, "# HELP {}_quantile Estimated quantiles of {}", name, name);
+                    let _ = writeln!(out, "# TYPE {}_quantile gauge", name);
+                    for q in QUANTILES {
+                        let v = h.quantile(*q).unwrap_or(0.0);
+                        let _ = writeln!(out, "{}_quantile{{quantile=\"{}\"}} {}", name, q, v);
+                    }
+                }
+            }
+        });
+        let mut family = String::new();
+        visit_labeled(|name, help, labels, sample| {
+            if family != name {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} {}", name, sample.kind());
+                family = name.to_string();
+            }
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, label_value(value)))
+                .collect();
+            let labels = labels.join(",");
+            match sample {
+                Sample::Counter(v) => {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, v);
+                }
+                Sample::Gauge(v) => {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, v);
+                }
+                Sample::Histogram(h) => render_histogram(&mut out, name, &labels, h),
+            }
+        });
+        out
+    }
+}
+
+/// Label of the rooms matching no pattern, or over `max_rooms`, and value
+/// of the labels over `labels.max_values`
+const OTHER: &str = "_other";
+
+/// Metrics of the rooms of one label
+#[derive(Debug)]
+struct RoomSeries {
+    members: Gauge,
+    published: Counter,
+    dropped: Counter,
+    fanout: Histogram,
+}
+
+#[derive(Debug)]
+struct RoomLabels {
+    config: Option<RoomMetricsConfig>,
+    series: BTreeMap<String, Arc<RoomSeries>>,
+    /// Labels that are a room name
+    named: usize,
+}
+
+impl RoomLabels {
+    /// Label of room `name`
+    fn label<'a>(&'a self, config: &'a RoomMetricsConfig, name: &'a str) -> &'a str {
+        if config
+            .rooms
+            .iter()
+            .any(|pattern| config::glob(pattern, name))
+        {
+            if self.named < config.max_rooms || self.series.contains_key(name) {
+                return name;
+            }
+            return OTHER;
+        }
+        config
+            .aggregate
+            .iter()
+            .find(|pattern| config::glob(pattern, name))
+            .map_or(OTHER, String::as_str)
+    }
+}
+
+static ROOMS: RwLock<RoomLabels> = RwLock::new(RoomLabels {
+    config: None,
+    series: BTreeMap::new(),
+    named: 0,
+});
+
+/// Keep metrics by room as `config` says
+pub fn init_rooms(config: Option<&RoomMetricsConfig>) {
+    ROOMS.write().unwrap().config = config.cloned();
+}
+
+/// Metrics of room `name`, `None` without `[room_metrics]`
+fn room_series(name: &str) -> Option<Arc<RoomSeries>> {
+    {
+        let rooms = ROOMS.read().unwrap();
+        let config = rooms.config.as_ref()?;
+        if let Some(series) = rooms.series.get(rooms.label(config, name)) {
+            return Some(series.clone());
+        }
+    }
+    let mut rooms = ROOMS.write().unwrap();
+    let rooms = &mut *rooms;
+    let config = rooms.config.as_ref()?;
+    let label = rooms.label(config, name).to_string();
+    if label == name && !rooms.series.contains_key(name) {
+        rooms.named += 1;
+    }
+    let series = rooms.series.entry(label).or_insert_with(|| {
+        Arc::new(RoomSeries {
+            members: Gauge::new(),
+            published: Counter::new(),
+            dropped: Counter::new(),
+            fanout: Histogram::new(FAST_BUCKETS),
+        })
+    });
+    Some(series.clone())
+}
+
+/// Note that a message of room `name` took `elapsed` to queue for its
+/// members, and was dropped for `dropped` of them
+pub fn room_fanout(name: &str, elapsed: Duration, dropped: usize) {
+    if let Some(series) = room_series(name) {
+        series.fanout.observe(elapsed);
+        series.dropped.add(dropped as u64);
+    }
+}
+
+/// Prometheus label value, escaped
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Name, help and value of a family of labeled metrics
+type Family<T> = (&'static str, &'static str, fn(&T) -> Sample<'_>);
+
+/// Callback of [`visit_labeled`]
+type Visitor<'v> = dyn FnMut(&str, &str, &[(&str, &str)], Sample<'_>) + 'v;
+
+fn visit_rooms(f: &mut Visitor<'_>) {
+    let rooms = ROOMS.read().unwrap();
+    if rooms.config.is_none() {
+        return;
+    }
+    let families: [Family<RoomSeries>; 4] = [
+        ("wss_room_members", "Members of the room", |s| {
+            Sample::Gauge(s.members.get())
+        }),
+        (
+            "wss_room_published_total",
+            "Messages published to the room",
+            |s| Sample::Counter(s.published.get()),
+        ),
+        (
+            "wss_room_dropped_total",
+            "Room messages dropped because a member's queue was full",
+            |s| Sample::Counter(s.dropped.get()),
+        ),
+        (
+            "wss_room_fanout_seconds",
+            "Time to queue a room message for the members",
+            |s| Sample::Histogram(&s.fanout),
+        ),
+    ];
+    for (name, help, sample) in families {
+        for (room, series) in &rooms.series {
+            f(name, help, &[("room", room)], sample(series));
+        }
+    }
+}
+
+/// Buckets, sum and count of `histogram` with `labels`
+fn render_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (le, count) in histogram.cumulative() {
+        let le = match le.is_infinite() {
+            true => "+Inf".to_string(),
+            false => le.to_string(),
+        };
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+    }
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum());
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count());
+}
+
+/// Metrics of the connections of one value of a label
+#[derive(Debug)]
+struct LabelSeries {
+    connections: Gauge,
+    connections_total: Counter,
+    bytes_in: Counter,
+    bytes_out: Counter,
+}
+
+#[derive(Debug)]
+struct LabelValues {
+    /// Labels that are metric dimensions, those of `labels.metrics`
+    labels: Vec<String>,
+    max_values: usize,
+    /// By label and value
+    series: BTreeMap<String, BTreeMap<String, Arc<LabelSeries>>>,
+}
+
+impl LabelValues {
+    /// Metric value of `value` of `label`
+    fn value<'a>(&'a self, label: &str, value: &'a str) -> &'a str {
+        match self.series.get(label) {
+            Some(values) if !values.contains_key(value) => {
+                let used = values.len() - usize::from(values.contains_key(OTHER));
+                match used < self.max_values {
+                    true => value,
+                    false => OTHER,
+                }
+            }
+            _ => value,
+        }
+    }
+}
+
+static LABELS: RwLock<LabelValues> = RwLock::new(LabelValues {
+    labels: Vec::new(),
+    max_values: 0,
+    series: BTreeMap::new(),
+});
+
+/// Keep metrics by the connection labels `config` names
+pub fn init_labels(config: &LabelsConfig) {
+    let mut labels = LABELS.write().unwrap();
+    labels.labels = config.metrics.clone();
+    labels.max_values = config.max_values;
+}
+
+/// Metrics of the values of the labels of `stats` that are dimensions
+fn label_series(stats: &ConnStats) -> Vec<Arc<LabelSeries>> {
+    if LABELS.read().unwrap().labels.is_empty() {
+        return Vec::new();
+    }
+    let mut labels = LABELS.write().unwrap();
+    let labels = &mut *labels;
+    let mut found = Vec::new();
+    for label in &labels.labels {
+        let Some(value) = stats.labels().get(label) else {
+            continue;
+        };
+        let value = labels.value(label, value).to_string();
+        let series = labels
+            .series
+            .entry(label.clone())
+            .or_default()
+            .entry(value)
+            .or_insert_with(|| {
+                Arc::new(LabelSeries {
+                    connections: Gauge::new(),
+                    connections_total: Counter::new(),
+                    bytes_in: Counter::new(),
+                    bytes_out: Counter::new(),
+                })
+            });
+        found.push(series.clone());
+    }
+    found
+}
+
+fn visit_labels(f: &mut Visitor<'_>) {
+    let labels = LABELS.read().unwrap();
+    if labels.labels.is_empty() {
+        return;
+    }
+    let families: [Family<LabelSeries>; 4] = [
+        (
+            "wss_label_connections",
+            "Open connections with the label value",
+            |s| Sample::Gauge(s.connections.get()),
+        ),
+        (
+            "wss_label_connections_total",
+            "Connections opened with the label value",
+            |s| Sample::Counter(s.connections_total.get()),
+        ),
+        (
+            "wss_label_received_bytes_total",
+            "Bytes received by closed connections with the label value",
+            |s| Sample::Counter(s.bytes_in.get()),
+        ),
+        (
+            "wss_label_sent_bytes_total",
+            "Bytes sent to closed connections with the label value",
+            |s| Sample::Counter(s.bytes_out.get()),
+        ),
+    ];
+    for (name, help, sample) in families {
+        for (label, values) in &labels.series {
+            for (value, series) in values {
+                f(
+                    name,
+                    help,
+                    &[("label", label), ("value", value)],
+                    sample(series),
+                );
+            }
+        }
+    }
+}
+
+/// Metrics of the connections of one handler pipeline
+#[derive(Debug)]
+struct PipelineSeries {
+    connections: Gauge,
+    connections_total: Counter,
+    errors: Counter,
+    handler: Histogram,
+}
+
+/// By pipeline name, one for each registered pipeline that had connections
+static PIPELINES: RwLock<BTreeMap<&'static str, Arc<PipelineSeries>>> =
+    RwLock::new(BTreeMap::new());
+
+fn pipeline_series(name: &'static str) -> Arc<PipelineSeries> {
+    if let Some(series) = PIPELINES.read().unwrap().get(name) {
+        return series.clone();
+    }
+    let mut pipelines = PIPELINES.write().unwrap();
+    let series = pipelines.entry(name).or_insert_with(|| {
+        Arc::new(PipelineSeries {
+            connections: Gauge::new(),
+            connections_total: Counter::new(),
+            errors: Counter::new(),
+            handler: Histogram::new(FAST_BUCKETS),
+        })
+    });
+    series.clone()
+}
+
+/// Note that a connection of pipeline `name` took `elapsed` to handle a
+/// frame
+pub fn pipeline_handled(name: &str, elapsed: Duration) {
+    if let Some(series) = PIPELINES.read().unwrap().get(name) {
+        series.handler.observe(elapsed);
+    }
+}
+
+/// Note that a connection of pipeline `name` was answered with an error
+pub fn pipeline_error(name: &str) {
+    if let Some(series) = PIPELINES.read().unwrap().get(name) {
+        series.errors.inc();
+    }
+}
+
+fn visit_pipelines(f: &mut Visitor<'_>) {
+    let pipelines = PIPELINES.read().unwrap();
+    let families: [Family<PipelineSeries>; 4] = [
+        (
+            "wss_pipeline_connections",
+            "Open connections of the handler pipeline",
+            |s| Sample::Gauge(s.connections.get()),
+        ),
+        (
+            "wss_pipeline_connections_total",
+            "Connections opened with the handler pipeline",
+            |s| Sample::Counter(s.connections_total.get()),
+        ),
+        (
+            "wss_pipeline_errors_total",
+            "Error answers to connections of the handler pipeline",
+            |s| Sample::Counter(s.errors.get()),
+        ),
+        (
+            "wss_pipeline_handler_seconds",
+            "Time spent handling one incoming frame of the handler pipeline",
+            |s| Sample::Histogram(&s.handler),
+        ),
+    ];
+    for (name, help, sample) in families {
+        for (pipeline, series) in pipelines.iter() {
+            f(name, help, &[("pipeline", pipeline)], sample(series));
+        }
+    }
+}
+
+/// Call `f` with name, help, labels and current value of every series of
+/// the metrics labeled by room, connection label and pipeline, the series
+/// of a metric one after the other
+pub fn visit_labeled<F: FnMut(&str, &str, &[(&str, &str)], Sample<'_>)>(mut f: F) {
+    visit_rooms(&mut f);
+    visit_labels(&mut f);
+    visit_pipelines(&mut f);
+}
+
+/// `GET /metrics`
+pub async fn index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
@@ -0,0 +1,206 @@
+//! OIDC bearer token validation.
+//!
+//! Tokens are JWTs signed by the configured issuer. Its signing keys come
+//! from the JWKS document (discovered through
+//! `/.well-known/openid-configuration` unless `jwks_uri` is set), fetched by
+//! a background task every `refresh_secs`. Validation only uses the cached
+//! keys: a handshake handler cannot wait on io, so a token with an unknown
+//! key id is rejected and wakes the task for an early refresh, at most once
+//! per `MIN_REFRESH`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use ntex::http::client::Client;
+use ntex::time;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::auth::{AuthError, Identity};
+use crate::config::OidcConfig;
+use crate::tasks::TaskHandle;
+
+/// Lower bound between two JWKS fetches
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+
+/// Max size of discovery and JWKS documents
+const MAX_DOCUMENT: usize = 1024 * 1024;
+
+pub struct Oidc {
+    config: OidcConfig,
+    /// Signing keys by key id, `None` until the first successful fetch
+    keys: RwLock<Option<HashMap<String, DecodingKey>>>,
+    wake: mpsc::UnboundedSender<()>,
+    woken: Mutex<Option<mpsc::UnboundedReceiver<()>>>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+async fn fetch<T: DeserializeOwned>(url: &str) -> Result<T, String> {
+    let client = Client::build().timeout(Duration::from_secs(5)).finish();
+    let mut res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("{}: {}", url, e))?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", url, res.status()));
+    }
+    // some providers serve key sets without a json content type
+    let body = res
+        .body()
+        .limit(MAX_DOCUMENT)
+        .await
+        .map_err(|e| format!("{}: {}", url, e))?;
+    serde_json::from_slice(&body).map_err(|e| format!("{}: {}", url, e))
+}
+
+/// Claim at dotted `path`, e.g. `realm_access.roles`
+pub(crate) fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(claims, |v, key| v.get(key))
+}
+
+fn invalid(reason: &str) -> AuthError {
+    AuthError::Invalid(reason.to_string())
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Oidc {
+        let (wake, woken) = mpsc::unbounded();
+        Oidc {
+            config,
+            keys: RwLock::new(None),
+            wake,
+            woken: Mutex::new(Some(woken)),
+        }
+    }
+
+    async fn fetch_keys(&self) -> Result<usize, String> {
+        let jwks_uri = match self.config.jwks_uri {
+            Some(ref uri) => uri.clone(),
+            None => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                fetch::<Discovery>(&url).await?.jwks_uri
+            }
+        };
+        let set: JwkSet = fetch(&jwks_uri).await?;
+        let keys: HashMap<_, _> = set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let kid = jwk.common.key_id.clone()?;
+                Some((kid, DecodingKey::from_jwk(jwk).ok()?))
+            })
+            .collect();
+        let count = keys.len();
+        *self.keys.write().unwrap() = Some(keys);
+        Ok(count)
+    }
+
+    /// Signing key `kid`, requests an early refresh if it is unknown
+    fn key(&self, kid: &str) -> Result<DecodingKey, AuthError> {
+        let found = match *self.keys.read().unwrap() {
+            Some(ref keys) => keys
+                .get(kid)
+                .cloned()
+                .ok_or_else(|| invalid("unknown signing key")),
+            None => Err(AuthError::Unavailable(
+                "signing keys not loaded".to_string(),
+            )),
+        };
+        if found.is_err() {
+            let _ = self.wake.unbounded_send(());
+        }
+        found
+    }
+
+    /// Check token signature, issuer, audience and expiry, map claims to identity
+    pub fn validate(&self, token: &str) -> Result<Identity, AuthError> {
+        let header = decode_header(token).map_err(|e| AuthError::Invalid(e.to_string()))?;
+        // shared secret algorithms would let a public key act as secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid("symmetric algorithm"));
+        }
+        let kid = header.kid.ok_or_else(|| invalid("token has no kid"))?;
+        let key = self.key(&kid)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        let claims = match decode::<Value>(token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(e) => {
+                return Err(match e.kind() {
+                    jsonwebtoken::errors::ErrorKind::ExpiredSignature => AuthError::Expired,
+                    _ => AuthError::Invalid(e.to_string()),
+                })
+            }
+        };
+
+        let user = claims["sub"]
+            .as_str()
+            .ok_or_else(|| invalid("token has no sub"))?
+            .to_string();
+        let roles = claim(&claims, &self.config.roles_claim)
+            .and_then(Value::as_array)
+            .map(|roles| {
+                roles
+                    .iter()
+                    .filter_map(|r| r.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let tenant = self
+            .config
+            .tenant_claim
+            .as_deref()
+            .and_then(|path| claim(&claims, path))
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        Ok(Identity {
+            user,
+            roles,
+            expires_at: claims["exp"].as_u64(),
+            tenant,
+            claims,
+        })
+    }
+}
+
+/// Keep signing keys fresh, a failed fetch keeps the previous keys
+pub async fn refresh_keys(oidc: Arc<Oidc>) {
+    let mut woken = match oidc.woken.lock().unwrap().take() {
+        Some(woken) => woken,
+        None => return,
+    };
+    let task = TaskHandle::register("jwks", None);
+    let interval = Duration::from_secs(oidc.config.refresh_secs).max(MIN_REFRESH);
+    loop {
+        task.set_state("fetching");
+        match oidc.fetch_keys().await {
+            Ok(count) => log::info!("Loaded {} signing keys of {}", count, oidc.config.issuer),
+            Err(e) => log::warn!("Cannot refresh signing keys: {}", e),
+        }
+        // requests up to now are answered by this fetch
+        while woken.try_recv().is_ok() {}
+        task.set_state("sleeping");
+        time::sleep(MIN_REFRESH).await;
+        // scheduled refresh, or earlier when a token named an unknown key
+        let _ = time::timeout(interval - MIN_REFRESH, woken.next()).await;
+    }
+}
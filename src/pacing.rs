@@ -0,0 +1,157 @@
+//! Throttled and debounced delivery of room messages.
+//!
+//! For rooms carrying UI state only the newest value matters, so a member
+//! need not get every message. Rooms matching a `[[rooms.limits]]` pattern
+//! with `throttle_ms` send each member at most one `room.message` per
+//! interval: one arriving sooner is held back, replacing any held before,
+//! and sent when the interval is over. With `debounce_ms` a member gets a
+//! message only once no newer one came for the interval. Either way the
+//! member ends up with the newest message; messages are sequenced and
+//! stored as usual.
+//!
+//! Aggregated rooms are not paced, their windows already cap the frames.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::StreamExt;
+use ntex::{time, ws};
+
+use crate::config::Pace;
+use crate::hub::Hub;
+use crate::tasks::TaskHandle;
+
+/// When a held back message of member `.1` in room `.2` is due
+type Due = (Instant, u64, String);
+
+/// Pacing state of a member in one room
+#[derive(Debug, Default)]
+struct Slot {
+    /// Last delivery
+    sent: Option<Instant>,
+    held: Option<ws::Message>,
+    /// When `held` goes out, debouncing moves it on
+    due: Option<Instant>,
+    /// Time `run` wakes up for the slot at, at most one per slot
+    scheduled: Option<Instant>,
+}
+
+/// Slots by connection and room
+#[derive(Debug)]
+pub struct Pacer {
+    slots: Mutex<HashMap<u64, HashMap<String, Slot>>>,
+    wakeups: mpsc::UnboundedSender<Due>,
+    wakeups_rx: Mutex<Option<mpsc::UnboundedReceiver<Due>>>,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        let (wakeups, wakeups_rx) = mpsc::unbounded();
+        Pacer {
+            slots: Mutex::new(HashMap::new()),
+            wakeups,
+            wakeups_rx: Mutex::new(Some(wakeups_rx)),
+        }
+    }
+}
+
+impl Pacer {
+    /// Whether `msg` of room `name` goes to member `id` now, else it is
+    /// held back for later
+    pub fn offer(&self, id: u64, name: &str, msg: &ws::Message, pace: Pace) -> bool {
+        let now = Instant::now();
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots
+            .entry(id)
+            .or_default()
+            .entry(name.to_string())
+            .or_default();
+        let due = match pace {
+            Pace::Throttle(interval) => match slot.sent {
+                Some(sent) if now < sent + interval => sent + interval,
+                _ if slot.held.is_none() => {
+                    slot.sent = Some(now);
+                    return true;
+                }
+                _ => now,
+            },
+            Pace::Debounce(interval) => now + interval,
+        };
+        slot.held = Some(msg.clone());
+        slot.due = Some(due);
+        if slot.scheduled.is_none() {
+            slot.scheduled = Some(due);
+            let _ = self.wakeups.unbounded_send((due, id, name.to_string()));
+        }
+        false
+    }
+
+    /// The held back message of member `id` in `name` if due at `at`, or
+    /// the later time to look again
+    fn wake(&self, id: u64, name: &str, at: Instant) -> Result<Option<ws::Message>, Instant> {
+        let mut slots = self.slots.lock().unwrap();
+        let Some(slot) = slots.get_mut(&id).and_then(|rooms| rooms.get_mut(name)) else {
+            return Ok(None);
+        };
+        match slot.due {
+            Some(due) if due > at => {
+                slot.scheduled = Some(due);
+                Err(due)
+            }
+            _ => {
+                slot.scheduled = None;
+                slot.due = None;
+                let held = slot.held.take();
+                if held.is_some() {
+                    slot.sent = Some(Instant::now());
+                }
+                Ok(held)
+            }
+        }
+    }
+
+    /// Drop the slots of a closed connection
+    pub fn forget(&self, id: u64) {
+        self.slots.lock().unwrap().remove(&id);
+    }
+}
+
+/// Send held back messages as they get due
+pub async fn run(hub: Arc<Hub>) {
+    let Some(mut wakeups) = hub.pacer().wakeups_rx.lock().unwrap().take() else {
+        return;
+    };
+    let task = TaskHandle::register("pacing", None);
+    let mut due: BinaryHeap<Reverse<Due>> = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while let Some(Reverse((at, _, _))) = due.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse((at, id, name)) = due.pop().expect("peeked");
+            match hub.pacer().wake(id, &name, at) {
+                Ok(Some(msg)) => {
+                    hub.send(id, msg);
+                }
+                Ok(None) => {}
+                Err(later) => due.push(Reverse((later, id, name))),
+            }
+        }
+        task.set_queue_depth(due.len());
+        task.set_state("waiting");
+        let wait = match due.peek() {
+            Some(Reverse((at, _, _))) => at.saturating_duration_since(now),
+            None => Duration::from_secs(3600),
+        };
+        match select(Box::pin(time::sleep(wait)), wakeups.next()).await {
+            Either::Left(_) => {}
+            Either::Right((Some(wakeup), _)) => due.push(Reverse(wakeup)),
+            Either::Right((None, _)) => return,
+        }
+    }
+}
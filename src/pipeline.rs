@@ -0,0 +1,194 @@
+//! Handler pipelines, versions of how protocol messages are handled.
+//!
+//! A pipeline sees each protocol message of its connections before the
+//! built-in handlers, once the type is known and the payload upgraded to its
+//! current version, and answers it or leaves it to them. Pipelines are
+//! registered by name at startup; `default` is the built-in handlers alone.
+//!
+//! Each websocket route has an active pipeline, `default` until
+//! `PUT /admin/pipelines/active` switches it: new connections of the route
+//! get the new one, those open keep theirs until they close, or are closed
+//! with reconnect hints if asked to. The switch is not persisted, a restart
+//! goes back to `default`.
+//!
+//! `[canary]` sends some new connections to another pipeline, to try a
+//! protocol change on a few clients before all of them: `percent` of them,
+//! and those whose labels match the `labels` selector:
+//!
+//! ```toml
+//! [canary]
+//! pipeline = "v2"
+//! percent = 5
+//! labels = "plan=beta"
+//! ```
+//!
+//! Other connections get the route's active pipeline. Metrics are labeled
+//! by `pipeline`, see `metrics`, so the canary's errors and handler time can
+//! be compared with those of the active one.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use nanorand::{Rng, WyRand};
+use serde_json::Value;
+
+use crate::config::CanaryConfig;
+use crate::hub::ConnStats;
+use crate::labels::{Labels, Selector};
+use crate::protocol;
+
+/// Name of the built-in handlers
+pub const DEFAULT: &str = "default";
+
+/// Websocket routes, those with an active pipeline
+pub const ROUTES: &[&str] = &["/ws"];
+
+/// Protocol message of a client, as seen by a pipeline
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub conn: u64,
+    pub stats: &'a ConnStats,
+    pub ty: &'a str,
+    pub id: Option<&'a str>,
+    pub payload: &'a Value,
+}
+
+/// What a pipeline did with a message
+#[derive(Debug)]
+pub enum Handled {
+    /// Leave it to the built-in handlers
+    Pass,
+    /// Answer with a message of the same type carrying this payload
+    Reply(Value),
+    Error(protocol::Error),
+    /// Handled, without an answer
+    NoReply,
+}
+
+/// Handles protocol messages, on the connection's worker
+pub trait Pipeline: Send + Sync {
+    fn handle(&self, request: &Request<'_>) -> Handled;
+}
+
+static PIPELINES: RwLock<Vec<(&'static str, Arc<dyn Pipeline>)>> = RwLock::new(Vec::new());
+
+/// Make `pipeline` available by `name`, replacing an earlier one
+pub fn register(name: &'static str, pipeline: Arc<dyn Pipeline>) {
+    let mut pipelines = PIPELINES.write().unwrap();
+    pipelines.retain(|(n, _)| *n != name);
+    pipelines.push((name, pipeline));
+}
+
+/// Pipeline registered by `name`, `None` for the built-in handlers
+pub fn get(name: &str) -> Option<Arc<dyn Pipeline>> {
+    let pipelines = PIPELINES.read().unwrap();
+    pipelines
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, pipeline)| pipeline.clone())
+}
+
+/// Name a pipeline was registered by
+fn registered(name: &str) -> Option<&'static str> {
+    let pipelines = PIPELINES.read().unwrap();
+    pipelines.iter().map(|(n, _)| *n).find(|n| *n == name)
+}
+
+/// Names of the pipelines, `default` first
+pub fn names() -> Vec<&'static str> {
+    let pipelines = PIPELINES.read().unwrap();
+    let mut names = vec![DEFAULT];
+    names.extend(pipelines.iter().map(|(n, _)| *n).filter(|n| *n != DEFAULT));
+    names
+}
+
+/// Active pipeline by route, `default` for the routes not in it
+static ACTIVE: RwLock<BTreeMap<&'static str, &'static str>> = RwLock::new(BTreeMap::new());
+
+/// Pipeline new connections of `route` get
+pub fn active(route: &str) -> &'static str {
+    ACTIVE
+        .read()
+        .unwrap()
+        .get(route)
+        .copied()
+        .unwrap_or(DEFAULT)
+}
+
+/// Active pipeline of every route
+pub fn routes() -> BTreeMap<&'static str, &'static str> {
+    ROUTES.iter().map(|route| (*route, active(route))).collect()
+}
+
+#[derive(Debug)]
+pub enum ActivateError {
+    NoRoute(String),
+    NoPipeline(String),
+}
+
+impl fmt::Display for ActivateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivateError::NoRoute(route) => write!(f, "no websocket route `{}`", route),
+            ActivateError::NoPipeline(name) => write!(f, "no pipeline `{}` is registered", name),
+        }
+    }
+}
+
+/// Give new connections of `route` pipeline `name`, returns the one it
+/// replaces
+pub fn activate(route: &str, name: &str) -> Result<&'static str, ActivateError> {
+    let route = ROUTES
+        .iter()
+        .find(|r| **r == route)
+        .ok_or_else(|| ActivateError::NoRoute(route.to_string()))?;
+    let name = match name {
+        DEFAULT => DEFAULT,
+        name => registered(name).ok_or_else(|| ActivateError::NoPipeline(name.to_string()))?,
+    };
+    let previous = ACTIVE.write().unwrap().insert(route, name);
+    Ok(previous.unwrap_or(DEFAULT))
+}
+
+/// Check the canary goes to a registered pipeline
+pub fn validate(config: &CanaryConfig) -> Result<(), String> {
+    if registered(&config.pipeline).is_none() {
+        return Err(format!(
+            "canary.pipeline: no pipeline `{}` is registered",
+            config.pipeline
+        ));
+    }
+    if !(0.0..=100.0).contains(&config.percent) {
+        return Err(format!(
+            "canary.percent must be between 0 and 100, not {}",
+            config.percent
+        ));
+    }
+    if let Some(ref labels) = config.labels {
+        labels
+            .parse::<Selector>()
+            .map_err(|e| format!("canary.labels: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Pipeline of a new connection of `route` with `labels`, the canary's or
+/// the route's active one
+pub fn pick(canary: Option<&CanaryConfig>, route: &str, labels: &Labels) -> &'static str {
+    let active = active(route);
+    let Some(canary) = canary else {
+        return active;
+    };
+    let selected = canary
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.parse::<Selector>().ok())
+        .is_some_and(|selector| selector.matches(labels));
+    let sampled = canary.percent > 0.0
+        && (WyRand::new().generate_range(0..1_000_000u32) as f64) < canary.percent * 1e4;
+    match selected || sampled {
+        true => registered(&canary.pipeline).unwrap_or(active),
+        false => active,
+    }
+}
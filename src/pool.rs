@@ -0,0 +1,116 @@
+//! Per-worker pool of payload buffers.
+//!
+//! Used to reassemble fragmented messages and to serialize and pack
+//! outbound ones without growing a fresh buffer for every message. Each
+//! worker thread keeps its own free list.
+
+use std::cell::RefCell;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+
+use ntex::util::{Bytes, BytesMut};
+
+use crate::config::PoolConfig;
+use crate::metrics::METRICS;
+
+static CONFIG: OnceLock<PoolConfig> = OnceLock::new();
+
+thread_local! {
+    static FREE: RefCell<Vec<BytesMut>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set pool parameters, must be called before workers start
+pub fn init(config: PoolConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static PoolConfig {
+    CONFIG.get_or_init(PoolConfig::default)
+}
+
+/// Buffer borrowed from the pool, returned on drop
+#[derive(Debug)]
+pub struct PooledBuf(Option<BytesMut>);
+
+/// Take buffer from the pool or allocate a new one
+pub fn acquire() -> PooledBuf {
+    let buf = FREE.with(|free| free.borrow_mut().pop());
+    let buf = match buf {
+        Some(buf) => {
+            METRICS.pool_hits_total.inc();
+            buf
+        }
+        None => {
+            METRICS.pool_misses_total.inc();
+            BytesMut::with_capacity(config().buffer_capacity)
+        }
+    };
+    PooledBuf(Some(buf))
+}
+
+/// Longest message reassembled from fragments, bytes
+pub fn max_message_size() -> usize {
+    config().max_message_size
+}
+
+impl Default for PooledBuf {
+    fn default() -> Self {
+        acquire()
+    }
+}
+
+impl PooledBuf {
+    /// Contents without copying; the buffer gets its memory back on reuse
+    /// once the returned bytes are dropped
+    pub fn take(&mut self) -> Bytes {
+        self.split().freeze()
+    }
+}
+
+impl io::Write for PooledBuf {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(mut buf) = self.0.take() {
+            let cfg = config();
+            // oversized buffers would pin memory, let them go
+            if buf.capacity() > cfg.max_buffer_capacity {
+                METRICS.pool_discarded_total.inc();
+                return;
+            }
+            FREE.with(|free| {
+                let mut free = free.borrow_mut();
+                if free.len() < cfg.size {
+                    buf.clear();
+                    free.push(buf);
+                } else {
+                    METRICS.pool_discarded_total.inc();
+                }
+            });
+        }
+    }
+}
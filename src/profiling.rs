@@ -0,0 +1,53 @@
+//! CPU profiling via `pprof`.
+
+use std::time::Duration;
+
+use ntex::time;
+use pprof::protos::Message;
+use serde::Deserialize;
+
+/// Sampling frequency, Hz
+const FREQUENCY: i32 = 99;
+/// Longest allowed capture
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Flamegraph SVG
+    Flamegraph,
+    /// Protobuf `perftools.profiles.Profile`, readable by `go tool pprof`
+    Pprof,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Flamegraph => "image/svg+xml",
+            Format::Pprof => "application/octet-stream",
+        }
+    }
+}
+
+/// Sample the whole process for `duration` and render the report
+pub async fn capture(duration: Duration, format: Format) -> Result<Vec<u8>, String> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    time::sleep(duration.min(MAX_DURATION)).await;
+
+    let report = guard.report().build().map_err(|e| e.to_string())?;
+    let mut body = Vec::new();
+    match format {
+        Format::Flamegraph => report.flamegraph(&mut body).map_err(|e| e.to_string())?,
+        Format::Pprof => report
+            .pprof()
+            .map_err(|e| e.to_string())?
+            .write_to_vec(&mut body)
+            .map_err(|e| e.to_string())?,
+    }
+    Ok(body)
+}
@@ -0,0 +1,2023 @@
+//! JSON message protocol spoken over text frames.
+//!
+//! Client messages are envelopes:
+//!
+//! ```json
+//! {"type": "echo", "id": "42", "payload": {"any": "json"}}
+//! ```
+//!
+//! `id` is optional and copied into replies and errors for correlation.
+//! `version` is the payload schema version the client speaks, 1 if absent;
+//! older payloads are upgraded to the current version before handling.
+//! Rejected input is answered with an error message instead of a close:
+//!
+//! ```json
+//! {"type": "error", "id": "42", "code": "bad_json", "message": "...", "retryable": false}
+//! ```
+
+use ntex::util::ByteString;
+use ntex::ws;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::schema::{Field, Schema};
+use crate::{pool, utf8};
+
+/// Who sends a message type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+    Both,
+}
+
+/// How fields of a message are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `{"type", "id", "payload"}`, schema describes `payload`
+    Envelope,
+    /// `type` and `id` next to the fields described by schema
+    Flat,
+}
+
+/// Turns payload of version `n` into version `n + 1`
+pub type Upgrade = fn(Value) -> Result<Value, String>;
+
+/// Registered message type
+#[derive(Debug)]
+pub struct MessageType {
+    pub name: &'static str,
+    pub direction: Direction,
+    pub layout: Layout,
+    pub summary: &'static str,
+    /// Schema of the current version
+    pub schema: Schema,
+    /// `upgrades[n]` migrates payload version `n + 1` to `n + 2`
+    pub upgrades: &'static [Upgrade],
+}
+
+impl MessageType {
+    /// Current payload version
+    pub fn version(&self) -> u32 {
+        self.upgrades.len() as u32 + 1
+    }
+}
+
+/// Find registered message type by name
+pub fn message_type(name: &str) -> Option<&'static MessageType> {
+    MESSAGE_TYPES.iter().find(|m| m.name == name)
+}
+
+/// `sys.time` version 1 named `client_time` `time`
+fn sys_time_v2(mut payload: Value) -> Result<Value, String> {
+    if let Some(fields) = payload.as_object_mut() {
+        if let Some(time) = fields.remove("time") {
+            fields.entry("client_time").or_insert(time);
+        }
+    }
+    Ok(payload)
+}
+
+/// All message types of the protocol, the source for generated docs and clients
+pub static MESSAGE_TYPES: &[MessageType] = &[
+    MessageType {
+        name: "echo",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Payload is sent back unchanged",
+        schema: Schema::Any,
+        upgrades: &[],
+    },
+    MessageType {
+        name: "hello",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Describe the client, string attributes replace earlier ones; answered with the connection id",
+        schema: Schema::Object(&[
+            Field {
+                name: "device",
+                schema: Schema::String,
+                required: false,
+                doc: "Device type, e.g. `mobile`; any other string attribute may be sent too",
+            },
+            Field {
+                name: "app_version",
+                schema: Schema::String,
+                required: false,
+                doc: "Client application version",
+            },
+            Field {
+                name: "locale",
+                schema: Schema::String,
+                required: false,
+                doc: "BCP 47 language tag",
+            },
+            Field {
+                name: "heartbeat",
+                schema: Schema::Object(&[
+                    Field {
+                        name: "interval_ms",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Time between server pings",
+                    },
+                    Field {
+                        name: "timeout_ms",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Silence after which the connection is dropped",
+                    },
+                    Field {
+                        name: "clock",
+                        schema: Schema::Boolean,
+                        required: false,
+                        doc: "Put the server clock in pings, for the client's clock in pongs and `sys.clock`",
+                    },
+                ]),
+                required: false,
+                doc: "Proposed heartbeat; the server answers with what it granted within `[keepalive]`",
+            },
+            Field {
+                name: "connection_id",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Id of the connection in admin listings, sent by the server",
+            },
+            Field {
+                name: "resume",
+                schema: Schema::String,
+                required: false,
+                doc: "Token for `session.resume` once this connection closed, sent by the server with `[store]`",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "sys.time",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Server clock for client side clock sync, the client's send time is echoed back",
+        schema: Schema::Object(&[
+            Field {
+                name: "client_time",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Client clock when sending, unix milliseconds; echoed in the reply",
+            },
+            Field {
+                name: "server_time",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Server clock when replying, unix milliseconds, sent by the server",
+            },
+        ]),
+        upgrades: &[sys_time_v2],
+    },
+    MessageType {
+        name: "sys.rtt",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Round-trip time the server measured with heartbeat pings",
+        schema: Schema::Object(&[Field {
+            name: "rtt_ms",
+            schema: Schema::Number,
+            required: false,
+            doc: "Last measured round-trip time, null before the first pong; sent by the server",
+        }]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "sys.clock",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "How far the client clock is off, estimated from heartbeat pongs carrying it",
+        schema: Schema::Object(&[
+            Field {
+                name: "server_time",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Server clock when replying, unix milliseconds, sent by the server",
+            },
+            Field {
+                name: "rtt_ms",
+                schema: Schema::Number,
+                required: false,
+                doc: "Last measured round-trip time, null before the first pong; sent by the server",
+            },
+            Field {
+                name: "skew_ms",
+                schema: Schema::Integer,
+                required: false,
+                doc: "How far the client clock is ahead, null before a pong with it; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "auth.refresh",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary:
+            "Replace the connection's credential before it expires, answered with the new expiry",
+        schema: Schema::Object(&[
+            Field {
+                name: "token",
+                schema: Schema::String,
+                required: false,
+                doc: "Bearer token or session cookie value of the same user, sent by the client",
+            },
+            Field {
+                name: "expires_at",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Unix seconds the new credential expires, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "rpc.call",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Request of the server to this client, to be answered with an `rpc.result` of the same `id` within `timeout_ms`",
+        schema: Schema::Object(&[
+            Field {
+                name: "method",
+                schema: Schema::String,
+                required: true,
+                doc: "What the server asks for, named by the application",
+            },
+            Field {
+                name: "params",
+                schema: Schema::Any,
+                required: true,
+                doc: "Arguments of the method",
+            },
+            Field {
+                name: "timeout_ms",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Time after which the server stops waiting for the result",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "rpc.result",
+        direction: Direction::ClientToServer,
+        layout: Layout::Envelope,
+        summary: "Answer to an `rpc.call`, with its `id`; not answered, late results are dropped",
+        schema: Schema::Object(&[
+            Field {
+                name: "result",
+                schema: Schema::Any,
+                required: false,
+                doc: "Result of the method",
+            },
+            Field {
+                name: "error",
+                schema: Schema::Any,
+                required: false,
+                doc: "Why the method failed instead, passed on to the caller",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "$cancel",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Give up on a message still being handled, which is answered with `cancelled`",
+        schema: Schema::Object(&[
+            Field {
+                name: "id",
+                schema: Schema::String,
+                required: true,
+                doc: "`id` of the message to cancel",
+            },
+            Field {
+                name: "cancelled",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Handlers that were cancelled, 0 if it was answered already; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "$end",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Last message of a reply streamed in parts, each carrying the request's `id` and its `part` number",
+        schema: Schema::Object(&[
+            Field {
+                name: "type",
+                schema: Schema::String,
+                required: true,
+                doc: "Type of the parts",
+            },
+            Field {
+                name: "parts",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Number of parts sent",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "session.resume",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Rejoin the rooms of a closed connection of the same user, answered with the last sequence number of each when it closed",
+        schema: Schema::Object(&[
+            Field {
+                name: "token",
+                schema: Schema::String,
+                required: false,
+                doc: "`resume` token of the closed connection's `hello`, works once; sent by the client",
+            },
+            Field {
+                name: "rooms",
+                schema: Schema::Array(&Schema::Object(&[
+                    Field {
+                        name: "room",
+                        schema: Schema::String,
+                        required: true,
+                        doc: "Room name",
+                    },
+                    Field {
+                        name: "seq",
+                        schema: Schema::Integer,
+                        required: true,
+                        doc: "Last sequence number when the connection closed, for `history.fetch`",
+                    },
+                    Field {
+                        name: "members",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Member count after joining",
+                    },
+                    Field {
+                        name: "position",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Place in the queue if the room is full",
+                    },
+                ])),
+                required: false,
+                doc: "Rooms joined again, sent by the server",
+            },
+            Field {
+                name: "failed",
+                schema: Schema::Array(&Schema::Any),
+                required: false,
+                doc: "Rooms that could not be joined, with `room` and `error`, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "presence.last_seen",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "When users were last seen, answered with whether each is online and the unix ms they were last seen, null if never; needs `[store.last_seen]`",
+        schema: Schema::Object(&[
+            Field {
+                name: "users",
+                schema: Schema::Any,
+                required: true,
+                doc: "User names, at most 100; by the server, `{\"online\", \"last_seen\"}` by user",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.join",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Join a room; sent by the server when a waiting connection becomes a member",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name, at most 128 bytes",
+            },
+            Field {
+                name: "invite",
+                schema: Schema::String,
+                required: false,
+                doc: "Invite token, required to join a private room",
+            },
+            Field {
+                name: "private",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Make a room created by this join private",
+            },
+            Field {
+                name: "filter",
+                schema: Schema::String,
+                required: false,
+                doc: "Only get the messages this expression holds for, e.g. `data.level >= 3`",
+            },
+            Field {
+                name: "group",
+                schema: Schema::String,
+                required: false,
+                doc: "Consumer group; each message goes to one member of the group in turn",
+            },
+            Field {
+                name: "members",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Member count after joining, sent by the server",
+            },
+            Field {
+                name: "partitions",
+                schema: Schema::Array(&Schema::Object(&[
+                    Field {
+                        name: "room",
+                        schema: Schema::String,
+                        required: true,
+                        doc: "Partition name, `<room>#<number>`",
+                    },
+                    Field {
+                        name: "members",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Member count after joining",
+                    },
+                    Field {
+                        name: "position",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Queue position if the partition is full",
+                    },
+                ])),
+                required: false,
+                doc: "Partitions joined for a partitioned room, instead of `members`, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.waiting",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Room is full, the connection is queued; sent again when its position changes",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "position",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Place in the queue, 1 is next",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.leave",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Leave a room or its queue",
+        schema: Schema::Object(&[Field {
+            name: "room",
+            schema: Schema::String,
+            required: true,
+            doc: "Room name",
+        }]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.closed",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Room was destroyed, the connection is no longer a member",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "reason",
+                schema: Schema::Enum(&["expired", "kicked"]),
+                required: true,
+                doc: "`expired` once the room's `ttl_secs` passed, `kicked` by a moderator",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.publish",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Send data to the other members of a joined room, answered with its sequence number",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: false,
+                doc: "Message for the members, sent by the client",
+            },
+            Field {
+                name: "ack",
+                schema: Schema::Object(&[
+                    Field {
+                        name: "quorum",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Percent of the members to wait for, default 100",
+                    },
+                    Field {
+                        name: "timeout_ms",
+                        schema: Schema::Integer,
+                        required: false,
+                        doc: "Time to wait, default 10000, at most 60000",
+                    },
+                    Field {
+                        name: "exactly_once",
+                        schema: Schema::Boolean,
+                        required: false,
+                        doc: "Deliver it once only, with `room.release` and `room.complete`",
+                    },
+                ]),
+                required: false,
+                doc: "Ask the members to acknowledge it, reported with `room.acked`",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: false,
+                doc: "Ordering key, picks the partition of a partitioned room",
+            },
+            Field {
+                name: "delay_ms",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Publish it this much later instead of now",
+            },
+            Field {
+                name: "deliver_at",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Publish it at this time, unix milliseconds; when scheduled, also sent by the server",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Sequence number of the message in the room, sent by the server",
+            },
+            Field {
+                name: "scheduled",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Number of the scheduled message, sent by the server instead of `seq`",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.publish_batch",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Send data to several joined rooms at once, all published or none, answered with the sequence numbers",
+        schema: Schema::Object(&[Field {
+            name: "messages",
+            schema: Schema::Array(&Schema::Object(&[
+                Field {
+                    name: "room",
+                    schema: Schema::String,
+                    required: true,
+                    doc: "Room name",
+                },
+                Field {
+                    name: "data",
+                    schema: Schema::Any,
+                    required: false,
+                    doc: "Message for the members, sent by the client",
+                },
+                Field {
+                    name: "key",
+                    schema: Schema::String,
+                    required: false,
+                    doc: "Ordering key, picks the partition of a partitioned room",
+                },
+                Field {
+                    name: "seq",
+                    schema: Schema::Integer,
+                    required: false,
+                    doc: "Sequence number of the message in the room, sent by the server",
+                },
+            ])),
+            required: true,
+            doc: "1 to 64 messages, answered in the same order",
+        }]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.ephemeral",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Transient room event such as a typing indicator; not sequenced, not acknowledged, dropped for busy recipients",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: false,
+                doc: "Event, e.g. `{\"typing\": true}`",
+            },
+            Field {
+                name: "from",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Connection id of the sender, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.read",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Report messages up to `seq` as read, answered with the user's read position; other members get it with `user` when it moves",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the last message read",
+            },
+            Field {
+                name: "user",
+                schema: Schema::String,
+                required: false,
+                doc: "Reader, sent by the server to other members",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.receipts",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Read positions of a joined room's users",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "receipts",
+                schema: Schema::Any,
+                required: false,
+                doc: "Highest read sequence number by user, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.ack",
+        direction: Direction::ClientToServer,
+        layout: Layout::Envelope,
+        summary: "Acknowledge a `room.message` that asked for it, not answered",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.release",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Answer to the `room.ack` of an exactly-once message, to be answered with `room.complete`",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message, no longer sent again",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.complete",
+        direction: Direction::ClientToServer,
+        layout: Layout::Envelope,
+        summary: "Answer to `room.release`, the server forgets the message; not answered",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.acked",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Who acknowledged a message published with `ack`, once the quorum did or time is up; carries the publish's `id`",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message",
+            },
+            Field {
+                name: "members",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Members the message was sent to",
+            },
+            Field {
+                name: "acked",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Members that acknowledged it",
+            },
+            Field {
+                name: "complete",
+                schema: Schema::Boolean,
+                required: true,
+                doc: "Whether the quorum was reached",
+            },
+            Field {
+                name: "missing",
+                schema: Schema::Array(&Schema::Any),
+                required: true,
+                doc: "Members that did not acknowledge it, `{connection_id, user}`",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.moderate",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Moderate a room, for its moderators; answered with the same payload",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "action",
+                schema: Schema::Enum(&["mute", "unmute", "kick", "slow_mode", "grant", "revoke"]),
+                required: true,
+                doc: "`grant`/`revoke` change the moderators",
+            },
+            Field {
+                name: "user",
+                schema: Schema::String,
+                required: false,
+                doc: "Target user, for all actions but `slow_mode`",
+            },
+            Field {
+                name: "duration_secs",
+                schema: Schema::Integer,
+                required: false,
+                doc: "`mute`: until unmuted if absent; `kick`: time before rejoining, default 60",
+            },
+            Field {
+                name: "interval_secs",
+                schema: Schema::Integer,
+                required: false,
+                doc: "`slow_mode`: min interval between publishes of a user, 0 turns it off",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.message",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Message published to a joined room",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number, increasing per room",
+            },
+            Field {
+                name: "from",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Connection id of the publisher",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: true,
+                doc: "Published data",
+            },
+            Field {
+                name: "ack",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Acknowledge it with `room.ack`",
+            },
+            Field {
+                name: "exactly_once",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Keep its `room` and `seq` until `room.release`, drop it if already kept",
+            },
+            Field {
+                name: "dup",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Sent again to a resumed session, it may have been received",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "room.batch",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Messages of an aggregated room published within one window",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "messages",
+                schema: Schema::Array(&Schema::Any),
+                required: true,
+                doc: "`room.message` payloads in order",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "message.edit",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Replace the data of an own room message; answered and passed on to the other members with `edited_at`",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: false,
+                doc: "New data",
+            },
+            Field {
+                name: "edited_at",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Unix milliseconds of the edit, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "message.delete",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Delete an own room message, leaving a tombstone; answered and passed on to the other members",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "seq",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Sequence number of the message",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "history.fetch",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Page through the kept messages of a joined room, in sequence order with tombstones",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: true,
+                doc: "Room name",
+            },
+            Field {
+                name: "before",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Messages older than this `seq`; the latest messages if neither `before` nor `after` is set",
+            },
+            Field {
+                name: "after",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Messages newer than this `seq`, starting with the oldest",
+            },
+            Field {
+                name: "limit",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Page size, default 50, at most 200",
+            },
+            Field {
+                name: "stream",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Answer with all pages in the fetched direction, as parts ended by `$end`",
+            },
+            Field {
+                name: "messages",
+                schema: Schema::Any,
+                required: false,
+                doc: "Messages `{seq, from, user, data, at, edited_at, deleted}`, sent by the server",
+            },
+            Field {
+                name: "more",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Whether there are further messages in the fetched direction, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "history.search",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Search messages of the joined rooms, newest first; deleted messages are left out",
+        schema: Schema::Object(&[
+            Field {
+                name: "query",
+                schema: Schema::String,
+                required: true,
+                doc: "Words that must all occur in the message's strings, matched as word prefixes, case-insensitive",
+            },
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Only this joined room",
+            },
+            Field {
+                name: "since",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Published at or after, unix milliseconds",
+            },
+            Field {
+                name: "until",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Published before, unix milliseconds",
+            },
+            Field {
+                name: "limit",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Page size, default 20, at most 100",
+            },
+            Field {
+                name: "offset",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Matches to skip, `next_offset` of the previous page",
+            },
+            Field {
+                name: "stream",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Answer with all pages, as parts ended by `$end`",
+            },
+            Field {
+                name: "results",
+                schema: Schema::Any,
+                required: false,
+                doc: "Matching messages `{room, seq, from, user, data, at, edited_at}`, sent by the server",
+            },
+            Field {
+                name: "next_offset",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Offset of the next page, `null` on the last one; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "channel.open",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Open a virtual channel served by a named service, answered with its channel and service and, if flow controlled, credits",
+        schema: Schema::Object(&[
+            Field {
+                name: "channel",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Id chosen by the client, unique among its open channels",
+            },
+            Field {
+                name: "service",
+                schema: Schema::String,
+                required: true,
+                doc: "Service serving the channel, e.g. `echo`",
+            },
+            Field {
+                name: "params",
+                schema: Schema::Any,
+                required: false,
+                doc: "Passed to the service on open",
+            },
+            Field {
+                name: "credits",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Enables flow control, from the client the `channel.data` the server may send before more `channel.credit`, in the reply those the client may send",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "channel.data",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Data on an open channel; not acknowledged, answered only if rejected",
+        schema: Schema::Object(&[
+            Field {
+                name: "channel",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Channel id",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: false,
+                doc: "Any JSON",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "channel.credit",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Allow the other side to send more data on a flow controlled channel; not acknowledged, answered only if rejected",
+        schema: Schema::Object(&[
+            Field {
+                name: "channel",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Channel id",
+            },
+            Field {
+                name: "credits",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Further `channel.data` messages allowed",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "channel.close",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Close a channel; sent by the server unsolicited when the service closes it",
+        schema: Schema::Object(&[
+            Field {
+                name: "channel",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Channel id",
+            },
+            Field {
+                name: "reason",
+                schema: Schema::String,
+                required: false,
+                doc: "Why the service closed the channel, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.get",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Read a shared key, answered with its value and version, both null for a missing key",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the keys, for its members; those of the connection's tenant without",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: true,
+                doc: "Key",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: false,
+                doc: "Value, sent by the server",
+            },
+            Field {
+                name: "version",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Version of the last write, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.set",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Write a shared key, answered with the version of the write; subscribers get it as `kv.changed`",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the keys, for its members; those of the connection's tenant without",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: true,
+                doc: "Key",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: true,
+                doc: "Value, not null",
+            },
+            Field {
+                name: "if_version",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Only write if the key is at this version, 0 for a missing key; answered with `conflict` otherwise",
+            },
+            Field {
+                name: "version",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Version of the write, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.delete",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Remove a shared key, answered with whether it was there; subscribers get `kv.changed` with a null value",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the keys, for its members; those of the connection's tenant without",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: true,
+                doc: "Key",
+            },
+            Field {
+                name: "if_version",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Only write if the key is at this version, 0 for a missing key; answered with `conflict` otherwise",
+            },
+            Field {
+                name: "deleted",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Whether the key was there, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.subscribe",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Follow a shared key, or every key of the scope without one; answered with their entries",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the keys, for its members; those of the connection's tenant without",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: false,
+                doc: "Key, every key of the scope if unset",
+            },
+            Field {
+                name: "entries",
+                schema: Schema::Any,
+                required: false,
+                doc: "Value and version by key followed, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.unsubscribe",
+        direction: Direction::ClientToServer,
+        layout: Layout::Envelope,
+        summary: "Stop following a shared key, or every key of the scope without one",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the keys, for its members; those of the connection's tenant without",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: false,
+                doc: "Key, every key of the scope if unset",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "kv.changed",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Shared key followed was written",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the key, unset for a tenant key",
+            },
+            Field {
+                name: "key",
+                schema: Schema::String,
+                required: true,
+                doc: "Key",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: true,
+                doc: "New value, null for a delete",
+            },
+            Field {
+                name: "version",
+                schema: Schema::Integer,
+                required: true,
+                doc: "Version of the write",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "crdt.get",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Read a shared counter, set or register, answered with its kind and value, both null if it does not exist",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the object, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Object name",
+            },
+            Field {
+                name: "kind",
+                schema: Schema::Enum(&["counter", "set", "register"]),
+                required: false,
+                doc: "Kind of the object, sent by the server",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: false,
+                doc: "Value: an integer, an array of strings or any value, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "crdt.update",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Update a shared counter, set or register, created by its first update; answered with its new value",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the object, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Object name",
+            },
+            Field {
+                name: "kind",
+                schema: Schema::Enum(&["counter", "set", "register"]),
+                required: true,
+                doc: "Kind of the object",
+            },
+            Field {
+                name: "by",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Added to a counter, 1 if unset; negative to take away",
+            },
+            Field {
+                name: "add",
+                schema: Schema::String,
+                required: false,
+                doc: "Element added to a set",
+            },
+            Field {
+                name: "remove",
+                schema: Schema::String,
+                required: false,
+                doc: "Element removed from a set",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: false,
+                doc: "Value written to a register, or the new value sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "crdt.subscribe",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Follow a shared object, answered with its kind and value",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the object, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Object name",
+            },
+            Field {
+                name: "kind",
+                schema: Schema::Enum(&["counter", "set", "register"]),
+                required: false,
+                doc: "Kind of the object, sent by the server",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: false,
+                doc: "Value: an integer, an array of strings or any value, sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "crdt.unsubscribe",
+        direction: Direction::ClientToServer,
+        layout: Layout::Envelope,
+        summary: "Stop following a shared object",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the object, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Object name",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "crdt.changed",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Shared object followed changed, here or on another cluster node",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the object, unset for a tenant's",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Object name",
+            },
+            Field {
+                name: "kind",
+                schema: Schema::Enum(&["counter", "set", "register"]),
+                required: true,
+                doc: "Kind of the object",
+            },
+            Field {
+                name: "value",
+                schema: Schema::Any,
+                required: true,
+                doc: "New value",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "lock.acquire",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Take a lock, answered with its lease; `conflict` if it is held, or once granted with `wait_ms`",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the lock, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Lock name",
+            },
+            Field {
+                name: "ttl_ms",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Lease length, `locks.default_ttl_ms` if unset",
+            },
+            Field {
+                name: "wait_ms",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Wait up to this long for a held lock, `timeout` if not granted by then",
+            },
+            Field {
+                name: "token",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Fencing token, higher for every grant; sent by the server",
+            },
+            Field {
+                name: "expires_at",
+                schema: Schema::Integer,
+                required: false,
+                doc: "End of the lease, unix ms; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "lock.renew",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Extend the lease of a held lock, answered with its new end",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the lock, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Lock name",
+            },
+            Field {
+                name: "ttl_ms",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Lease length, `locks.default_ttl_ms` if unset",
+            },
+            Field {
+                name: "token",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Fencing token, higher for every grant; sent by the server",
+            },
+            Field {
+                name: "expires_at",
+                schema: Schema::Integer,
+                required: false,
+                doc: "End of the lease, unix ms; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "lock.release",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Give up a held lock, handing it to the next connection waiting",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the lock, for its members; the connection's tenant's without",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Lock name",
+            },
+            Field {
+                name: "released",
+                schema: Schema::Boolean,
+                required: false,
+                doc: "Sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "lock.lost",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Lease of a held lock expired or was released by an admin",
+        schema: Schema::Object(&[
+            Field {
+                name: "room",
+                schema: Schema::String,
+                required: false,
+                doc: "Room of the lock, unset for a tenant's",
+            },
+            Field {
+                name: "name",
+                schema: Schema::String,
+                required: true,
+                doc: "Lock name",
+            },
+            Field {
+                name: "reason",
+                schema: Schema::Enum(&["expired", "released"]),
+                required: true,
+                doc: "Why the lock was taken away",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "user.send",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Send a direct message to every connection of a user, answered with the connections of this node it reached; pushed to the user's devices if none, with `[push]`",
+        schema: Schema::Object(&[
+            Field {
+                name: "to",
+                schema: Schema::String,
+                required: true,
+                doc: "Recipient user, of the sender's tenant",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: false,
+                doc: "Message data, `null` if unset",
+            },
+            Field {
+                name: "delivered",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Connections of this node the message was queued for; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "user.message",
+        direction: Direction::ServerToClient,
+        layout: Layout::Envelope,
+        summary: "Direct message of another user",
+        schema: Schema::Object(&[
+            Field {
+                name: "from",
+                schema: Schema::String,
+                required: true,
+                doc: "Sending user",
+            },
+            Field {
+                name: "data",
+                schema: Schema::Any,
+                required: true,
+                doc: "Message data",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "push.register",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Register a device of the connection's user for push notifications of direct messages sent while offline",
+        schema: Schema::Object(&[
+            Field {
+                name: "platform",
+                schema: Schema::Enum(&["fcm", "apns", "web"]),
+                required: true,
+                doc: "Push service of the token",
+            },
+            Field {
+                name: "token",
+                schema: Schema::String,
+                required: true,
+                doc: "Device token, for `web` the `PushSubscription` as JSON",
+            },
+            Field {
+                name: "devices",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Devices the user has registered; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "push.unregister",
+        direction: Direction::Both,
+        layout: Layout::Envelope,
+        summary: "Stop push notifications to a device of the connection's user",
+        schema: Schema::Object(&[
+            Field {
+                name: "token",
+                schema: Schema::String,
+                required: true,
+                doc: "Device token it was registered with",
+            },
+            Field {
+                name: "devices",
+                schema: Schema::Integer,
+                required: false,
+                doc: "Devices the user has left; sent by the server",
+            },
+        ]),
+        upgrades: &[],
+    },
+    MessageType {
+        name: "error",
+        direction: Direction::ServerToClient,
+        layout: Layout::Flat,
+        summary: "Client message was rejected",
+        schema: Schema::Object(&[
+            Field {
+                name: "code",
+                schema: Schema::Enum(&[
+                    "bad_json",
+                    "unknown_type",
+                    "bad_payload",
+                    "permission_denied",
+                    "rate_limited",
+                    "unsupported_version",
+                    "room_full",
+                    "timeout",
+                    "cancelled",
+                    "conflict",
+                    "internal",
+                ]),
+                required: true,
+                doc: "Machine readable reason",
+            },
+            Field {
+                name: "message",
+                schema: Schema::String,
+                required: true,
+                doc: "Human readable reason",
+            },
+            Field {
+                name: "retryable",
+                schema: Schema::Boolean,
+                required: true,
+                doc: "Whether sending the same message again may succeed",
+            },
+        ]),
+        upgrades: &[],
+    },
+];
+
+/// Incoming message
+#[derive(Debug, Clone, Deserialize)]
+pub struct Envelope {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Payload version, 1 if absent
+    #[serde(default)]
+    pub version: Option<u32>,
+    #[serde(default)]
+    pub payload: Value,
+}
+
+impl Envelope {
+    pub fn parse(data: &[u8]) -> Result<Envelope, Error> {
+        serde_json::from_slice(data).map_err(|e| Error::new(ErrorCode::BadJson, e.to_string()))
+    }
+
+    /// Find message type and upgrade payload to its current version
+    pub fn resolve(&mut self) -> Result<&'static MessageType, Error> {
+        let ty = match message_type(&self.kind) {
+            Some(ty) if ty.direction != Direction::ServerToClient => ty,
+            _ => {
+                return Err(Error::new(
+                    ErrorCode::UnknownType,
+                    format!("unknown message type `{}`", self.kind),
+                ))
+            }
+        };
+        let version = self.version.unwrap_or(1);
+        if version == 0 || version > ty.version() {
+            return Err(Error::new(
+                ErrorCode::UnsupportedVersion,
+                format!("`{}` supports versions 1..={}", ty.name, ty.version()),
+            ));
+        }
+        for upgrade in &ty.upgrades[version as usize - 1..] {
+            let payload = std::mem::take(&mut self.payload);
+            self.payload = upgrade(payload).map_err(|e| Error::new(ErrorCode::BadPayload, e))?;
+        }
+        self.version = Some(ty.version());
+        Ok(ty)
+    }
+}
+
+#[derive(Serialize)]
+struct Outgoing<'a, T: Serialize> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    /// Number of a streamed reply's part, see `stream`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<u64>,
+    payload: &'a T,
+}
+
+/// Build message of `kind` correlated to request `id`
+pub fn message<T: Serialize>(kind: &str, id: Option<&str>, payload: &T) -> ws::Message {
+    let out = Outgoing {
+        kind,
+        id,
+        part: None,
+        payload,
+    };
+    ws::Message::Text(to_text(&out))
+}
+
+/// Build part `part` of the streamed reply of `kind` to request `id`
+pub fn part<T: Serialize>(kind: &str, id: Option<&str>, part: u64, payload: &T) -> ws::Message {
+    let out = Outgoing {
+        kind,
+        id,
+        part: Some(part),
+        payload,
+    };
+    ws::Message::Text(to_text(&out))
+}
+
+fn to_text<T: Serialize>(value: &T) -> ByteString {
+    let mut buf = pool::acquire();
+    // serializing our own types into a buffer cannot fail
+    serde_json::to_writer(&mut buf, value).expect("serializable message");
+    utf8::to_bytestring(buf.take()).expect("json is utf-8")
+}
+
+/// Why client input was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Text frame is not a valid envelope
+    BadJson,
+    /// No handler for message type
+    UnknownType,
+    /// Payload does not match message type
+    BadPayload,
+    /// Payload version is newer than the server knows, or invalid
+    UnsupportedVersion,
+    PermissionDenied,
+    RateLimited,
+    /// Room is at capacity and does not queue joins
+    RoomFull,
+    /// Handler took longer than `[handlers]` allows
+    Timeout,
+    /// Client cancelled the message with `$cancel`
+    Cancelled,
+    /// Key is not at the version the write expected, see `kv`
+    Conflict,
+    /// Server failed to handle the message
+    Internal,
+}
+
+impl ErrorCode {
+    /// Whether sending the same message again may succeed
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited | ErrorCode::RoomFull | ErrorCode::Timeout | ErrorCode::Internal
+        )
+    }
+}
+
+/// Error sent back to the client
+#[derive(Debug, Clone, Serialize)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new<T: Into<String>>(code: ErrorCode, message: T) -> Error {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Render as error message correlated to request `id`
+    pub fn to_message(&self, id: Option<&str>) -> ws::Message {
+        #[derive(Serialize)]
+        struct Out<'a> {
+            #[serde(rename = "type")]
+            kind: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            id: Option<&'a str>,
+            code: ErrorCode,
+            message: &'a str,
+            retryable: bool,
+        }
+        ws::Message::Text(to_text(&Out {
+            kind: "error",
+            id,
+            code: self.code,
+            message: &self.message,
+            retryable: self.code.retryable(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn resolved(envelope: Value) -> Result<Envelope, Error> {
+        let mut envelope = Envelope::parse(envelope.to_string().as_bytes())?;
+        envelope.resolve()?;
+        Ok(envelope)
+    }
+
+    #[test]
+    fn old_version_is_upgraded() {
+        let envelope = json!({ "type": "sys.time", "version": 1, "payload": { "time": 42 } });
+        let envelope = resolved(envelope).unwrap();
+        assert_eq!(envelope.version, Some(2));
+        assert_eq!(envelope.payload, json!({ "client_time": 42 }));
+    }
+
+    #[test]
+    fn current_shape_passes_upgrades() {
+        // without a version a payload is taken for version 1
+        let envelope = json!({ "type": "sys.time", "payload": { "client_time": 42 } });
+        let envelope = resolved(envelope).unwrap();
+        assert_eq!(envelope.payload, json!({ "client_time": 42 }));
+
+        let envelope = json!({ "type": "sys.time", "version": 2, "payload": { "time": 1 } });
+        assert_eq!(resolved(envelope).unwrap().payload, json!({ "time": 1 }));
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        for version in [0, 3] {
+            let envelope = json!({ "type": "sys.time", "version": version, "payload": {} });
+            let e = resolved(envelope).unwrap_err();
+            assert_eq!(e.code, ErrorCode::UnsupportedVersion);
+        }
+    }
+}
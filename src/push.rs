@@ -0,0 +1,470 @@
+//! Push notifications of direct messages to offline users.
+//!
+//! A `user.send` reaches every connection of its recipient, on every cluster
+//! node. With `[push]`, one to a user with none is handed to a notifier
+//! instead, for the devices the user registered with `push.register`: FCM
+//! or APNs tokens, or web push subscriptions. The notifier is picked by
+//! name: `log` only logs notifications, for development, and `webhook`
+//! posts them to a gateway holding the providers' credentials:
+//!
+//! ```text
+//! POST url  {"user": "..", "tenant": "..", "from": "..", "title": "..",
+//!            "body": "..", "data": .., "devices": [{"platform": "fcm", "token": ".."}]}
+//! 2xx
+//! ```
+//!
+//! Others, e.g. one calling a provider directly, are registered with
+//! `register` before the server starts.
+//!
+//! A notification's title and body are rendered from the `title` and `body`
+//! templates, where `{from}` is the sender, `{to}` the recipient, `{data}`
+//! the message data as JSON and `{data.a.b}` one of its fields; a missing
+//! field renders empty. A user gets at most `max_per_minute` notifications,
+//! further messages that minute are not pushed. Direct messages are not
+//! stored: an offline user only gets the notification.
+//!
+//! The recipient is offline without connections on this node and, with
+//! `[store]`, without a session on any instance, so in cluster mode the
+//! `redis` store is needed to tell.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::LocalBoxFuture;
+use ntex::{http::client::Client, time};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{Config, PushConfig, StoreBackend};
+use crate::metrics::METRICS;
+use crate::protocol::{self, ErrorCode};
+use crate::store::Store;
+
+/// Longest device token, web push subscriptions included
+const MAX_TOKEN_LEN: usize = 4096;
+
+/// Longest rendered title or body, in characters
+const MAX_TEXT_LEN: usize = 1024;
+
+/// Window of `push.max_per_minute`
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Fcm,
+    Apns,
+    /// Token is the browser's `PushSubscription` as JSON
+    Web,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub platform: Platform,
+    pub token: String,
+}
+
+/// Direct message whose recipient had no connection
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub tenant: Option<String>,
+    pub from: String,
+    pub to: String,
+    pub data: Value,
+}
+
+/// What a notifier is asked to send
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub user: String,
+    pub tenant: Option<String>,
+    pub from: String,
+    pub title: String,
+    pub body: String,
+    pub data: Value,
+    pub devices: Vec<Device>,
+}
+
+/// Sends notifications to devices; it may be sending others at the same
+/// time, on other workers too
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+static NOTIFIERS: RwLock<Vec<(&'static str, Arc<dyn Notifier>)>> = RwLock::new(Vec::new());
+
+/// Make `notifier` available by `name`, replacing an earlier one
+pub fn register(name: &'static str, notifier: Arc<dyn Notifier>) {
+    let mut notifiers = NOTIFIERS.write().unwrap();
+    notifiers.retain(|(n, _)| *n != name);
+    notifiers.push((name, notifier));
+}
+
+/// Notifier registered by `name`
+fn get(name: &str) -> Option<Arc<dyn Notifier>> {
+    let notifiers = NOTIFIERS.read().unwrap();
+    notifiers
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, notifier)| notifier.clone())
+}
+
+/// Check `[push]` can be set up in `config`
+pub fn validate(config: &Config) -> Result<(), String> {
+    let Some(ref push) = config.push else {
+        return Ok(());
+    };
+    notifier(push)?;
+    check_template(&push.title).map_err(|e| format!("push.title: {}", e))?;
+    check_template(&push.body).map_err(|e| format!("push.body: {}", e))?;
+    if push.max_per_minute == 0 {
+        return Err("push.max_per_minute must be at least 1".to_string());
+    }
+    if push.max_devices == 0 {
+        return Err("push.max_devices must be at least 1".to_string());
+    }
+    let shared = config
+        .store
+        .as_ref()
+        .is_some_and(|store| store.backend == StoreBackend::Redis);
+    if config.cluster.is_some() && !shared {
+        return Err(
+            "push: cluster mode needs the redis [store] to tell which users are offline"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Notifier `config` names
+fn notifier(config: &PushConfig) -> Result<Arc<dyn Notifier>, String> {
+    if let Some(notifier) = get(&config.notifier) {
+        return Ok(notifier);
+    }
+    match config.notifier.as_str() {
+        "log" => Ok(Arc::new(Log)),
+        "webhook" => {
+            let url = config
+                .url
+                .clone()
+                .ok_or("push.url is required by the webhook notifier")?;
+            Ok(Arc::new(Webhook {
+                url,
+                token: config.token.clone(),
+                timeout: Duration::from_millis(config.timeout_ms),
+            }))
+        }
+        name => Err(format!(
+            "push.notifier: no notifier `{}` is registered",
+            name
+        )),
+    }
+}
+
+/// Placeholders of `template`, checked to be known
+fn check_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `{{` in {:?}", template))?;
+        let name = &rest[start + 1..start + end];
+        match name {
+            "from" | "to" | "data" => {}
+            name if name
+                .strip_prefix("data.")
+                .is_some_and(|path| !path.is_empty()) => {}
+            name => return Err(format!("unknown placeholder `{{{}}}`", name)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// `template` with the placeholders of `msg` filled in
+fn render(template: &str, msg: &Message) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        match &rest[start + 1..start + end] {
+            "from" => out.push_str(&msg.from),
+            "to" => out.push_str(&msg.to),
+            "data" => out.push_str(&msg.data.to_string()),
+            name => {
+                let path = name.strip_prefix("data.").unwrap_or(name);
+                let field = path
+                    .split('.')
+                    .try_fold(&msg.data, |value, key| value.get(key));
+                match field {
+                    Some(Value::String(text)) => out.push_str(text),
+                    Some(Value::Null) | None => {}
+                    Some(value) => out.push_str(&value.to_string()),
+                }
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    match out.char_indices().nth(MAX_TEXT_LEN) {
+        Some((at, _)) => out[..at].to_string(),
+        None => out,
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    BadToken,
+}
+
+impl From<DeviceError> for protocol::Error {
+    fn from(e: DeviceError) -> Self {
+        match e {
+            DeviceError::BadToken => protocol::Error::new(
+                ErrorCode::BadPayload,
+                format!("token must be 1 to {} bytes", MAX_TOKEN_LEN),
+            ),
+        }
+    }
+}
+
+pub struct Push {
+    config: PushConfig,
+    notifier: Arc<dyn Notifier>,
+    /// Registered devices by user, oldest first
+    devices: Mutex<BTreeMap<String, Vec<Device>>>,
+    /// Start of the current window and notifications sent in it, by user
+    sent: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl fmt::Debug for Push {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Push")
+            .field("notifier", &self.config.notifier)
+            .field("users", &self.devices.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Push {
+    /// Set up `[push]`, with the devices of `push.devices_file`
+    pub fn open(config: &PushConfig) -> Result<Push, String> {
+        let notifier = notifier(config)?;
+        let devices = match config.devices_file {
+            Some(ref file) => load(file)?,
+            None => BTreeMap::new(),
+        };
+        if let Some(ref file) = config.devices_file {
+            log::info!(
+                "Loaded the push devices of {} users from {}",
+                devices.len(),
+                file.display()
+            );
+        }
+        Ok(Push {
+            config: config.clone(),
+            notifier,
+            devices: Mutex::new(devices),
+            sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Register a device of `user`, or move it to the newest; returns the
+    /// number of devices the user has
+    pub fn register(&self, user: &str, device: Device) -> Result<usize, DeviceError> {
+        if device.token.is_empty() || device.token.len() > MAX_TOKEN_LEN {
+            return Err(DeviceError::BadToken);
+        }
+        let mut devices = self.devices.lock().unwrap();
+        let list = devices.entry(user.to_string()).or_default();
+        list.retain(|d| d.token != device.token);
+        list.push(device);
+        if list.len() > self.config.max_devices {
+            list.remove(0);
+        }
+        let count = list.len();
+        self.save(&devices);
+        Ok(count)
+    }
+
+    /// Drop device `token` of `user`; returns the number of devices left
+    pub fn unregister(&self, user: &str, token: &str) -> usize {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(list) = devices.get_mut(user) else {
+            return 0;
+        };
+        let before = list.len();
+        list.retain(|d| d.token != token);
+        let count = list.len();
+        if count == 0 {
+            devices.remove(user);
+        }
+        if count != before {
+            self.save(&devices);
+        }
+        count
+    }
+
+    pub fn devices(&self, user: &str) -> Vec<Device> {
+        let devices = self.devices.lock().unwrap();
+        devices.get(user).cloned().unwrap_or_default()
+    }
+
+    fn save(&self, devices: &BTreeMap<String, Vec<Device>>) {
+        if let Some(ref file) = self.config.devices_file {
+            if let Err(e) = save(file, devices) {
+                log::warn!("Cannot save push devices to {}: {}", file.display(), e);
+            }
+        }
+    }
+
+    /// Whether `user` may get another notification now
+    fn allow(&self, user: &str) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        let now = Instant::now();
+        sent.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+        let (_, count) = sent.entry(user.to_string()).or_insert((now, 0));
+        if *count >= self.config.max_per_minute {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Notify the devices of the recipient of `msg`
+    async fn push(&self, msg: Message) {
+        let devices = self.devices(&msg.to);
+        if devices.is_empty() {
+            log::debug!("Not pushing a message to {}: no device registered", msg.to);
+            return;
+        }
+        if !self.allow(&msg.to) {
+            METRICS.pushes_rate_limited_total.inc();
+            log::debug!("Not pushing a message to {}: rate limited", msg.to);
+            return;
+        }
+        let notification = Notification {
+            title: render(&self.config.title, &msg),
+            body: render(&self.config.body, &msg),
+            user: msg.to,
+            tenant: msg.tenant,
+            from: msg.from,
+            data: msg.data,
+            devices,
+        };
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let sent = time::timeout(timeout, self.notifier.notify(&notification))
+            .await
+            .unwrap_or_else(|()| Err("timed out".to_string()));
+        match sent {
+            Ok(()) => METRICS.pushes_sent_total.inc(),
+            Err(e) => {
+                METRICS.pushes_failed_total.inc();
+                log::warn!(
+                    "Cannot push a message to {} with {}: {}",
+                    notification.user,
+                    self.config.notifier,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Push `msg` unless its recipient has a session on another instance
+pub async fn notify(push: Arc<Push>, store: Option<Arc<Store>>, msg: Message) {
+    if let Some(store) = store {
+        match store.presence(&msg.to).await {
+            // sessions record the tenant a client asked for, so any counts
+            Ok(sessions) if !sessions.is_empty() => return,
+            Ok(_) => {}
+            Err(e) => {
+                METRICS.pushes_failed_total.inc();
+                log::warn!("Cannot tell whether {} is offline: {}", msg.to, e);
+                return;
+            }
+        }
+    }
+    push.push(msg).await;
+}
+
+fn load(file: &Path) -> Result<BTreeMap<String, Vec<Device>>, String> {
+    match fs::read(file) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| format!("push.devices_file {}: {}", file.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(format!("push.devices_file {}: {}", file.display(), e)),
+    }
+}
+
+fn save(file: &Path, devices: &BTreeMap<String, Vec<Device>>) -> io::Result<()> {
+    let data = serde_json::to_vec(devices)?;
+    // replace atomically, a crash must not leave half a file
+    let tmp = file.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, file)
+}
+
+/// Only logs notifications
+#[derive(Debug)]
+struct Log;
+
+impl Notifier for Log {
+    fn notify<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> LocalBoxFuture<'a, Result<(), String>> {
+        log::info!(
+            "Push to {} ({} devices): {:?} {:?}",
+            notification.user,
+            notification.devices.len(),
+            notification.title,
+            notification.body
+        );
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Posts notifications to a gateway, see the module docs
+#[derive(Debug)]
+struct Webhook {
+    url: String,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl Notifier for Webhook {
+    fn notify<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> LocalBoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut req = Client::build()
+                .timeout(self.timeout)
+                .finish()
+                .post(&self.url);
+            if let Some(ref token) = self.token {
+                req = req.bearer_auth(token);
+            }
+            let res = req
+                .send_json(notification)
+                .await
+                .map_err(|e| e.to_string())?;
+            match res.status().is_success() {
+                true => Ok(()),
+                false => Err(res.status().to_string()),
+            }
+        })
+    }
+}
@@ -0,0 +1,119 @@
+//! Reconnect hints in close frames.
+//!
+//! Connections the server closes for its own reasons rather than the
+//! client's get JSON in the close reason telling them when, and maybe where,
+//! to come back, so well-behaved clients back off instead of hammering the
+//! server the moment it closes them:
+//!
+//! ```json
+//! {"reason": "shutdown", "retry_after": 12, "endpoint": "wss://b.example.com/ws"}
+//! ```
+//!
+//! `retry_after` is seconds, `[reconnect]`'s `retry_after_secs` plus a
+//! random part of up to `jitter_secs` per connection, and `endpoint` is only
+//! there if configured. The close code says the reason too:
+//!
+//! - `shutdown`, 1001 (going away): on `SIGTERM`, to every connection,
+//! - `maintenance`, 1012 (service restart): via `POST /admin/drain`,
+//! - `overload`, 1013 (try again later): via `POST /admin/drain` with a
+//!   `count` to shed, the connections with the fullest queues first.
+
+use std::sync::Arc;
+
+use nanorand::{Rng, WyRand};
+use ntex::{rt, ws};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ReconnectConfig;
+use crate::hub::Hub;
+
+/// Longest close reason a control frame carries
+const MAX_REASON: usize = 123;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cause {
+    Shutdown,
+    Maintenance,
+    Overload,
+}
+
+impl Cause {
+    fn code(self) -> ws::CloseCode {
+        match self {
+            Cause::Shutdown => ws::CloseCode::Away,
+            Cause::Maintenance => ws::CloseCode::Restart,
+            Cause::Overload => ws::CloseCode::Again,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Hint<'a> {
+    reason: Cause,
+    retry_after: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<&'a str>,
+}
+
+/// Check the hints fit into a close frame
+pub fn validate(config: &ReconnectConfig) -> Result<(), String> {
+    let longest = Hint {
+        reason: Cause::Maintenance,
+        retry_after: config.retry_after_secs.saturating_add(config.jitter_secs),
+        endpoint: config.endpoint.as_deref(),
+    };
+    let len = serde_json::to_string(&longest).expect("hint").len();
+    match len > MAX_REASON {
+        true => Err(format!(
+            "reconnect.endpoint is too long, close reasons fit {} bytes and would take {}",
+            MAX_REASON, len
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Close reason of connections closed for `cause`, with its own jitter
+pub fn close_reason(config: &ReconnectConfig, cause: Cause) -> ws::CloseReason {
+    let jitter = match config.jitter_secs {
+        0 => 0,
+        max => WyRand::new().generate_range(0..=max),
+    };
+    let hint = Hint {
+        reason: cause,
+        retry_after: config.retry_after_secs.saturating_add(jitter),
+        endpoint: config.endpoint.as_deref(),
+    };
+    ws::CloseReason {
+        code: cause.code(),
+        description: Some(serde_json::to_string(&hint).expect("hint")),
+    }
+}
+
+/// Close up to `count` connections for `cause`, all if `None`, those with
+/// the most queued messages first; returns how many were closed
+pub fn close(hub: &Hub, config: &ReconnectConfig, cause: Cause, count: Option<usize>) -> usize {
+    let mut conns = hub.connections();
+    conns.sort_by_key(|c| std::cmp::Reverse(c.queued));
+    conns
+        .iter()
+        .take(count.unwrap_or(usize::MAX))
+        .filter(|c| hub.close(c.id, close_reason(config, cause)))
+        .count()
+}
+
+/// Close every connection with shutdown hints on `SIGTERM`, while the
+/// server stops gracefully
+pub async fn on_shutdown(hub: Arc<Hub>, config: ReconnectConfig) {
+    while let Some(rx) = rt::signal() {
+        match rx.await {
+            Ok(rt::Signal::Term) => {
+                let closed = close(&hub, &config, Cause::Shutdown, None);
+                log::info!("Shutting down, {} connections closed", closed);
+                return;
+            }
+            Ok(_) => (),
+            Err(_) => return,
+        }
+    }
+}
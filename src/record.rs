@@ -0,0 +1,580 @@
+//! Recordings of a connection's frames, and their replay.
+//!
+//! With `[record]` a connection is recorded from its handshake if its user
+//! matches one of `record.users`, or from `POST /admin/connections/{id}/record`
+//! until `DELETE` or its close. A recording goes to a file of its own in
+//! `record.dir`, a header line then a line per frame either way, with the
+//! microseconds since the recording started:
+//!
+//! ```json
+//! {"connection": 7, "user": "alice", "started_at": 1700000000000}
+//! {"t_us": 0, "dir": "in", "kind": "text", "data": "{\"type\":\"room.join\",\"payload\":{\"room\":\"lobby\"}}"}
+//! {"t_us": 412, "dir": "out", "kind": "text", "data": "{\"type\":\"room.join\",\"payload\":{\"members\":3,\"room\":\"lobby\"}}"}
+//! {"t_us": 5001023, "dir": "out", "kind": "ping", "base64": "AAAAAAAATEs="}
+//! ```
+//!
+//! Frames are kept whole, unlike the copies of `tap`, so ask before
+//! recording someone's traffic. Past `record.max_bytes` of frames the
+//! recording stops.
+//!
+//! `Recording::open` reads one back; `replay` feeds the frames the client
+//! sent through a `WsState`, as a test would to reproduce what a user ran
+//! into, and returns its answers to compare with `outbound`.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ntex::time;
+use ntex::util::Bytes;
+use ntex::ws;
+use serde::{Deserialize, Serialize};
+
+use crate::config::RecordConfig;
+use crate::history::now_millis;
+use crate::hub::ConnStats;
+use crate::labels::Labels;
+use crate::session::WsState;
+
+/// First line of a recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub connection: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    /// Unix milliseconds
+    pub started_at: u64,
+}
+
+/// Way a frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dir {
+    /// From the client
+    In,
+    Out,
+}
+
+/// Part of a fragmented message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Part {
+    FirstText,
+    FirstBinary,
+    Continue,
+    Last,
+}
+
+/// A recorded frame; text that is not utf-8 is kept in `base64`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Frame {
+    Text {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base64: Option<String>,
+    },
+    Binary {
+        base64: String,
+    },
+    Ping {
+        base64: String,
+    },
+    Pong {
+        base64: String,
+    },
+    Continuation {
+        part: Part,
+        base64: String,
+    },
+    Close {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+}
+
+/// Line of a recording after the header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Microseconds since the recording started
+    pub t_us: u64,
+    pub dir: Dir,
+    #[serde(flatten)]
+    pub frame: Frame,
+}
+
+fn encode(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+fn decode(data: &str) -> Bytes {
+    Bytes::from(STANDARD.decode(data).unwrap_or_default())
+}
+
+fn text(data: &[u8]) -> Frame {
+    match std::str::from_utf8(data) {
+        Ok(text) => Frame::Text {
+            data: Some(text.to_string()),
+            base64: None,
+        },
+        Err(_) => Frame::Text {
+            data: None,
+            base64: Some(encode(data)),
+        },
+    }
+}
+
+fn part(item: &ws::Item) -> Frame {
+    let (part, data) = match item {
+        ws::Item::FirstText(data) => (Part::FirstText, data),
+        ws::Item::FirstBinary(data) => (Part::FirstBinary, data),
+        ws::Item::Continue(data) => (Part::Continue, data),
+        ws::Item::Last(data) => (Part::Last, data),
+    };
+    Frame::Continuation {
+        part,
+        base64: encode(data),
+    }
+}
+
+fn close(reason: &Option<ws::CloseReason>) -> Frame {
+    Frame::Close {
+        code: reason.as_ref().map(|r| u16::from(r.code)),
+        reason: reason.as_ref().and_then(|r| r.description.clone()),
+    }
+}
+
+impl Frame {
+    fn of_frame(frame: &ws::Frame) -> Frame {
+        match frame {
+            ws::Frame::Text(data) => text(data),
+            ws::Frame::Binary(data) => Frame::Binary {
+                base64: encode(data),
+            },
+            ws::Frame::Ping(data) => Frame::Ping {
+                base64: encode(data),
+            },
+            ws::Frame::Pong(data) => Frame::Pong {
+                base64: encode(data),
+            },
+            ws::Frame::Continuation(item) => part(item),
+            ws::Frame::Close(reason) => close(reason),
+        }
+    }
+
+    fn of_message(msg: &ws::Message) -> Frame {
+        match msg {
+            ws::Message::Text(data) => text(data.as_bytes()),
+            ws::Message::Binary(data) => Frame::Binary {
+                base64: encode(data),
+            },
+            ws::Message::Ping(data) => Frame::Ping {
+                base64: encode(data),
+            },
+            ws::Message::Pong(data) => Frame::Pong {
+                base64: encode(data),
+            },
+            ws::Message::Continuation(item) => part(item),
+            ws::Message::Close(reason) => close(reason),
+        }
+    }
+
+    fn bytes(&self) -> Bytes {
+        match self {
+            Frame::Text {
+                data: Some(data), ..
+            } => Bytes::copy_from_slice(data.as_bytes()),
+            Frame::Text { base64, .. } => decode(base64.as_deref().unwrap_or_default()),
+            Frame::Binary { base64 }
+            | Frame::Ping { base64 }
+            | Frame::Pong { base64 }
+            | Frame::Continuation { base64, .. } => decode(base64),
+            Frame::Close { .. } => Bytes::new(),
+        }
+    }
+
+    fn item(part: Part, data: Bytes) -> ws::Item {
+        match part {
+            Part::FirstText => ws::Item::FirstText(data),
+            Part::FirstBinary => ws::Item::FirstBinary(data),
+            Part::Continue => ws::Item::Continue(data),
+            Part::Last => ws::Item::Last(data),
+        }
+    }
+
+    fn close_reason(&self) -> Option<ws::CloseReason> {
+        match self {
+            Frame::Close {
+                code: Some(code),
+                reason,
+            } => Some(ws::CloseReason {
+                code: ws::CloseCode::from(*code),
+                description: reason.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// As the frame the server received
+    pub fn to_frame(&self) -> ws::Frame {
+        match self {
+            Frame::Text { .. } => ws::Frame::Text(self.bytes()),
+            Frame::Binary { .. } => ws::Frame::Binary(self.bytes()),
+            Frame::Ping { .. } => ws::Frame::Ping(self.bytes()),
+            Frame::Pong { .. } => ws::Frame::Pong(self.bytes()),
+            Frame::Continuation { part, .. } => {
+                ws::Frame::Continuation(Frame::item(*part, self.bytes()))
+            }
+            Frame::Close { .. } => ws::Frame::Close(self.close_reason()),
+        }
+    }
+
+    /// As the message the server sent; text that is not utf-8 is lossy
+    pub fn to_message(&self) -> ws::Message {
+        match self {
+            Frame::Text { .. } => {
+                ws::Message::Text(String::from_utf8_lossy(&self.bytes()).into_owned().into())
+            }
+            Frame::Binary { .. } => ws::Message::Binary(self.bytes()),
+            Frame::Ping { .. } => ws::Message::Ping(self.bytes()),
+            Frame::Pong { .. } => ws::Message::Pong(self.bytes()),
+            Frame::Continuation { part, .. } => {
+                ws::Message::Continuation(Frame::item(*part, self.bytes()))
+            }
+            Frame::Close { .. } => ws::Message::Close(self.close_reason()),
+        }
+    }
+}
+
+/// What a recording holds, when it stops
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub file: PathBuf,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+struct Active {
+    out: BufWriter<File>,
+    file: PathBuf,
+    started: Instant,
+    frames: u64,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    /// The connection is being recorded already
+    Recording,
+    Io(io::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Recording => write!(f, "connection is being recorded already"),
+            RecordError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RecordError {
+    fn from(e: io::Error) -> RecordError {
+        RecordError::Io(e)
+    }
+}
+
+/// Recording of a connection, if one is made
+#[derive(Debug, Default)]
+pub struct Recorder {
+    /// Checked before taking the lock
+    recording: AtomicBool,
+    active: Mutex<Option<Active>>,
+}
+
+impl Recorder {
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Stop the recording, returns what it holds
+    pub fn stop(&self) -> Option<Summary> {
+        let mut active = self.active.lock().unwrap().take()?;
+        self.recording.store(false, Ordering::Relaxed);
+        if let Err(e) = active.out.flush() {
+            log::error!("Failed to write {}: {}", active.file.display(), e);
+        }
+        Some(Summary {
+            file: active.file,
+            frames: active.frames,
+            bytes: active.bytes,
+        })
+    }
+
+    /// Record a frame received from the client
+    pub fn inbound(&self, frame: &ws::Frame) {
+        if self.is_recording() {
+            self.write(Dir::In, Frame::of_frame(frame));
+        }
+    }
+
+    /// Record a message sent to the client
+    pub fn outbound(&self, msg: &ws::Message) {
+        if self.is_recording() {
+            self.write(Dir::Out, Frame::of_message(msg));
+        }
+    }
+
+    fn write(&self, dir: Dir, frame: Frame) {
+        let mut guard = self.active.lock().unwrap();
+        let Some(ref mut active) = *guard else {
+            return;
+        };
+        let entry = Entry {
+            t_us: active.started.elapsed().as_micros() as u64,
+            dir,
+            frame,
+        };
+        let line = serde_json::to_string(&entry).expect("entry serializes");
+        active.frames += 1;
+        active.bytes += line.len() as u64 + 1;
+        let full = active.bytes > active.max_bytes;
+        if let Err(e) = writeln!(active.out, "{}", line).and_then(|_| active.out.flush()) {
+            log::error!("Failed to write {}: {}", active.file.display(), e);
+        }
+        drop(guard);
+        if full {
+            if let Some(summary) = self.stop() {
+                log::info!(
+                    "Recording {} stopped at record.max_bytes",
+                    summary.file.display()
+                );
+            }
+        }
+    }
+}
+
+/// Start recording connection `id` of `stats`, returns the file
+pub fn start(config: &RecordConfig, id: u64, stats: &ConnStats) -> Result<PathBuf, RecordError> {
+    let recorder = stats.recorder();
+    let mut active = recorder.active.lock().unwrap();
+    if active.is_some() {
+        return Err(RecordError::Recording);
+    }
+    fs::create_dir_all(&config.dir)?;
+    let header = Header {
+        connection: id,
+        user: stats.user().map(str::to_string),
+        tenant: stats.tenant().map(str::to_string),
+        labels: stats.labels().clone(),
+        started_at: now_millis(),
+    };
+    let file = config
+        .dir
+        .join(format!("{}-{}.jsonl", header.started_at, id));
+    let mut out = BufWriter::new(File::create(&file)?);
+    let line = serde_json::to_string(&header).expect("header serializes");
+    writeln!(out, "{}", line)?;
+    out.flush()?;
+    *active = Some(Active {
+        out,
+        file: file.clone(),
+        started: Instant::now(),
+        frames: 0,
+        bytes: 0,
+        max_bytes: config.max_bytes,
+    });
+    recorder.recording.store(true, Ordering::Relaxed);
+    log::info!("Recording connection {} to {}", id, file.display());
+    Ok(file)
+}
+
+/// A recording read back
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub header: Header,
+    pub entries: Vec<Entry>,
+}
+
+impl Recording {
+    pub fn open(path: &Path) -> io::Result<Recording> {
+        let invalid = |n: usize, e: serde_json::Error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), n + 1, e),
+            )
+        };
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).map_err(|e| invalid(0, e))?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: empty recording", path.display()),
+                ))
+            }
+        };
+        let mut entries = Vec::new();
+        for (n, line) in lines.enumerate() {
+            entries.push(serde_json::from_str(&line?).map_err(|e| invalid(n + 1, e))?);
+        }
+        Ok(Recording { header, entries })
+    }
+
+    /// Frames the client sent, with the time since the recording started
+    pub fn inbound(&self) -> impl Iterator<Item = (Duration, ws::Frame)> + '_ {
+        self.entries
+            .iter()
+            .filter(|entry| entry.dir == Dir::In)
+            .map(|entry| (Duration::from_micros(entry.t_us), entry.frame.to_frame()))
+    }
+
+    /// Messages the server sent, its answers and those of the hub
+    pub fn outbound(&self) -> Vec<ws::Message> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.dir == Dir::Out)
+            .map(|entry| entry.frame.to_message())
+            .collect()
+    }
+
+    /// Feed the frames the client sent through `state`, returns its answer
+    /// to each
+    pub fn replay(&self, state: &mut WsState) -> Vec<Option<ws::Message>> {
+        self.inbound()
+            .map(|(_, frame)| state.handle_frame(frame))
+            .collect()
+    }
+
+    /// Like `replay`, waiting between the frames as long as the client did
+    pub async fn replay_timed(&self, state: &mut WsState) -> Vec<Option<ws::Message>> {
+        let start = Instant::now();
+        let mut answers = Vec::new();
+        for (at, frame) in self.inbound() {
+            time::sleep(at.saturating_sub(start.elapsed())).await;
+            answers.push(state.handle_frame(frame));
+        }
+        answers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::auth::Authenticator;
+    use crate::config::{AuthConfig, HistoryConfig, HubConfig, RoomsConfig};
+    use crate::history::History;
+    use crate::hub::Hub;
+
+    fn state(id: u64) -> WsState {
+        let hub = Hub::new(
+            &HubConfig::default(),
+            RoomsConfig::default(),
+            History::open(&HistoryConfig::default()).unwrap(),
+            None,
+        );
+        let auth = Authenticator::new(&AuthConfig::default(), None);
+        WsState::new(id, None, None, Arc::new(hub), Arc::new(auth))
+    }
+
+    fn text(data: &str) -> ws::Frame {
+        ws::Frame::Text(Bytes::copy_from_slice(data.as_bytes()))
+    }
+
+    fn part(item: ws::Item) -> ws::Frame {
+        ws::Frame::Continuation(item)
+    }
+
+    #[test]
+    fn replay_reproduces_a_session() {
+        let config = RecordConfig {
+            dir: std::env::temp_dir().join(format!("wss-record-{}", process::id())),
+            ..RecordConfig::default()
+        };
+        let frames = [
+            text(r#"{"type":"echo","id":"1","payload":{"any":"json"}}"#),
+            ws::Frame::Ping(Bytes::from_static(b"\x00\xff")),
+            part(ws::Item::FirstText(Bytes::from_static(
+                br#"{"type":"echo","#,
+            ))),
+            part(ws::Item::Continue(Bytes::from_static(br#""id":"2","#))),
+            part(ws::Item::Last(Bytes::copy_from_slice(
+                "\"payload\":\"é\"}".as_bytes(),
+            ))),
+            text(r#"{"type":"room.join","id":"3","payload":{"room":"lobby"}}"#),
+            text("not json"),
+            ws::Frame::Binary(Bytes::from_static(b"\x01\x02")),
+        ];
+
+        // what ws_service does with each frame
+        let mut live = state(1);
+        let stats = live.stats().clone();
+        let file = start(&config, 1, &stats).unwrap();
+        assert!(matches!(
+            start(&config, 1, &stats),
+            Err(RecordError::Recording)
+        ));
+        let mut answers = Vec::new();
+        for frame in frames.clone() {
+            stats.received(&frame);
+            let answer = live.handle_frame(frame);
+            if let Some(ref answer) = answer {
+                stats.sent(answer);
+            }
+            answers.push(answer);
+        }
+        let summary = stats.recorder().stop().unwrap();
+        assert_eq!(summary.file, file);
+        assert!(!stats.recorder().is_recording());
+
+        let recording = Recording::open(&file).unwrap();
+        fs::remove_dir_all(&config.dir).unwrap();
+        assert_eq!(recording.header.connection, 1);
+        let inbound: Vec<ws::Frame> = recording.inbound().map(|(_, frame)| frame).collect();
+        assert_eq!(inbound, frames);
+        let sent: Vec<ws::Message> = answers.iter().flatten().cloned().collect();
+        assert_eq!(sent.len() as u64 + frames.len() as u64, summary.frames);
+        assert_eq!(recording.outbound(), sent);
+
+        // a fresh server answers the recorded frames the same way
+        assert_eq!(recording.replay(&mut state(2)), answers);
+    }
+
+    #[test]
+    fn frames_survive_a_recording() {
+        let close = ws::Frame::Close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("bye".to_string()),
+        }));
+        for frame in [
+            text("plain"),
+            ws::Frame::Text(Bytes::from_static(b"\xff not utf-8")),
+            ws::Frame::Pong(Bytes::new()),
+            part(ws::Item::FirstBinary(Bytes::from_static(b"\x00"))),
+            ws::Frame::Close(None),
+            close,
+        ] {
+            let line = serde_json::to_string(&Frame::of_frame(&frame)).unwrap();
+            let read: Frame = serde_json::from_str(&line).unwrap();
+            assert_eq!(read.to_frame(), frame, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! HTTP to HTTPS redirects for listeners with `redirect_https = true`.
+//!
+//! Requests arriving on such a listener never reach the app, they get a
+//! `301` to the same host, path and query on the first TLS listener's port.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use ntex::http::header::{self, HeaderValue};
+use ntex::service::{Service, Transform};
+use ntex::web::{HttpResponse, WebRequest, WebResponse};
+
+use crate::config::ListenerConfig;
+
+#[derive(Debug)]
+struct Inner {
+    /// Local addresses of redirecting listeners
+    addrs: Vec<SocketAddr>,
+    /// Port of the HTTPS origin, `None` for 443
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    inner: Arc<Inner>,
+}
+
+impl Redirect {
+    pub fn new(listeners: &[ListenerConfig]) -> Result<Redirect, String> {
+        let mut addrs = Vec::new();
+        for listener in listeners.iter().filter(|l| l.redirect_https) {
+            if listener.tls {
+                return Err(format!("listener {} redirects to itself", listener.address));
+            }
+            let resolved = listener
+                .address
+                .to_socket_addrs()
+                .map_err(|e| format!("{}: {}", listener.address, e))?;
+            addrs.extend(resolved);
+        }
+        let port = match listeners.iter().find(|l| l.tls) {
+            Some(l) => match l.address.rsplit_once(':').map(|(_, p)| p.parse::<u16>()) {
+                Some(Ok(443)) => None,
+                Some(Ok(port)) => Some(port),
+                _ => return Err(format!("listener {} has no port", l.address)),
+            },
+            None if !addrs.is_empty() => {
+                return Err("redirect_https needs a tls listener".to_string())
+            }
+            None => None,
+        };
+        Ok(Redirect {
+            inner: Arc::new(Inner { addrs, port }),
+        })
+    }
+}
+
+/// Strip port from a `Host` header value, keeps IPv6 brackets
+fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+impl Inner {
+    fn location<E>(&self, req: &WebRequest<E>) -> Option<HeaderValue> {
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_else(|| req.app_config().host());
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let url = match self.port {
+            Some(port) => format!("https://{}:{}{}", host_name(host), port, path),
+            None => format!("https://{}{}", host_name(host), path),
+        };
+        HeaderValue::try_from(url).ok()
+    }
+}
+
+impl<S> Transform<S> for Redirect {
+    type Service = RedirectMiddleware<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        RedirectMiddleware {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub struct RedirectMiddleware<S> {
+    service: S,
+    inner: Arc<Inner>,
+}
+
+impl<S, E> Service<WebRequest<E>> for RedirectMiddleware<S>
+where
+    S: Service<WebRequest<E>, Response = WebResponse>,
+    S::Future: 'static,
+{
+    type Response = WebResponse;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: WebRequest<E>) -> Self::Future {
+        if !self.inner.addrs.contains(&req.app_config().local_addr()) {
+            return Box::pin(self.service.call(req));
+        }
+        let res = match self.inner.location(&req) {
+            Some(location) => HttpResponse::MovedPermanently()
+                .header(header::LOCATION, location)
+                .finish(),
+            None => HttpResponse::BadRequest().finish(),
+        };
+        let res = req.into_response(res);
+        Box::pin(async move { Ok(res) })
+    }
+}
@@ -0,0 +1,369 @@
+//! Minimal client of a Redis server.
+//!
+//! Speaks RESP2 over one TCP connection, opened on first use and again after
+//! a failure. A batch of commands is pipelined: written at once, then the
+//! replies are read in order. Only what `store` needs is implemented.
+
+use std::fmt;
+use std::time::Duration;
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use ntex::time;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Time a batch may take, connecting included
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest bulk string accepted
+const MAX_BULK: usize = 64 * 1024 * 1024;
+
+/// Most elements of an array accepted
+const MAX_ARRAY: usize = 1024 * 1024;
+
+/// Arguments of a command, the name first
+pub type Command = Vec<String>;
+
+/// Build a command
+pub fn cmd(args: &[&str]) -> Command {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    /// Null bulk string or array
+    Nil,
+}
+
+impl Reply {
+    /// Bulk string as text, `None` for nil or other replies
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Reply::Bulk(bytes) => String::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    /// Elements of an array, none for other replies
+    pub fn into_array(self) -> Vec<Reply> {
+        match self {
+            Reply::Array(items) => items,
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub struct Redis {
+    /// `host:port`
+    addr: String,
+    db: u32,
+    password: Option<String>,
+    conn: Option<BufReader<TcpStream>>,
+}
+
+impl fmt::Debug for Redis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redis")
+            .field("addr", &self.addr)
+            .field("db", &self.db)
+            .field("connected", &self.conn.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Redis {
+    /// Client of `redis://host[:port][/db]`, not connected yet
+    pub fn new(url: &str, password: Option<String>) -> Result<Redis, String> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("redis url {} does not start with redis://", url))?;
+        let (host, db) = match rest.split_once('/') {
+            Some((host, "")) => (host, 0),
+            Some((host, db)) => match db.parse() {
+                Ok(db) => (host, db),
+                Err(_) => return Err(format!("redis url {}: bad database `{}`", url, db)),
+            },
+            None => (rest, 0),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(format!(
+                "redis url {}: expected a host, the password goes into `password`",
+                url
+            ));
+        }
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => host.to_string(),
+            _ => format!("{}:6379", host),
+        };
+        Ok(Redis {
+            addr,
+            db,
+            password,
+            conn: None,
+        })
+    }
+
+    /// Send `commands` in one batch, returns their replies in order; a
+    /// failed command is a `Reply::Error`, a failed connection an `Err`
+    pub async fn run(&mut self, commands: &[Command]) -> Result<Vec<Reply>, String> {
+        let result = time::timeout(TIMEOUT, self.exchange(commands))
+            .await
+            .unwrap_or_else(|()| Err("timed out".to_string()));
+        if result.is_err() {
+            self.conn = None;
+        }
+        result
+    }
+
+    async fn exchange(&mut self, commands: &[Command]) -> Result<Vec<Reply>, String> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect().await?);
+        }
+        let conn = self.conn.as_mut().expect("connected");
+        send(conn, commands).await
+    }
+
+    /// Connect, authenticate and select the database
+    async fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(|e| format!("cannot connect to {}: {}", self.addr, e))?;
+        let _ = stream.set_nodelay(true);
+        let mut conn = BufReader::new(stream);
+        let mut setup = Vec::new();
+        if let Some(ref password) = self.password {
+            setup.push(cmd(&["AUTH", password]));
+        }
+        if self.db != 0 {
+            setup.push(cmd(&["SELECT", &self.db.to_string()]));
+        }
+        for reply in send(&mut conn, &setup).await? {
+            if let Reply::Error(e) = reply {
+                return Err(format!("{}: {}", self.addr, e));
+            }
+        }
+        log::debug!("Connected to redis at {}", self.addr);
+        Ok(conn)
+    }
+}
+
+async fn send(conn: &mut BufReader<TcpStream>, commands: &[Command]) -> Result<Vec<Reply>, String> {
+    if commands.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut buf = Vec::new();
+    for command in commands {
+        encode(command, &mut buf);
+    }
+    conn.get_mut()
+        .write_all(&buf)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut replies = Vec::with_capacity(commands.len());
+    for _ in commands {
+        replies.push(read(conn).await?);
+    }
+    Ok(replies)
+}
+
+/// Command as an array of bulk strings
+fn encode(command: &Command, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+    for arg in command {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// One reply, read from wherever the previous one ended
+fn read<R: AsyncBufRead + Unpin>(conn: &mut R) -> LocalBoxFuture<'_, Result<Reply, String>> {
+    async move {
+        let mut line = String::new();
+        match conn.read_line(&mut line).await {
+            Ok(0) => return Err("connection closed".to_string()),
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        let line = match line.strip_suffix("\r\n") {
+            Some(line) => line,
+            None => return Err("connection closed".to_string()),
+        };
+        let kind = line.chars().next();
+        let rest = &line[kind.map_or(0, char::len_utf8)..];
+        let len = || -> Result<i64, String> {
+            rest.parse()
+                .map_err(|_| format!("bad reply length `{}`", rest))
+        };
+        match kind {
+            Some('+') => Ok(Reply::Status(rest.to_string())),
+            Some('-') => Ok(Reply::Error(rest.to_string())),
+            Some(':') => len().map(Reply::Integer),
+            Some('$') => match len()? {
+                n if n < 0 => Ok(Reply::Nil),
+                n if n as usize > MAX_BULK => Err(format!("bulk reply of {} bytes", n)),
+                n => {
+                    let mut bytes = vec![0; n as usize + 2];
+                    conn.read_exact(&mut bytes)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    if !bytes.ends_with(b"\r\n") {
+                        return Err("bulk reply not terminated".to_string());
+                    }
+                    bytes.truncate(n as usize);
+                    Ok(Reply::Bulk(bytes))
+                }
+            },
+            Some('*') => match len()? {
+                n if n < 0 => Ok(Reply::Nil),
+                n if n as usize > MAX_ARRAY => Err(format!("array reply of {} items", n)),
+                n => {
+                    let mut items = Vec::with_capacity(n as usize);
+                    for _ in 0..n {
+                        items.push(read(conn).await?);
+                    }
+                    Ok(Reply::Array(items))
+                }
+            },
+            _ => Err(format!("bad reply `{}`", line)),
+        }
+    }
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::executor::block_on;
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    use super::*;
+
+    /// Hands out its input a byte at a time, pending in between
+    struct Trickle {
+        data: Vec<u8>,
+        at: usize,
+        ready: bool,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            if let Some(&b) = self.data.get(self.at) {
+                self.at += 1;
+                buf.put_slice(&[b]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn replies(data: &[u8]) -> Vec<Result<Reply, String>> {
+        let mut conn = BufReader::new(Trickle {
+            data: data.to_vec(),
+            at: 0,
+            ready: false,
+        });
+        let mut replies = Vec::new();
+        loop {
+            let reply = block_on(read(&mut conn));
+            let failed = reply.is_err();
+            replies.push(reply);
+            if failed {
+                return replies;
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_bulk_arrays() {
+        let mut buf = Vec::new();
+        encode(&cmd(&["SET", "k", ""]), &mut buf);
+        encode(&cmd(&["GET", "ké"]), &mut buf);
+        assert_eq!(
+            buf,
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$0\r\n\r\n*2\r\n$3\r\nGET\r\n$3\r\nk\xc3\xa9\r\n"
+        );
+    }
+
+    #[test]
+    fn decodes_partial_reads() {
+        let data = b"+OK\r\n:-42\r\n$5\r\na\r\nb!\r\n$0\r\n\r\n$-1\r\n*-1\r\n\
+                     *3\r\n:1\r\n*1\r\n$1\r\nx\r\n$-1\r\n*0\r\n";
+        let replies = replies(data);
+        assert_eq!(
+            replies,
+            [
+                Ok(Reply::Status("OK".to_string())),
+                Ok(Reply::Integer(-42)),
+                Ok(Reply::Bulk(b"a\r\nb!".to_vec())),
+                Ok(Reply::Bulk(Vec::new())),
+                Ok(Reply::Nil),
+                Ok(Reply::Nil),
+                Ok(Reply::Array(vec![
+                    Reply::Integer(1),
+                    Reply::Array(vec![Reply::Bulk(b"x".to_vec())]),
+                    Reply::Nil,
+                ])),
+                Ok(Reply::Array(Vec::new())),
+                Err("connection closed".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_replies() {
+        let replies = replies(b"-WRONGTYPE Operation against a key\r\n*2\r\n-ERR x\r\n+OK\r\n");
+        assert_eq!(
+            replies[..2],
+            [
+                Ok(Reply::Error(
+                    "WRONGTYPE Operation against a key".to_string()
+                )),
+                Ok(Reply::Array(vec![
+                    Reply::Error("ERR x".to_string()),
+                    Reply::Status("OK".to_string()),
+                ])),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_replies() {
+        for data in [
+            &b""[..],
+            b"+OK",
+            b"+OK\n",
+            b"\r\n",
+            b"?what\r\n",
+            b"\xc3\xa9\r\n",
+            b"\xff\r\n",
+            b":x\r\n",
+            b"$\r\n",
+            b"$3\r\nab",
+            b"$3\r\nabcd\r\n",
+            b"$99999999999\r\n",
+            b"*2\r\n:1\r\n",
+            b"*99999999999\r\n",
+        ] {
+            let replies = replies(data);
+            assert!(replies[0].is_err(), "{:?} gave {:?}", data, replies);
+        }
+    }
+}
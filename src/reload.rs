@@ -0,0 +1,357 @@
+//! Live config reload on `SIGHUP` or when the config file changes.
+//!
+//! Only log levels, feature flags, IP filter rules and cron jobs are applied
+//! to the running server; changes to other sections are logged and need a
+//! restart.
+//! A config that fails to load or validate is rejected as a whole.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use ntex::{rt, time};
+
+use crate::config::{Config, JobConfig};
+use crate::logging::{self, LogLevels};
+use crate::tasks::TaskHandle;
+use crate::{
+    affinity, chaos, flags, inflight, ipfilter, jobs, labels, pipeline, push, reconnect, store,
+};
+
+/// Editors write files in several steps, wait for them to settle
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Describe changed sections, secrets are not printed
+fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.log.filter != new.log.filter {
+        changes.push(format!(
+            "log.filter: `{}` -> `{}`",
+            old.log.filter, new.log.filter
+        ));
+    }
+    if old.log.file != new.log.file {
+        changes.push(format!(
+            "log.file (restart required): {:?} -> {:?}",
+            old.log.file, new.log.file
+        ));
+    }
+    if old.access_log != new.access_log {
+        changes.push(format!(
+            "access_log (restart required): {:?} -> {:?}",
+            old.access_log, new.access_log
+        ));
+    }
+    if old.flags != new.flags {
+        changes.push(format!("flags: {:?} -> {:?}", old.flags, new.flags));
+    }
+    if old.listeners != new.listeners || old.tls != new.tls {
+        changes.push("listeners/tls (restart required)".to_string());
+    }
+    if old.cors != new.cors {
+        changes.push(format!(
+            "cors (restart required): {:?} -> {:?}",
+            old.cors, new.cors
+        ));
+    }
+    if old.security_headers != new.security_headers {
+        changes.push(format!(
+            "security_headers (restart required): {:?} -> {:?}",
+            old.security_headers, new.security_headers
+        ));
+    }
+    if old.static_files != new.static_files {
+        changes.push(format!(
+            "static (restart required): {:?} -> {:?}",
+            old.static_files, new.static_files
+        ));
+    }
+    if old.admin != new.admin {
+        changes.push("admin (restart required)".to_string());
+    }
+    if old.auth != new.auth {
+        changes.push("auth (restart required)".to_string());
+    }
+    if old.pool != new.pool {
+        changes.push(format!(
+            "pool (restart required): {:?} -> {:?}",
+            old.pool, new.pool
+        ));
+    }
+    if old.hub != new.hub {
+        changes.push(format!(
+            "hub (restart required): {:?} -> {:?}",
+            old.hub, new.hub
+        ));
+    }
+    if old.keepalive != new.keepalive {
+        changes.push(format!(
+            "keepalive (restart required): {:?} -> {:?}",
+            old.keepalive, new.keepalive
+        ));
+    }
+    if old.handlers != new.handlers {
+        changes.push(format!(
+            "handlers (restart required): {:?} -> {:?}",
+            old.handlers, new.handlers
+        ));
+    }
+    if old.schedule != new.schedule {
+        changes.push(format!(
+            "schedule (restart required): {:?} -> {:?}",
+            old.schedule, new.schedule
+        ));
+    }
+    if old.kv != new.kv {
+        changes.push(format!(
+            "kv (restart required): {:?} -> {:?}",
+            old.kv, new.kv
+        ));
+    }
+    if old.crdt != new.crdt {
+        changes.push(format!(
+            "crdt (restart required): {:?} -> {:?}",
+            old.crdt, new.crdt
+        ));
+    }
+    if old.locks != new.locks {
+        changes.push(format!(
+            "locks (restart required): {:?} -> {:?}",
+            old.locks, new.locks
+        ));
+    }
+    if old.jobs != new.jobs {
+        let names = |jobs: &[JobConfig]| jobs.iter().map(|j| j.name.clone()).collect::<Vec<_>>();
+        changes.push(format!(
+            "jobs: {:?} -> {:?}",
+            names(&old.jobs),
+            names(&new.jobs)
+        ));
+    }
+    if old.reconnect != new.reconnect {
+        changes.push(format!(
+            "reconnect (restart required): {:?} -> {:?}",
+            old.reconnect, new.reconnect
+        ));
+    }
+    if old.accept != new.accept {
+        changes.push(format!(
+            "accept (restart required): {:?} -> {:?}",
+            old.accept, new.accept
+        ));
+    }
+    if old.ip_filter.file != new.ip_filter.file {
+        changes.push("ip_filter.file (restart required)".to_string());
+    }
+    if old.ip_filter.allow != new.ip_filter.allow || old.ip_filter.deny != new.ip_filter.deny {
+        changes.push(format!(
+            "ip_filter: allow {:?} -> {:?}, deny {:?} -> {:?}",
+            old.ip_filter.allow, new.ip_filter.allow, old.ip_filter.deny, new.ip_filter.deny
+        ));
+    }
+    if old.rooms != new.rooms {
+        changes.push(format!(
+            "rooms (restart required): {:?} -> {:?}",
+            old.rooms, new.rooms
+        ));
+    }
+    if old.history != new.history {
+        changes.push(format!(
+            "history (restart required): {:?} -> {:?}",
+            old.history, new.history
+        ));
+    }
+    if old.statsd != new.statsd {
+        changes.push("statsd (restart required)".to_string());
+    }
+    if old.room_metrics != new.room_metrics {
+        changes.push(format!(
+            "room_metrics (restart required): {:?} -> {:?}",
+            old.room_metrics, new.room_metrics
+        ));
+    }
+    if old.labels != new.labels {
+        changes.push(format!(
+            "labels (restart required): {:?} -> {:?}",
+            old.labels, new.labels
+        ));
+    }
+    if old.tap != new.tap {
+        changes.push(format!(
+            "tap (restart required): {:?} -> {:?}",
+            old.tap, new.tap
+        ));
+    }
+    if old.record != new.record {
+        changes.push(format!(
+            "record (restart required): {:?} -> {:?}",
+            old.record, new.record
+        ));
+    }
+    if old.chaos != new.chaos {
+        changes.push(format!(
+            "chaos (restart required): {:?} -> {:?}",
+            old.chaos, new.chaos
+        ));
+    }
+    if old.canary != new.canary {
+        changes.push(format!(
+            "canary (restart required): {:?} -> {:?}",
+            old.canary, new.canary
+        ));
+    }
+    if old.sentry != new.sentry {
+        changes.push("sentry (restart required)".to_string());
+    }
+    if old.grpc != new.grpc {
+        changes.push(format!(
+            "grpc (restart required): {:?} -> {:?}",
+            old.grpc, new.grpc
+        ));
+    }
+    if old.affinity != new.affinity {
+        changes.push(format!(
+            "affinity (restart required): {:?} -> {:?}",
+            old.affinity, new.affinity
+        ));
+    }
+    if old.store != new.store {
+        changes.push(format!(
+            "store (restart required): {:?} -> {:?}",
+            old.store, new.store
+        ));
+    }
+    if old.push != new.push {
+        changes.push(format!(
+            "push (restart required): {:?} -> {:?}",
+            old.push, new.push
+        ));
+    }
+    if old.cluster != new.cluster {
+        changes.push(format!(
+            "cluster (restart required): {:?} -> {:?}",
+            old.cluster, new.cluster
+        ));
+    }
+    changes
+}
+
+/// Load config from `path` and apply it, returns the new config or why it was rejected
+pub fn reload(path: &Path, cli: &[String], current: &Config) -> Result<Config, String> {
+    // a missing file would mean defaults, unlikely what was meant
+    if !path.exists() {
+        return Err("file not found".to_string());
+    }
+    let config = Config::read(path, cli).map_err(|e| e.to_string())?;
+
+    // validate everything before touching running state
+    let levels = LogLevels::parse(&config.log.filter)?;
+    flags::validate(&config.flags)?;
+    ipfilter::validate(&config.ip_filter)?;
+    reconnect::validate(&config.reconnect)?;
+    inflight::validate(&config.handlers)?;
+    jobs::validate(&config.jobs)?;
+    labels::validate(&config.labels)?;
+    if let Some(ref chaos) = config.chaos {
+        chaos::validate(chaos)?;
+    }
+    if let Some(ref canary) = config.canary {
+        pipeline::validate(canary)?;
+    }
+    affinity::validate(&config)?;
+    if let Some(ref store) = config.store {
+        store::validate(store)?;
+    }
+    push::validate(&config)?;
+
+    let changes = diff(current, &config);
+    if changes.is_empty() {
+        log::info!("Config {} reloaded, nothing changed", path.display());
+        return Ok(config);
+    }
+    if current.log.filter != config.log.filter {
+        logging::set_levels(levels);
+    }
+    if current.flags != config.flags {
+        flags::init(&config.flags)?;
+    }
+    if current.ip_filter != config.ip_filter {
+        ipfilter::set_configured(&config.ip_filter)?;
+    }
+    if current.jobs != config.jobs {
+        jobs::set_configured(&config.jobs)?;
+    }
+    log::info!("Config {} reloaded: {}", path.display(), changes.join(", "));
+    Ok(config)
+}
+
+/// Forward `SIGHUP` into the reload queue
+async fn sighup(tx: mpsc::UnboundedSender<()>) {
+    while let Some(rx) = rt::signal() {
+        match rx.await {
+            Ok(rt::Signal::Hup) => {
+                if tx.unbounded_send(()).is_err() {
+                    return;
+                }
+            }
+            Ok(_) => (),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Watch the config file and reload it on change or `SIGHUP`
+///
+/// `cli` overrides are re-applied on every reload, so is the environment.
+pub async fn run(path: PathBuf, cli: Vec<String>, mut current: Config) {
+    let (tx, mut rx) = mpsc::unbounded();
+    rt::spawn(sighup(tx.clone()));
+
+    // watch the directory, editors often replace the file instead of writing it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name().map(|n| n.to_os_string());
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let ours = event
+                .paths
+                .iter()
+                .any(|p| p.file_name().map(|n| n.to_os_string()) == name);
+            if ours && (event.kind.is_create() || event.kind.is_modify()) {
+                let _ = tx.unbounded_send(());
+            }
+        }
+    });
+    let _watcher: Option<RecommendedWatcher> =
+        match watcher.and_then(|mut w| w.watch(&dir, RecursiveMode::NonRecursive).map(|_| w)) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                log::warn!(
+                    "Cannot watch {}, reload on SIGHUP only: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+
+    let task = TaskHandle::register("config", None);
+    loop {
+        task.set_state("waiting");
+        if rx.next().await.is_none() {
+            return;
+        }
+        time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+
+        task.set_state("reloading");
+        match reload(&path, &cli, &current) {
+            Ok(config) => current = config,
+            Err(e) => log::error!("Config {} rejected, keeping current: {}", path.display(), e),
+        }
+    }
+}
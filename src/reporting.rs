@@ -0,0 +1,105 @@
+//! Error reporting to Sentry.
+//!
+//! Without a configured DSN the Sentry client is not bound and every call
+//! here only logs. The last `RECENT_ERRORS` reports are also kept for the
+//! admin dashboard.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sentry::{ClientInitGuard, ClientOptions, Hub, Level, ScopeGuard, User};
+use serde::Serialize;
+
+use crate::auth::Identity;
+use crate::config::SentryConfig;
+
+/// Number of reports kept for `recent`
+const RECENT_ERRORS: usize = 100;
+
+static RECENT: Mutex<VecDeque<ErrorReport>> = Mutex::new(VecDeque::new());
+
+/// Reported error, for `GET /admin/errors`
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    /// Unix seconds
+    pub at: u64,
+    pub conn_id: u64,
+    pub message: String,
+}
+
+fn remember(conn_id: u64, message: &str) {
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == RECENT_ERRORS {
+        recent.pop_front();
+    }
+    recent.push_back(ErrorReport {
+        at,
+        conn_id,
+        message: message.to_string(),
+    });
+}
+
+/// Latest reports, newest first
+pub fn recent() -> Vec<ErrorReport> {
+    RECENT.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Start Sentry client, panics are reported from here on.
+/// Keep returned guard alive for the lifetime of the process.
+pub fn init(config: &SentryConfig) -> ClientInitGuard {
+    sentry::init((
+        config.dsn.as_str(),
+        ClientOptions {
+            environment: config.environment.clone().map(Into::into),
+            release: sentry::release_name!(),
+            sample_rate: config.sample_rate,
+            ..Default::default()
+        },
+    ))
+}
+
+/// Attach connection context to anything reported, including panics,
+/// until the guard is dropped: the connection id and the user and tenant of
+/// its identity
+pub fn connection_scope(conn_id: u64, identity: Option<&Identity>) -> ScopeGuard {
+    let guard = Hub::current().push_scope();
+    sentry::configure_scope(|scope| {
+        scope.set_tag("conn_id", conn_id);
+        if let Some(identity) = identity {
+            scope.set_user(Some(User {
+                id: Some(identity.user.clone()),
+                ..Default::default()
+            }));
+            if let Some(ref tenant) = identity.tenant {
+                scope.set_tag("tenant", tenant);
+            }
+        }
+    });
+    guard
+}
+
+/// Tag what is reported in the current connection scope with the room the
+/// handled message is about
+pub fn set_room(room: &str) {
+    sentry::configure_scope(|scope| scope.set_tag("room", room));
+}
+
+/// Report error caused by connection's input
+pub fn handler_error(conn_id: u64, message: &str) {
+    log::warn!("Connection {}: {}", conn_id, message);
+    remember(conn_id, message);
+    // inside a frame's scope, which has the user already
+    let _scope = connection_scope(conn_id, None);
+    sentry::capture_message(message, Level::Warning);
+}
+
+/// Record a handler panic, the panic itself reaches Sentry through its hook
+pub fn handler_panic(conn_id: u64) {
+    log::error!("Handler of connection {} panicked, closing it", conn_id);
+    remember(conn_id, "handler panicked");
+}
@@ -0,0 +1,150 @@
+//! Log files rotated by size or time.
+//!
+//! The live file is `path`; on rotation it is renamed to `path.1`, older
+//! ones move up to `path.2` and so on, and the one past `keep` is deleted.
+//! Rotation happens before a write that would take the file over
+//! `max_bytes`, or on the first write in a new hour or day (UTC).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{LogFileConfig, Rotation};
+
+#[derive(Debug)]
+pub struct RollingFile {
+    config: LogFileConfig,
+    file: File,
+    /// Size of the live file
+    written: u64,
+    /// Hour or day the live file was opened in
+    period: u64,
+}
+
+fn period(rotation: Rotation) -> u64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match rotation {
+        Rotation::Size => 0,
+        Rotation::Hourly => secs / 3600,
+        Rotation::Daily => secs / 86400,
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RollingFile {
+    /// Open `config.path` for appending
+    pub fn open(config: &LogFileConfig) -> io::Result<RollingFile> {
+        let file = open(&config.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.path.display(), e)))?;
+        let written = file.metadata()?.len();
+        // a file left from an earlier period is rotated on the first write
+        let modified = file
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let period = match config.rotation {
+            Rotation::Size => 0,
+            Rotation::Hourly => modified / 3600,
+            Rotation::Daily => modified / 86400,
+        };
+        Ok(RollingFile {
+            config: config.clone(),
+            file,
+            written,
+            period,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let _ = fs::remove_file(rotated(path, self.config.keep));
+            for n in (1..self.config.keep).rev() {
+                let from = rotated(path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated(path, 1))?;
+        }
+        self.file = open(path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Whether to rotate before writing `len` bytes, an empty file never is
+    fn due(&mut self, len: usize) -> bool {
+        let due = match self.config.rotation {
+            Rotation::Size => self.written + len as u64 > self.config.max_bytes,
+            rotation => {
+                let now = period(rotation);
+                std::mem::replace(&mut self.period, now) != now
+            }
+        };
+        due && self.written > 0
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            // keep writing to the old file rather than lose lines
+            if let Err(e) = self.rotate() {
+                eprintln!("Cannot rotate {}: {}", self.config.path.display(), e);
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Handle to a `RollingFile` shared by writers
+#[derive(Debug, Clone)]
+pub struct SharedFile(Arc<Mutex<RollingFile>>);
+
+impl SharedFile {
+    pub fn open(config: &LogFileConfig) -> io::Result<SharedFile> {
+        RollingFile::open(config).map(|file| SharedFile(Arc::new(Mutex::new(file))))
+    }
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    /// Whole buffer under one lock, a line is not interleaved with another
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
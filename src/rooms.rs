@@ -0,0 +1,1154 @@
+//! Named rooms connections join to exchange messages.
+//!
+//! A room exists while it has members or waiting connections. Its size can be
+//! capped with `[rooms]`, globally or per name pattern; a join of a full room
+//! is either rejected with `room_full` or queued. Queued connections are told
+//! their position and become members in arrival order as members leave.
+//!
+//! A private room, by pattern or asked for by the connection creating it,
+//! is only joined with an invite minted through the admin API, see `invites`.
+//!
+//! Moderators of a room, its authenticated creator and whoever they or the
+//! admin API grant it to, can mute users, kick them for a while and set a
+//! slow mode limiting how often each user publishes, see `Moderation`.
+//! Anonymous members cannot be moderated.
+//!
+//! Members report the last sequence number they read; the highest per user is
+//! kept for the room's lifetime and passed on to the other members.
+//!
+//! Members joining with a `group` name form a consumer group: each message
+//! goes to one of them in turn instead of all, see `Room::recipients`.
+//! Groups are per node like memberships: in cluster mode every node gives
+//! a message to one of the group's members connected to it.
+//!
+//! A room matching a pattern with `partitions` is split into rooms of their
+//! own named `<room>#0` to `<room>#<partitions - 1>`, see `route`; joining
+//! or leaving the room itself joins or leaves all of them.
+//!
+//! An empty room is destroyed at once or after `empty_ttl_secs`, any room
+//! after `ttl_secs` from creation; pinned rooms exist from startup on and are
+//! never destroyed. `run` sweeps expired rooms and tells their connections.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use ntex::time;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::auth::AuthError;
+use crate::config::{RoomPolicy, RoomsConfig, WhenFull};
+use crate::history::{self, Entry, Fetch, FetchPage, History, HistoryError, Search, SearchPage};
+use crate::hub::Hub;
+use crate::invites::Invite;
+use crate::metrics::METRICS;
+use crate::protocol::{self, ErrorCode};
+use crate::tasks::TaskHandle;
+
+/// How often expired rooms are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max length of a room name, bytes
+pub const MAX_ROOM_NAME: usize = 128;
+
+/// Default time a kicked user cannot rejoin
+const KICK_DURATION: u64 = 60;
+
+/// Who a slow mode interval applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Sender {
+    User(String),
+    /// Anonymous connection
+    Connection(u64),
+}
+
+#[derive(Debug)]
+struct Room {
+    members: BTreeSet<u64>,
+    /// Connections waiting for a free slot, first in line at the front
+    waiting: VecDeque<u64>,
+    /// Sequence number of the last published message
+    seq: u64,
+    created_at: Instant,
+    /// When the last connection left, `None` while in use
+    emptied_at: Option<Instant>,
+    /// Joined with an invite only
+    private: bool,
+    moderators: BTreeSet<String>,
+    /// Muted users, until the given time or unmuted
+    muted: HashMap<String, Option<Instant>>,
+    /// Kicked users cannot rejoin until the given time
+    kicked: HashMap<String, Instant>,
+    /// Min interval between publishes of one sender
+    slow_mode: Option<Duration>,
+    last_publish: HashMap<Sender, Instant>,
+    /// Highest sequence number each user read
+    read: BTreeMap<String, u64>,
+    /// Consumer groups by name
+    groups: BTreeMap<String, Group>,
+}
+
+/// Members sharing the messages of a room, each going to one of them
+#[derive(Debug, Default)]
+struct Group {
+    /// In join order
+    members: Vec<u64>,
+    /// Index of the member next in turn
+    next: usize,
+}
+
+impl Room {
+    fn new(private: bool) -> Room {
+        Room {
+            private,
+            moderators: BTreeSet::new(),
+            muted: HashMap::new(),
+            kicked: HashMap::new(),
+            slow_mode: None,
+            last_publish: HashMap::new(),
+            read: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            members: BTreeSet::new(),
+            waiting: VecDeque::new(),
+            seq: 0,
+            created_at: Instant::now(),
+            emptied_at: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.waiting.is_empty()
+    }
+
+    /// Members to deliver a message to, but `except`: those in no group,
+    /// and of each group its members from the next one in turn on
+    fn recipients(&mut self, except: Option<u64>) -> Recipients {
+        let grouped: HashSet<u64> = self
+            .groups
+            .values()
+            .flat_map(|group| group.members.iter().copied())
+            .collect();
+        let members = self
+            .members
+            .iter()
+            .copied()
+            .filter(|m| Some(*m) != except && !grouped.contains(m))
+            .collect();
+        let mut groups = Vec::new();
+        for group in self.groups.values_mut() {
+            let len = group.members.len();
+            let turn: Vec<u64> = (0..len)
+                .map(|i| group.members[(group.next + i) % len])
+                // waiting ones are not members yet
+                .filter(|m| Some(*m) != except && self.members.contains(m))
+                .collect();
+            if !turn.is_empty() {
+                groups.push(turn);
+                group.next = (group.next + 1) % len;
+            }
+        }
+        Recipients { members, groups }
+    }
+
+    /// Put `id` into consumer group `group`, out of any if `None`
+    fn set_group(&mut self, id: u64, group: Option<&str>) {
+        self.groups.retain(|_, g| {
+            g.members.retain(|m| *m != id);
+            !g.members.is_empty()
+        });
+        if let Some(group) = group {
+            let group = self.groups.entry(group.to_string()).or_default();
+            group.members.push(id);
+        }
+    }
+
+    /// Whether `policy` says the room is to be destroyed
+    fn is_expired(&self, policy: &RoomPolicy) -> bool {
+        if policy.pinned {
+            return false;
+        }
+        let ttl = policy
+            .ttl
+            .is_some_and(|ttl| self.created_at.elapsed() >= ttl);
+        let empty = self
+            .emptied_at
+            .is_some_and(|at| at.elapsed() >= policy.empty_ttl);
+        ttl || empty
+    }
+
+    fn is_muted(&self, user: &str) -> bool {
+        match self.muted.get(user) {
+            Some(Some(until)) => *until > Instant::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn is_kicked(&self, user: &str) -> bool {
+        self.kicked
+            .get(user)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Forget expired mutes, kicks and slow mode timestamps
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.muted.retain(|_, until| until.is_none_or(|t| t > now));
+        self.kicked.retain(|_, until| *until > now);
+        match self.slow_mode {
+            Some(interval) => self.last_publish.retain(|_, at| at.elapsed() < interval),
+            None => self.last_publish.clear(),
+        }
+    }
+}
+
+/// Moderation action, sent with `room.moderate` or to the admin API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Moderation {
+    /// Refuse the user's publishes, for `duration_secs` or until unmuted
+    Mute {
+        user: String,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    Unmute {
+        user: String,
+    },
+    /// Remove the user's connections, rejoining is refused for `duration_secs`
+    Kick {
+        user: String,
+        #[serde(default = "default_kick_duration")]
+        duration_secs: u64,
+    },
+    /// Min interval between publishes of each user, 0 turns it off
+    SlowMode {
+        interval_secs: u64,
+    },
+    /// Make the user a moderator
+    Grant {
+        user: String,
+    },
+    Revoke {
+        user: String,
+    },
+}
+
+fn default_kick_duration() -> u64 {
+    KICK_DURATION
+}
+
+/// Connections to notify after a moderation action
+#[derive(Debug, Default)]
+pub struct Moderated {
+    /// Kicked connections
+    pub kicked: Vec<u64>,
+    /// Effect of the kicks on the waiting connections
+    pub left: Vec<Left>,
+}
+
+/// Room listing entry, for `GET /admin/rooms`
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+    pub waiting: usize,
+    pub max_members: Option<usize>,
+    /// Messages published so far
+    pub seq: u64,
+    pub age_secs: u64,
+    pub pinned: bool,
+    pub private: bool,
+}
+
+/// Outcome of a join
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Join {
+    /// Connection is a member, with the room's member count
+    Member(usize),
+    /// Room is full, connection is queued at this position (1 is next)
+    Waiting(usize),
+}
+
+/// Room destroyed by `Rooms::expire`
+#[derive(Debug)]
+pub struct Expired {
+    pub name: String,
+    /// Members and waiting connections at the time
+    pub connections: Vec<u64>,
+}
+
+/// Connections to notify after one left a room
+#[derive(Debug, Default)]
+pub struct Left {
+    /// Waiting connection that became a member, with the new member count
+    pub promoted: Option<(u64, usize)>,
+    /// Waiting connections with their new position
+    pub moved: Vec<(u64, usize)>,
+}
+
+/// Members to deliver a room message to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recipients {
+    /// Those in no consumer group, each gets the message
+    pub members: Vec<u64>,
+    /// Members of each consumer group, the one in turn first; the message
+    /// goes to the first that can take it
+    pub groups: Vec<Vec<u64>>,
+}
+
+/// Sequence number of a published message, and the members to deliver it to
+pub type Published = (u64, Recipients);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomError {
+    BadName(&'static str),
+    /// Room is at capacity and joins are rejected, or its queue is full
+    Full,
+    NotMember,
+    /// Room is private and no invite was given
+    InviteRequired,
+    BadInvite(String),
+    /// `rooms.invite_key` is not configured
+    InvitesDisabled,
+    NotFound,
+    NotModerator,
+    Muted,
+    /// User was kicked and may not rejoin yet
+    Kicked,
+    /// Slow mode is on, the sender may publish again after this
+    SlowMode(Duration),
+    /// Read receipts need an authenticated user
+    Anonymous,
+    /// Reported sequence number was not published yet
+    BadSeq,
+    History(HistoryError),
+    /// Cluster node owning the room is not reachable
+    OwnerUnreachable,
+    /// Room is owned by another cluster node, which has to sequence it
+    NotOwner,
+}
+
+impl From<HistoryError> for RoomError {
+    fn from(e: HistoryError) -> RoomError {
+        RoomError::History(e)
+    }
+}
+
+impl From<RoomError> for protocol::Error {
+    fn from(e: RoomError) -> protocol::Error {
+        match e {
+            RoomError::BadName(reason) => protocol::Error::new(ErrorCode::BadPayload, reason),
+            RoomError::Full => protocol::Error::new(ErrorCode::RoomFull, "room is full"),
+            RoomError::NotMember => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "not a member of the room")
+            }
+            RoomError::InviteRequired => protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "room is private, an invite is required",
+            ),
+            RoomError::BadInvite(reason) => protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                format!("invalid invite: {}", reason),
+            ),
+            RoomError::InvitesDisabled => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "room invites are disabled")
+            }
+            RoomError::NotFound => protocol::Error::new(ErrorCode::BadPayload, "no such room"),
+            RoomError::NotModerator => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "not a moderator of the room")
+            }
+            RoomError::Muted => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "muted in the room")
+            }
+            RoomError::Kicked => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "kicked from the room")
+            }
+            RoomError::Anonymous => protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "read receipts require authentication",
+            ),
+            RoomError::BadSeq => {
+                protocol::Error::new(ErrorCode::BadPayload, "seq is past the last message")
+            }
+            RoomError::History(HistoryError::UnknownMessage) => {
+                protocol::Error::new(ErrorCode::BadPayload, "no such message")
+            }
+            RoomError::History(HistoryError::NotAuthor) => {
+                protocol::Error::new(ErrorCode::PermissionDenied, "not the author of the message")
+            }
+            RoomError::SlowMode(wait) => protocol::Error::new(
+                ErrorCode::RateLimited,
+                format!("slow mode, wait {} ms", wait.as_millis()),
+            ),
+            RoomError::OwnerUnreachable => {
+                protocol::Error::new(ErrorCode::Internal, "room owner unreachable")
+            }
+            RoomError::NotOwner => {
+                protocol::Error::new(ErrorCode::BadPayload, "room is owned by another node")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    rooms: HashMap<String, Room>,
+    /// Rooms each connection is a member of or waiting for
+    joined: HashMap<u64, BTreeSet<String>>,
+    /// Joins made with each invite, by `jti`, with the invite's expiry
+    invite_uses: HashMap<String, (u32, u64)>,
+    /// Authenticated user of each connection in a room
+    users: HashMap<u64, String>,
+}
+
+#[derive(Debug)]
+pub struct Rooms {
+    config: RoomsConfig,
+    state: Mutex<State>,
+    /// Published messages, recorded with `state` locked so they are stored in
+    /// sequence order
+    history: History,
+    /// Partition of the next message without a key
+    next_partition: AtomicU32,
+}
+
+impl Rooms {
+    pub fn new(config: RoomsConfig, history: History) -> Rooms {
+        let mut state = State::default();
+        for name in &config.pinned {
+            let mut room = Room::new(config.policy(name).private);
+            room.seq = history.last_seq(name);
+            state.rooms.insert(name.clone(), room);
+        }
+        Rooms {
+            config,
+            state: Mutex::new(state),
+            history,
+            next_partition: AtomicU32::new(0),
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Room state, also after a handler panicked holding it: the rooms of
+    /// every other connection live here too
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Effective limits of room `name`
+    pub fn policy(&self, name: &str) -> RoomPolicy {
+        self.config.policy(name)
+    }
+
+    /// Partitions of room `name`, `None` if it is not partitioned
+    pub fn partitions(&self, name: &str) -> Option<Vec<String>> {
+        let partitions = self.config.policy(name).partitions?;
+        Some((0..partitions).map(|n| format!("{}#{}", name, n)).collect())
+    }
+
+    /// Room a message to `name` goes to: for a partitioned room the
+    /// partition of its ordering `key`, the same on every node, or the next
+    /// in turn without one; else `name` itself
+    pub fn route(&self, name: &str, key: Option<&str>) -> String {
+        let Some(partitions) = self.config.policy(name).partitions else {
+            return name.to_string();
+        };
+        let partition = match key {
+            Some(key) => {
+                let digest = Sha256::digest(key.as_bytes());
+                let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+                (hash % partitions as u64) as u32
+            }
+            None => self.next_partition.fetch_add(1, Ordering::Relaxed) % partitions,
+        };
+        format!("{}#{}", name, partition)
+    }
+
+    /// Join `name` as connection `id` of `user`, creating the room, private if
+    /// `private` is set; joining again is a no-op. `invite` is required for
+    /// private rooms.
+    pub fn join(
+        &self,
+        id: u64,
+        user: Option<&str>,
+        name: &str,
+        invite: Option<&str>,
+        private: bool,
+    ) -> Result<Join, RoomError> {
+        validate(name)?;
+        let policy = self.config.policy(name);
+        let mut state = self.state();
+        let needs_invite = match state.rooms.get(name) {
+            Some(room) => {
+                room.private && !room.members.contains(&id) && !room.waiting.contains(&id)
+            }
+            None => policy.private,
+        };
+        let kicked = user.is_some_and(|user| {
+            state
+                .rooms
+                .get(name)
+                .is_some_and(|room| room.is_kicked(user))
+        });
+        if kicked {
+            return Err(RoomError::Kicked);
+        }
+        let invite = match (needs_invite, invite) {
+            (false, _) => None,
+            (true, None) => return Err(RoomError::InviteRequired),
+            (true, Some(token)) => Some(self.check_invite(&state, name, token)?),
+        };
+        let room = state.rooms.entry(name.to_string()).or_insert_with(|| {
+            let mut room = Room::new(policy.private || private);
+            room.moderators.extend(user.map(str::to_string));
+            room.seq = self.history.last_seq(name);
+            room
+        });
+        if room.members.contains(&id) {
+            return Ok(Join::Member(room.members.len()));
+        }
+        if let Some(i) = room.waiting.iter().position(|w| *w == id) {
+            return Ok(Join::Waiting(i + 1));
+        }
+        let join = match policy.max_members {
+            Some(max) if room.members.len() >= max => match policy.when_full {
+                WhenFull::Wait if room.waiting.len() < self.config.max_waiting => {
+                    room.waiting.push_back(id);
+                    Join::Waiting(room.waiting.len())
+                }
+                _ => return Err(RoomError::Full),
+            },
+            _ => {
+                room.members.insert(id);
+                Join::Member(room.members.len())
+            }
+        };
+        room.emptied_at = None;
+        state.joined.entry(id).or_default().insert(name.to_string());
+        if let Some(user) = user {
+            state.users.insert(id, user.to_string());
+        }
+        if let Some(invite) = invite {
+            state
+                .invite_uses
+                .entry(invite.jti)
+                .or_insert((0, invite.exp))
+                .0 += 1;
+        }
+        Ok(join)
+    }
+
+    /// Mint an invite to room `name`, whether or not it exists yet;
+    /// returns the signed token
+    pub fn invite(
+        &self,
+        name: &str,
+        ttl: Duration,
+        max_uses: u32,
+    ) -> Result<(String, Invite), RoomError> {
+        validate(name)?;
+        let key = self
+            .config
+            .invite_key
+            .as_ref()
+            .ok_or(RoomError::InvitesDisabled)?;
+        let invite = Invite::new(name, ttl, max_uses);
+        Ok((invite.sign(key.as_bytes()), invite))
+    }
+
+    /// Check an invite to `name` has uses left
+    fn check_invite(&self, state: &State, name: &str, token: &str) -> Result<Invite, RoomError> {
+        let key = self
+            .config
+            .invite_key
+            .as_ref()
+            .ok_or(RoomError::InvitesDisabled)?;
+        let invite = Invite::verify(token, key.as_bytes()).map_err(|e| match e {
+            AuthError::Invalid(reason) => RoomError::BadInvite(reason),
+            e => RoomError::BadInvite(e.to_string()),
+        })?;
+        if invite.room != name {
+            return Err(RoomError::BadInvite(
+                "invite is for another room".to_string(),
+            ));
+        }
+        let used = state.invite_uses.get(&invite.jti).map_or(0, |(n, _)| *n);
+        if used >= invite.max_uses {
+            return Err(RoomError::BadInvite("invite is used up".to_string()));
+        }
+        Ok(invite)
+    }
+
+    /// Leave `name` as member or waiting connection, `None` if in neither
+    pub fn leave(&self, id: u64, name: &str) -> Option<Left> {
+        let mut state = self.state();
+        let left = self.remove(&mut state, id, name)?;
+        forget(&mut state, id, name);
+        Some(left)
+    }
+
+    /// Put member or waiting connection `id` of `name` into consumer group
+    /// `group`, or out of any; see `validate_group`
+    pub fn set_group(&self, id: u64, name: &str, group: Option<&str>) {
+        let mut state = self.state();
+        if let Some(room) = state.rooms.get_mut(name) {
+            if room.members.contains(&id) || room.waiting.contains(&id) {
+                room.set_group(id, group);
+            }
+        }
+    }
+
+    /// Leave all rooms of a closed connection
+    pub fn leave_all(&self, id: u64) -> Vec<(String, Left)> {
+        let mut state = self.state();
+        let names = state.joined.remove(&id).unwrap_or_default();
+        state.users.remove(&id);
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let left = self.remove(&mut state, id, &name)?;
+                Some((name, left))
+            })
+            .collect()
+    }
+
+    /// Store `data` published by member `id` under the room's next sequence
+    /// number, returns it with the other members to deliver the message to.
+    /// `sequenced` is called with the stored message while the room is
+    /// locked, so it sees messages in sequence order.
+    pub fn publish(
+        &self,
+        id: u64,
+        name: &str,
+        data: &Value,
+        sequenced: impl FnOnce(&Entry),
+    ) -> Result<Published, RoomError> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let user = check_publish(state, id, name, true)?;
+        let room = state.rooms.get_mut(name).expect("checked above");
+        room.seq += 1;
+        let entry = Entry {
+            seq: room.seq,
+            from: id,
+            user,
+            node: None,
+            data: data.clone(),
+            at: history::now_millis(),
+            edited_at: None,
+            deleted: false,
+        };
+        sequenced(&entry);
+        self.history.record(name, entry);
+        Ok((room.seq, room.recipients(Some(id))))
+    }
+
+    /// Store `messages` of member `id` as `publish` does, all or none: the
+    /// first that may not be published is returned with its index. Slow
+    /// mode counts a batch once per room.
+    pub fn publish_all(
+        &self,
+        id: u64,
+        messages: &[(String, Value)],
+        mut sequenced: impl FnMut(&str, &Entry),
+    ) -> Result<Vec<Published>, (usize, RoomError)> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        for (i, (name, _)) in messages.iter().enumerate() {
+            check_publish(state, id, name, false).map_err(|e| (i, e))?;
+        }
+        let mut counted = HashSet::new();
+        let mut published = Vec::with_capacity(messages.len());
+        for (name, data) in messages {
+            let user = match counted.insert(name.as_str()) {
+                true => check_publish(state, id, name, true).expect("checked above"),
+                false => state.users.get(&id).cloned(),
+            };
+            let room = state.rooms.get_mut(name).expect("checked above");
+            room.seq += 1;
+            let entry = Entry {
+                seq: room.seq,
+                from: id,
+                user,
+                node: None,
+                data: data.clone(),
+                at: history::now_millis(),
+                edited_at: None,
+                deleted: false,
+            };
+            sequenced(name, &entry);
+            self.history.record(name, entry);
+            published.push((room.seq, room.recipients(Some(id))));
+        }
+        Ok(published)
+    }
+
+    /// Check that member `id` may publish to `name` now, for a message
+    /// sequenced by another cluster node; returns the member's user
+    pub fn authorize_publish(&self, id: u64, name: &str) -> Result<Option<String>, RoomError> {
+        let mut state = self.state();
+        check_publish(&mut state, id, name, true)
+    }
+
+    /// Whether member `id` could publish to `name` now, without counting it
+    /// for slow mode; for a message checked again when actually published
+    pub fn may_publish(&self, id: u64, name: &str) -> Result<Option<String>, RoomError> {
+        let mut state = self.state();
+        check_publish(&mut state, id, name, false)
+    }
+
+    /// Store a message of another cluster node's connection under the next
+    /// sequence number, as the room's cluster owner; returns the stored
+    /// message with the members to deliver it to
+    pub fn sequence(
+        &self,
+        name: &str,
+        mut entry: Entry,
+        sequenced: impl FnOnce(&Entry),
+    ) -> (Entry, Recipients) {
+        let mut state = self.state();
+        let (seq, members) = match state.rooms.get_mut(name) {
+            Some(room) => {
+                room.seq += 1;
+                (room.seq, room.recipients(None))
+            }
+            None => (self.history.last_seq(name) + 1, Recipients::default()),
+        };
+        entry.seq = seq;
+        sequenced(&entry);
+        self.history.record(name, entry.clone());
+        (entry, members)
+    }
+
+    /// Sequence number of the last message of `name`
+    pub fn last_seq(&self, name: &str) -> u64 {
+        let state = self.state();
+        let seq = state.rooms.get(name).map_or(0, |room| room.seq);
+        seq.max(self.history.last_seq(name))
+    }
+
+    /// Continue the sequence of `name` after `seq`, as its new cluster owner
+    pub fn resume(&self, name: &str, seq: u64) {
+        let mut state = self.state();
+        if let Some(room) = state.rooms.get_mut(name) {
+            room.seq = room.seq.max(seq);
+        }
+        self.history.advance(name, seq);
+    }
+
+    /// Names of the rooms open here or with stored messages
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.history.rooms();
+        names.extend(self.state().rooms.keys().cloned());
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Store a message sequenced by the room's cluster owner, returns the
+    /// members to deliver it to
+    pub fn deliver(&self, name: &str, entry: Entry) -> Recipients {
+        let mut state = self.state();
+        let members = match state.rooms.get_mut(name) {
+            Some(room) => {
+                room.seq = room.seq.max(entry.seq);
+                let local = entry.node.is_none();
+                room.recipients(local.then_some(entry.from))
+            }
+            None => Recipients::default(),
+        };
+        if entry.seq > self.history.last_seq(name) {
+            self.history.record(name, entry);
+        }
+        members
+    }
+
+    /// Replace data of message `seq` published by member `id`, returns the
+    /// edit time with the other members to deliver the edit to
+    pub fn edit(
+        &self,
+        id: u64,
+        name: &str,
+        seq: u64,
+        data: Value,
+    ) -> Result<(u64, Vec<u64>), RoomError> {
+        let state = self.state();
+        let room = match state.rooms.get(name) {
+            Some(room) if room.members.contains(&id) => room,
+            _ => return Err(RoomError::NotMember),
+        };
+        let user = state.users.get(&id).map(String::as_str);
+        let edited_at = self.history.edit(name, seq, id, user, data)?;
+        let to = room.members.iter().copied().filter(|m| *m != id).collect();
+        Ok((edited_at, to))
+    }
+
+    /// Turn message `seq` published by member `id` into a tombstone, returns
+    /// the other members to deliver the delete to
+    pub fn delete(&self, id: u64, name: &str, seq: u64) -> Result<Vec<u64>, RoomError> {
+        let state = self.state();
+        let room = match state.rooms.get(name) {
+            Some(room) if room.members.contains(&id) => room,
+            _ => return Err(RoomError::NotMember),
+        };
+        let user = state.users.get(&id).map(String::as_str);
+        self.history.delete(name, seq, id, user)?;
+        Ok(room.members.iter().copied().filter(|m| *m != id).collect())
+    }
+
+    /// Other members to pass an ephemeral message of member `id` to;
+    /// slow mode does not apply
+    pub fn ephemeral(&self, id: u64, name: &str) -> Result<Vec<u64>, RoomError> {
+        let state = self.state();
+        let room = match state.rooms.get(name) {
+            Some(room) if room.members.contains(&id) => room,
+            _ => return Err(RoomError::NotMember),
+        };
+        if state.users.get(&id).is_some_and(|user| room.is_muted(user)) {
+            return Err(RoomError::Muted);
+        }
+        Ok(room.members.iter().copied().filter(|m| *m != id).collect())
+    }
+
+    /// Whether connection `id` is a member of room `name`
+    pub fn is_member(&self, id: u64, name: &str) -> bool {
+        let state = self.state();
+        state
+            .rooms
+            .get(name)
+            .is_some_and(|room| room.members.contains(&id))
+    }
+
+    /// Record that member `id` read up to `seq`, returns the user's highest
+    /// read sequence number and whether it moved, with the other members
+    pub fn read(&self, id: u64, name: &str, seq: u64) -> Result<(u64, bool, Vec<u64>), RoomError> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let room = match state.rooms.get_mut(name) {
+            Some(room) if room.members.contains(&id) => room,
+            _ => return Err(RoomError::NotMember),
+        };
+        let user = state.users.get(&id).ok_or(RoomError::Anonymous)?;
+        if seq > room.seq {
+            return Err(RoomError::BadSeq);
+        }
+        let read = room.read.entry(user.clone()).or_insert(0);
+        let moved = seq > *read;
+        *read = seq.max(*read);
+        let to = room.members.iter().copied().filter(|m| *m != id).collect();
+        Ok((*read, moved, to))
+    }
+
+    /// Page of the history of room `name`, for its member `id`
+    pub fn fetch(&self, id: u64, fetch: &Fetch) -> Result<FetchPage, RoomError> {
+        let state = self.state();
+        match state.rooms.get(&fetch.room) {
+            Some(room) if room.members.contains(&id) => Ok(self.history.fetch(fetch)),
+            _ => Err(RoomError::NotMember),
+        }
+    }
+
+    /// Messages matching `search` in the rooms connection `id` is a member
+    /// of, or in all rooms for the admin API if `None`
+    pub fn search(&self, id: Option<u64>, search: &Search) -> Result<SearchPage, RoomError> {
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(self.history.search(search, None)),
+        };
+        let state = self.state();
+        let member = |name: &String| {
+            state
+                .rooms
+                .get(name)
+                .is_some_and(|r| r.members.contains(&id))
+        };
+        let rooms: Vec<String> = match search.room {
+            Some(ref name) if member(name) => vec![name.clone()],
+            Some(_) => return Err(RoomError::NotMember),
+            None => state
+                .joined
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .filter(|name| member(name))
+                .cloned()
+                .collect(),
+        };
+        drop(state);
+        Ok(self.history.search(search, Some(&rooms)))
+    }
+
+    /// Highest read sequence number per user of room `name`; for a member
+    /// `id`, or the admin API if `None`
+    pub fn receipts(
+        &self,
+        id: Option<u64>,
+        name: &str,
+    ) -> Result<BTreeMap<String, u64>, RoomError> {
+        let state = self.state();
+        match state.rooms.get(name) {
+            Some(room) if id.is_none_or(|id| room.members.contains(&id)) => Ok(room.read.clone()),
+            Some(_) => Err(RoomError::NotMember),
+            None if id.is_some() => Err(RoomError::NotMember),
+            None => Err(RoomError::NotFound),
+        }
+    }
+
+    /// Apply `action` to room `name`, `by` a moderator or the admin API if `None`
+    pub fn moderate(
+        &self,
+        by: Option<&str>,
+        name: &str,
+        action: Moderation,
+    ) -> Result<Moderated, RoomError> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let room = state.rooms.get_mut(name).ok_or(RoomError::NotFound)?;
+        if by.is_some_and(|by| !room.moderators.contains(by)) {
+            return Err(RoomError::NotModerator);
+        }
+        let mut moderated = Moderated::default();
+        match action {
+            Moderation::Mute {
+                user,
+                duration_secs,
+            } => {
+                let until = duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+                room.muted.insert(user, until);
+            }
+            Moderation::Unmute { user } => {
+                room.muted.remove(&user);
+            }
+            Moderation::Kick {
+                user,
+                duration_secs,
+            } => {
+                let until = Instant::now() + Duration::from_secs(duration_secs);
+                room.kicked.insert(user.clone(), until);
+                let kicked: Vec<_> = room
+                    .members
+                    .iter()
+                    .chain(&room.waiting)
+                    .copied()
+                    .filter(|id| state.users.get(id) == Some(&user))
+                    .collect();
+                for id in &kicked {
+                    moderated.left.extend(self.remove(state, *id, name));
+                    forget(state, *id, name);
+                }
+                moderated.kicked = kicked;
+            }
+            Moderation::SlowMode { interval_secs } => {
+                room.slow_mode = Some(Duration::from_secs(interval_secs)).filter(|d| !d.is_zero());
+            }
+            Moderation::Grant { user } => {
+                room.moderators.insert(user);
+            }
+            Moderation::Revoke { user } => {
+                room.moderators.remove(&user);
+            }
+        }
+        Ok(moderated)
+    }
+
+    /// Snapshot of all rooms, ordered by name
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let state = self.state();
+        let mut list: Vec<_> = state
+            .rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                members: room.members.len(),
+                waiting: room.waiting.len(),
+                max_members: self.config.policy(name).max_members,
+                seq: room.seq,
+                age_secs: room.created_at.elapsed().as_secs(),
+                pinned: self.config.policy(name).pinned,
+                private: room.private,
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Destroy rooms past their TTL or empty for longer than allowed
+    pub fn expire(&self) -> Vec<Expired> {
+        let mut state = self.state();
+        let now = crate::auth::now_secs();
+        state.invite_uses.retain(|_, (_, exp)| *exp > now);
+        for room in state.rooms.values_mut() {
+            room.prune();
+        }
+        let names: Vec<_> = state
+            .rooms
+            .iter()
+            .filter(|(name, room)| room.is_expired(&self.config.policy(name)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let room = state.rooms.remove(&name)?;
+                let connections: Vec<_> = room.members.into_iter().chain(room.waiting).collect();
+                for id in &connections {
+                    forget(&mut state, *id, &name);
+                }
+                Some(Expired { name, connections })
+            })
+            .collect()
+    }
+
+    /// Destroy the empty room `name` or start its grace period
+    fn vacate(&self, state: &mut State, name: &str, policy: &RoomPolicy) {
+        if policy.pinned {
+            return;
+        }
+        if policy.empty_ttl.is_zero() {
+            state.rooms.remove(name);
+        } else if let Some(room) = state.rooms.get_mut(name) {
+            room.emptied_at = Some(Instant::now());
+        }
+    }
+
+    /// Remove `id` from room `name`, promoting the first waiting connection
+    /// into a freed slot
+    fn remove(&self, state: &mut State, id: u64, name: &str) -> Option<Left> {
+        let room = state.rooms.get_mut(name)?;
+        let mut left = Left::default();
+        let policy = self.config.policy(name);
+        room.set_group(id, None);
+        let from = if room.members.remove(&id) {
+            if policy
+                .max_members
+                .is_none_or(|max| room.members.len() < max)
+            {
+                if let Some(next) = room.waiting.pop_front() {
+                    room.members.insert(next);
+                    left.promoted = Some((next, room.members.len()));
+                }
+            }
+            if left.promoted.is_none() {
+                room.waiting.len()
+            } else {
+                0
+            }
+        } else {
+            let i = room.waiting.iter().position(|w| *w == id)?;
+            room.waiting.remove(i);
+            i
+        };
+        left.moved = room
+            .waiting
+            .iter()
+            .enumerate()
+            .skip(from)
+            .map(|(i, w)| (*w, i + 1))
+            .collect();
+        if room.is_empty() {
+            self.vacate(state, name, &policy);
+        }
+        Some(left)
+    }
+}
+
+/// Drop `name` from the rooms of connection `id`
+fn forget(state: &mut State, id: u64, name: &str) {
+    if let Some(rooms) = state.joined.get_mut(&id) {
+        rooms.remove(name);
+        if rooms.is_empty() {
+            state.joined.remove(&id);
+            state.users.remove(&id);
+        }
+    }
+}
+
+/// Destroy expired rooms every second, telling their connections with
+/// `room.closed`
+pub async fn run(hub: Arc<Hub>) {
+    let task = TaskHandle::register("rooms", None);
+    loop {
+        task.set_state("sleeping");
+        time::sleep(SWEEP_INTERVAL).await;
+        task.set_state("sweeping");
+        for room in hub.rooms().expire() {
+            log::info!(
+                "Room {} destroyed, {} connections left it",
+                room.name,
+                room.connections.len()
+            );
+            METRICS.rooms_expired_total.inc();
+            let msg = protocol::message(
+                "room.closed",
+                None,
+                &serde_json::json!({ "room": room.name, "reason": "expired" }),
+            );
+            for id in room.connections {
+                hub.send(id, msg.clone());
+            }
+        }
+    }
+}
+
+/// Check that member `id` may publish to `name` now, applying mute and
+/// slow mode; `record` starts the slow mode interval. Returns the member's
+/// user.
+fn check_publish(
+    state: &mut State,
+    id: u64,
+    name: &str,
+    record: bool,
+) -> Result<Option<String>, RoomError> {
+    let user = state.users.get(&id).cloned();
+    let room = match state.rooms.get_mut(name) {
+        Some(room) if room.members.contains(&id) => room,
+        _ => return Err(RoomError::NotMember),
+    };
+    if user.as_deref().is_some_and(|user| room.is_muted(user)) {
+        return Err(RoomError::Muted);
+    }
+    if let Some(interval) = room.slow_mode {
+        let sender = match user {
+            Some(ref user) => Sender::User(user.clone()),
+            None => Sender::Connection(id),
+        };
+        if let Some(at) = room.last_publish.get(&sender) {
+            if let Some(wait) = interval.checked_sub(at.elapsed()) {
+                return Err(RoomError::SlowMode(wait));
+            }
+        }
+        if record {
+            room.last_publish.insert(sender, Instant::now());
+        }
+    }
+    Ok(user)
+}
+
+/// Check a consumer group name, like a room name
+pub fn validate_group(name: &str) -> Result<(), RoomError> {
+    match validate(name) {
+        Err(RoomError::BadName(_)) => Err(RoomError::BadName(
+            "group name is empty, too long or contains control characters",
+        )),
+        result => result,
+    }
+}
+
+fn validate(name: &str) -> Result<(), RoomError> {
+    if name.is_empty() {
+        Err(RoomError::BadName("room name is empty"))
+    } else if name.len() > MAX_ROOM_NAME {
+        Err(RoomError::BadName("room name is too long"))
+    } else if name.chars().any(char::is_control) {
+        Err(RoomError::BadName("room name contains control characters"))
+    } else {
+        Ok(())
+    }
+}
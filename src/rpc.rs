@@ -0,0 +1,156 @@
+//! Requests from the server to one client.
+//!
+//! Server code, `POST /admin/connections/{id}/call` and the gRPC `Call`
+//! send a connection an `rpc.call` and wait for the client to answer it
+//! with an `rpc.result` carrying the same `id`:
+//!
+//! ```json
+//! {"type": "rpc.call", "id": "srv-7", "payload": {"method": "device.reboot", "params": {"delay": 5}, "timeout_ms": 10000}}
+//! {"type": "rpc.result", "id": "srv-7", "payload": {"result": {"ok": true}}}
+//! {"type": "rpc.result", "id": "srv-7", "payload": {"error": {"code": "busy", "message": "..."}}}
+//! ```
+//!
+//! A call fails once its timeout passed, or when the connection closes
+//! first. Results of calls that are over, or of calls made to another
+//! connection, are dropped. A connection has at most `MAX_PENDING` calls
+//! waiting.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use ntex::time;
+use serde_json::{json, Value};
+
+use crate::hub::Hub;
+use crate::protocol;
+
+/// Timeout of a call that names none
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest timeout of a call
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Calls a connection may have waiting at once
+pub const MAX_PENDING: usize = 64;
+
+/// Prefix of the `id` of calls, telling them from client ids
+const PREFIX: &str = "srv-";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallError {
+    /// No such connection
+    NotConnected,
+    /// Too many calls of the connection are waiting, or its queue is full
+    Busy,
+    /// The connection closed before answering
+    Disconnected,
+    TimedOut,
+    /// The client answered with this error
+    Failed(Value),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::NotConnected => write!(f, "no such connection"),
+            CallError::Busy => write!(f, "connection is busy"),
+            CallError::Disconnected => write!(f, "connection closed"),
+            CallError::TimedOut => write!(f, "timed out"),
+            CallError::Failed(error) => write!(f, "client error: {}", error),
+        }
+    }
+}
+
+/// Connection a call went to, and where its result goes
+type Pending = (u64, oneshot::Sender<Result<Value, CallError>>);
+
+/// Calls waiting for their result, by number
+#[derive(Debug, Default)]
+pub struct Calls {
+    next: AtomicU64,
+    pending: Mutex<HashMap<u64, Pending>>,
+}
+
+impl Calls {
+    /// Hand the result of call `id` to its caller, if connection `conn` was
+    /// the one called; returns whether a call was waiting for it
+    pub fn answer(&self, conn: u64, id: &str, outcome: Result<Value, Value>) -> bool {
+        let Some(number) = id.strip_prefix(PREFIX).and_then(|n| n.parse().ok()) else {
+            return false;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(&number) {
+            Some((called, _)) if *called == conn => {}
+            _ => return false,
+        }
+        let (_, tx) = pending.remove(&number).expect("pending");
+        let _ = tx.send(outcome.map_err(CallError::Failed));
+        true
+    }
+
+    /// Fail the calls waiting for closed connection `conn`
+    pub fn disconnected(&self, conn: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let numbers: Vec<u64> = pending
+            .iter()
+            .filter(|(_, (called, _))| *called == conn)
+            .map(|(number, _)| *number)
+            .collect();
+        for number in numbers {
+            if let Some((_, tx)) = pending.remove(&number) {
+                let _ = tx.send(Err(CallError::Disconnected));
+            }
+        }
+    }
+}
+
+/// Call `method` of connection `conn` and wait for its result, for at most
+/// `timeout` (capped at `MAX_TIMEOUT`)
+pub async fn call(
+    hub: &Hub,
+    conn: u64,
+    method: &str,
+    params: Value,
+    timeout: Duration,
+) -> Result<Value, CallError> {
+    if !hub.is_connected(conn) {
+        return Err(CallError::NotConnected);
+    }
+    let timeout = timeout.min(MAX_TIMEOUT);
+    let calls = hub.calls();
+    let number = calls.next.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut pending = calls.pending.lock().unwrap();
+        let waiting = pending
+            .values()
+            .filter(|(called, _)| *called == conn)
+            .count();
+        if waiting >= MAX_PENDING {
+            return Err(CallError::Busy);
+        }
+        pending.insert(number, (conn, tx));
+    }
+    let id = format!("{}{}", PREFIX, number);
+    let payload = json!({
+        "method": method,
+        "params": params,
+        "timeout_ms": timeout.as_millis() as u64,
+    });
+    if !hub.send(conn, protocol::message("rpc.call", Some(&id), &payload)) {
+        calls.pending.lock().unwrap().remove(&number);
+        return Err(CallError::Busy);
+    }
+    match time::timeout(timeout, rx).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(_)) => Err(CallError::Disconnected),
+        Err(()) => {
+            calls.pending.lock().unwrap().remove(&number);
+            Err(CallError::TimedOut)
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Room messages published for later.
+//!
+//! A `room.publish` with `delay_ms`, or `deliver_at` in unix milliseconds,
+//! is checked like any other when it comes in, and answered with the number
+//! of the scheduled message and its time instead of a sequence number:
+//!
+//! ```json
+//! {"type": "room.publish", "id": "4", "payload": {"room": "lobby", "data": {"text": "standup!"}, "delay_ms": 60000}}
+//! {"type": "room.publish", "id": "4", "payload": {"room": "lobby", "scheduled": 7, "deliver_at": 1700000060000}}
+//! ```
+//!
+//! Waiting messages sit in a timer wheel of `SLOTS` slots of `TICK` each,
+//! in the slot of their time, and stay there as it turns until they are
+//! due. Each tick the messages due in its slot are sequenced and sent to the
+//! members of their room, as from their publisher even if it closed
+//! meanwhile. At most `max_pending` wait, up to `max_delay_secs` ahead.
+//!
+//! With `schedule.file` set every message scheduled and delivered is also
+//! appended to it, replayed and compacted to the waiting ones at startup;
+//! those that fell due while the server was down go out at the first tick.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ntex::time;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::ScheduleConfig;
+use crate::history::now_millis;
+use crate::hub::Hub;
+use crate::metrics::METRICS;
+
+/// Time a slot of the wheel covers
+const TICK: Duration = Duration::from_millis(100);
+
+/// Slots of the wheel, a turn takes `SLOTS * TICK`
+const SLOTS: usize = 512;
+
+/// Room message waiting for its time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scheduled {
+    pub id: u64,
+    pub room: String,
+    /// Connection that published it
+    pub from: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub data: Value,
+    /// Unix milliseconds
+    pub deliver_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    /// Further ahead than `max_delay_secs`
+    TooFar,
+    /// `max_pending` messages are waiting
+    Full,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::TooFar => write!(f, "scheduled too far ahead"),
+            ScheduleError::Full => write!(f, "too many scheduled messages"),
+        }
+    }
+}
+
+/// Line of the schedule log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Scheduled(Scheduled),
+    Delivered { id: u64 },
+}
+
+#[derive(Debug)]
+struct Wheel {
+    slots: Vec<Vec<Scheduled>>,
+    /// Slot of the next tick
+    cursor: usize,
+    /// Time of the next tick, unix milliseconds
+    next_tick: u64,
+    pending: usize,
+    next_id: u64,
+}
+
+impl Wheel {
+    fn new(now: u64) -> Wheel {
+        Wheel {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            cursor: 0,
+            next_tick: now,
+            pending: 0,
+            next_id: 1,
+        }
+    }
+
+    /// Put `msg` in the slot of the first tick at or after its time
+    fn insert(&mut self, msg: Scheduled) {
+        let tick = TICK.as_millis() as u64;
+        let ahead = msg.deliver_at.saturating_sub(self.next_tick).div_ceil(tick);
+        let slot = (self.cursor + (ahead % SLOTS as u64) as usize) % SLOTS;
+        self.slots[slot].push(msg);
+        self.pending += 1;
+    }
+
+    /// Turn the wheel up to `now`, returns the messages due meanwhile
+    fn turn(&mut self, now: u64) -> Vec<Scheduled> {
+        let mut due = Vec::new();
+        while self.next_tick <= now {
+            let slot = std::mem::take(&mut self.slots[self.cursor]);
+            let (ready, waiting): (Vec<_>, Vec<_>) = slot
+                .into_iter()
+                .partition(|msg| msg.deliver_at <= self.next_tick);
+            self.slots[self.cursor] = waiting;
+            due.extend(ready);
+            self.cursor = (self.cursor + 1) % SLOTS;
+            self.next_tick += TICK.as_millis() as u64;
+        }
+        self.pending -= due.len();
+        due.sort_by_key(|msg| (msg.deliver_at, msg.id));
+        due
+    }
+}
+
+#[derive(Debug)]
+pub struct Schedule {
+    config: ScheduleConfig,
+    wheel: Mutex<Wheel>,
+    log: Mutex<Option<BufWriter<File>>>,
+}
+
+impl Schedule {
+    /// Empty schedule kept in memory only
+    pub fn new(config: &ScheduleConfig) -> Schedule {
+        Schedule {
+            config: config.clone(),
+            wheel: Mutex::new(Wheel::new(now_millis())),
+            log: Mutex::new(None),
+        }
+    }
+
+    /// Empty schedule, or the one replayed from `schedule.file`
+    pub fn open(config: &ScheduleConfig) -> io::Result<Schedule> {
+        let schedule = Schedule::new(config);
+        if let Some(ref path) = config.file {
+            let waiting = match path.exists() {
+                true => replay(path)?,
+                false => Vec::new(),
+            };
+            compact(path, &waiting)?;
+            log::info!(
+                "Loaded {} scheduled messages from {}",
+                waiting.len(),
+                path.display()
+            );
+            let mut wheel = schedule.wheel.lock().unwrap();
+            wheel.next_id = waiting.iter().map(|msg| msg.id + 1).max().unwrap_or(1);
+            for msg in waiting {
+                wheel.insert(msg);
+            }
+            drop(wheel);
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            *schedule.log.lock().unwrap() = Some(BufWriter::new(file));
+        }
+        Ok(schedule)
+    }
+
+    /// Schedule `data` for room `name` at `deliver_at`, from connection
+    /// `from` of `user`; returns its number
+    pub fn add(
+        &self,
+        name: &str,
+        from: u64,
+        user: Option<String>,
+        data: Value,
+        deliver_at: u64,
+    ) -> Result<u64, ScheduleError> {
+        if deliver_at > now_millis() + self.config.max_delay_secs * 1000 {
+            return Err(ScheduleError::TooFar);
+        }
+        let mut wheel = self.wheel.lock().unwrap();
+        if wheel.pending >= self.config.max_pending {
+            return Err(ScheduleError::Full);
+        }
+        let msg = Scheduled {
+            id: wheel.next_id,
+            room: name.to_string(),
+            from,
+            user,
+            data,
+            deliver_at,
+        };
+        wheel.next_id += 1;
+        METRICS.scheduled_total.inc();
+        self.append(&Record::Scheduled(msg.clone()));
+        let id = msg.id;
+        wheel.insert(msg);
+        Ok(id)
+    }
+
+    fn append(&self, record: &Record) {
+        let mut log = self.log.lock().unwrap();
+        let Some(ref mut out) = *log else {
+            return;
+        };
+        let line = serde_json::to_string(record).expect("record serializes");
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            log::error!("Failed to write the schedule log: {}", e);
+        }
+    }
+}
+
+/// Turn the wheel of `hub`'s schedule, publishing the messages due
+pub async fn run(hub: Arc<Hub>) {
+    loop {
+        time::sleep(TICK).await;
+        let schedule = hub.schedule();
+        let due = schedule.wheel.lock().unwrap().turn(now_millis());
+        for msg in due {
+            hub.publish_scheduled(&msg);
+            schedule.append(&Record::Delivered { id: msg.id });
+        }
+    }
+}
+
+/// Messages of the schedule log not delivered yet; malformed records are
+/// skipped
+fn replay(path: &Path) -> io::Result<Vec<Scheduled>> {
+    let mut waiting = BTreeMap::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str::<Record>(&line) {
+            Ok(Record::Scheduled(msg)) => {
+                waiting.insert(msg.id, msg);
+            }
+            Ok(Record::Delivered { id }) => {
+                waiting.remove(&id);
+            }
+            Err(e) => log::warn!(
+                "{}:{}: skipping schedule record: {}",
+                path.display(),
+                n + 1,
+                e
+            ),
+        }
+    }
+    Ok(waiting.into_values().collect())
+}
+
+/// Rewrite the schedule log with just `waiting`
+fn compact(path: &Path, waiting: &[Scheduled]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    for msg in waiting {
+        let record = Record::Scheduled(msg.clone());
+        let line = serde_json::to_string(&record).expect("record serializes");
+        writeln!(out, "{}", line)?;
+    }
+    out.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,58 @@
+//! Static description of message payloads, used to generate API docs
+//! and client types.
+
+use serde_json::{json, Map, Value};
+
+/// Shape of a JSON value
+#[derive(Debug)]
+pub enum Schema {
+    /// Any JSON value
+    Any,
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// One of the listed strings
+    Enum(&'static [&'static str]),
+    Array(&'static Schema),
+    Object(&'static [Field]),
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub schema: Schema,
+    pub required: bool,
+    pub doc: &'static str,
+}
+
+impl Schema {
+    /// Render as JSON Schema
+    pub fn to_json_schema(&self) -> Value {
+        match self {
+            Schema::Any => json!({}),
+            Schema::String => json!({ "type": "string" }),
+            Schema::Integer => json!({ "type": "integer" }),
+            Schema::Number => json!({ "type": "number" }),
+            Schema::Boolean => json!({ "type": "boolean" }),
+            Schema::Enum(values) => json!({ "type": "string", "enum": values }),
+            Schema::Array(item) => json!({ "type": "array", "items": item.to_json_schema() }),
+            Schema::Object(fields) => {
+                let mut props = Map::new();
+                for f in fields.iter() {
+                    let mut schema = f.schema.to_json_schema();
+                    if !f.doc.is_empty() {
+                        schema["description"] = json!(f.doc);
+                    }
+                    props.insert(f.name.to_string(), schema);
+                }
+                let required: Vec<_> = fields
+                    .iter()
+                    .filter(|f| f.required)
+                    .map(|f| f.name)
+                    .collect();
+                json!({ "type": "object", "properties": props, "required": required })
+            }
+        }
+    }
+}
@@ -0,0 +1,2619 @@
+//! Websocket connection handling: frame dispatch, heartbeat and the
+//! writer task draining the connection's hub queue.
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{atomic::AtomicU64, atomic::Ordering, Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, rc::Rc};
+
+use futures::channel::mpsc;
+use futures::future::{ready, select, Either};
+use futures::StreamExt;
+use ntex::http::body::BodySize;
+use ntex::http::header::{self, HeaderName, HeaderValue};
+use ntex::http::{h1, StatusCode};
+use ntex::io::{DispatchItem, Dispatcher, IoRef};
+use ntex::service::{
+    apply_fn, fn_factory_with_config, fn_service, IntoServiceFactory, Service, ServiceFactory,
+};
+use ntex::time::Seconds;
+use ntex::web::{self, types::Query, types::State, ws, Error, HttpRequest, HttpResponse};
+use ntex::ws::error::{HandshakeError, ProtocolError, WsError};
+use ntex::ws::Item;
+use ntex::{channel::oneshot, rt, time, util::Bytes};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::accept::AcceptLimiter;
+use crate::acks::AckRequest;
+use crate::auth::{AuthError, Authenticator, Identity};
+use crate::channels::Channels;
+use crate::config::{
+    self, Config, HandlersConfig, KeepaliveConfig, RecordConfig, SessionPolicy, WhenExceeded,
+};
+use crate::crdt::{Kind, Op};
+use crate::events::{self, Event};
+use crate::expr::Expr;
+use crate::filter;
+use crate::framing::{self, Framing, Packer, Unpack};
+use crate::history::{self, Fetch, Search};
+use crate::hub::{ConnStats, Hub, Metadata, Outbound};
+use crate::inflight::{Inflight, Ticket};
+use crate::kv::{KvError, Scope};
+use crate::labels::{self, Labels};
+use crate::metrics::{self, METRICS};
+use crate::pipeline::{self, Handled, Pipeline, Request};
+use crate::pool::{self, PooledBuf};
+use crate::protocol::{self, Envelope, ErrorCode};
+use crate::push::{Device, Platform};
+use crate::rooms::{self, Join, Moderation, RoomError};
+use crate::schedule::ScheduleError;
+use crate::tasks::TaskHandle;
+use crate::{affinity, bans, flags, locks, record, reporting, stream, utf8};
+
+/// Max number of `hello` attributes
+const MAX_ATTRIBUTES: usize = 16;
+/// Max length of a `hello` attribute name and value, bytes
+const MAX_ATTRIBUTE_LEN: usize = 128;
+/// Max number of messages of a `room.publish_batch`
+const MAX_BATCH: usize = 64;
+/// Max number of users of a `presence.last_seen`
+const MAX_LAST_SEEN_USERS: usize = 100;
+
+/// Close code for a connection replaced by a newer one of the same user,
+/// see `auth.sessions`
+pub const SESSION_REPLACED: u16 = 4001;
+
+/// Close code for a connection over the user's `auth.sessions.max_connections`
+pub const QUOTA_EXCEEDED: u16 = 4002;
+
+/// Connection id generator
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Reference point for ping timestamps
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Ping payload: microseconds since `EPOCH`, big-endian u64, with `clock`
+/// followed by the server clock in unix milliseconds
+fn ping_payload(clock: bool) -> Bytes {
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    let us = Instant::now().duration_since(epoch).as_micros() as u64;
+    let mut payload = us.to_be_bytes().to_vec();
+    if clock {
+        payload.extend_from_slice(&history::now_millis().to_be_bytes());
+    }
+    Bytes::from(payload)
+}
+
+/// Round-trip time for a pong echoing `ping_payload()`, and how far the
+/// client clock is ahead if the client appended it in unix milliseconds
+fn pong_timing(payload: &[u8]) -> Option<(Duration, Option<i64>)> {
+    let epoch = *EPOCH.get()?;
+    let (sent, clocks) = payload.split_first_chunk::<8>()?;
+    let sent = Duration::from_micros(u64::from_be_bytes(*sent));
+    let rtt = Instant::now().duration_since(epoch).checked_sub(sent)?;
+    let skew = match clocks.len() {
+        0 | 8 => None,
+        16 => {
+            let (server, client) = clocks.split_at(8);
+            // echoed by the client, so anything; a sample out of range is
+            // dropped, `i64::MIN` means unknown
+            let server = i64::try_from(u64::from_be_bytes(server.try_into().ok()?)).ok()?;
+            let client = i64::try_from(u64::from_be_bytes(client.try_into().ok()?)).ok()?;
+            // the client read its clock about half the round trip later
+            let half_rtt = i64::try_from(rtt.as_millis() / 2).ok()?;
+            let skew = client.checked_sub(server)?.checked_sub(half_rtt)?;
+            Some(skew).filter(|skew| *skew != i64::MIN)
+        }
+        _ => return None,
+    };
+    Some((rtt, skew))
+}
+
+pub struct WsState {
+    id: u64,
+    /// Introspection entry of this connection
+    task: TaskHandle,
+    /// Client must send ping or pong at least once per `timeout`,
+    /// otherwise we drop connection.
+    hb: Instant,
+    /// Bounds of what a client may ask for in `hello`
+    keepalive: KeepaliveConfig,
+    /// How often heartbeat pings are sent
+    interval: Duration,
+    /// How long before lack of client response causes a timeout
+    timeout: Duration,
+    /// Whether pings carry the server clock, see `hello`
+    clock: bool,
+    /// Fragmented message being reassembled, `true` for text
+    fragments: Option<(bool, PooledBuf)>,
+    stats: Arc<ConnStats>,
+    /// Authenticated user, `None` for anonymous connections
+    identity: Option<Identity>,
+    /// Rooms and delivery to other connections
+    hub: Arc<Hub>,
+    /// Checks credentials of `auth.refresh`
+    auth: Arc<Authenticator>,
+    /// Messages waiting for the content filter, the task draining it is
+    /// started by the first message to a filtered room
+    filter_queue: OnceCell<mpsc::UnboundedSender<Filtering>>,
+    /// Handlers answering later, cancelled by `$cancel`
+    inflight: Inflight,
+    /// Binary frames carry packed protocol messages, see `framing`
+    framing: Framing,
+    /// Virtual channels opened by the client
+    channels: Channels,
+    /// Token resuming this connection's rooms once it closed, `None`
+    /// without `[store]`
+    resume_token: Option<String>,
+    /// Sees protocol messages before the built-in handlers, `None` for
+    /// those alone
+    pipeline: Option<Arc<dyn Pipeline>>,
+}
+
+impl WsState {
+    /// `identity` tenant wins over the requested `tenant`
+    pub fn new(
+        id: u64,
+        tenant: Option<String>,
+        identity: Option<Identity>,
+        hub: Arc<Hub>,
+        auth: Arc<Authenticator>,
+    ) -> WsState {
+        EPOCH.get_or_init(Instant::now);
+        let task = TaskHandle::register("connection", Some(id));
+        task.set_state("open");
+        let claimed = identity.as_ref().and_then(|i| i.tenant.clone());
+        let tenant_claimed = claimed.is_some();
+        let tenant = claimed.or(tenant);
+        let user = identity.as_ref().map(|i| i.user.clone());
+        WsState {
+            id,
+            task,
+            hb: Instant::now(),
+            keepalive: KeepaliveConfig::default(),
+            interval: Duration::from_millis(KeepaliveConfig::default().interval_ms),
+            timeout: Duration::from_millis(KeepaliveConfig::default().timeout_ms),
+            clock: false,
+            fragments: None,
+            stats: Arc::new(ConnStats::new(tenant, user).with_claimed_tenant(tenant_claimed)),
+            identity,
+            channels: Channels::new(hub.clone(), id),
+            hub,
+            auth,
+            filter_queue: OnceCell::new(),
+            inflight: Inflight::new(HandlersConfig::default()),
+            framing: Framing::Plain,
+            resume_token: None,
+            pipeline: None,
+        }
+    }
+
+    /// Framing of the connection, before its stats are shared with the hub
+    pub fn with_framing(mut self, framing: Framing) -> WsState {
+        self.stats = Arc::new(self.new_stats().with_framing(framing));
+        self.framing = framing;
+        self
+    }
+
+    pub fn with_handlers(mut self, handlers: HandlersConfig) -> WsState {
+        self.inflight = Inflight::new(handlers);
+        self
+    }
+
+    /// Labels of the connection, before its stats are shared with the hub
+    pub fn with_labels(mut self, labels: Labels) -> WsState {
+        self.stats = Arc::new(self.new_stats().with_labels(labels));
+        self
+    }
+
+    /// Handler pipeline registered by `name`, before the connection's stats
+    /// are shared with the hub
+    pub fn with_pipeline(mut self, name: &'static str) -> WsState {
+        self.stats = Arc::new(self.new_stats().with_pipeline(name));
+        self.pipeline = pipeline::get(name);
+        self
+    }
+
+    /// Fresh stats with the handshake's attributes of the current ones
+    fn new_stats(&self) -> ConnStats {
+        let tenant = self.stats.tenant().map(str::to_string);
+        let user = self.stats.user().map(str::to_string);
+        ConnStats::new(tenant, user)
+            .with_claimed_tenant(self.stats.claimed_tenant().is_some())
+            .with_labels(self.stats.labels().clone())
+            .with_pipeline(self.stats.pipeline())
+            .with_framing(self.stats.framing())
+    }
+
+    pub fn with_keepalive(mut self, keepalive: KeepaliveConfig) -> WsState {
+        self.keepalive = keepalive;
+        self.interval = Duration::from_millis(keepalive.interval_ms);
+        self.timeout = Duration::from_millis(keepalive.timeout_ms);
+        self
+    }
+
+    /// Stats shared with the hub
+    pub fn stats(&self) -> &Arc<ConnStats> {
+        &self.stats
+    }
+
+    /// Authenticated user, `None` for anonymous connections
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Handle incoming frame, returns reply if any
+    pub fn handle_frame(&mut self, frame: ws::Frame) -> Option<ws::Message> {
+        let start = Instant::now();
+        self.task.touch();
+        METRICS.frames_received_total.inc();
+
+        let scope = reporting::connection_scope(self.id, self.identity.as_ref());
+        let reply = self.dispatch(frame);
+        drop(scope);
+        METRICS.handler_seconds.observe(start.elapsed());
+        metrics::pipeline_handled(self.stats.pipeline(), start.elapsed());
+        reply
+    }
+
+    fn dispatch(&mut self, frame: ws::Frame) -> Option<ws::Message> {
+        match frame {
+            ws::Frame::Ping(msg) => {
+                self.hb = Instant::now();
+                Some(ws::Message::Pong(msg))
+            }
+            ws::Frame::Pong(msg) => {
+                self.hb = Instant::now();
+                if let Some((rtt, skew)) = pong_timing(&msg) {
+                    self.stats.set_rtt(rtt);
+                    METRICS.rtt_seconds.observe(rtt);
+                    if let Some(skew) = skew {
+                        self.stats.set_skew(skew);
+                    }
+                }
+                None
+            }
+            ws::Frame::Text(text) => self.text_message(text),
+            ws::Frame::Binary(bin) => self.binary_message(bin),
+            ws::Frame::Continuation(item) => continuation(self, item),
+            ws::Frame::Close(reason) => Some(ws::Message::Close(reason)),
+        }
+    }
+}
+
+impl WsState {
+    fn flag(&self, name: &str) -> bool {
+        flags::enabled(name, self.stats.tenant())
+    }
+
+    /// Echo binary message, unless disabled for the tenant
+    fn binary_message(&mut self, bin: Bytes) -> Option<ws::Message> {
+        if self.framing == Framing::Packed {
+            return self.packed_messages(bin);
+        }
+        if self.flag("binary_echo") {
+            Some(ws::Message::Binary(bin))
+        } else {
+            None
+        }
+    }
+
+    /// Handle the protocol messages of a packed frame, their replies are
+    /// packed into one frame
+    fn packed_messages(&mut self, bin: Bytes) -> Option<ws::Message> {
+        let mut replies = Packer::new();
+        for record in Unpack::new(bin) {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return Some(reject(self.id, ws::CloseCode::Invalid, e)),
+            };
+            match self.text_message(record) {
+                Some(ws::Message::Text(text)) => replies.push(text.as_slice()),
+                Some(close @ ws::Message::Close(_)) => return Some(close),
+                Some(_) | None => {}
+            }
+        }
+        match replies.is_empty() {
+            true => None,
+            false => Some(ws::Message::Binary(replies.finish())),
+        }
+    }
+
+    /// Handle protocol message, closes connection with 1007 if it is not valid utf-8
+    fn text_message(&mut self, text: Bytes) -> Option<ws::Message> {
+        if utf8::to_bytestring(text.clone()).is_none() {
+            return Some(reject(self.id, ws::CloseCode::Invalid, "invalid utf-8"));
+        }
+        let mut envelope = match Envelope::parse(&text) {
+            Ok(envelope) => envelope,
+            Err(e) => return Some(self.error(e, None)),
+        };
+        let ty = match envelope.resolve() {
+            Ok(ty) => ty,
+            Err(e) => return Some(self.error(e, envelope.id.as_deref())),
+        };
+        let id = envelope.id.as_deref();
+        if let Some(room) = envelope.payload.get("room").and_then(Value::as_str) {
+            reporting::set_room(room);
+        }
+        // message types without a flag are always on
+        if flags::known(ty.name) && !self.flag(ty.name) {
+            return Some(self.error(
+                protocol::Error::new(
+                    ErrorCode::PermissionDenied,
+                    format!("`{}` is disabled", ty.name),
+                ),
+                id,
+            ));
+        }
+        if let Some(ref pipeline) = self.pipeline {
+            let request = Request {
+                conn: self.id,
+                stats: &self.stats,
+                ty: ty.name,
+                id,
+                payload: &envelope.payload,
+            };
+            match pipeline.handle(&request) {
+                Handled::Pass => {}
+                Handled::Reply(payload) => return Some(protocol::message(ty.name, id, &payload)),
+                Handled::Error(e) => return Some(self.error(e, id)),
+                Handled::NoReply => return None,
+            }
+        }
+        let reply = match ty.name {
+            "echo" => protocol::message("echo", id, &envelope.payload),
+            "hello" => self.hello(&envelope.payload, id),
+            "sys.time" => sys_time(&envelope.payload, id),
+            "sys.rtt" => {
+                let rtt_ms = self.stats.rtt().map(|d| d.as_secs_f64() * 1000.0);
+                protocol::message("sys.rtt", id, &json!({ "rtt_ms": rtt_ms }))
+            }
+            "sys.clock" => {
+                let reply = json!({
+                    "server_time": history::now_millis(),
+                    "rtt_ms": self.stats.rtt().map(|d| d.as_secs_f64() * 1000.0),
+                    "skew_ms": self.stats.skew(),
+                });
+                protocol::message("sys.clock", id, &reply)
+            }
+            "auth.refresh" => self.refresh_credential(&envelope.payload, id),
+            "$cancel" => self.cancel(&envelope.payload, id),
+            // answers a call of the server, see `rpc`
+            "rpc.result" => return self.rpc_result(&envelope.payload, id),
+            // answered through the hub queue once the store has the state
+            "session.resume" => return self.resume(&envelope.payload, id),
+            "presence.last_seen" => return self.last_seen(&envelope.payload, id),
+            "room.join" => self.join(&envelope.payload, id),
+            "room.leave" => self.leave(&envelope.payload, id),
+            "room.moderate" => self.moderate(&envelope.payload, id),
+            "room.read" => self.read(&envelope.payload, id),
+            "room.receipts" => self.receipts(&envelope.payload, id),
+            "kv.get" | "kv.set" | "kv.delete" | "kv.subscribe" | "kv.unsubscribe" => {
+                self.kv(ty.name, &envelope.payload, id)
+            }
+            "crdt.get" | "crdt.update" | "crdt.subscribe" | "crdt.unsubscribe" => {
+                self.crdt(ty.name, &envelope.payload, id)
+            }
+            // a waiting acquire is answered through the hub queue
+            "lock.acquire" | "lock.renew" | "lock.release" => {
+                return self.lock(ty.name, &envelope.payload, id)
+            }
+            "user.send" => self.direct(&envelope.payload, id),
+            "push.register" | "push.unregister" => self.push_device(ty.name, &envelope.payload, id),
+            // answered only if rejected
+            "room.ack" => return self.ack(&envelope.payload, id),
+            "room.complete" => return self.complete(&envelope.payload, id),
+            "message.edit" => self.edit(&envelope.payload, id),
+            "message.delete" => self.delete(&envelope.payload, id),
+            // streamed through the hub queue if asked to
+            "history.fetch" => return self.fetch(&envelope.payload, id),
+            "history.search" => return self.search(&envelope.payload, id),
+            // answered through the hub queue
+            "channel.open" | "channel.data" | "channel.credit" | "channel.close" => {
+                return self.channel(ty.name, &envelope.payload, id)
+            }
+            // answered only if rejected
+            "room.ephemeral" => return self.ephemeral(&envelope.payload, id),
+            // answered by the room's owner if forwarded to another node
+            "room.publish" => return self.publish(&envelope.payload, id),
+            "room.publish_batch" => return self.publish_batch(&envelope.payload, id),
+            name => self.error(
+                protocol::Error::new(ErrorCode::Internal, format!("no handler for `{}`", name)),
+                id,
+            ),
+        };
+        Some(reply)
+    }
+
+    /// Store client attributes, replacing those of an earlier `hello`, and
+    /// negotiate the heartbeat if proposed
+    fn hello(&mut self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let mut payload = payload.clone();
+        let heartbeat = payload
+            .as_object_mut()
+            .and_then(|attrs| attrs.remove("heartbeat"))
+            .map(HeartbeatPayload::deserialize)
+            .transpose();
+        let heartbeat = match heartbeat {
+            Ok(heartbeat) => heartbeat,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let metadata = match metadata(&payload) {
+            Ok(metadata) => metadata,
+            Err(e) => return self.error(protocol::Error::new(ErrorCode::BadPayload, e), id),
+        };
+        self.stats.set_metadata(metadata);
+        let mut reply = json!({ "connection_id": self.id });
+        if let Some(heartbeat) = heartbeat {
+            let (interval_ms, timeout_ms) = self
+                .keepalive
+                .negotiate(heartbeat.interval_ms, heartbeat.timeout_ms);
+            self.interval = Duration::from_millis(interval_ms);
+            self.timeout = Duration::from_millis(timeout_ms);
+            self.clock = heartbeat.clock;
+            reply["heartbeat"] = json!({ "interval_ms": interval_ms, "timeout_ms": timeout_ms });
+            if heartbeat.clock {
+                reply["heartbeat"]["clock"] = json!(true);
+            }
+        }
+        if let Some(ref token) = self.resume_token {
+            reply["resume"] = json!(token);
+        }
+        protocol::message("hello", id, &reply)
+    }
+
+    /// Replace the connection's credential with a newer one of the same user,
+    /// the tenant stays the one of the handshake
+    fn refresh_credential(&mut self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let payload = match RefreshPayload::deserialize(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                return self.error(
+                    protocol::Error::new(ErrorCode::BadPayload, e.to_string()),
+                    id,
+                )
+            }
+        };
+        let identity = match self.auth.reauthenticate(&payload.token) {
+            Ok(identity) => identity,
+            Err(e @ AuthError::Unavailable(_)) => {
+                return self.error(protocol::Error::new(ErrorCode::Internal, e.to_string()), id)
+            }
+            Err(e) => {
+                return self.error(
+                    protocol::Error::new(ErrorCode::PermissionDenied, e.to_string()),
+                    id,
+                )
+            }
+        };
+        let denied = match self.identity {
+            Some(ref current) if current.user == identity.user => None,
+            Some(_) => Some("credential of another user"),
+            None => Some("connection is anonymous"),
+        };
+        if let Some(reason) = denied {
+            return self.error(
+                protocol::Error::new(ErrorCode::PermissionDenied, reason),
+                id,
+            );
+        }
+        log::debug!(
+            "Connection {}: credential refreshed, expires at {:?}",
+            self.id,
+            identity.expires_at
+        );
+        let reply = json!({ "expires_at": identity.expires_at });
+        self.identity = Some(identity);
+        protocol::message("auth.refresh", id, &reply)
+    }
+
+    /// Abort the handlers of an earlier message still waiting for something,
+    /// they answer it with `cancelled`
+    fn cancel(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        match CancelPayload::deserialize(payload) {
+            Ok(p) => {
+                let cancelled = self.inflight.cancel(&p.id);
+                protocol::message(
+                    "$cancel",
+                    id,
+                    &json!({ "id": p.id, "cancelled": cancelled }),
+                )
+            }
+            Err(e) => self.bad_payload(e, id),
+        }
+    }
+
+    /// Hand the result of an `rpc.call` to the server code waiting for it
+    fn rpc_result(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let Some(call) = id else {
+            let err = protocol::Error::new(ErrorCode::BadPayload, "`id` of the call is missing");
+            return Some(self.error(err, None));
+        };
+        let p = match RpcResultPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        let outcome = match p.error {
+            Some(error) => Err(error),
+            None => Ok(p.result),
+        };
+        if !self.hub.calls().answer(self.id, call, outcome) {
+            log::debug!("Connection {}: result of call {} dropped", self.id, call);
+        }
+        None
+    }
+
+    /// Rejoin the rooms of a closed connection of the same user
+    fn resume(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let token = match ResumePayload::deserialize(payload) {
+            Ok(p) => p.token,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        let Some(store) = self.hub.store().cloned() else {
+            let err = protocol::Error::new(ErrorCode::PermissionDenied, "sessions are not stored");
+            return Some(self.error(err, id));
+        };
+        let ticket = match self.inflight.start("session.resume", id) {
+            Ok(ticket) => ticket,
+            Err(e) => return Some(self.error(e, id)),
+        };
+        let hub = self.hub.clone();
+        let conn = self.id;
+        let user = self.identity.as_ref().map(|i| i.user.clone());
+        let tenant = self.stats.tenant().map(str::to_string);
+        let reply_id = id.map(str::to_string);
+        rt::spawn(async move {
+            let reply_id = reply_id.as_deref();
+            let mut deliveries = Vec::new();
+            let reply = match ticket.run(store.resume(&token)).await {
+                Ok(Ok(Some(resume))) if resume.user == user && resume.tenant == tenant => {
+                    deliveries = resume.deliveries;
+                    Ok(rejoin(&hub, conn, resume.rooms, reply_id))
+                }
+                Ok(Ok(_)) => Err(protocol::Error::new(
+                    ErrorCode::BadPayload,
+                    "unknown or expired session",
+                )),
+                Err(err) => Err(err),
+                Ok(Err(e)) => {
+                    log::warn!("Connection {}: cannot resume: {}", conn, e);
+                    Err(protocol::Error::new(
+                        ErrorCode::Internal,
+                        "session store unavailable",
+                    ))
+                }
+            };
+            let reply = reply.unwrap_or_else(|err| {
+                events::emit(Event::Rejected {
+                    id: conn,
+                    code: err.code,
+                });
+                err.to_message(reply_id)
+            });
+            hub.send(conn, reply);
+            hub.redeliver(conn, deliveries);
+        });
+        None
+    }
+
+    /// When users were last seen, answered through the hub queue once the
+    /// store has them
+    fn last_seen(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let users = match LastSeenPayload::deserialize(payload) {
+            Ok(p) => p.users,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        if users.len() > MAX_LAST_SEEN_USERS {
+            let err = protocol::Error::new(
+                ErrorCode::BadPayload,
+                format!("at most {} users at once", MAX_LAST_SEEN_USERS),
+            );
+            return Some(self.error(err, id));
+        }
+        let Some(store) = self
+            .hub
+            .store()
+            .filter(|store| store.records_last_seen())
+            .cloned()
+        else {
+            let err = protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "last-seen times are not recorded",
+            );
+            return Some(self.error(err, id));
+        };
+        let ticket = match self.inflight.start("presence.last_seen", id) {
+            Ok(ticket) => ticket,
+            Err(e) => return Some(self.error(e, id)),
+        };
+        let hub = self.hub.clone();
+        let conn = self.id;
+        let reply_id = id.map(str::to_string);
+        rt::spawn(async move {
+            let lookup = async {
+                let mut seen = BTreeMap::new();
+                for user in users {
+                    let last_seen = store.last_seen(&user).await?;
+                    seen.insert(user, last_seen);
+                }
+                Ok::<_, String>(seen)
+            };
+            let reply = match ticket.run(lookup).await {
+                Ok(Ok(seen)) => protocol::message(
+                    "presence.last_seen",
+                    reply_id.as_deref(),
+                    &json!({ "users": seen }),
+                ),
+                Err(err) => err.to_message(reply_id.as_deref()),
+                Ok(Err(e)) => {
+                    log::warn!("Connection {}: cannot read last-seen times: {}", conn, e);
+                    protocol::Error::new(ErrorCode::Internal, "session store unavailable")
+                        .to_message(reply_id.as_deref())
+                }
+            };
+            hub.send(conn, reply);
+        });
+        None
+    }
+
+    /// Join a room, answered with `room.waiting` if queued for a full one
+    fn join(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let JoinPayload {
+            room,
+            invite,
+            private,
+            filter,
+            group,
+        } = match JoinPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        if let Some(Err(e)) = group.as_deref().map(rooms::validate_group) {
+            return self.error(e.into(), id);
+        }
+        let group = group.as_deref();
+        let filter = match filter.as_deref().map(Expr::parse).transpose() {
+            Ok(filter) => filter,
+            Err(e) => {
+                let message = format!("bad filter: {}", e);
+                return self.error(protocol::Error::new(ErrorCode::BadPayload, message), id);
+            }
+        };
+        if let Some(partitions) = self.hub.rooms().partitions(&room) {
+            return self.join_partitions(&room, partitions, private, group, filter, id);
+        }
+        // before joining, so no message gets past it
+        self.stats.set_filter(&room, filter);
+        let joined = self.hub.join(self.id, &room, invite.as_deref(), private);
+        match joined {
+            Ok(_) => self.hub.rooms().set_group(self.id, &room, group),
+            Err(_) => self.stats.set_filter(&room, None),
+        }
+        match joined {
+            Ok(Join::Member(members)) => {
+                let mut reply = json!({ "room": room, "members": members });
+                if let Some(group) = group {
+                    reply["group"] = json!(group);
+                }
+                protocol::message("room.join", id, &reply)
+            }
+            Ok(Join::Waiting(position)) => protocol::message(
+                "room.waiting",
+                id,
+                &json!({ "room": room, "position": position }),
+            ),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Join all partitions of partitioned room `room`, private and into
+    /// consumer group as given, or none
+    fn join_partitions(
+        &self,
+        room: &str,
+        partitions: Vec<String>,
+        private: bool,
+        group: Option<&str>,
+        filter: Option<Expr>,
+        id: Option<&str>,
+    ) -> ws::Message {
+        let mut joined = Vec::with_capacity(partitions.len());
+        for partition in &partitions {
+            self.stats.set_filter(partition, filter.clone());
+            let join = self.hub.join(self.id, partition, None, private);
+            if join.is_ok() {
+                self.hub.rooms().set_group(self.id, partition, group);
+            }
+            match join {
+                Ok(Join::Member(members)) => {
+                    joined.push(json!({ "room": partition, "members": members }))
+                }
+                Ok(Join::Waiting(position)) => {
+                    joined.push(json!({ "room": partition, "position": position }))
+                }
+                Err(e) => {
+                    for partition in &partitions[..=joined.len()] {
+                        self.hub.leave(self.id, partition);
+                        self.stats.set_filter(partition, None);
+                    }
+                    return self.error(e.into(), id);
+                }
+            }
+        }
+        let mut reply = json!({ "room": room, "partitions": joined });
+        if let Some(group) = group {
+            reply["group"] = json!(group);
+        }
+        protocol::message("room.join", id, &reply)
+    }
+
+    /// Leave a room or its queue, leaving a room not joined is not an error;
+    /// leaving a partitioned room leaves all its partitions
+    fn leave(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        match RoomPayload::deserialize(payload) {
+            Ok(p) => {
+                let rooms = self.hub.rooms().partitions(&p.room);
+                for room in rooms.as_deref().unwrap_or(std::slice::from_ref(&p.room)) {
+                    self.hub.leave(self.id, room);
+                    self.stats.set_filter(room, None);
+                }
+                protocol::message("room.leave", id, &json!({ "room": p.room }))
+            }
+            Err(e) => self.bad_payload(e, id),
+        }
+    }
+
+    /// Send `data` to the other members of a joined room, or to a
+    /// partition of a partitioned room by its ordering `key`
+    fn publish(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let mut p = match PublishPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        p.room = self.hub.rooms().route(&p.room, p.key.as_deref());
+        if let Some(Err(e)) = p.ack.as_ref().map(AckRequest::validate) {
+            return Some(self.error(protocol::Error::new(ErrorCode::BadPayload, e), id));
+        }
+        if p.delay_ms.is_some() || p.deliver_at.is_some() {
+            return Some(self.schedule(p, id));
+        }
+        if self.hub.content_filter(&p.room).is_some() {
+            // refuse what the hub would before waiting for the filter, it is
+            // checked again when published
+            let user = match self.hub.rooms().may_publish(self.id, &p.room) {
+                Ok(user) => user,
+                Err(e) => return Some(self.error(e.into(), id)),
+            };
+            // the time to answer includes waiting for earlier messages
+            let ticket = match self.inflight.start("room.publish", id) {
+                Ok(ticket) => ticket,
+                Err(e) => return Some(self.error(e, id)),
+            };
+            let msg = filter::Message {
+                room: p.room,
+                user,
+                data: p.data,
+            };
+            let filtering = Filtering {
+                pending: Pending::Publish(msg, p.ack),
+                reply_id: id.map(str::to_string),
+                ticket,
+            };
+            let _ = self.filter_queue().unbounded_send(filtering);
+            return None;
+        }
+        match self
+            .hub
+            .publish(self.id, &p.room, &p.data, id, p.ack.as_ref())
+        {
+            Ok(Some(seq)) => {
+                let reply =
+                    protocol::message("room.publish", id, &json!({ "room": p.room, "seq": seq }));
+                let Some(ack) = p.ack else {
+                    return Some(reply);
+                };
+                // through the queue, ahead of the report
+                self.hub.send(self.id, reply);
+                let reply_id = id.map(str::to_string);
+                rt::spawn(report_acks(
+                    self.hub.clone(),
+                    self.id,
+                    p.room,
+                    seq,
+                    ack,
+                    reply_id,
+                ));
+                None
+            }
+            Ok(None) => None,
+            Err(e) => Some(self.error(e.into(), id)),
+        }
+    }
+
+    /// Keep a message published with `delay_ms` or `deliver_at` until its
+    /// time, see `schedule`
+    fn schedule(&self, p: PublishPayload, id: Option<&str>) -> ws::Message {
+        let deliver_at = match (p.delay_ms, p.deliver_at) {
+            (Some(delay), None) => history::now_millis().saturating_add(delay),
+            (None, Some(at)) => at,
+            _ => {
+                let e = "delay_ms and deliver_at cannot be combined";
+                return self.error(protocol::Error::new(ErrorCode::BadPayload, e), id);
+            }
+        };
+        let refused = match (p.ack.is_some(), self.hub.content_filter(&p.room).is_some()) {
+            (true, _) => Some("a scheduled message cannot ask for acknowledgments"),
+            (_, true) => Some("messages to rooms with a content filter cannot be scheduled"),
+            _ => None,
+        };
+        if let Some(e) = refused {
+            return self.error(protocol::Error::new(ErrorCode::BadPayload, e), id);
+        }
+        let user = match self.hub.rooms().authorize_publish(self.id, &p.room) {
+            Ok(user) => user,
+            Err(e) => return self.error(e.into(), id),
+        };
+        let scheduled = match self
+            .hub
+            .schedule()
+            .add(&p.room, self.id, user, p.data, deliver_at)
+        {
+            Ok(scheduled) => scheduled,
+            Err(e @ ScheduleError::TooFar) => {
+                return self.error(
+                    protocol::Error::new(ErrorCode::BadPayload, e.to_string()),
+                    id,
+                )
+            }
+            Err(e @ ScheduleError::Full) => {
+                return self.error(
+                    protocol::Error::new(ErrorCode::RateLimited, e.to_string()),
+                    id,
+                )
+            }
+        };
+        let reply = json!({ "room": p.room, "scheduled": scheduled, "deliver_at": deliver_at });
+        protocol::message("room.publish", id, &reply)
+    }
+
+    /// Note a member acknowledged a message published with `ack`
+    fn ack(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match SeqPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        let acked = self.hub.acks().ack(self.id, &p.room, p.seq);
+        if self.hub.acks().receive(self.id, &p.room, p.seq) {
+            let payload = json!({ "room": p.room, "seq": p.seq });
+            return Some(protocol::message("room.release", None, &payload));
+        }
+        if !acked {
+            log::debug!(
+                "Connection {}: acknowledgment of {} {} dropped",
+                self.id,
+                p.room,
+                p.seq
+            );
+        }
+        None
+    }
+
+    /// End the handshake of an exactly-once message that was released
+    fn complete(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match SeqPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        if !self.hub.acks().complete(self.id, &p.room, p.seq) {
+            log::debug!(
+                "Connection {}: completion of {} {} dropped",
+                self.id,
+                p.room,
+                p.seq
+            );
+        }
+        None
+    }
+
+    /// Publish messages to several joined rooms, all or none
+    fn publish_batch(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let messages = match BatchPayload::deserialize(payload) {
+            Ok(p) => p.messages,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        if messages.is_empty() || messages.len() > MAX_BATCH {
+            let message = format!("a batch has 1 to {} messages", MAX_BATCH);
+            return Some(self.error(protocol::Error::new(ErrorCode::BadPayload, message), id));
+        }
+        let rooms = self.hub.rooms();
+        let messages: Vec<(String, Value)> = messages
+            .into_iter()
+            .map(|m| (rooms.route(&m.room, m.key.as_deref()), m.data))
+            .collect();
+        if !messages
+            .iter()
+            .any(|(room, _)| self.hub.content_filter(room).is_some())
+        {
+            return Some(
+                publish_batch(&self.hub, self.id, &messages, id)
+                    .unwrap_or_else(|e| self.error(e, id)),
+            );
+        }
+        let mut msgs = Vec::with_capacity(messages.len());
+        for (i, (room, data)) in messages.into_iter().enumerate() {
+            // refused before filtering, checked again when published
+            let user = match self.hub.rooms().may_publish(self.id, &room) {
+                Ok(user) => user,
+                Err(e) => return Some(self.error(batch_error(i, e.into()), id)),
+            };
+            msgs.push(filter::Message { room, user, data });
+        }
+        let ticket = match self.inflight.start("room.publish_batch", id) {
+            Ok(ticket) => ticket,
+            Err(e) => return Some(self.error(e, id)),
+        };
+        let filtering = Filtering {
+            pending: Pending::Batch(msgs),
+            reply_id: id.map(str::to_string),
+            ticket,
+        };
+        let _ = self.filter_queue().unbounded_send(filtering);
+        None
+    }
+
+    fn filter_queue(&self) -> &mpsc::UnboundedSender<Filtering> {
+        self.filter_queue.get_or_init(|| {
+            let (tx, rx) = mpsc::unbounded();
+            rt::spawn(filter_messages(self.hub.clone(), self.id, rx));
+            tx
+        })
+    }
+
+    /// Pass `data` on to the other members without a sequence number,
+    /// dropped for members with a backlog
+    fn ephemeral(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match PublishPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        match self.hub.ephemeral(self.id, &p.room, &p.data) {
+            Ok(_) => None,
+            Err(e) => Some(self.error(e.into(), id)),
+        }
+    }
+
+    /// Replace data of an own message
+    fn edit(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match EditPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        match self.hub.edit(self.id, &p.room, p.seq, &p.data) {
+            Ok(edited_at) => protocol::message(
+                "message.edit",
+                id,
+                &json!({ "room": p.room, "seq": p.seq, "data": p.data, "edited_at": edited_at }),
+            ),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Delete an own message
+    fn delete(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match SeqPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        match self.hub.delete(self.id, &p.room, p.seq) {
+            Ok(()) => protocol::message(
+                "message.delete",
+                id,
+                &json!({ "room": p.room, "seq": p.seq }),
+            ),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Page through the history of a joined room, with `stream` all pages
+    /// in the fetched direction
+    fn fetch(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let (fetch, streamed) = match (
+            Fetch::deserialize(payload),
+            StreamPayload::deserialize(payload),
+        ) {
+            (Ok(fetch), Ok(p)) => (fetch, p.stream),
+            (Err(e), _) | (_, Err(e)) => return Some(self.bad_payload(e, id)),
+        };
+        if !streamed {
+            return Some(match self.hub.rooms().fetch(self.id, &fetch) {
+                Ok(page) => protocol::message("history.fetch", id, &page),
+                Err(e) => self.error(e.into(), id),
+            });
+        }
+        let hub = self.hub.clone();
+        let conn = self.id;
+        let mut next = Some(fetch);
+        self.stream("history.fetch", id, move || {
+            let Some(mut fetch) = next.take() else {
+                return Ok(None);
+            };
+            let page = hub.rooms().fetch(conn, &fetch)?;
+            if page.more {
+                match fetch.after {
+                    Some(_) => fetch.after = page.messages.last().map(|e| e.seq),
+                    None => fetch.before = page.messages.first().map(|e| e.seq),
+                }
+                next = Some(fetch);
+            }
+            Ok(Some(page))
+        })
+    }
+
+    /// Search the history of the joined rooms, with `stream` all pages
+    fn search(&self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let (search, streamed) = match (
+            Search::deserialize(payload),
+            StreamPayload::deserialize(payload),
+        ) {
+            (Ok(search), Ok(p)) => (search, p.stream),
+            (Err(e), _) | (_, Err(e)) => return Some(self.bad_payload(e, id)),
+        };
+        if !streamed {
+            return Some(match self.hub.rooms().search(Some(self.id), &search) {
+                Ok(page) => protocol::message("history.search", id, &page),
+                Err(e) => self.error(e.into(), id),
+            });
+        }
+        let hub = self.hub.clone();
+        let conn = self.id;
+        let mut next = Some(search);
+        self.stream("history.search", id, move || {
+            let Some(mut search) = next.take() else {
+                return Ok(None);
+            };
+            let page = hub.rooms().search(Some(conn), &search)?;
+            if let Some(offset) = page.next_offset {
+                search.offset = offset;
+                next = Some(search);
+            }
+            Ok(Some(page))
+        })
+    }
+
+    /// Answer message `kind` with the parts `next` yields, see `stream`
+    fn stream<P, F>(&self, kind: &'static str, id: Option<&str>, next: F) -> Option<ws::Message>
+    where
+        P: Serialize + 'static,
+        F: FnMut() -> Result<Option<P>, protocol::Error> + 'static,
+    {
+        let ticket = match self.inflight.start(kind, id) {
+            Ok(ticket) => ticket,
+            Err(e) => return Some(self.error(e, id)),
+        };
+        let id = id.map(str::to_string);
+        rt::spawn(stream::run(
+            self.hub.clone(),
+            self.id,
+            kind,
+            id,
+            ticket,
+            next,
+        ));
+        None
+    }
+
+    /// Report messages up to `seq` as read by the connection's user
+    fn read(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match SeqPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let result = match self.identity {
+            Some(ref identity) => self.hub.read(self.id, &identity.user, &p.room, p.seq),
+            None => Err(RoomError::Anonymous),
+        };
+        match result {
+            Ok(seq) => protocol::message("room.read", id, &json!({ "room": p.room, "seq": seq })),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Read positions of the users of a joined room
+    fn receipts(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let room = match RoomPayload::deserialize(payload) {
+            Ok(p) => p.room,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        match self.hub.rooms().receipts(Some(self.id), &room) {
+            Ok(receipts) => protocol::message(
+                "room.receipts",
+                id,
+                &json!({ "room": room, "receipts": receipts }),
+            ),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Apply a moderation action, only for moderators of the room
+    fn moderate(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match ModeratePayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let user = match self.identity {
+            Some(ref identity) => identity.user.as_str(),
+            None => {
+                return self.error(
+                    protocol::Error::new(
+                        ErrorCode::PermissionDenied,
+                        "moderation requires authentication",
+                    ),
+                    id,
+                )
+            }
+        };
+        match self.hub.moderate(Some(user), &p.room, p.action) {
+            Ok(()) => protocol::message("room.moderate", id, payload),
+            Err(e) => self.error(e.into(), id),
+        }
+    }
+
+    /// Scope of the connection's tenant, only for a tenant its credential
+    /// names: one asked for with `?tenant=` is the client's word
+    fn tenant_scope(&self) -> Result<Scope, protocol::Error> {
+        match self.stats.claimed_tenant() {
+            Some(tenant) => Ok(Scope::Tenant(Some(tenant.to_string()))),
+            None => Err(protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "tenant state needs a credential naming the tenant, join a room instead",
+            )),
+        }
+    }
+
+    /// Handle a `kv.*` message on the keys of a joined room, or of the
+    /// connection's tenant, see `kv`
+    fn kv(&self, name: &str, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match KvPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let scope = match p.room {
+            Some(ref room) if !self.hub.rooms().is_member(self.id, room) => {
+                return self.error(RoomError::NotMember.into(), id)
+            }
+            Some(ref room) => Scope::Room(room.clone()),
+            None => match self.tenant_scope() {
+                Ok(scope) => scope,
+                Err(e) => return self.error(e, id),
+            },
+        };
+        let kv = self.hub.kv();
+        if matches!(name, "kv.set" | "kv.delete") && !kv.writable(&scope) {
+            return self.error(KvError::ReadOnly.into(), id);
+        }
+        // only subscriptions may be to every key
+        let key = p.key.as_deref();
+        if key.is_none() && matches!(name, "kv.get" | "kv.set" | "kv.delete") {
+            let e = protocol::Error::new(ErrorCode::BadPayload, "missing field `key`");
+            return self.error(e, id);
+        }
+        let mut answer = match name {
+            "kv.get" => {
+                let entry = kv.get(&scope, key.unwrap_or_default());
+                json!({
+                    "key": key,
+                    "value": entry.as_ref().map(|entry| &entry.value),
+                    "version": entry.as_ref().map(|entry| entry.version),
+                })
+            }
+            "kv.set" => {
+                let Some(value) = p.value.filter(|value| !value.is_null()) else {
+                    let e = protocol::Error::new(
+                        ErrorCode::BadPayload,
+                        "`value` must be set, `kv.delete` removes a key",
+                    );
+                    return self.error(e, id);
+                };
+                let key = key.unwrap_or_default();
+                match self.hub.kv_set(&scope, key, value, p.if_version) {
+                    Ok(version) => json!({ "key": key, "version": version }),
+                    Err(e) => return self.error(e.into(), id),
+                }
+            }
+            "kv.delete" => {
+                let key = key.unwrap_or_default();
+                match self.hub.kv_delete(&scope, key, p.if_version) {
+                    Ok(deleted) => json!({ "key": key, "deleted": deleted }),
+                    Err(e) => return self.error(e.into(), id),
+                }
+            }
+            "kv.subscribe" => match kv.subscribe(self.id, &scope, key) {
+                Ok(entries) => json!({ "key": key, "entries": entries }),
+                Err(e) => return self.error(e.into(), id),
+            },
+            _ => {
+                kv.unsubscribe(self.id, &scope, key);
+                json!({ "key": key })
+            }
+        };
+        if let Some(room) = p.room {
+            answer["room"] = json!(room);
+        }
+        protocol::message(name, id, &answer)
+    }
+
+    /// Handle a `crdt.*` message on an object of a joined room, or of the
+    /// connection's tenant, see `crdt`
+    fn crdt(&self, name: &str, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match CrdtPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let scope = match p.room {
+            Some(ref room) if !self.hub.rooms().is_member(self.id, room) => {
+                return self.error(RoomError::NotMember.into(), id)
+            }
+            Some(ref room) => Scope::Room(room.clone()),
+            None => match self.tenant_scope() {
+                Ok(scope) => scope,
+                Err(e) => return self.error(e, id),
+            },
+        };
+        let crdts = self.hub.crdts();
+        let object = match name {
+            "crdt.get" => crdts
+                .get(&scope, &p.name)
+                .map(|object| (object.kind(), object.value())),
+            "crdt.update" => {
+                let kind = p.kind;
+                let op = match (p.kind, p.by, p.add, p.remove, p.value) {
+                    (Some(Kind::Counter), by, None, None, None) => Op::Increment(by.unwrap_or(1)),
+                    (Some(Kind::Set), None, Some(element), None, None) => Op::Add(element),
+                    (Some(Kind::Set), None, None, Some(element), None) => Op::Remove(element),
+                    (Some(Kind::Register), None, None, None, Some(value)) if !value.is_null() => {
+                        Op::Assign(value)
+                    }
+                    _ => {
+                        let e = protocol::Error::new(
+                            ErrorCode::BadPayload,
+                            "expected a `kind` with its update: `by` for a counter, `add` or \
+                             `remove` for a set, `value` for a register",
+                        );
+                        return self.error(e, id);
+                    }
+                };
+                match self.hub.crdt_update(&scope, &p.name, op) {
+                    Ok(value) => kind.map(|kind| (kind, value)),
+                    Err(e) => return self.error(e.into(), id),
+                }
+            }
+            "crdt.subscribe" => match crdts.subscribe(self.id, &scope, &p.name) {
+                Ok(object) => object.map(|object| (object.kind(), object.value())),
+                Err(e) => return self.error(e.into(), id),
+            },
+            _ => {
+                crdts.unsubscribe(self.id, &scope, &p.name);
+                None
+            }
+        };
+        let mut answer = json!({
+            "name": p.name,
+            "kind": object.as_ref().map(|(kind, _)| kind),
+            "value": object.map(|(_, value)| value),
+        });
+        if let Some(room) = p.room {
+            answer["room"] = json!(room);
+        }
+        protocol::message(name, id, &answer)
+    }
+
+    /// Handle a `lock.*` message on a lock of a joined room, or of the
+    /// connection's tenant, see `locks`
+    fn lock(&self, name: &str, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match LockPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        let scope = match p.room {
+            Some(ref room) if !self.hub.rooms().is_member(self.id, room) => {
+                return Some(self.error(RoomError::NotMember.into(), id))
+            }
+            Some(ref room) => Scope::Room(room.clone()),
+            None => match self.tenant_scope() {
+                Ok(scope) => scope,
+                Err(e) => return Some(self.error(e, id)),
+            },
+        };
+        let locks = self.hub.locks();
+        let result = match name {
+            "lock.acquire" => {
+                let wait = p.wait_ms.map(|wait_ms| (id, wait_ms));
+                match locks.acquire(self.id, &scope, &p.name, p.ttl_ms, wait) {
+                    Ok(Some(grant)) => Ok(json!(grant)),
+                    Ok(None) => return None,
+                    Err(e) => Err(e),
+                }
+            }
+            "lock.renew" => locks
+                .renew(self.id, &scope, &p.name, p.ttl_ms)
+                .map(|grant| json!(grant)),
+            _ => locks.release(self.id, &scope, &p.name).map(|notices| {
+                self.hub.lock_notices(notices);
+                json!({ "released": true })
+            }),
+        };
+        let reply = match result {
+            Ok(fields) => protocol::message(name, id, &locks::payload(&scope, &p.name, fields)),
+            Err(e) => self.error(e.into(), id),
+        };
+        Some(reply)
+    }
+
+    /// Send a direct message to the connections of a user, see `push` for
+    /// one who has none
+    fn direct(&self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match DirectPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let Some(from) = self.stats.user() else {
+            let err = protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "direct messages are sent by authenticated users",
+            );
+            return self.error(err, id);
+        };
+        if p.to.is_empty() {
+            let err = protocol::Error::new(ErrorCode::BadPayload, "`to` must not be empty");
+            return self.error(err, id);
+        }
+        let delivered = self
+            .hub
+            .direct(self.stats.claimed_tenant(), from, &p.to, p.data, self.id);
+        protocol::message(
+            "user.send",
+            id,
+            &json!({ "to": p.to, "delivered": delivered }),
+        )
+    }
+
+    /// Register or drop a push device of the connection's user
+    fn push_device(&self, name: &str, payload: &Value, id: Option<&str>) -> ws::Message {
+        let Some(push) = self.hub.push() else {
+            let err = protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "push notifications are not configured",
+            );
+            return self.error(err, id);
+        };
+        let Some(user) = self.stats.user() else {
+            let err = protocol::Error::new(
+                ErrorCode::PermissionDenied,
+                "push devices are registered by authenticated users",
+            );
+            return self.error(err, id);
+        };
+        let p = match DevicePayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        let devices = match (name, p.platform) {
+            ("push.register", Some(platform)) => {
+                let device = Device {
+                    platform,
+                    token: p.token,
+                };
+                match push.register(user, device) {
+                    Ok(devices) => devices,
+                    Err(e) => return self.error(e.into(), id),
+                }
+            }
+            ("push.register", None) => {
+                let err = protocol::Error::new(ErrorCode::BadPayload, "`platform` is required");
+                return self.error(err, id);
+            }
+            _ => push.unregister(user, &p.token),
+        };
+        protocol::message(name, id, &json!({ "devices": devices }))
+    }
+
+    fn bad_payload(&self, e: serde_json::Error, id: Option<&str>) -> ws::Message {
+        self.error(
+            protocol::Error::new(ErrorCode::BadPayload, e.to_string()),
+            id,
+        )
+    }
+
+    /// Handle a `channel.*` message, see `channels`. Replies are queued like
+    /// the data handlers send, so the client gets them in order.
+    fn channel(&mut self, ty: &str, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let reply = match ty {
+            "channel.open" => Some(self.channel_open(payload, id)),
+            "channel.close" => Some(self.channel_close(payload, id)),
+            "channel.credit" => self.channel_credit(payload, id),
+            // answered only if rejected
+            _ => self.channel_data(payload, id),
+        };
+        if let Some(reply) = reply {
+            self.hub.send(self.id, reply);
+        }
+        None
+    }
+
+    /// Open a virtual channel
+    fn channel_open(&mut self, payload: &Value, id: Option<&str>) -> ws::Message {
+        let p = match ChannelOpenPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return self.bad_payload(e, id),
+        };
+        match self
+            .channels
+            .open(p.channel, &p.service, &p.params, p.credits)
+        {
+            Ok(None) => protocol::message(
+                "channel.open",
+                id,
+                &json!({ "channel": p.channel, "service": p.service }),
+            ),
+            Ok(Some(credits)) => protocol::message(
+                "channel.open",
+                id,
+                &json!({ "channel": p.channel, "service": p.service, "credits": credits }),
+            ),
+            Err(e) => self.error(protocol::Error::new(ErrorCode::BadPayload, e), id),
+        }
+    }
+
+    fn channel_data(&mut self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match ChannelDataPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        match self.channels.data(p.channel, p.data) {
+            Ok(()) => None,
+            Err(e) => Some(self.error(protocol::Error::new(ErrorCode::BadPayload, e), id)),
+        }
+    }
+
+    /// Credit for the server to send more data on a channel, answered only
+    /// if rejected
+    fn channel_credit(&mut self, payload: &Value, id: Option<&str>) -> Option<ws::Message> {
+        let p = match ChannelCreditPayload::deserialize(payload) {
+            Ok(p) => p,
+            Err(e) => return Some(self.bad_payload(e, id)),
+        };
+        match self.channels.credit(p.channel, p.credits) {
+            Ok(()) => None,
+            Err(e) => Some(self.error(protocol::Error::new(ErrorCode::BadPayload, e), id)),
+        }
+    }
+
+    /// Close a virtual channel, closing one not open is not an error
+    fn channel_close(&mut self, payload: &Value, id: Option<&str>) -> ws::Message {
+        match ChannelPayload::deserialize(payload) {
+            Ok(p) => {
+                self.channels.close(p.channel);
+                protocol::message("channel.close", id, &json!({ "channel": p.channel }))
+            }
+            Err(e) => self.bad_payload(e, id),
+        }
+    }
+
+    /// Report rejected message and build error reply
+    fn error(&self, err: protocol::Error, id: Option<&str>) -> ws::Message {
+        log::debug!("Connection {}: {:?}", self.id, err);
+        metrics::pipeline_error(self.stats.pipeline());
+        events::emit(Event::Rejected {
+            id: self.id,
+            code: err.code,
+        });
+        err.to_message(id)
+    }
+}
+
+/// `room.publish` or `room.publish_batch` waiting for the content filter
+struct Filtering {
+    pending: Pending,
+    reply_id: Option<String>,
+    ticket: Ticket,
+}
+
+enum Pending {
+    Publish(filter::Message, Option<AckRequest>),
+    /// Published all or none
+    Batch(Vec<filter::Message>),
+}
+
+/// Filter and publish messages of connection `id` in the order they came,
+/// until the connection drops its queue
+async fn filter_messages(hub: Arc<Hub>, id: u64, mut rx: mpsc::UnboundedReceiver<Filtering>) {
+    let task = TaskHandle::register("filter", Some(id));
+    task.set_state("idle");
+    while let Some(Filtering {
+        pending,
+        reply_id,
+        ticket,
+    }) = rx.next().await
+    {
+        task.set_state("filtering");
+        let result = match pending {
+            Pending::Publish(msg, ack) => {
+                filter_publish(&hub, id, msg, ack, &reply_id, ticket).await
+            }
+            Pending::Batch(msgs) => filter_batch(&hub, id, msgs, &reply_id, ticket).await,
+        };
+        if let Err(err) = result {
+            log::debug!("Connection {}: {:?}", id, err);
+            events::emit(Event::Rejected { id, code: err.code });
+            hub.send(id, err.to_message(reply_id.as_deref()));
+        }
+        task.set_state("idle");
+    }
+}
+
+async fn filter_publish(
+    hub: &Arc<Hub>,
+    id: u64,
+    msg: filter::Message,
+    ack: Option<AckRequest>,
+    reply_id: &Option<String>,
+    ticket: Ticket,
+) -> Result<(), protocol::Error> {
+    let room = msg.room.clone();
+    let data = match hub.content_filter(&room) {
+        Some(filter) => ticket.run(filter.run(msg)).await??,
+        None => msg.data,
+    };
+    let seq = match hub.publish(id, &room, &data, reply_id.as_deref(), ack.as_ref())? {
+        Some(seq) => seq,
+        // forwarded, the room's owner answers
+        None => return Ok(()),
+    };
+    hub.send(
+        id,
+        protocol::message(
+            "room.publish",
+            reply_id.as_deref(),
+            &json!({ "room": room, "seq": seq }),
+        ),
+    );
+    if let Some(ack) = ack {
+        rt::spawn(report_acks(
+            hub.clone(),
+            id,
+            room,
+            seq,
+            ack,
+            reply_id.clone(),
+        ));
+    }
+    Ok(())
+}
+
+/// Filter every message of a batch, then publish them unless one was
+/// rejected
+async fn filter_batch(
+    hub: &Hub,
+    id: u64,
+    msgs: Vec<filter::Message>,
+    reply_id: &Option<String>,
+    ticket: Ticket,
+) -> Result<(), protocol::Error> {
+    let filtered = ticket
+        .run(async {
+            let mut messages = Vec::with_capacity(msgs.len());
+            for (i, msg) in msgs.into_iter().enumerate() {
+                let room = msg.room.clone();
+                let data = match hub.content_filter(&room) {
+                    Some(filter) => filter.run(msg).await.map_err(|e| batch_error(i, e))?,
+                    None => msg.data,
+                };
+                messages.push((room, data));
+            }
+            Ok::<_, protocol::Error>(messages)
+        })
+        .await??;
+    let reply = publish_batch(hub, id, &filtered, reply_id.as_deref())?;
+    hub.send(id, reply);
+    Ok(())
+}
+
+/// Publish a batch, answered with the sequence numbers in order
+fn publish_batch(
+    hub: &Hub,
+    id: u64,
+    messages: &[(String, Value)],
+    reply_id: Option<&str>,
+) -> Result<ws::Message, protocol::Error> {
+    let seqs = hub
+        .publish_batch(id, messages)
+        .map_err(|(i, e)| batch_error(i, e.into()))?;
+    let published: Vec<Value> = messages
+        .iter()
+        .zip(seqs)
+        .map(|((room, _), seq)| json!({ "room": room, "seq": seq }))
+        .collect();
+    Ok(protocol::message(
+        "room.publish_batch",
+        reply_id,
+        &json!({ "messages": published }),
+    ))
+}
+
+/// Error of the `i`th message of a batch, failing all of it
+fn batch_error(i: usize, err: protocol::Error) -> protocol::Error {
+    protocol::Error::new(err.code, format!("messages[{}]: {}", i, err.message))
+}
+
+/// Answer a publish with `ack` with `room.acked` once the members
+/// acknowledged message `seq` or time is up
+async fn report_acks(
+    hub: Arc<Hub>,
+    id: u64,
+    room: String,
+    seq: u64,
+    ack: AckRequest,
+    reply_id: Option<String>,
+) {
+    let report = hub
+        .acks()
+        .wait(&room, seq, ack.timeout(), |member| hub.user(member))
+        .await;
+    hub.send(
+        id,
+        protocol::message("room.acked", reply_id.as_deref(), &report),
+    );
+}
+
+/// Reply to `sys.time` with the server clock, echoing the client's
+fn sys_time(payload: &Value, id: Option<&str>) -> ws::Message {
+    let server_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let reply = json!({
+        "client_time": payload.get("client_time"),
+        "server_time": server_time,
+    });
+    protocol::message("sys.time", id, &reply)
+}
+
+/// Attributes of a `hello` payload, an object of strings
+fn metadata(payload: &Value) -> Result<Metadata, String> {
+    let attrs = payload
+        .as_object()
+        .ok_or_else(|| "payload must be an object".to_string())?;
+    if attrs.len() > MAX_ATTRIBUTES {
+        return Err(format!("at most {} attributes", MAX_ATTRIBUTES));
+    }
+    attrs
+        .iter()
+        .map(|(name, value)| match value.as_str() {
+            Some(_) if name.len() > MAX_ATTRIBUTE_LEN => {
+                Err("attribute name is too long".to_string())
+            }
+            Some(v) if v.len() > MAX_ATTRIBUTE_LEN => Err(format!("`{}` is too long", name)),
+            Some(v) => Ok((name.clone(), v.to_string())),
+            None => Err(format!("`{}` must be a string", name)),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct HeartbeatPayload {
+    #[serde(default)]
+    interval_ms: Option<u64>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    /// Put the server clock in pings, for `sys.clock`
+    #[serde(default)]
+    clock: bool,
+}
+
+#[derive(Deserialize)]
+struct RefreshPayload {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResultPayload {
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+/// Asks for all pages of a reply instead of one, see `stream`
+#[derive(Deserialize)]
+struct StreamPayload {
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct CancelPayload {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ResumePayload {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct LastSeenPayload {
+    users: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JoinPayload {
+    room: String,
+    #[serde(default)]
+    invite: Option<String>,
+    #[serde(default)]
+    private: bool,
+    /// See `expr`
+    #[serde(default)]
+    filter: Option<String>,
+    /// Consumer group, see `rooms`
+    #[serde(default)]
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RoomPayload {
+    room: String,
+}
+
+#[derive(Deserialize)]
+struct KvPayload {
+    room: Option<String>,
+    key: Option<String>,
+    value: Option<Value>,
+    if_version: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CrdtPayload {
+    room: Option<String>,
+    name: String,
+    kind: Option<Kind>,
+    by: Option<i64>,
+    add: Option<String>,
+    remove: Option<String>,
+    value: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct LockPayload {
+    room: Option<String>,
+    name: String,
+    ttl_ms: Option<u64>,
+    wait_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct DirectPayload {
+    to: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct DevicePayload {
+    platform: Option<Platform>,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct BatchPayload {
+    messages: Vec<BatchMessage>,
+}
+
+#[derive(Deserialize)]
+struct BatchMessage {
+    room: String,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SeqPayload {
+    room: String,
+    seq: u64,
+}
+
+#[derive(Deserialize)]
+struct EditPayload {
+    room: String,
+    seq: u64,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct ModeratePayload {
+    room: String,
+    #[serde(flatten)]
+    action: Moderation,
+}
+
+#[derive(Deserialize)]
+struct ChannelOpenPayload {
+    channel: u64,
+    service: String,
+    #[serde(default)]
+    params: Value,
+    /// Flow control, the messages the server may send before more credit
+    credits: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ChannelDataPayload {
+    channel: u64,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct ChannelCreditPayload {
+    channel: u64,
+    credits: u32,
+}
+
+#[derive(Deserialize)]
+struct ChannelPayload {
+    channel: u64,
+}
+
+#[derive(Deserialize)]
+struct PublishPayload {
+    room: String,
+    #[serde(default)]
+    data: Value,
+    /// Wait for the members to acknowledge it, see `acks`
+    #[serde(default)]
+    ack: Option<AckRequest>,
+    /// Picks the partition of a partitioned room
+    #[serde(default)]
+    key: Option<String>,
+    /// Publish it this much later, see `schedule`
+    #[serde(default)]
+    delay_ms: Option<u64>,
+    /// Publish it at this time, unix milliseconds
+    #[serde(default)]
+    deliver_at: Option<u64>,
+}
+
+/// What the handshake settled for a connection
+#[derive(Debug, Clone)]
+struct Handshake {
+    tenant: Option<String>,
+    identity: Option<Identity>,
+    policy: SessionPolicy,
+    peer: Option<SocketAddr>,
+    framing: Framing,
+    keepalive: KeepaliveConfig,
+    handlers: HandlersConfig,
+    labels: Labels,
+    /// Whether the connection is recorded from the start
+    record: Option<RecordConfig>,
+    /// Handler pipeline, see `pipeline`
+    pipeline: &'static str,
+}
+
+/// WebSockets service factory
+async fn ws_service(
+    sink: Sink,
+    hub: Arc<Hub>,
+    auth: Arc<Authenticator>,
+    handshake: Handshake,
+) -> Result<impl Service<ws::Frame, Response = Option<ws::Message>, Error = io::Error>, web::Error>
+{
+    let Handshake {
+        tenant,
+        identity,
+        policy,
+        peer,
+        framing,
+        keepalive,
+        handlers,
+        labels,
+        record,
+        pipeline,
+    } = handshake;
+    let id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+    let user = identity.as_ref().map(|i| i.user.clone());
+    let state = Rc::new(RefCell::new(
+        WsState::new(id, tenant, identity, hub.clone(), auth.clone())
+            .with_framing(framing)
+            .with_keepalive(keepalive)
+            .with_handlers(handlers)
+            .with_labels(labels)
+            .with_pipeline(pipeline),
+    ));
+
+    // disconnect notification
+    let (tx, rx) = oneshot::channel();
+
+    // start writer task for messages pushed through the hub
+    let stats = state.borrow().stats().clone();
+    let outbound = hub.register(id, stats.clone());
+    if let Some(ref config) = record {
+        if let Err(e) = record::start(config, id, &stats) {
+            log::error!("Cannot record connection {}: {}", id, e);
+        }
+    }
+    if let Some(store) = hub.store() {
+        let token = store.open(id, user.as_deref(), stats.tenant());
+        state.borrow_mut().resume_token = Some(token);
+    }
+    events::emit(Event::Connected { id, stats: &stats });
+    match (policy, user) {
+        (SessionPolicy::Replace, Some(user)) => replace_sessions(&hub, &user, id),
+        (_, Some(user)) => enforce_quota(&hub, &auth, &user, id),
+        (_, None) => (),
+    }
+    rt::spawn(writer(id, outbound, stats.clone(), sink.clone(), framing));
+
+    // start heartbeat task
+    rt::spawn(heartbeat(state.clone(), sink.clone(), rx));
+
+    // websockets handler service
+    let session = stats.clone();
+    Ok(fn_service(move |frame| {
+        println!("WS Frame: {:?}", frame);
+        stats.received(&frame);
+
+        // a panicking handler only takes down its own connection
+        let result =
+            panic::catch_unwind(AssertUnwindSafe(|| state.borrow_mut().handle_frame(frame)));
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(_) => {
+                METRICS.handler_panics_total.inc();
+                reporting::handler_panic(id);
+                let io = sink.io().clone();
+                rt::spawn(async move { io.close() });
+                Some(ws::Message::Close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Error,
+                    description: Some("internal error".to_string()),
+                })))
+            }
+        };
+        if let Some(ref reply) = reply {
+            stats.sent(reply);
+        }
+        ready(Ok(reply))
+    })
+    // on_shutdown callback is being called when service get shutdowned by dispatcher
+    // in this case when connection get dropped
+    .on_shutdown(move || {
+        hub.unregister(id);
+        events::emit(Event::Disconnected {
+            id,
+            peer,
+            stats: &session,
+        });
+        let _ = tx.send(());
+    }))
+}
+
+/// Join connection `id` to `rooms` again, answered with where each stood
+/// when the earlier connection closed
+fn rejoin(hub: &Hub, id: u64, rooms: BTreeMap<String, u64>, reply_id: Option<&str>) -> ws::Message {
+    let mut joined = Vec::new();
+    let mut failed = Vec::new();
+    for (room, seq) in rooms {
+        match hub.join(id, &room, None, false) {
+            Ok(Join::Member(members)) => {
+                joined.push(json!({ "room": room, "seq": seq, "members": members }))
+            }
+            Ok(Join::Waiting(position)) => {
+                joined.push(json!({ "room": room, "seq": seq, "position": position }))
+            }
+            Err(e) => failed.push(json!({ "room": room, "error": protocol::Error::from(e) })),
+        }
+    }
+    protocol::message(
+        "session.resume",
+        reply_id,
+        &json!({ "rooms": joined, "failed": failed }),
+    )
+}
+
+/// Close connections of `user` other than `id`
+fn replace_sessions(hub: &Hub, user: &str, id: u64) {
+    for old in hub
+        .user_connections(user)
+        .into_iter()
+        .filter(|old| *old != id)
+    {
+        log::info!("Connection {} of {} replaced by {}", old, user, id);
+        hub.close(
+            old,
+            ws::CloseReason {
+                code: ws::CloseCode::from(SESSION_REPLACED),
+                description: Some("session replaced".to_string()),
+            },
+        );
+    }
+}
+
+/// Close connections of `user` over its quota, the new one `id` or the oldest
+fn enforce_quota(hub: &Hub, auth: &Authenticator, user: &str, id: u64) {
+    let (max, when_exceeded) = auth.connection_quota();
+    let mut ids = hub.user_connections(user);
+    if max == 0 || ids.len() <= max {
+        return;
+    }
+    let close = |id: u64| {
+        hub.close(
+            id,
+            ws::CloseReason {
+                code: ws::CloseCode::from(QUOTA_EXCEEDED),
+                description: Some("connection quota exceeded".to_string()),
+            },
+        );
+    };
+    match when_exceeded {
+        WhenExceeded::RefuseNewest => {
+            log::info!(
+                "Connection {} of {} refused, over quota of {}",
+                id,
+                user,
+                max
+            );
+            close(id);
+        }
+        WhenExceeded::CloseOldest => {
+            // ids grow, the smallest are the oldest
+            ids.sort_unstable();
+            let extra = ids.len() - max;
+            for old in ids.into_iter().filter(|old| *old != id).take(extra) {
+                log::info!(
+                    "Connection {} of {} closed, over quota of {}",
+                    old,
+                    user,
+                    max
+                );
+                close(old);
+            }
+        }
+    }
+}
+
+/// Report bad client input and close the connection
+fn reject(id: u64, code: ws::CloseCode, description: &str) -> ws::Message {
+    reporting::handler_error(id, description);
+    ws::Message::Close(Some(ws::CloseReason {
+        code,
+        description: Some(description.to_string()),
+    }))
+}
+
+/// Collect message fragments into a pooled buffer, returns reply once
+/// the last fragment arrives
+fn continuation(state: &mut WsState, item: Item) -> Option<ws::Message> {
+    let (first, last, data) = match item {
+        Item::FirstText(data) => (Some(true), false, data),
+        Item::FirstBinary(data) => (Some(false), false, data),
+        Item::Continue(data) => (None, false, data),
+        Item::Last(data) => (None, true, data),
+    };
+    if let Some(is_text) = first {
+        state.fragments = Some((is_text, pool::acquire()));
+    }
+    let Some((_, ref mut buf)) = state.fragments else {
+        return Some(reject(
+            state.id,
+            ws::CloseCode::Protocol,
+            "unexpected continuation",
+        ));
+    };
+    if buf.len() + data.len() > pool::max_message_size() {
+        state.fragments = None;
+        return Some(reject(state.id, ws::CloseCode::Size, "message too big"));
+    }
+    buf.extend_from_slice(&data);
+    if !last {
+        return None;
+    }
+    let (is_text, mut buf) = state.fragments.take()?;
+    let data = buf.take();
+    if is_text {
+        state.text_message(data)
+    } else {
+        state.binary_message(data)
+    }
+}
+
+/// Forward messages queued in the hub to the client; with packed framing,
+/// text messages queued together go out as one packed frame
+async fn writer(
+    id: u64,
+    mut outbound: Outbound,
+    stats: Arc<ConnStats>,
+    sink: Sink,
+    framing: Framing,
+) {
+    let task = TaskHandle::register("writer", Some(id));
+    task.set_state("idle");
+    // message that ended a packed frame, written next
+    let mut next = None;
+    loop {
+        let (queued_at, msg) = match next.take() {
+            Some(queued) => queued,
+            None => match outbound.rx.next().await {
+                Some(queued) => queued,
+                None => break,
+            },
+        };
+        METRICS.queue_wait_seconds.observe(queued_at.elapsed());
+        task.set_state("throttled");
+        outbound.throttle(&msg).await;
+        // queued messages going out in this frame
+        let mut messages = 1;
+        let msg = match msg {
+            ws::Message::Text(text) if framing == Framing::Packed => {
+                let mut packed = Packer::new();
+                packed.push(text.as_slice());
+                while packed.len() < framing::MAX_PACKED {
+                    match outbound.rx.try_recv() {
+                        Ok((queued_at, ws::Message::Text(text))) => {
+                            METRICS.queue_wait_seconds.observe(queued_at.elapsed());
+                            outbound.throttle_bytes(text.len()).await;
+                            packed.push(text.as_slice());
+                            messages += 1;
+                        }
+                        Ok(queued) => {
+                            next = Some(queued);
+                            break;
+                        }
+                        // empty or closed
+                        Err(_) => break,
+                    }
+                }
+                ws::Message::Binary(packed.finish())
+            }
+            msg => msg,
+        };
+        let faults = outbound.faults(&msg);
+        if let Some(delay) = faults.delay {
+            task.set_state("delayed");
+            time::sleep(delay).await;
+        }
+        if faults.disconnect {
+            log::debug!("Connection {}: disconnected by chaos", id);
+            sink.io().close();
+            break;
+        }
+        if faults.drop {
+            task.set_queue_depth(outbound.written(messages));
+            task.set_state("idle");
+            continue;
+        }
+        task.set_state("writing");
+        let close = matches!(msg, ws::Message::Close(_));
+        if faults.duplicate {
+            stats.sent(&msg);
+            if sink.send(msg.clone()).await.is_err() {
+                break;
+            }
+        }
+        stats.sent(&msg);
+        if sink.send(msg).await.is_err() {
+            break;
+        }
+        task.set_queue_depth(outbound.written(messages));
+        if close {
+            // do not wait for the client to answer the close frame
+            sink.io().close();
+            break;
+        }
+        task.set_state("idle");
+    }
+}
+
+/// helper method that sends ping to client every heartbeat interval
+async fn heartbeat(state: Rc<RefCell<WsState>>, sink: Sink, mut rx: oneshot::Receiver<()>) {
+    let task = TaskHandle::register("heartbeat", Some(state.borrow().id));
+    loop {
+        task.set_state("sleeping");
+        // negotiated by the client, a change applies from the next ping
+        let (interval, timeout, clock) = {
+            let state = state.borrow();
+            (state.interval, state.timeout, state.clock)
+        };
+        match select(Box::pin(time::sleep(interval)), &mut rx).await {
+            Either::Left(_) => {
+                // check client heartbeats
+                if Instant::now().duration_since(state.borrow().hb) > timeout {
+                    // heartbeat timed out
+                    println!("Websocket Client heartbeat failed, disconnecting!");
+                    return;
+                }
+
+                // credentials are only checked once per heartbeat
+                let expired = state.borrow().identity().is_some_and(Identity::is_expired);
+                if expired {
+                    log::debug!("Connection {}: credential expired", state.borrow().id);
+                    let close = ws::Message::Close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Policy,
+                        description: Some("credential expired".to_string()),
+                    }));
+                    state.borrow().stats().sent(&close);
+                    let _ = sink.send(close).await;
+                    return;
+                }
+
+                // send ping
+                task.set_state("sending ping");
+                let ping = ws::Message::Ping(ping_payload(clock));
+                state.borrow().stats().sent(&ping);
+                if sink.send(ping).await.is_err() {
+                    return;
+                }
+            }
+            Either::Right(_) => {
+                println!("Connection is dropped, stop heartbeat task");
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    /// Selects per tenant feature flag overrides
+    tenant: Option<String>,
+    /// Anonymous handshakes with `[auth.challenge]`, see `challenge`
+    challenge: Option<String>,
+    solution: Option<String>,
+    /// `packed` for several messages per binary frame, see `framing`
+    #[serde(default)]
+    framing: Framing,
+    /// Sticky session token, for clients without cookies, see `affinity`
+    affinity: Option<String>,
+}
+
+/// do websocket handshake and start web sockets service
+pub async fn ws_index(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    auth: State<Arc<Authenticator>>,
+    limiter: State<Arc<AcceptLimiter>>,
+    query: Query<WsQuery>,
+) -> Result<HttpResponse, Error> {
+    // before auth, which may be the costly part of a handshake
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Err(wait) = limiter.admit(ip) {
+        log::debug!("Handshake rejected: rate limited, retry in {:?}", wait);
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return Ok(HttpResponse::TooManyRequests()
+            .header(header::RETRY_AFTER, secs.to_string())
+            .body("too many handshakes"));
+    }
+    let identity = match auth.authenticate(&req) {
+        Ok(identity) => identity,
+        Err(e @ AuthError::Unavailable(_)) => {
+            log::warn!("Handshake rejected: {}", e);
+            return Ok(HttpResponse::ServiceUnavailable().body(e.to_string()));
+        }
+        Err(e) => {
+            log::debug!("Handshake rejected: {}", e);
+            return Ok(HttpResponse::Unauthorized().body(e.to_string()));
+        }
+    };
+    let query = query.into_inner();
+    if let (None, Some(challenges)) = (&identity, auth.challenges()) {
+        if let Err(reason) = challenges.check(query.challenge.as_deref(), query.solution.as_deref())
+        {
+            log::debug!("Handshake rejected: {}", reason);
+            return Ok(HttpResponse::Unauthorized().body(reason));
+        }
+    }
+    let tenant = identity
+        .as_ref()
+        .and_then(|i| i.tenant.clone())
+        .or(query.tenant);
+    let policy = match identity {
+        Some(ref identity) => {
+            if bans::is_banned(&identity.user) {
+                log::debug!("Handshake rejected: {} is banned", identity.user);
+                return Ok(HttpResponse::Forbidden().body("user is banned"));
+            }
+            // the credential's tenant, a client picking one by `?tenant=`
+            // must not pick its policy
+            let policy = auth.session_policy(identity.tenant.as_deref());
+            if policy == SessionPolicy::Reject && !hub.user_connections(&identity.user).is_empty() {
+                log::debug!("Handshake rejected: {} is already connected", identity.user);
+                return Ok(HttpResponse::Conflict().body("user already connected"));
+            }
+            policy
+        }
+        None => SessionPolicy::Multiple,
+    };
+    let labels = labels::of(&config.labels, identity.as_ref(), &req);
+    let record = config.record.clone().filter(|record| {
+        identity.as_ref().is_some_and(|identity| {
+            record
+                .users
+                .iter()
+                .any(|pattern| config::glob(pattern, &identity.user))
+        })
+    });
+    let pipeline = pipeline::pick(config.canary.as_ref(), req.path(), &labels);
+    let hub = hub.get_ref().clone();
+    let auth = auth.get_ref().clone();
+    let handshake = Handshake {
+        tenant,
+        identity,
+        policy,
+        peer: req.peer_addr(),
+        framing: query.framing,
+        keepalive: config.keepalive,
+        handlers: config.handlers.clone(),
+        labels,
+        record,
+        pipeline,
+    };
+    affinity::check(&config, &req, query.affinity.as_deref());
+    upgrade(
+        req,
+        affinity::headers(&config),
+        fn_factory_with_config(move |sink| {
+            ws_service(sink, hub.clone(), auth.clone(), handshake.clone())
+        }),
+    )
+    .await
+}
+
+/// Writes frames to a connection started by `upgrade`, like `ws::WsSink`
+/// whose constructor ntex keeps to itself
+#[derive(Clone)]
+struct Sink(Rc<(IoRef, ntex::ws::Codec)>);
+
+impl Sink {
+    fn io(&self) -> &IoRef {
+        &self.0 .0
+    }
+
+    async fn send(&self, msg: ws::Message) -> Result<(), ProtocolError> {
+        let (io, codec) = &*self.0;
+        let close = matches!(msg, ws::Message::Close(_)) && codec.is_closed();
+        io.encode(msg, codec)?;
+        if close {
+            io.close();
+        }
+        Ok(())
+    }
+}
+
+/// `ws::start` with `headers` added to the handshake response, which
+/// `ws::start` writes before handing over the connection
+async fn upgrade<T, F>(
+    req: HttpRequest,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    factory: F,
+) -> Result<HttpResponse, Error>
+where
+    T: ServiceFactory<ws::Frame, Sink, Response = Option<ws::Message>> + 'static,
+    T::Error: fmt::Debug,
+    F: IntoServiceFactory<T, ws::Frame, Sink>,
+    Error: From<T::InitError>,
+{
+    let inner = factory.into_factory().map_err(WsError::Service);
+    let factory = fn_factory_with_config(move |sink: Sink| {
+        let srv = inner.new_service(sink.clone());
+        async move {
+            let srv = srv.await?;
+            Ok::<_, T::InitError>(apply_fn(srv, move |item, srv| match item {
+                DispatchItem::Item(frame) => {
+                    let close = matches!(frame, ws::Frame::Close(_)).then(|| sink.clone());
+                    let fut = srv.call(frame);
+                    Either::Left(async move {
+                        let result = fut.await;
+                        if let Some(sink) = close {
+                            rt::spawn(async move { sink.io().close() });
+                        }
+                        result
+                    })
+                }
+                DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
+                    Either::Right(ready(Ok(None)))
+                }
+                DispatchItem::KeepAliveTimeout => Either::Right(ready(Err(WsError::KeepAlive))),
+                DispatchItem::DecoderError(e) | DispatchItem::EncoderError(e) => {
+                    Either::Right(ready(Err(WsError::Protocol(e))))
+                }
+                DispatchItem::Disconnect(e) => Either::Right(ready(Err(WsError::Disconnected(e)))),
+            }))
+        }
+    });
+
+    let mut res = ntex::ws::handshake(req.head())?;
+    for (name, value) in headers {
+        res.header(name, value);
+    }
+    let res = res.finish().into_parts().0;
+    let (io, codec) = *req
+        .head()
+        .take_io()
+        .ok_or(HandshakeError::NoWebsocketUpgrade)?;
+    io.encode(h1::Message::Item((res, BodySize::Empty)), &codec)
+        .map_err(|_| HandshakeError::NoWebsocketUpgrade)?;
+
+    let codec = ntex::ws::Codec::new();
+    let sink = Sink(Rc::new((io.get_ref(), codec.clone())));
+    let srv = factory.new_service(sink).await?;
+    rt::spawn(async move {
+        let res = Dispatcher::new(io, codec, srv)
+            .keepalive_timeout(Seconds::ZERO)
+            .await;
+        log::trace!("Ws handler is terminated: {:?}", res);
+    });
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::io::Io;
+    use ntex::testing::IoTest;
+
+    use super::*;
+    use crate::chaos::Chaos;
+    use crate::config::{AuthConfig, ChaosConfig, HistoryConfig, HubConfig, RoomsConfig};
+    use crate::history::History;
+
+    fn hub(queue_size: usize) -> Hub {
+        Hub::new(
+            &HubConfig {
+                queue_size,
+                ..HubConfig::default()
+            },
+            RoomsConfig::default(),
+            History::open(&HistoryConfig::default()).unwrap(),
+            None,
+        )
+    }
+
+    fn state(tenant: Option<&str>, identity: Option<Identity>) -> WsState {
+        let auth = Authenticator::new(&AuthConfig::default(), None);
+        WsState::new(
+            1,
+            tenant.map(str::to_string),
+            identity,
+            Arc::new(hub(16)),
+            Arc::new(auth),
+        )
+    }
+
+    fn identity(tenant: Option<&str>) -> Identity {
+        Identity {
+            user: "alice".to_string(),
+            roles: Vec::new(),
+            expires_at: None,
+            tenant: tenant.map(str::to_string),
+            claims: Value::Null,
+        }
+    }
+
+    /// Answer to a text message, as JSON
+    fn ask(state: &mut WsState, msg: Value) -> Value {
+        let frame = ws::Frame::Text(Bytes::from(msg.to_string()));
+        match state.handle_frame(frame) {
+            Some(ws::Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("answered {:?}", other),
+        }
+    }
+
+    #[test]
+    fn requested_tenant_gets_no_tenant_state() {
+        for (tenant, identity) in [(Some("acme"), None), (Some("acme"), Some(identity(None)))] {
+            let mut state = state(tenant, identity);
+            assert_eq!(state.stats().tenant(), Some("acme"));
+            assert_eq!(state.stats().claimed_tenant(), None);
+            for msg in [
+                json!({"type": "kv.get", "payload": {"key": "k"}}),
+                json!({"type": "kv.set", "payload": {"key": "k", "value": 1}}),
+                json!({"type": "crdt.get", "payload": {"name": "c"}}),
+                json!({"type": "crdt.update", "payload": {"name": "c", "kind": "counter"}}),
+                json!({"type": "lock.acquire", "payload": {"name": "l"}}),
+            ] {
+                let answer = ask(&mut state, msg.clone());
+                assert_eq!(
+                    answer["code"], "permission_denied",
+                    "{} got {}",
+                    msg, answer
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn credential_tenant_scopes_tenant_state() {
+        let mut state = state(Some("other"), Some(identity(Some("acme"))));
+        assert_eq!(state.stats().claimed_tenant(), Some("acme"));
+        assert!(matches!(
+            state.tenant_scope(),
+            Ok(Scope::Tenant(Some(ref tenant))) if tenant == "acme"
+        ));
+        let answer = ask(
+            &mut state,
+            json!({"type": "crdt.update", "payload": {"name": "c", "kind": "counter", "by": 2}}),
+        );
+        assert_eq!(answer["payload"]["value"], 2, "{}", answer);
+        let answer = ask(
+            &mut state,
+            json!({"type": "lock.acquire", "payload": {"name": "l"}}),
+        );
+        assert_eq!(answer["type"], "lock.acquire", "{}", answer);
+    }
+
+    #[test]
+    fn claimed_tenant_survives_stats_rebuilds() {
+        let claimed = state(None, Some(identity(Some("acme"))))
+            .with_labels(Labels::new())
+            .with_pipeline(pipeline::DEFAULT)
+            .with_framing(Framing::Packed);
+        assert_eq!(claimed.stats().claimed_tenant(), Some("acme"));
+        assert_eq!(claimed.stats().framing(), Framing::Packed);
+        assert!(claimed.tenant_scope().is_ok());
+
+        let requested = state(Some("acme"), None)
+            .with_labels(Labels::new())
+            .with_pipeline(pipeline::DEFAULT);
+        assert_eq!(requested.stats().tenant(), Some("acme"));
+        assert_eq!(requested.stats().claimed_tenant(), None);
+        assert!(requested.tenant_scope().is_err());
+    }
+
+    fn queued(hub: &Hub) -> usize {
+        hub.connections()[0].queued
+    }
+
+    #[test]
+    fn packed_writer_empties_the_queue() {
+        rt::System::new("test").block_on(async {
+            let dropping = Chaos::new(ChaosConfig {
+                drop: 1.0,
+                ..ChaosConfig::default()
+            });
+            for (hub, dropped) in [(hub(4), false), (hub(4).with_chaos(dropping), true)] {
+                let (client, server) = IoTest::create();
+                client.remote_buffer_cap(1024);
+                let io = Io::new(server);
+                let sink = Sink(Rc::new((io.get_ref(), ntex::ws::Codec::new())));
+                let stats = Arc::new(ConnStats::default());
+                let outbound = hub.register(1, stats.clone());
+                rt::spawn(writer(1, outbound, stats, sink, Framing::Packed));
+                for round in 0..3 {
+                    // queued before the writer gets to run, so packed together
+                    for i in 0..4 {
+                        assert!(hub.send(1, ws::Message::Text(format!("{}", i).into())));
+                    }
+                    assert!(!hub.send(1, ws::Message::Text("full".into())));
+                    assert_eq!(queued(&hub), 4);
+                    time::sleep(Duration::from_millis(20)).await;
+                    assert_eq!(queued(&hub), 0, "round {}", round);
+                    // one binary frame of 4 records, 2 + 4 * 5 bytes
+                    let sent = client.read_any();
+                    match dropped {
+                        true => assert!(sent.is_empty()),
+                        false => assert_eq!(sent[..2], [0x82, 20]),
+                    }
+                }
+                hub.unregister(1);
+            }
+        });
+    }
+}
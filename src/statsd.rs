@@ -0,0 +1,175 @@
+//! StatsD / DogStatsD push exporter.
+//!
+//! Periodically sends the same metrics `/metrics` serves over UDP:
+//! counters as deltas, gauges as-is and histograms as count/sum deltas plus
+//! estimated quantile gauges. Labeled series become DogStatsD tags, plain
+//! StatsD appends the label values to the name, e.g. `wss_room_members.lobby`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use ntex::time;
+
+use crate::config::{StatsdConfig, StatsdFlavor};
+use crate::metrics::{self, Sample, METRICS};
+use crate::tasks::TaskHandle;
+
+/// Keep datagrams under a typical MTU
+const MAX_PACKET: usize = 1400;
+
+struct Exporter {
+    socket: UdpSocket,
+    prefix: String,
+    flavor: StatsdFlavor,
+    /// Configured tags rendered as `k:v`
+    tags: Vec<String>,
+    /// Last sent value of every counter series
+    last: HashMap<String, u64>,
+}
+
+impl Exporter {
+    fn new(config: &StatsdConfig) -> io::Result<Exporter> {
+        let agent = config.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "statsd.address resolves to nothing",
+            )
+        })?;
+        // a socket only sends to its own address family
+        let local = match agent {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(agent)?;
+        socket.set_nonblocking(true)?;
+
+        let tags = config
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .collect();
+        Ok(Exporter {
+            socket,
+            prefix: config.prefix.clone(),
+            flavor: config.flavor,
+            tags,
+            last: HashMap::new(),
+        })
+    }
+
+    fn delta(&mut self, name: &str, value: u64) -> u64 {
+        let prev = self.last.insert(name.to_string(), value).unwrap_or(0);
+        value.saturating_sub(prev)
+    }
+
+    /// Name and tag suffix of a series with `labels`
+    fn series(&self, name: &str, labels: &[(&str, &str)]) -> (String, String) {
+        let mut name = format!("{}{}", self.prefix, name);
+        if self.flavor == StatsdFlavor::Statsd {
+            for (_, value) in labels {
+                name.push('.');
+                // dots and colons would change the name's meaning
+                name.extend(value.chars().map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                    _ => '_',
+                }));
+            }
+            return (name, String::new());
+        }
+        let tags: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}:{}", label, value.replace([',', '|'], "_")))
+            .chain(self.tags.iter().cloned())
+            .collect();
+        if tags.is_empty() {
+            (name, String::new())
+        } else {
+            (name, format!("|#{}", tags.join(",")))
+        }
+    }
+
+    /// Render all metrics as statsd lines
+    fn lines(&mut self) -> Vec<String> {
+        let mut samples = Vec::new();
+        let mut push = |name: &str, labels: &[(&str, &str)], sample: Sample<'_>| {
+            let (name, tags) = self.series(name, labels);
+            match sample {
+                Sample::Counter(v) => samples.push((name, tags, 'c', v as f64)),
+                Sample::Gauge(v) => samples.push((name, tags, 'g', v as f64)),
+                Sample::Histogram(h) => {
+                    let count = format!("{}.count", name);
+                    samples.push((count, tags.clone(), 'c', h.count() as f64));
+                    // sum is a float, send it in microseconds to keep deltas exact
+                    let sum = format!("{}.sum_us", name);
+                    samples.push((sum, tags.clone(), 'c', (h.sum() * 1e6).round()));
+                    for (q, label) in [(0.5, "p50"), (0.95, "p95"), (0.99, "p99")] {
+                        let v = h.quantile(q).unwrap_or(0.0);
+                        samples.push((format!("{}.{}", name, label), tags.clone(), 'g', v));
+                    }
+                }
+            }
+        };
+        METRICS.visit(|name, _, sample| push(name, &[], sample));
+        metrics::visit_labeled(|name, _, labels, sample| push(name, labels, sample));
+
+        let mut lines = Vec::with_capacity(samples.len());
+        for (name, tags, kind, value) in samples {
+            let line = if kind == 'c' {
+                let delta = self.delta(&format!("{}{}", name, tags), value as u64);
+                format!("{}:{}|c{}", name, delta, tags)
+            } else {
+                format!("{}:{}|g{}", name, value, tags)
+            };
+            lines.push(line);
+        }
+        lines
+    }
+
+    fn flush(&mut self) {
+        let mut packet = String::new();
+        for line in self.lines() {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET {
+                self.send(&packet);
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            let _ = write!(packet, "{}", line);
+        }
+        if !packet.is_empty() {
+            self.send(&packet);
+        }
+    }
+
+    fn send(&self, packet: &str) {
+        if let Err(e) = self.socket.send(packet.as_bytes()) {
+            log::debug!("Cannot send statsd packet: {}", e);
+        }
+    }
+}
+
+/// Push metrics every `config.interval_secs`, runs forever
+pub async fn run(config: StatsdConfig) {
+    let mut exporter = match Exporter::new(&config) {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            log::error!("Cannot start statsd exporter for {}: {}", config.address, e);
+            return;
+        }
+    };
+    log::info!("Pushing metrics to {} every {}s", config.address, config.interval_secs);
+
+    let task = TaskHandle::register("statsd", None);
+    let interval = Duration::from_secs(config.interval_secs.max(1));
+    loop {
+        task.set_state("sleeping");
+        time::sleep(interval).await;
+        task.set_state("flushing");
+        exporter.flush();
+    }
+}
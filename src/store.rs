@@ -0,0 +1,581 @@
+//! Sessions, presence and resumption state.
+//!
+//! With `[store]`, every connection is recorded as a session, and when it
+//! closes, the rooms it was in are kept for `resume_ttl_secs` under the
+//! `resume` token its `hello` was answered with. A reconnecting client sends
+//! `session.resume` with the token to rejoin them; `GET /admin/presence/{user}`
+//! lists a user's live sessions.
+//!
+//! The `memory` backend keeps all of it in the process. With `redis` it is
+//! written to Redis too, so it survives restarts and is shared by the
+//! instances using the same server and `prefix`:
+//!
+//! - `<prefix>session:<instance>:<id>`: a session as JSON, expiring after
+//!   `ttl_secs`,
+//! - `<prefix>presence:<user>`: sorted set of the user's sessions by
+//!   `<instance>:<id>`, scored by when they expire (unix ms),
+//! - `<prefix>resume:<token>`: resumption state as JSON, expiring after
+//!   `resume_ttl_secs`.
+//!
+//! With `[store.last_seen]` it also records when each user was last seen,
+//! and their presence changes: online when their first session on an
+//! instance opens, offline when the last one closes, newest first:
+//!
+//! - `<prefix>seen:<user>`: unix ms of the user's last open, close or
+//!   refresh of a session,
+//! - `<prefix>transitions:<user>`: list of the latest `transitions` changes
+//!   as JSON,
+//!
+//! both expiring `ttl_secs` after they were last written. A user with a live
+//! session is seen now; `presence.last_seen` and
+//! `GET /admin/presence/{user}/last-seen` ask.
+//!
+//! Each instance writes its sessions again every third of `ttl_secs`, so
+//! those of an instance that died expire. Writes are queued to one Redis
+//! connection and never hold up a connection; one that fails is not
+//! retried. The process keeps a local copy of what it wrote, read before
+//! Redis, and reuses presence read from Redis for `cache_ms`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{select, Either};
+use futures::StreamExt;
+use ntex::time;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::acks::Delivery;
+use crate::config::{StoreBackend, StoreConfig};
+use crate::metrics::METRICS;
+use crate::redis::{cmd, Command, Redis, Reply};
+use crate::tasks::TaskHandle;
+
+/// Live connection as other instances see it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub instance: String,
+    pub connection_id: u64,
+    /// `None` for anonymous connections, which have no presence
+    pub user: Option<String>,
+    pub tenant: Option<String>,
+    /// Unix seconds
+    pub connected_at: u64,
+}
+
+impl Session {
+    /// `<instance>:<id>`, unique across instances
+    fn name(&self) -> String {
+        format!("{}:{}", self.instance, self.connection_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Online,
+    Offline,
+}
+
+/// Change of a user's presence on an instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    pub state: PresenceState,
+    /// Unix ms
+    pub at: u64,
+    pub instance: String,
+}
+
+/// When a user was last seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LastSeen {
+    /// Whether they have a live session
+    pub online: bool,
+    /// Unix ms, now if online; `None` if never seen or forgotten
+    pub last_seen: Option<u64>,
+}
+
+/// Rooms of a closed connection, rejoined by `session.resume`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Resume {
+    pub user: Option<String>,
+    pub tenant: Option<String>,
+    /// Last sequence number of each room when the connection closed
+    pub rooms: BTreeMap<String, u64>,
+    /// Exactly-once messages the connection had not completed
+    #[serde(default)]
+    pub deliveries: Vec<Delivery>,
+}
+
+#[derive(Debug, Default)]
+struct Local {
+    /// Sessions of this instance, with their resume tokens
+    sessions: HashMap<u64, (String, Session)>,
+    /// Resumption state of connections closed here, with its expiry
+    resume: HashMap<String, (Instant, Resume)>,
+    /// Presence read from Redis, with when it was read
+    presence: HashMap<String, (Instant, Vec<Session>)>,
+    /// Last-seen time by user, of the memory backend
+    seen: HashMap<String, u64>,
+    /// Presence changes by user, newest first, of the memory backend
+    transitions: HashMap<String, VecDeque<Transition>>,
+}
+
+impl Local {
+    /// Whether `user` has a session on this instance
+    fn has_session(&self, user: &str) -> bool {
+        self.sessions
+            .values()
+            .any(|(_, session)| session.user.as_deref() == Some(user))
+    }
+}
+
+/// Redis commands for `run`, and where their replies go if anywhere
+type Job = (
+    Vec<Command>,
+    Option<oneshot::Sender<Result<Vec<Reply>, String>>>,
+);
+
+#[derive(Debug)]
+pub struct Store {
+    config: StoreConfig,
+    instance: String,
+    local: Mutex<Local>,
+    /// Queue of the Redis connection, `None` with the memory backend
+    jobs: Option<mpsc::UnboundedSender<Job>>,
+    /// Redis connection and the other end of `jobs`, taken by `run`
+    redis: Mutex<Option<(Redis, mpsc::UnboundedReceiver<Job>)>>,
+}
+
+/// Check `[store]` before connecting to anything
+pub fn validate(config: &StoreConfig) -> Result<(), String> {
+    if config.ttl_secs == 0 {
+        return Err("store.ttl_secs must be at least 1".to_string());
+    }
+    if config.backend == StoreBackend::Redis {
+        Redis::new(&config.url, None)?;
+    }
+    Ok(())
+}
+
+impl Store {
+    /// `node` names the instance unless `store.instance` does
+    pub fn new(config: StoreConfig, node: Option<&str>) -> Result<Store, String> {
+        validate(&config)?;
+        let instance = config
+            .instance
+            .clone()
+            .or(node.map(str::to_string))
+            .unwrap_or_else(|| token(8));
+        let (jobs, redis) = match config.backend {
+            StoreBackend::Memory => (None, None),
+            StoreBackend::Redis => {
+                let redis = Redis::new(&config.url, config.password.clone())?;
+                let (jobs, jobs_rx) = mpsc::unbounded();
+                (Some(jobs), Some((redis, jobs_rx)))
+            }
+        };
+        Ok(Store {
+            config,
+            instance,
+            local: Mutex::new(Local::default()),
+            jobs,
+            redis: Mutex::new(redis),
+        })
+    }
+
+    /// Name of this instance in session keys
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    fn key(&self, kind: &str, name: &str) -> String {
+        format!("{}{}:{}", self.config.prefix, kind, name)
+    }
+
+    /// Record the session of new connection `id`, returns its resume token
+    pub fn open(&self, id: u64, user: Option<&str>, tenant: Option<&str>) -> String {
+        let token = token(16);
+        let session = Session {
+            instance: self.instance.clone(),
+            connection_id: id,
+            user: user.map(str::to_string),
+            tenant: tenant.map(str::to_string),
+            connected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        let mut commands = match self.jobs {
+            Some(_) => self.session_commands(&session),
+            None => Vec::new(),
+        };
+        let mut local = self.local.lock().unwrap();
+        if let Some(user) = user {
+            local.presence.remove(user);
+            if !local.has_session(user) {
+                commands.extend(self.transition(&mut local, user, PresenceState::Online));
+            }
+        }
+        local.sessions.insert(id, (token.clone(), session));
+        drop(local);
+        self.queue(commands);
+        token
+    }
+
+    /// End the session of closed connection `id`, keeping the last sequence
+    /// numbers of its `rooms` and its unfinished `deliveries` to resume
+    pub fn close(&self, id: u64, rooms: BTreeMap<String, u64>, deliveries: Vec<Delivery>) {
+        let mut local = self.local.lock().unwrap();
+        let Some((token, session)) = local.sessions.remove(&id) else {
+            return;
+        };
+        if let Some(ref user) = session.user {
+            local.presence.remove(user);
+        }
+        let resume = Resume {
+            user: session.user.clone(),
+            tenant: session.tenant.clone(),
+            rooms,
+            deliveries,
+        };
+        let mut commands = vec![cmd(&["DEL", &self.key("session", &session.name())])];
+        if let Some(ref user) = session.user {
+            let key = self.key("presence", user);
+            commands.push(cmd(&["ZREM", &key, &session.name()]));
+            if !local.has_session(user) {
+                commands.extend(self.transition(&mut local, user, PresenceState::Offline));
+            }
+        }
+        if self.config.resume_ttl_secs > 0 {
+            let json = serde_json::to_string(&resume).expect("resume");
+            let ttl = self.config.resume_ttl_secs.to_string();
+            commands.push(cmd(&[
+                "SET",
+                &self.key("resume", &token),
+                &json,
+                "EX",
+                &ttl,
+            ]));
+            let expires = Instant::now() + Duration::from_secs(self.config.resume_ttl_secs);
+            local.resume.insert(token, (expires, resume));
+        }
+        drop(local);
+        self.queue(commands);
+    }
+
+    /// Take the resumption state under `token`, each token works once
+    pub async fn resume(&self, token: &str) -> Result<Option<Resume>, String> {
+        let key = self.key("resume", token);
+        let local = self.local.lock().unwrap().resume.remove(token);
+        if let Some((expires, resume)) = local {
+            self.queue(vec![cmd(&["DEL", &key])]);
+            return Ok((expires > Instant::now()).then_some(resume));
+        }
+        if self.jobs.is_none() {
+            return Ok(None);
+        }
+        let reply = first(self.call(vec![cmd(&["GETDEL", &key])]).await?)?;
+        Ok(reply
+            .into_string()
+            .and_then(|json| serde_json::from_str(&json).ok()))
+    }
+
+    /// Live sessions of `user` on every instance, oldest first
+    pub async fn presence(&self, user: &str) -> Result<Vec<Session>, String> {
+        let cache = Duration::from_millis(self.config.cache_ms);
+        {
+            let local = self.local.lock().unwrap();
+            if self.jobs.is_none() {
+                let mut sessions: Vec<Session> = local
+                    .sessions
+                    .values()
+                    .map(|(_, session)| session)
+                    .filter(|session| session.user.as_deref() == Some(user))
+                    .cloned()
+                    .collect();
+                sessions.sort_by_key(|s| (s.connected_at, s.connection_id));
+                return Ok(sessions);
+            }
+            match local.presence.get(user) {
+                Some((read, sessions)) if read.elapsed() < cache => return Ok(sessions.clone()),
+                _ => {}
+            }
+        }
+        let now = unix_ms().to_string();
+        let range = cmd(&["ZRANGEBYSCORE", &self.key("presence", user), &now, "+inf"]);
+        let names: Vec<String> = first(self.call(vec![range]).await?)?
+            .into_array()
+            .into_iter()
+            .filter_map(Reply::into_string)
+            .collect();
+        let mut sessions: Vec<Session> = match names.is_empty() {
+            true => Vec::new(),
+            false => {
+                let mut mget = cmd(&["MGET"]);
+                mget.extend(names.iter().map(|name| self.key("session", name)));
+                first(self.call(vec![mget]).await?)?
+                    .into_array()
+                    .into_iter()
+                    .filter_map(Reply::into_string)
+                    .filter_map(|json| serde_json::from_str(&json).ok())
+                    .collect()
+            }
+        };
+        sessions.sort_by(|a, b| {
+            (a.connected_at, &a.instance, a.connection_id).cmp(&(
+                b.connected_at,
+                &b.instance,
+                b.connection_id,
+            ))
+        });
+        if !cache.is_zero() {
+            let mut local = self.local.lock().unwrap();
+            local
+                .presence
+                .insert(user.to_string(), (Instant::now(), sessions.clone()));
+        }
+        Ok(sessions)
+    }
+
+    /// Whether last-seen times are recorded
+    pub fn records_last_seen(&self) -> bool {
+        self.config.last_seen.is_some()
+    }
+
+    /// Record that `user` came online or went offline here, returns the
+    /// commands writing it
+    fn transition(&self, local: &mut Local, user: &str, state: PresenceState) -> Vec<Command> {
+        let Some(ref config) = self.config.last_seen else {
+            return Vec::new();
+        };
+        let transition = Transition {
+            state,
+            at: unix_ms(),
+            instance: self.instance.clone(),
+        };
+        if self.jobs.is_none() {
+            local.seen.insert(user.to_string(), transition.at);
+            let transitions = local.transitions.entry(user.to_string()).or_default();
+            transitions.push_front(transition);
+            transitions.truncate(config.transitions);
+            return Vec::new();
+        }
+        let mut commands = self.seen_commands(user, transition.at);
+        if config.transitions > 0 {
+            let key = self.key("transitions", user);
+            let json = serde_json::to_string(&transition).expect("transition");
+            let last = (config.transitions - 1).to_string();
+            commands.push(cmd(&["LPUSH", &key, &json]));
+            commands.push(cmd(&["LTRIM", &key, "0", &last]));
+            if config.ttl_secs > 0 {
+                commands.push(cmd(&["EXPIRE", &key, &config.ttl_secs.to_string()]));
+            }
+        }
+        commands
+    }
+
+    /// Commands writing that `user` was seen at `at`
+    fn seen_commands(&self, user: &str, at: u64) -> Vec<Command> {
+        let ttl = self.config.last_seen.as_ref().map_or(0, |c| c.ttl_secs);
+        let mut set = cmd(&["SET", &self.key("seen", user), &at.to_string()]);
+        if ttl > 0 {
+            set.extend(["EX".to_string(), ttl.to_string()]);
+        }
+        vec![set]
+    }
+
+    /// When `user` was last seen, on any instance
+    pub async fn last_seen(&self, user: &str) -> Result<LastSeen, String> {
+        if !self.presence(user).await?.is_empty() {
+            return Ok(LastSeen {
+                online: true,
+                last_seen: Some(unix_ms()),
+            });
+        }
+        let last_seen = match self.jobs {
+            None => self.local.lock().unwrap().seen.get(user).copied(),
+            Some(_) => first(
+                self.call(vec![cmd(&["GET", &self.key("seen", user)])])
+                    .await?,
+            )?
+            .into_string()
+            .and_then(|at| at.parse().ok()),
+        };
+        Ok(LastSeen {
+            online: false,
+            last_seen,
+        })
+    }
+
+    /// Latest `limit` presence changes of `user`, newest first
+    pub async fn transitions(&self, user: &str, limit: usize) -> Result<Vec<Transition>, String> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        if self.jobs.is_none() {
+            let local = self.local.lock().unwrap();
+            let transitions = local.transitions.get(user).into_iter().flatten();
+            return Ok(transitions.take(limit).cloned().collect());
+        }
+        let key = self.key("transitions", user);
+        let range = cmd(&["LRANGE", &key, "0", &(limit - 1).to_string()]);
+        Ok(first(self.call(vec![range]).await?)?
+            .into_array()
+            .into_iter()
+            .filter_map(Reply::into_string)
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect())
+    }
+
+    /// Commands writing `session` with a new expiry
+    fn session_commands(&self, session: &Session) -> Vec<Command> {
+        let name = session.name();
+        let ttl = self.config.ttl_secs;
+        let json = serde_json::to_string(session).expect("session");
+        let key = self.key("session", &name);
+        let mut commands = vec![cmd(&["SET", &key, &json, "EX", &ttl.to_string()])];
+        if let Some(ref user) = session.user {
+            let key = self.key("presence", user);
+            let now = unix_ms();
+            let expires = (now + ttl * 1000).to_string();
+            commands.push(cmd(&["ZADD", &key, &expires, &name]));
+            commands.push(cmd(&["ZREMRANGEBYSCORE", &key, "-inf", &now.to_string()]));
+            commands.push(cmd(&["PEXPIRE", &key, &(ttl * 1000).to_string()]));
+        }
+        commands
+    }
+
+    /// Drop expired resumption state and cached presence, returns the
+    /// commands writing all sessions of this instance again, and that their
+    /// users are still seen
+    fn refresh(&self) -> Vec<Command> {
+        let now = Instant::now();
+        let cache = Duration::from_millis(self.config.cache_ms);
+        let mut local = self.local.lock().unwrap();
+        local.resume.retain(|_, (expires, _)| *expires > now);
+        local.presence.retain(|_, (read, _)| read.elapsed() < cache);
+        if self.jobs.is_none() {
+            return Vec::new();
+        }
+        let mut commands: Vec<Command> = local
+            .sessions
+            .values()
+            .flat_map(|(_, session)| self.session_commands(session))
+            .collect();
+        if self.config.last_seen.is_some() {
+            let now = unix_ms();
+            let users: BTreeSet<&str> = local
+                .sessions
+                .values()
+                .filter_map(|(_, session)| session.user.as_deref())
+                .collect();
+            for user in users {
+                commands.extend(self.seen_commands(user, now));
+            }
+        }
+        commands
+    }
+
+    fn queue(&self, commands: Vec<Command>) {
+        if let Some(ref jobs) = self.jobs {
+            let _ = jobs.unbounded_send((commands, None));
+        }
+    }
+
+    async fn call(&self, commands: Vec<Command>) -> Result<Vec<Reply>, String> {
+        let stopped = || "session store stopped".to_string();
+        let jobs = self.jobs.as_ref().ok_or_else(stopped)?;
+        let (tx, rx) = oneshot::channel();
+        jobs.unbounded_send((commands, Some(tx)))
+            .map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+}
+
+/// Reply to the only command of a batch
+fn first(replies: Vec<Reply>) -> Result<Reply, String> {
+    match replies.into_iter().next() {
+        Some(Reply::Error(e)) => Err(e),
+        Some(reply) => Ok(reply),
+        None => Ok(Reply::Nil),
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Random hex of `len` bytes
+fn token(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system randomness");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Send queued commands to Redis and refresh this instance's sessions every
+/// third of `ttl_secs`
+pub async fn run(store: Arc<Store>) {
+    let task = TaskHandle::register("store", None);
+    let mut redis = store.redis.lock().unwrap().take();
+    let interval = Duration::from_millis(store.config.ttl_secs * 1000 / 3);
+    let mut next = Instant::now() + interval;
+    let mut failing = false;
+    loop {
+        task.set_state("waiting");
+        let wait = next.saturating_duration_since(Instant::now());
+        let job = match redis {
+            Some((_, ref mut jobs)) => match select(Box::pin(time::sleep(wait)), jobs.next()).await
+            {
+                Either::Left(_) => None,
+                Either::Right((Some(job), _)) => Some(job),
+                Either::Right((None, _)) => return,
+            },
+            None => {
+                time::sleep(wait).await;
+                None
+            }
+        };
+        let (commands, reply) = match job {
+            Some(job) => job,
+            None if Instant::now() >= next => {
+                next = Instant::now() + interval;
+                (store.refresh(), None)
+            }
+            None => continue,
+        };
+        let Some((ref mut conn, _)) = redis else {
+            continue;
+        };
+        if commands.is_empty() {
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok(Vec::new()));
+            }
+            continue;
+        }
+        task.set_state("sending");
+        let result = conn.run(&commands).await;
+        match result {
+            Ok(_) if failing => {
+                failing = false;
+                log::info!("Session store reached redis again");
+            }
+            Ok(_) => {}
+            Err(ref e) => {
+                METRICS.store_errors_total.inc();
+                if !failing {
+                    failing = true;
+                    log::warn!("Session store cannot write to redis: {}", e);
+                }
+            }
+        }
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
+    }
+}
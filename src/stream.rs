@@ -0,0 +1,89 @@
+//! Replies streamed in parts.
+//!
+//! `history.fetch` and `history.search` with `"stream": true` are answered
+//! with all pages to the end instead of one, in parts carrying the request's
+//! `id` and their number, then `$end`:
+//!
+//! ```json
+//! {"type": "history.fetch", "id": "7", "payload": {"room": "lobby", "after": 0, "stream": true}}
+//! {"type": "history.fetch", "id": "7", "part": 0, "payload": {"room": "lobby", "messages": [...], "more": true}}
+//! {"type": "history.fetch", "id": "7", "part": 1, "payload": {"room": "lobby", "messages": [...], "more": false}}
+//! {"type": "$end", "id": "7", "payload": {"type": "history.fetch", "parts": 2}}
+//! ```
+//!
+//! A stream that fails ends with an `error` carrying the `id` instead of
+//! `$end`. Parts wait while the connection has half its queue or more
+//! queued, so a stream does not get other messages dropped. A stream is a
+//! handler in flight, see `inflight`: it has a timeout and may be cancelled.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ntex::time;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::events::{self, Event};
+use crate::hub::Hub;
+use crate::inflight::Ticket;
+use crate::protocol;
+
+/// How long a part waits before looking at the queue again
+const BACKLOG_WAIT: Duration = Duration::from_millis(20);
+
+/// Send what `next` yields until it yields `None` as the parts of the
+/// reply of `kind` to request `id`, then `$end`
+pub async fn run<P, F>(
+    hub: Arc<Hub>,
+    conn: u64,
+    kind: &'static str,
+    id: Option<String>,
+    ticket: Ticket,
+    next: F,
+) where
+    P: Serialize,
+    F: FnMut() -> Result<Option<P>, protocol::Error>,
+{
+    let id = id.as_deref();
+    let reply = match ticket.run(parts(&hub, conn, kind, id, next)).await {
+        Ok(Ok(Some(parts))) => {
+            protocol::message("$end", id, &json!({ "type": kind, "parts": parts }))
+        }
+        // the connection is gone
+        Ok(Ok(None)) => return,
+        Ok(Err(err)) | Err(err) => {
+            log::debug!("Connection {}: {:?}", conn, err);
+            events::emit(Event::Rejected {
+                id: conn,
+                code: err.code,
+            });
+            err.to_message(id)
+        }
+    };
+    hub.send(conn, reply);
+}
+
+/// Send the parts, returns how many or `None` once the connection is gone
+async fn parts<P, F>(
+    hub: &Hub,
+    conn: u64,
+    kind: &str,
+    id: Option<&str>,
+    mut next: F,
+) -> Result<Option<u64>, protocol::Error>
+where
+    P: Serialize,
+    F: FnMut() -> Result<Option<P>, protocol::Error>,
+{
+    let mut sent = 0;
+    while let Some(payload) = next()? {
+        while hub.backlogged(conn) {
+            time::sleep(BACKLOG_WAIT).await;
+        }
+        if !hub.send(conn, protocol::part(kind, id, sent, &payload)) {
+            return Ok(None);
+        }
+        sent += 1;
+    }
+    Ok(Some(sent))
+}
@@ -0,0 +1,467 @@
+//! Read-only copies of a connection's frames, for debugging in production.
+//!
+//! `GET /admin/connections/{id}/tap` upgrades to a websocket that gets a
+//! text message for each frame the connection receives and sends, until
+//! either closes:
+//!
+//! ```json
+//! {"dir": "in", "at": 1700000000000, "kind": "text", "size": 61, "data": "{\"type\":\"auth\",\"payload\":{\"token\":\"[redacted]\"}}"}
+//! {"dir": "out", "at": 1700000000002, "kind": "binary", "size": 4096, "base64": "AAEC...", "truncated": true}
+//! ```
+//!
+//! Query parameters narrow it down: `direction` (`in`, `out` or `both`),
+//! `sample`, the fraction of frames copied, and `max_bytes` of each frame's
+//! data, at most `tap.max_bytes`. Text frames that are JSON have the values
+//! of the fields named in `tap.redact` replaced, at any depth, then go
+//! through the redactors added with `add_redactor`; other text is copied as
+//! is. Binary frames of a `?framing=packed` connection carry protocol
+//! messages too: a copy of one has their text, redacted the same way, in
+//! `records` rather than the frame in `base64`. A tap that cannot keep up loses frames rather than holding up the
+//! connection, the next copy says how many with `dropped`.
+//!
+//! A connection has at most `tap.max_taps`; copies are made on the
+//! connection's worker, so only while a tap is open.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{ready, select, Either};
+use futures::StreamExt;
+use nanorand::{Rng, WyRand};
+use ntex::rt;
+use ntex::service::{fn_factory_with_config, fn_service};
+use ntex::util::Bytes;
+use ntex::web::{self, types::Path, types::Query, types::State, ws, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::admin::authorize;
+use crate::config::{Config, TapConfig};
+use crate::framing::{Framing, Unpack};
+use crate::history::now_millis;
+use crate::hub::Hub;
+
+/// Copies waiting for a tap's websocket, more are dropped
+const QUEUE: usize = 256;
+
+/// Value of a redacted field
+const REDACTED: &str = "[redacted]";
+
+/// Way a frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+    Both,
+}
+
+/// Hides what the copies of JSON frames must not show
+pub trait Redact: Send + Sync {
+    fn redact(&self, dir: Direction, data: &mut Value);
+}
+
+static REDACTORS: RwLock<Vec<Arc<dyn Redact>>> = RwLock::new(Vec::new());
+
+/// Run `redactor` on the copies of every tap
+pub fn add_redactor(redactor: Arc<dyn Redact>) {
+    REDACTORS.write().unwrap().push(redactor);
+}
+
+/// What a tap asks for, the query of its websocket
+#[derive(Debug, Clone, Deserialize)]
+pub struct TapOptions {
+    #[serde(default = "default_direction")]
+    pub direction: Direction,
+    #[serde(default = "default_sample")]
+    pub sample: f64,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+}
+
+fn default_direction() -> Direction {
+    Direction::Both
+}
+
+fn default_sample() -> f64 {
+    1.0
+}
+
+fn default_max_bytes() -> usize {
+    1024
+}
+
+impl TapOptions {
+    pub fn validate(&self, config: &TapConfig) -> Result<(), String> {
+        if !(self.sample > 0.0 && self.sample <= 1.0) {
+            return Err(format!(
+                "sample must be above 0 and at most 1, not {}",
+                self.sample
+            ));
+        }
+        if self.max_bytes > config.max_bytes {
+            return Err(format!("max_bytes is at most {}", config.max_bytes));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum TapError {
+    /// The connection has `tap.max_taps` already
+    Full,
+}
+
+#[derive(Debug)]
+struct Tap {
+    tx: mpsc::Sender<String>,
+    options: TapOptions,
+    redact: Arc<[String]>,
+    /// Copies lost since the last one sent
+    dropped: u64,
+}
+
+impl Tap {
+    /// Copy of the frame with `data` if the tap wants it, `false` once
+    /// the tap closed
+    fn copy(&mut self, dir: Direction, kind: &str, data: &[u8], framing: Framing) -> bool {
+        if self.tx.is_closed() {
+            return false;
+        }
+        if self.options.direction != Direction::Both && self.options.direction != dir {
+            return true;
+        }
+        if self.options.sample < 1.0
+            && WyRand::new().generate_range(0..1_000_000u32) as f64 >= self.options.sample * 1e6
+        {
+            return true;
+        }
+        let mut copy = Map::new();
+        copy.insert("dir".to_string(), json!(dir));
+        copy.insert("at".to_string(), json!(now_millis()));
+        copy.insert("kind".to_string(), json!(kind));
+        copy.insert("size".to_string(), json!(data.len()));
+        let max = self.options.max_bytes;
+        let truncated = match kind {
+            "text" => {
+                let text = self.redacted(dir, data);
+                let end = floor_char_boundary(&text, max);
+                copy.insert("data".to_string(), json!(text[..end]));
+                end < text.len()
+            }
+            "binary" if framing == Framing::Packed => {
+                let mut records = Vec::new();
+                let mut left = max;
+                let mut truncated = false;
+                for record in Unpack::new(Bytes::copy_from_slice(data)) {
+                    // the rest could be anything, never copied unredacted
+                    let Ok(record) = record else {
+                        truncated = true;
+                        break;
+                    };
+                    let text = self.redacted(dir, &record);
+                    let end = floor_char_boundary(&text, left);
+                    records.push(json!(text[..end]));
+                    left -= end;
+                    if end < text.len() {
+                        truncated = true;
+                        break;
+                    }
+                }
+                copy.insert("records".to_string(), json!(records));
+                truncated
+            }
+            "binary" => {
+                let end = data.len().min(max);
+                copy.insert("base64".to_string(), json!(STANDARD.encode(&data[..end])));
+                end < data.len()
+            }
+            _ => false,
+        };
+        if truncated {
+            copy.insert("truncated".to_string(), json!(true));
+        }
+        if self.dropped > 0 {
+            copy.insert("dropped".to_string(), json!(self.dropped));
+        }
+        match self.tx.try_send(Value::Object(copy).to_string()) {
+            Ok(()) => self.dropped = 0,
+            Err(e) if e.is_disconnected() => return false,
+            Err(_) => self.dropped += 1,
+        }
+        true
+    }
+
+    /// Text of `data` with the fields to hide replaced
+    fn redacted(&self, dir: Direction, data: &[u8]) -> String {
+        let text = String::from_utf8_lossy(data);
+        let Ok(mut value) = serde_json::from_str::<Value>(&text) else {
+            return text.into_owned();
+        };
+        redact_fields(&mut value, &self.redact);
+        for redactor in REDACTORS.read().unwrap().iter() {
+            redactor.redact(dir, &mut value);
+        }
+        value.to_string()
+    }
+}
+
+fn redact_fields(value: &mut Value, names: &[String]) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                match names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                    true => *value = json!(REDACTED),
+                    false => redact_fields(value, names),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_fields(item, names)),
+        _ => {}
+    }
+}
+
+/// Largest index at most `max` on a char boundary of `s`
+fn floor_char_boundary(s: &str, max: usize) -> usize {
+    if max >= s.len() {
+        return s.len();
+    }
+    (0..=max)
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// Taps of a connection
+#[derive(Debug, Default)]
+pub struct Taps {
+    /// Number of taps, checked before taking the lock
+    open: AtomicUsize,
+    taps: Mutex<Vec<Tap>>,
+}
+
+impl Taps {
+    /// Open a tap, returns the copies it gets
+    pub fn open(
+        &self,
+        config: &TapConfig,
+        options: TapOptions,
+    ) -> Result<mpsc::Receiver<String>, TapError> {
+        let mut taps = self.taps.lock().unwrap();
+        taps.retain(|tap| !tap.tx.is_closed());
+        if taps.len() >= config.max_taps {
+            return Err(TapError::Full);
+        }
+        let (tx, rx) = mpsc::channel(QUEUE);
+        taps.push(Tap {
+            tx,
+            options,
+            redact: config.redact.clone().into(),
+            dropped: 0,
+        });
+        self.open.store(taps.len(), Ordering::Relaxed);
+        Ok(rx)
+    }
+
+    /// Copy a frame received from the client
+    pub fn inbound(&self, frame: &ws::Frame, framing: Framing) {
+        if self.open.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let (kind, data): (_, &[u8]) = match frame {
+            ws::Frame::Text(data) => ("text", data),
+            ws::Frame::Binary(data) => ("binary", data),
+            ws::Frame::Ping(data) => ("ping", data),
+            ws::Frame::Pong(data) => ("pong", data),
+            ws::Frame::Continuation(item) => ("continuation", item_data(item)),
+            ws::Frame::Close(_) => ("close", &[]),
+        };
+        self.copy(Direction::In, kind, data, framing);
+    }
+
+    /// Copy a message sent to the client
+    pub fn outbound(&self, msg: &ws::Message, framing: Framing) {
+        if self.open.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let (kind, data): (_, &[u8]) = match msg {
+            ws::Message::Text(text) => ("text", text.as_bytes()),
+            ws::Message::Binary(data) => ("binary", data),
+            ws::Message::Ping(data) => ("ping", data),
+            ws::Message::Pong(data) => ("pong", data),
+            ws::Message::Continuation(item) => ("continuation", item_data(item)),
+            ws::Message::Close(_) => ("close", &[]),
+        };
+        self.copy(Direction::Out, kind, data, framing);
+    }
+
+    /// End the taps, the connection closed
+    pub fn close(&self) {
+        self.taps.lock().unwrap().clear();
+        self.open.store(0, Ordering::Relaxed);
+    }
+
+    fn copy(&self, dir: Direction, kind: &str, data: &[u8], framing: Framing) {
+        let mut taps = self.taps.lock().unwrap();
+        taps.retain_mut(|tap| tap.copy(dir, kind, data, framing));
+        self.open.store(taps.len(), Ordering::Relaxed);
+    }
+}
+
+fn item_data(item: &ntex::ws::Item) -> &[u8] {
+    match item {
+        ntex::ws::Item::FirstText(data)
+        | ntex::ws::Item::FirstBinary(data)
+        | ntex::ws::Item::Continue(data)
+        | ntex::ws::Item::Last(data) => data,
+    }
+}
+
+/// `GET /admin/connections/{id}/tap`, websocket getting copies of the
+/// connection's frames
+pub async fn index(
+    req: HttpRequest,
+    config: State<Arc<Config>>,
+    hub: State<Arc<Hub>>,
+    id: Path<u64>,
+    options: Query<TapOptions>,
+) -> Result<HttpResponse, web::Error> {
+    if let Err(res) = authorize(&req, &config) {
+        return Ok(res);
+    }
+    let options = options.into_inner();
+    if let Err(e) = options.validate(&config.tap) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let Some(stats) = hub.stats(*id) else {
+        return Ok(HttpResponse::NotFound().body("no such connection"));
+    };
+    let rx = match stats.taps().open(&config.tap, options) {
+        Ok(rx) => rx,
+        Err(TapError::Full) => {
+            return Ok(HttpResponse::TooManyRequests().body("connection has too many taps"))
+        }
+    };
+    log::info!("Connection {} tapped", id);
+    // the receiver goes with the tap's websocket
+    let rx = Arc::new(Mutex::new(Some(rx)));
+    ws::start(
+        req,
+        fn_factory_with_config(move |sink: ws::WsSink| {
+            let (closed_tx, closed_rx) = oneshot::channel::<()>();
+            if let Some(rx) = rx.lock().unwrap().take() {
+                rt::spawn(forward(sink, rx, closed_rx));
+            }
+            let service = fn_service(|frame| {
+                let reply = match frame {
+                    ws::Frame::Ping(msg) => Some(ws::Message::Pong(msg)),
+                    ws::Frame::Close(reason) => Some(ws::Message::Close(reason)),
+                    _ => None,
+                };
+                ready(Ok::<_, io::Error>(reply))
+            })
+            .on_shutdown(move || {
+                let _ = closed_tx.send(());
+            });
+            ready(Ok::<_, web::Error>(service))
+        }),
+    )
+    .await
+}
+
+/// Send the copies to the tap's websocket until either side closes
+async fn forward(
+    sink: ws::WsSink,
+    mut rx: mpsc::Receiver<String>,
+    mut closed: oneshot::Receiver<()>,
+) {
+    loop {
+        let copy = match select(rx.next(), &mut closed).await {
+            Either::Left((Some(copy), _)) => copy,
+            // the connection closed
+            Either::Left((None, _)) => {
+                let close = ws::Message::Close(Some(ws::CloseCode::Normal.into()));
+                let _ = sink.send(close).await;
+                return;
+            }
+            Either::Right(_) => return,
+        };
+        if sink.send(ws::Message::Text(copy.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::Packer;
+
+    fn tap() -> (Taps, mpsc::Receiver<String>) {
+        let taps = Taps::default();
+        let options = TapOptions {
+            direction: Direction::Both,
+            sample: 1.0,
+            max_bytes: 1024,
+        };
+        let rx = taps.open(&TapConfig::default(), options).unwrap();
+        (taps, rx)
+    }
+
+    fn next_copy(rx: &mut mpsc::Receiver<String>) -> Value {
+        serde_json::from_str(&rx.try_recv().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn packed_records_are_redacted() {
+        let (taps, mut rx) = tap();
+        let mut packer = Packer::new();
+        packer.push(br#"{"type":"auth.refresh","payload":{"token":"s3cr3t"}}"#);
+        packer.push(b"not json");
+        let frame = ws::Frame::Binary(packer.finish());
+        taps.inbound(&frame, Framing::Packed);
+        let copy = next_copy(&mut rx);
+        assert_eq!(copy["kind"], "binary");
+        assert_eq!(copy.get("base64"), None);
+        let records = copy["records"].as_array().unwrap();
+        let auth: Value = serde_json::from_str(records[0].as_str().unwrap()).unwrap();
+        assert_eq!(auth["payload"]["token"], REDACTED);
+        assert_eq!(records[1], "not json");
+        assert!(!copy.to_string().contains("s3cr3t"));
+
+        let mut packer = Packer::new();
+        packer.push(br#"{"payload":{"token":"s3cr3t"}}"#);
+        taps.outbound(&ws::Message::Binary(packer.finish()), Framing::Packed);
+        assert!(!next_copy(&mut rx).to_string().contains("s3cr3t"));
+    }
+
+    #[test]
+    fn broken_packed_frames_are_not_copied() {
+        let (taps, mut rx) = tap();
+        let frame = ws::Frame::Binary(Bytes::from_static(b"\0\0\0\x40{\"token\":\"s3cr3t\"}"));
+        taps.inbound(&frame, Framing::Packed);
+        let copy = next_copy(&mut rx);
+        assert_eq!(copy["records"], json!([]));
+        assert_eq!(copy["truncated"], true);
+        assert!(!copy.to_string().contains("s3cr3t"));
+    }
+
+    #[test]
+    fn plain_binary_is_copied() {
+        let (taps, mut rx) = tap();
+        taps.inbound(
+            &ws::Frame::Binary(Bytes::from_static(b"\x01\x02")),
+            Framing::Plain,
+        );
+        assert_eq!(next_copy(&mut rx)["base64"], "AQI=");
+        taps.inbound(
+            &ws::Frame::Text(Bytes::from_static(br#"{"password":1}"#)),
+            Framing::Plain,
+        );
+        assert_eq!(next_copy(&mut rx)["data"], r#"{"password":"[redacted]"}"#);
+    }
+}
@@ -0,0 +1,121 @@
+//! Registry of long-running internal tasks, for `GET /admin/tasks`.
+//!
+//! Tasks register themselves and get a [`TaskHandle`]; dropping the handle
+//! removes the entry, so a task stuck in some state stays visible.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use serde::Serialize;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| Registry {
+        next_id: AtomicU64::new(1),
+        tasks: Mutex::new(BTreeMap::new()),
+    })
+}
+
+struct Registry {
+    next_id: AtomicU64,
+    tasks: Mutex<BTreeMap<u64, Entry>>,
+}
+
+struct Entry {
+    kind: &'static str,
+    conn_id: Option<u64>,
+    state: &'static str,
+    queue_depth: usize,
+    started: Instant,
+    last_active: Instant,
+}
+
+/// Point-in-time view of a task
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub kind: &'static str,
+    pub conn_id: Option<u64>,
+    pub state: &'static str,
+    pub queue_depth: usize,
+    pub age_ms: u128,
+    pub idle_ms: u128,
+}
+
+/// Registration of a running task, unregisters on drop
+#[derive(Debug)]
+pub struct TaskHandle {
+    id: u64,
+}
+
+impl TaskHandle {
+    /// Register new task
+    pub fn register(kind: &'static str, conn_id: Option<u64>) -> TaskHandle {
+        let reg = registry();
+        let id = reg.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        reg.tasks.lock().unwrap().insert(
+            id,
+            Entry {
+                kind,
+                conn_id,
+                state: "starting",
+                queue_depth: 0,
+                started: now,
+                last_active: now,
+            },
+        );
+        TaskHandle { id }
+    }
+
+    /// Update task state, also marks task as active
+    pub fn set_state(&self, state: &'static str) {
+        self.update(|e| e.state = state);
+    }
+
+    /// Update number of items waiting to be processed by the task
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.update(|e| e.queue_depth = depth);
+    }
+
+    /// Mark task as active without changing its state
+    pub fn touch(&self) {
+        self.update(|_| ());
+    }
+
+    fn update<F: FnOnce(&mut Entry)>(&self, f: F) {
+        if let Some(e) = registry().tasks.lock().unwrap().get_mut(&self.id) {
+            e.last_active = Instant::now();
+            f(e);
+        }
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        registry().tasks.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Snapshot of all registered tasks
+pub fn list() -> Vec<TaskInfo> {
+    let now = Instant::now();
+    registry()
+        .tasks
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, e)| TaskInfo {
+            id: *id,
+            kind: e.kind,
+            conn_id: e.conn_id,
+            state: e.state,
+            queue_depth: e.queue_depth,
+            age_ms: now.duration_since(e.started).as_millis(),
+            idle_ms: now.duration_since(e.last_active).as_millis(),
+        })
+        .collect()
+}
@@ -0,0 +1,189 @@
+//! TLS setup for `tls = true` listeners.
+//!
+//! The certificate is served through `CertResolver` so a stapled OCSP
+//! response (`tls.ocsp_path`) can be swapped in without a restart.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ntex::time;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::version::{TLS12, TLS13};
+use rustls::{Certificate, PrivateKey, ServerConfig, SupportedCipherSuite};
+use rustls::{SupportedProtocolVersion, ALL_CIPHER_SUITES, ALL_KX_GROUPS};
+use rustls_pemfile::{certs, rsa_private_keys};
+
+use crate::config::TlsConfig;
+use crate::tasks::TaskHandle;
+
+/// Serves the one configured certificate, with the current OCSP response
+pub struct CertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn set_ocsp(&self, ocsp: Vec<u8>) {
+        let mut key = self.key.write().unwrap();
+        let mut updated = CertifiedKey::clone(&key);
+        updated.ocsp = Some(ocsp);
+        *key = Arc::new(updated);
+    }
+
+    fn ocsp(&self) -> Option<Vec<u8>> {
+        self.key.read().unwrap().ocsp.clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// DER encoded OCSP response, as written by e.g. `openssl ocsp -respout`
+fn read_ocsp(path: &str) -> io::Result<Vec<u8>> {
+    let der = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    if der.is_empty() {
+        return Err(invalid(format!("{}: empty OCSP response", path)));
+    }
+    Ok(der)
+}
+
+/// Protocol versions by config name, `1.2` or `1.3`
+fn versions(names: &[String]) -> io::Result<Vec<&'static SupportedProtocolVersion>> {
+    if names.is_empty() {
+        return Err(invalid("tls.versions is empty".to_string()));
+    }
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            "1.2" => Ok(&TLS12),
+            "1.3" => Ok(&TLS13),
+            _ => Err(invalid(format!("unknown TLS version `{}`", name))),
+        })
+        .collect()
+}
+
+/// Cipher suites by IANA name, e.g. `TLS13_AES_256_GCM_SHA384`; all if empty
+fn cipher_suites(names: &[String]) -> io::Result<Vec<SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(ALL_CIPHER_SUITES.to_vec());
+    }
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|s| format!("{:?}", s.suite()) == *name)
+                .copied()
+                .ok_or_else(|| invalid(format!("unknown cipher suite `{}`", name)))
+        })
+        .collect()
+}
+
+/// Build rustls config from the configured certificate chain and RSA key
+pub fn server_config(config: &TlsConfig) -> io::Result<(ServerConfig, Arc<CertResolver>)> {
+    let (cert_chain, key) = load_pem(config)?;
+    build(config, cert_chain, key)
+}
+
+/// Build rustls config with a fresh self-signed certificate for `localhost`,
+/// for `--dev`
+pub fn self_signed(config: &TlsConfig) -> io::Result<(ServerConfig, Arc<CertResolver>)> {
+    let names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let cert = rcgen::generate_simple_self_signed(names).map_err(|e| invalid(e.to_string()))?;
+    let der = cert.serialize_der().map_err(|e| invalid(e.to_string()))?;
+    let key = PrivateKey(cert.serialize_private_key_der());
+    build(config, vec![Certificate(der)], key)
+}
+
+fn load_pem(config: &TlsConfig) -> io::Result<(Vec<Certificate>, PrivateKey)> {
+    let key = rsa_private_keys(&mut open(&config.key_path)?)?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| invalid(format!("{}: no RSA private key", config.key_path)))?;
+    let cert_chain: Vec<_> = certs(&mut open(&config.cert_path)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if cert_chain.is_empty() {
+        return Err(invalid(format!("{}: no certificates", config.cert_path)));
+    }
+    Ok((cert_chain, key))
+}
+
+fn build(
+    config: &TlsConfig,
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+) -> io::Result<(ServerConfig, Arc<CertResolver>)> {
+    let versions = versions(&config.versions)?;
+    let suites = cipher_suites(&config.cipher_suites)?;
+    // rustls only checks that some suite is usable, require one per version
+    for version in &versions {
+        if !suites.iter().any(|s| s.version() == *version) {
+            return Err(invalid(format!(
+                "no cipher suite configured for {:?}",
+                version.version
+            )));
+        }
+    }
+    let key = sign::any_supported_type(&key).map_err(|e| invalid(e.to_string()))?;
+    let mut key = CertifiedKey::new(cert_chain, key);
+    if let Some(ref path) = config.ocsp_path {
+        key.ocsp = Some(read_ocsp(path)?);
+    }
+    let resolver = Arc::new(CertResolver {
+        key: RwLock::new(Arc::new(key)),
+    });
+    let server_config = ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_kx_groups(&ALL_KX_GROUPS)
+        .with_protocol_versions(&versions)
+        .map_err(|e| invalid(e.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    Ok((server_config, resolver))
+}
+
+/// Re-read `tls.ocsp_path` periodically, so an external fetcher can renew the staple
+pub async fn refresh_ocsp(resolver: Arc<CertResolver>, config: TlsConfig) {
+    let path = match config.ocsp_path {
+        Some(path) => path,
+        None => return,
+    };
+    let task = TaskHandle::register("ocsp", None);
+    let interval = Duration::from_secs(config.ocsp_refresh_secs.max(1));
+    loop {
+        task.set_state("sleeping");
+        time::sleep(interval).await;
+        task.set_state("reading");
+        match read_ocsp(&path) {
+            Ok(der) if Some(&der) != resolver.ocsp().as_ref() => {
+                log::info!("Stapling renewed OCSP response from {}", path);
+                resolver.set_ocsp(der);
+            }
+            Ok(_) => (),
+            // keep stapling the previous response
+            Err(e) => log::warn!("Cannot refresh OCSP response: {}", e),
+        }
+    }
+}
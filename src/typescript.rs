@@ -0,0 +1,209 @@
+//! TypeScript client generation, see `--export-typescript`.
+//!
+//! Emits one interface per registered message type, unions of client and
+//! server messages, and a thin client that correlates replies by `id`.
+
+use std::fmt::Write;
+
+use crate::protocol::{Direction, Layout, MessageType, MESSAGE_TYPES};
+use crate::schema::Schema;
+
+const CLIENT: &str = r#"
+type Handler<T> = (msg: T) => void;
+
+/** Thin client, replies are matched to requests by `id` */
+export class WssClient {
+  private ws: WebSocket;
+  private nextId = 1;
+  private pending = new Map<string, (msg: ServerMessage) => void>();
+  private streams = new Map<string, (msg: ServerMessage) => void>();
+  private handlers = new Map<string, Handler<any>[]>();
+
+  constructor(url: string) {
+    this.ws = new WebSocket(url);
+    this.ws.onmessage = (ev) => {
+      if (typeof ev.data !== "string") return;
+      const msg = JSON.parse(ev.data) as ServerMessage;
+      if (msg.id !== undefined && this.streams.has(msg.id)) {
+        this.streams.get(msg.id)!(msg);
+        return;
+      }
+      if (msg.id !== undefined && this.pending.has(msg.id)) {
+        this.pending.get(msg.id)!(msg);
+        this.pending.delete(msg.id);
+        return;
+      }
+      for (const h of this.handlers.get(msg.type) ?? []) h(msg);
+    };
+  }
+
+  /** Wait until the socket is open */
+  ready(): Promise<void> {
+    if (this.ws.readyState === WebSocket.OPEN) return Promise.resolve();
+    return new Promise((resolve, reject) => {
+      this.ws.addEventListener("open", () => resolve(), { once: true });
+      this.ws.addEventListener("error", (e) => reject(e), { once: true });
+    });
+  }
+
+  /** Send message without waiting for a reply */
+  send(msg: ClientMessage): void {
+    this.ws.send(JSON.stringify(msg));
+  }
+
+  /** Send message and resolve with the reply carrying the same id */
+  request(msg: ClientMessage): Promise<ServerMessage> {
+    const id = msg.id ?? String(this.nextId++);
+    return new Promise((resolve) => {
+      this.pending.set(id, resolve);
+      this.send({ ...msg, id });
+    });
+  }
+
+  /** Send message asking for a streamed reply, `onPart` gets each part;
+   * resolves on `$end`, rejects with the error ending the stream */
+  stream(msg: ClientMessage, onPart: Handler<ServerMessage>): Promise<void> {
+    const id = msg.id ?? String(this.nextId++);
+    return new Promise((resolve, reject) => {
+      this.streams.set(id, (reply) => {
+        if (reply.type !== "$end" && reply.type !== "error") return onPart(reply);
+        this.streams.delete(id);
+        if (reply.type === "$end") resolve();
+        else reject(reply);
+      });
+      this.send({ ...msg, id });
+    });
+  }
+
+  /** Subscribe to server messages of one type */
+  on<K extends ServerMessage["type"]>(
+    type: K,
+    handler: Handler<Extract<ServerMessage, { type: K }>>,
+  ): void {
+    const list = this.handlers.get(type) ?? [];
+    list.push(handler);
+    this.handlers.set(type, list);
+  }
+
+  close(): void {
+    this.ws.close();
+  }
+}
+"#;
+
+/// `auth.refresh` -> `AuthRefreshMessage`
+fn interface_name(msg: &MessageType) -> String {
+    let mut name = String::new();
+    for part in msg.name.split(['.', '_', '-', '$']) {
+        let mut chars = part.chars();
+        if let Some(c) = chars.next() {
+            name.extend(c.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    name.push_str("Message");
+    name
+}
+
+/// Inline TypeScript type for schema
+fn ts_type(schema: &Schema, indent: usize) -> String {
+    match schema {
+        Schema::Any => "unknown".to_string(),
+        Schema::String => "string".to_string(),
+        Schema::Integer | Schema::Number => "number".to_string(),
+        Schema::Boolean => "boolean".to_string(),
+        Schema::Enum(values) => values
+            .iter()
+            .map(|v| format!("{:?}", v))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Schema::Array(item) => format!("Array<{}>", ts_type(item, indent)),
+        Schema::Object(_) => {
+            let mut out = String::from("{\n");
+            fields(&mut out, schema, indent + 1);
+            let _ = write!(out, "{}}}", "  ".repeat(indent));
+            out
+        }
+    }
+}
+
+/// Write object fields, one per line
+fn fields(out: &mut String, schema: &Schema, indent: usize) {
+    if let Schema::Object(fields) = schema {
+        let pad = "  ".repeat(indent);
+        for f in fields.iter() {
+            if !f.doc.is_empty() {
+                let _ = writeln!(out, "{}/** {} */", pad, f.doc);
+            }
+            let opt = if f.required { "" } else { "?" };
+            let _ = writeln!(
+                out,
+                "{}{}{}: {};",
+                pad,
+                f.name,
+                opt,
+                ts_type(&f.schema, indent)
+            );
+        }
+    }
+}
+
+fn union<F: Fn(Direction) -> bool>(out: &mut String, name: &str, filter: F) {
+    let members: Vec<_> = MESSAGE_TYPES
+        .iter()
+        .filter(|m| filter(m.direction))
+        .map(interface_name)
+        .collect();
+    let _ = writeln!(
+        out,
+        "export type {} =\n  | {};\n",
+        name,
+        members.join("\n  | ")
+    );
+}
+
+/// Render TypeScript module with message types and client
+pub fn module() -> String {
+    let mut out = format!(
+        "// Generated by {} {} --export-typescript, do not edit.\n\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    for msg in MESSAGE_TYPES {
+        let _ = writeln!(out, "/** {} */", msg.summary);
+        let _ = writeln!(out, "export interface {} {{", interface_name(msg));
+        let _ = writeln!(out, "  type: {:?};", msg.name);
+        let _ = writeln!(out, "  /** Correlation id, copied from request to reply */");
+        let _ = writeln!(out, "  id?: string;");
+        if msg.direction != Direction::ClientToServer && msg.layout == Layout::Envelope {
+            let _ = writeln!(
+                out,
+                "  /** Number of the part of a streamed reply */\n  part?: number;"
+            );
+        }
+        if msg.direction != Direction::ServerToClient {
+            let _ = writeln!(
+                out,
+                "  /** Payload version, current is {} */\n  version?: number;",
+                msg.version()
+            );
+        }
+        match msg.layout {
+            Layout::Envelope => {
+                let _ = writeln!(out, "  payload?: {};", ts_type(&msg.schema, 1));
+            }
+            Layout::Flat => fields(&mut out, &msg.schema, 1),
+        }
+        let _ = writeln!(out, "}}\n");
+    }
+
+    union(&mut out, "ClientMessage", |d| {
+        d != Direction::ServerToClient
+    });
+    union(&mut out, "ServerMessage", |d| {
+        d != Direction::ClientToServer
+    });
+    out.push_str(CLIENT.trim_start());
+    out
+}
@@ -0,0 +1,13 @@
+//! UTF-8 validation for text payloads.
+
+use ntex::util::{ByteString, Bytes};
+
+/// Validate payload in place with SIMD and wrap it without copying
+pub fn to_bytestring(bytes: Bytes) -> Option<ByteString> {
+    if simdutf8::basic::from_utf8(&bytes).is_ok() {
+        // SAFETY: validated above
+        Some(unsafe { ByteString::from_bytes_unchecked(bytes) })
+    } else {
+        None
+    }
+}